@@ -19,7 +19,7 @@ impl Op {
     /// call this method on amoswap.w.aqrl will only return "amoswap.w"
     pub fn mnemonic(&self) -> &'static str {
         match *self {
-            Op::Illegal { .. } => "illegal",
+            Op::Illegal => "illegal",
             Op::Lb { .. } => "lb",
             Op::Lh { .. } => "lh",
             Op::Lw { .. } => "lw",
@@ -27,8 +27,8 @@ impl Op {
             Op::Lbu { .. } => "lbu",
             Op::Lhu { .. } => "lhu",
             Op::Lwu { .. } => "lwu",
-            Op::Fence { .. } => "fence",
-            Op::FenceI { .. } => "fence.i",
+            Op::Fence => "fence",
+            Op::FenceI => "fence.i",
             Op::Addi { .. } => "addi",
             Op::Slli { .. } => "slli",
             Op::Slti { .. } => "slti",
@@ -71,8 +71,8 @@ impl Op {
             Op::Bgeu { .. } => "bgeu",
             Op::Jalr { .. } => "jalr",
             Op::Jal { .. } => "jal",
-            Op::Ecall { .. } => "ecall",
-            Op::Ebreak { .. } => "ebreak",
+            Op::Ecall => "ecall",
+            Op::Ebreak => "ebreak",
             Op::Csrrw { .. } => "csrrw",
             Op::Csrrs { .. } => "csrrs",
             Op::Csrrc { .. } => "csrrc",
@@ -176,9 +176,9 @@ impl Op {
             Op::FmsubD { .. } => "fmsub.d",
             Op::FnmsubD { .. } => "fnmsub.d",
             Op::FnmaddD { .. } => "fnmadd.d",
-            Op::Mret { .. } => "mret",
-            Op::Sret { .. } => "sret",
-            Op::Wfi { .. } => "wfi",
+            Op::Mret => "mret",
+            Op::Sret => "sret",
+            Op::Wfi => "wfi",
             Op::SfenceVma { .. } => "sfence.vma",
         }
     }
@@ -461,11 +461,7 @@ struct Disasm<'a> {
 
 impl<'a> fmt::Display for Disasm<'a> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        if (self.pc & 0xFFFFFFFF) == self.pc {
-            write!(fmt, "PC: {:#16x}:       ", self.pc)?;
-        } else {
-            write!(fmt, "PC: {:#16x}:       ", self.pc)?;
-        }
+        write!(fmt, "PC: {:#16x}:       ", self.pc)?;
 
         if self.bits & 3 == 3 {
             write!(fmt, "{:08x}", self.bits)?;
@@ -474,6 +470,12 @@ impl<'a> fmt::Display for Disasm<'a> {
         }
 
         write!(fmt, "        ")?;
-        self.op.print(fmt, Some(self.pc))
+        // Branch and jump immediates are relative to `pc after - 4`.
+        let pc = if self.bits & 3 == 3 {
+            self.pc
+        } else {
+            self.pc.wrapping_sub(2)
+        };
+        self.op.print(fmt, Some(pc))
     }
 }
@@ -21,7 +21,10 @@ pub enum AccessType {
 pub struct PageWalkResult {
     /// The leaf PTE entry.
     pub pte: u64,
-    /// Size of this page. 4K page is 0, 2M page is 1, 1G page is 2.
+    /// Physical address of the leaf PTE, so that its A and D bits can be updated.
+    pub pte_addr: u64,
+    /// Size of this page. 4K page is 0, 2M page is 1, 1G page is 2, 512G page is 3, 256T page
+    /// is 4.
     pub granularity: u8,
 }
 
@@ -30,6 +33,7 @@ impl PageWalkResult {
     pub fn invalid() -> Self {
         PageWalkResult {
             pte: 0,
+            pte_addr: 0,
             granularity: 0,
         }
     }
@@ -38,6 +42,7 @@ impl PageWalkResult {
     pub fn from_4k_pte(pte: u64) -> Self {
         PageWalkResult {
             pte,
+            pte_addr: 0,
             granularity: 0,
         }
     }
@@ -51,27 +56,45 @@ impl PageWalkResult {
         let pte = self.pte | page_index_within_superpage << 10;
         Self {
             pte,
+            pte_addr: self.pte_addr,
             granularity: 0,
         }
     }
 }
 
-/// Walk the page table under SV39. We don't support SV48 at the moment.
+/// Number of page table levels for the translation mode in `satp`, or `None` for Bare and
+/// unsupported modes.
+pub fn levels(satp: u64) -> Option<u64> {
+    match satp >> 60 {
+        8 => Some(3),
+        9 => Some(4),
+        10 => Some(5),
+        _ => None,
+    }
+}
+
+/// Walk the page table under SV39, SV48 or SV57, as selected by the mode field of `satp`.
 pub fn walk_page(satp: u64, vpn: u64, mut read_mem: impl FnMut(u64) -> u64) -> PageWalkResult {
+    let levels = match levels(satp) {
+        Some(levels) => levels,
+        None => return PageWalkResult::invalid(),
+    };
+    let vpn_bits = levels * 9;
+
     // Check if the address is canonical.
-    if (((vpn << (64 - 27)) as i64) >> (64 - 27 - 12)) as u64 >> 12 != vpn {
+    if (((vpn << (64 - vpn_bits)) as i64) >> (64 - vpn_bits - 12)) as u64 >> 12 != vpn {
         return PageWalkResult::invalid();
     }
 
     let mut ppn = satp & ((1u64 << 44) - 1);
     let mut global = false;
 
-    for i in 0..3 {
-        let bits_left = 18 - i * 9;
+    for i in 0..levels {
+        let bits_left = vpn_bits - 9 - i * 9;
         let index = (vpn >> bits_left) & 511;
         let pte_addr = (ppn << 12) + index * 8;
         let mut pte = read_mem(pte_addr);
-        ppn = pte >> 10;
+        ppn = (pte >> 10) & ((1u64 << 44) - 1);
 
         // Check for invalid PTE
         if pte & PTE_V == 0 {
@@ -85,6 +108,10 @@ pub fn walk_page(satp: u64, vpn: u64, mut read_mem: impl FnMut(u64) -> u64) -> P
         if pte & (PTE_R | PTE_W | PTE_X) == PTE_W | PTE_X {
             return PageWalkResult::invalid();
         }
+        // Bits 63:54 are reserved for extensions we do not implement.
+        if pte >> 54 != 0 {
+            return PageWalkResult::invalid();
+        }
 
         // A global bit will cause the page to be global regardless if this is leaf.
         if pte & PTE_G != 0 {
@@ -106,7 +133,8 @@ pub fn walk_page(satp: u64, vpn: u64, mut read_mem: impl FnMut(u64) -> u64) -> P
         }
         return PageWalkResult {
             pte,
-            granularity: 2 - i,
+            pte_addr,
+            granularity: (levels - 1 - i) as u8,
         };
     }
 
@@ -114,6 +142,7 @@ pub fn walk_page(satp: u64, vpn: u64, mut read_mem: impl FnMut(u64) -> u64) -> P
     PageWalkResult::invalid()
 }
 
+#[allow(clippy::result_unit_err)]
 pub fn check_permission(pte: u64, access: AccessType, prv: u8, status: u64) -> Result<(), ()> {
     if pte & PTE_V == 0 {
         return Err(());
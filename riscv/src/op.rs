@@ -304,6 +304,75 @@ impl Op {
         }
     }
 
+    /// Whether the op belongs to the F or D extension, and so needs `mstatus.FS` enabled.
+    pub fn is_float(self) -> bool {
+        matches!(
+            self,
+            Op::Flw { .. }
+                | Op::Fsw { .. }
+                | Op::FaddS { .. }
+                | Op::FsubS { .. }
+                | Op::FmulS { .. }
+                | Op::FdivS { .. }
+                | Op::FsqrtS { .. }
+                | Op::FsgnjS { .. }
+                | Op::FsgnjnS { .. }
+                | Op::FsgnjxS { .. }
+                | Op::FminS { .. }
+                | Op::FmaxS { .. }
+                | Op::FcvtWS { .. }
+                | Op::FcvtWuS { .. }
+                | Op::FcvtLS { .. }
+                | Op::FcvtLuS { .. }
+                | Op::FmvXW { .. }
+                | Op::FclassS { .. }
+                | Op::FeqS { .. }
+                | Op::FltS { .. }
+                | Op::FleS { .. }
+                | Op::FcvtSW { .. }
+                | Op::FcvtSWu { .. }
+                | Op::FcvtSL { .. }
+                | Op::FcvtSLu { .. }
+                | Op::FmvWX { .. }
+                | Op::FmaddS { .. }
+                | Op::FmsubS { .. }
+                | Op::FnmsubS { .. }
+                | Op::FnmaddS { .. }
+                | Op::Fld { .. }
+                | Op::Fsd { .. }
+                | Op::FaddD { .. }
+                | Op::FsubD { .. }
+                | Op::FmulD { .. }
+                | Op::FdivD { .. }
+                | Op::FsqrtD { .. }
+                | Op::FsgnjD { .. }
+                | Op::FsgnjnD { .. }
+                | Op::FsgnjxD { .. }
+                | Op::FminD { .. }
+                | Op::FmaxD { .. }
+                | Op::FcvtSD { .. }
+                | Op::FcvtDS { .. }
+                | Op::FcvtWD { .. }
+                | Op::FcvtWuD { .. }
+                | Op::FcvtLD { .. }
+                | Op::FcvtLuD { .. }
+                | Op::FmvXD { .. }
+                | Op::FclassD { .. }
+                | Op::FeqD { .. }
+                | Op::FltD { .. }
+                | Op::FleD { .. }
+                | Op::FcvtDW { .. }
+                | Op::FcvtDWu { .. }
+                | Op::FcvtDL { .. }
+                | Op::FcvtDLu { .. }
+                | Op::FmvDX { .. }
+                | Op::FmaddD { .. }
+                | Op::FmsubD { .. }
+                | Op::FnmsubD { .. }
+                | Op::FnmaddD { .. }
+        )
+    }
+
     /// Retrieve the RD, RS1, RS2 from the op. If the operation does not use RD, RS1 or RS2, 0 is returned.
     pub fn get_regs(self) -> (u8, u8, u8) {
         match self {
@@ -0,0 +1,835 @@
+use super::bus::{Bus, BusOperation};
+use super::error::OperationError;
+use core::ops::{BitOr, Range, RangeInclusive};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+pub const PAGE_SIZE: usize = 4096;
+
+/// Round `value` up to a page boundary, or `None` if that overflows.
+pub fn page_up(value: usize) -> Option<usize> {
+    value.checked_next_multiple_of(PAGE_SIZE)
+}
+
+/// Access rights of a mapping, numbered like `PROT_*`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Prot(u8);
+
+impl Prot {
+    pub const NONE: Prot = Prot(0);
+    pub const READ: Prot = Prot(1);
+    pub const WRITE: Prot = Prot(2);
+    pub const EXEC: Prot = Prot(4);
+
+    /// Take the `PROT_READ`, `PROT_WRITE` and `PROT_EXEC` bits of `bits`.
+    pub const fn from_bits(bits: u64) -> Self {
+        Prot(bits as u8 & 0b111)
+    }
+
+    /// Convert the `PF_X`, `PF_W` and `PF_R` flags of an ELF segment.
+    pub const fn from_segment_flags(flags: u32) -> Self {
+        Prot(((flags & 1) << 2 | (flags & 2) | (flags & 4) >> 2) as u8)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Prot) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Prot {
+    type Output = Prot;
+    fn bitor(self, rhs: Prot) -> Prot {
+        Prot(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Mapping {
+    len: usize,
+    prot: Prot,
+    /// A shared file mapping. Its pages are a private copy of the file, so it must never become
+    /// writable.
+    shared: bool,
+}
+
+impl Mapping {
+    fn end(&self, start: usize) -> usize {
+        start + self.len
+    }
+}
+
+/// The memory of one page, allocated when it is first stored to.
+#[derive(Debug)]
+struct Page {
+    data: Box<[u8; PAGE_SIZE]>,
+    /// Number of stores made to the page, which `sc` checks to tell whether a reservation on it
+    /// still holds.
+    generation: u64,
+}
+
+impl Page {
+    fn new() -> Self {
+        Self {
+            data: Box::new([0; PAGE_SIZE]),
+            generation: 0,
+        }
+    }
+}
+
+/// Split `addr..addr + len` at page boundaries into the address of each page, the offset of the
+/// part in the page and the part's range within `0..len`.
+fn chunks(addr: usize, len: usize) -> impl Iterator<Item = (usize, usize, Range<usize>)> {
+    let mut done = 0;
+    std::iter::from_fn(move || {
+        (done < len).then(|| {
+            let at = addr + done;
+            let offset = at % PAGE_SIZE;
+            let size = (PAGE_SIZE - offset).min(len - done);
+            done += size;
+            (at - offset, offset, done - size..done)
+        })
+    })
+}
+
+/// Mappings only describe the address space; the memory behind them is held per page and
+/// allocated on the first store, so reserving a large range costs nothing until it is used.
+/// Adjacent mappings with the same rights are merged; an access may still span mappings with
+/// different rights, as long as each of them grants it.
+#[derive(Debug)]
+struct Layout {
+    /// Keyed by start address; never overlapping.
+    mappings: BTreeMap<usize, Mapping>,
+    /// Pages that have been stored to, keyed by address. Mapped pages without an entry read as
+    /// zeros. Each page has its own lock, so threads only contend when they touch the same
+    /// page.
+    pages: BTreeMap<usize, RwLock<Page>>,
+    brk_start: usize,
+    brk: usize,
+    /// `mmap` hands out addresses below this one.
+    mmap_top: usize,
+}
+
+impl Layout {
+    /// The mapping containing `addr`, with its start address.
+    fn mapping(&self, addr: usize) -> Option<(usize, &Mapping)> {
+        let (&start, mapping) = self.mappings.range(..=addr).next_back()?;
+        (addr < mapping.end(start)).then_some((start, mapping))
+    }
+
+    fn prot(&self, addr: usize) -> Option<Prot> {
+        self.mapping(addr).map(|(_, mapping)| mapping.prot)
+    }
+
+    fn is_free(&self, addr: usize, len: usize) -> bool {
+        let Some(end) = addr.checked_add(len) else {
+            return false;
+        };
+        self.mapping(addr).is_none() && self.mappings.range(addr..end).next().is_none()
+    }
+
+    fn is_mapped(&self, addr: usize, len: usize) -> bool {
+        let Some(end) = addr.checked_add(len) else {
+            return false;
+        };
+        let mut cursor = addr;
+        while cursor < end {
+            match self.mapping(cursor) {
+                Some((start, mapping)) => cursor = mapping.end(start),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Whether every mapping `addr..addr + len` touches grants `prot`, with no gap between them.
+    fn grants(&self, addr: usize, len: usize, prot: Prot) -> bool {
+        addr.checked_add(len).is_some() && self.granted_len(addr, len, prot) == len
+    }
+
+    /// Length of the longest prefix of `addr..addr + len` mapped with `prot`.
+    fn granted_len(&self, addr: usize, len: usize, prot: Prot) -> usize {
+        let end = addr.saturating_add(len);
+        let mut cursor = addr;
+        while cursor < end {
+            match self.mapping(cursor) {
+                Some((start, mapping)) if mapping.prot.contains(prot) => {
+                    cursor = mapping.end(start)
+                }
+                _ => break,
+            }
+        }
+        cursor.min(end) - addr
+    }
+
+    fn find_free(&self, len: usize) -> Option<usize> {
+        let floor = page_up(self.brk)?;
+        let mut end = self.mmap_top;
+        for (&start, mapping) in self.mappings.range(..self.mmap_top).rev() {
+            let mapping_end = mapping.end(start);
+            if mapping_end <= end && end - mapping_end >= len {
+                break;
+            }
+            end = end.min(start);
+        }
+        let addr = end.checked_sub(len)?;
+        (addr >= floor).then_some(addr)
+    }
+
+    /// Split the mapping straddling `addr` in two at `addr`.
+    fn split(&mut self, addr: usize) {
+        let Some((start, _)) = self.mapping(addr) else {
+            return;
+        };
+        if start == addr {
+            return;
+        }
+        let mapping = self.mappings.get_mut(&start).unwrap();
+        let tail = Mapping {
+            len: mapping.end(start) - addr,
+            ..*mapping
+        };
+        mapping.len = addr - start;
+        self.mappings.insert(addr, tail);
+    }
+
+    /// Merge the mappings starting in `start..=end` into their predecessors where they are
+    /// adjacent and alike.
+    fn coalesce(&mut self, start: usize, end: usize) {
+        let keys: Vec<usize> = self
+            .mappings
+            .range(start..=end)
+            .map(|(&key, _)| key)
+            .collect();
+        for key in keys {
+            let Some((&prev_start, prev)) = self.mappings.range(..key).next_back() else {
+                continue;
+            };
+            let mapping = self.mappings[&key];
+            if prev.end(prev_start) != key
+                || prev.prot != mapping.prot
+                || prev.shared != mapping.shared
+            {
+                continue;
+            }
+            self.mappings.remove(&key);
+            self.mappings.get_mut(&prev_start).unwrap().len += mapping.len;
+        }
+    }
+
+    fn map(&mut self, addr: usize, len: usize, prot: Prot, shared: bool) {
+        debug_assert!(addr.is_multiple_of(PAGE_SIZE) && len.is_multiple_of(PAGE_SIZE));
+        if len == 0 {
+            return;
+        }
+        self.unmap(addr, len);
+        self.mappings.insert(addr, Mapping { len, prot, shared });
+        self.coalesce(addr, addr + len);
+    }
+
+    fn unmap(&mut self, addr: usize, len: usize) {
+        let end = addr.saturating_add(len);
+        self.split(addr);
+        self.split(end);
+        let keys: Vec<usize> = self
+            .mappings
+            .range(addr..end)
+            .map(|(&key, _)| key)
+            .collect();
+        for key in keys {
+            self.mappings.remove(&key);
+        }
+        self.take_pages(addr, end);
+    }
+
+    /// Remove the allocated pages of `addr..end` and return them.
+    fn take_pages(&mut self, addr: usize, end: usize) -> BTreeMap<usize, RwLock<Page>> {
+        let mut taken = self.pages.split_off(&addr);
+        let mut rest = taken.split_off(&end);
+        self.pages.append(&mut rest);
+        taken
+    }
+
+    fn protect(&mut self, addr: usize, len: usize, prot: Prot) -> bool {
+        if !self.is_mapped(addr, len) {
+            return false;
+        }
+        let end = addr + len;
+        self.split(addr);
+        self.split(end);
+        for mapping in self
+            .mappings
+            .range_mut(addr..end)
+            .map(|(_, mapping)| mapping)
+        {
+            mapping.prot = prot;
+        }
+        self.coalesce(addr, end);
+        true
+    }
+
+    fn remap(
+        &mut self,
+        old: usize,
+        old_len: usize,
+        new_len: usize,
+        may_move: bool,
+    ) -> Option<usize> {
+        if !self.is_mapped(old, old_len) {
+            return None;
+        }
+        if new_len <= old_len {
+            self.unmap(old + new_len, old_len - new_len);
+            return Some(old);
+        }
+        let old_end = old + old_len;
+        let (_, &Mapping { prot, shared, .. }) = self.mapping(old_end - 1)?;
+        if old + new_len <= self.mmap_top && self.is_free(old_end, new_len - old_len) {
+            self.map(old_end, new_len - old_len, prot, shared);
+            return Some(old);
+        }
+        if !may_move {
+            return None;
+        }
+        let new = self.find_free(new_len)?;
+        self.map(new, new_len, prot, shared);
+        // The pages move to their new addresses without being copied.
+        let pages = self.take_pages(old, old_end);
+        self.pages.extend(
+            pages
+                .into_iter()
+                .map(|(page, data)| (page - old + new, data)),
+        );
+        self.unmap(old, old_len);
+        Some(new)
+    }
+
+    fn set_brk_start(&mut self, addr: usize) {
+        self.brk_start = addr;
+        self.brk = addr;
+    }
+
+    fn brk(&mut self, addr: usize) -> usize {
+        if addr < self.brk_start {
+            return self.brk;
+        }
+        let (Some(old_end), Some(new_end)) = (page_up(self.brk), page_up(addr)) else {
+            return self.brk;
+        };
+        if new_end > old_end {
+            if new_end > self.mmap_top || !self.is_free(old_end, new_end - old_end) {
+                return self.brk;
+            }
+            self.map(old_end, new_end - old_end, Prot::READ | Prot::WRITE, false);
+        } else {
+            self.unmap(new_end, old_end - new_end);
+        }
+        self.brk = addr;
+        self.brk
+    }
+
+    /// Whether every page of `addr..addr + len` has been allocated.
+    fn is_populated(&self, addr: usize, len: usize) -> bool {
+        chunks(addr, len).all(|(page, _, _)| self.pages.contains_key(&page))
+    }
+
+    /// Allocate the mapped pages of `addr..addr + len` that have never been stored to.
+    fn populate(&mut self, addr: usize, len: usize) {
+        for (page, _, _) in chunks(addr, len) {
+            if self.mapping(page).is_some() {
+                self.pages
+                    .entry(page)
+                    .or_insert_with(|| RwLock::new(Page::new()));
+            }
+        }
+    }
+
+    /// Copy `addr..addr + buf.len()` into `buf`, returning the sum of its pages' generations.
+    fn read_into(&self, addr: usize, buf: &mut [u8]) -> u64 {
+        let mut generation = 0;
+        for (page, offset, range) in chunks(addr, buf.len()) {
+            match self.pages.get(&page) {
+                Some(page) => {
+                    let page = page.read().unwrap();
+                    buf[range.clone()].copy_from_slice(&page.data[offset..offset + range.len()]);
+                    generation += page.generation;
+                }
+                None => buf[range].fill(0),
+            }
+        }
+        generation
+    }
+
+    /// Copy `data` to `addr`, whose pages must be allocated.
+    fn write_from(&self, addr: usize, data: &[u8]) {
+        for (page, offset, range) in chunks(addr, data.len()) {
+            let mut page = self.pages[&page].write().unwrap();
+            page.data[offset..offset + range.len()].copy_from_slice(&data[range]);
+            page.generation += 1;
+        }
+    }
+
+    /// Atomically read `addr..addr + buf.len()`, whose pages must be allocated, into `buf` and
+    /// pass it to `f` with the sum of the pages' generations. What `f` leaves in `buf` is stored
+    /// back if it returns `true`.
+    fn update(&self, addr: usize, buf: &mut [u8], f: impl FnOnce(&mut [u8], u64) -> bool) -> bool {
+        // Pages are locked in address order, so two updates cannot deadlock.
+        let mut pages: Vec<_> = chunks(addr, buf.len())
+            .map(|(page, offset, range)| (self.pages[&page].write().unwrap(), offset, range))
+            .collect();
+        let mut generation = 0;
+        for (page, offset, range) in &pages {
+            buf[range.clone()].copy_from_slice(&page.data[*offset..*offset + range.len()]);
+            generation += page.generation;
+        }
+        if !f(buf, generation) {
+            return false;
+        }
+        for (page, offset, range) in &mut pages {
+            page.data[*offset..*offset + range.len()].copy_from_slice(&buf[range.clone()]);
+            page.generation += 1;
+        }
+        true
+    }
+}
+
+/// The virtual address space of a user-mode process: page-granular mappings with access rights,
+/// a program break, and a top-down allocator for `mmap`. Accesses are checked against the
+/// rights of the mapping they fall in.
+///
+/// Clones share the same memory, as the threads of a process do. Accesses only take the lock
+/// of the layout for reading and lock the pages they touch; changing the layout, and the first
+/// store to a page, take it for writing. Atomic operations are atomic across all clones.
+#[derive(Debug, Clone)]
+pub struct AddressSpace {
+    layout: Arc<RwLock<Layout>>,
+    range: RangeInclusive<usize>,
+}
+
+impl AddressSpace {
+    pub fn new(mmap_top: usize) -> Self {
+        Self {
+            layout: Arc::new(RwLock::new(Layout {
+                mappings: BTreeMap::new(),
+                pages: BTreeMap::new(),
+                brk_start: 0,
+                brk: 0,
+                mmap_top,
+            })),
+            range: 0..=usize::MAX,
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Layout> {
+        self.layout.read().unwrap()
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Layout> {
+        self.layout.write().unwrap()
+    }
+
+    /// Copy `addr..addr + buf.len()` into `buf` if it is mapped with the rights `prot`,
+    /// returning the sum of its pages' generations.
+    fn load_into(&self, addr: usize, buf: &mut [u8], prot: Prot) -> Option<u64> {
+        let layout = self.read();
+        layout
+            .grants(addr, buf.len(), prot)
+            .then(|| layout.read_into(addr, buf))
+    }
+
+    /// The layout, locked for reading, for a store to `addr..addr + len` once its pages are
+    /// allocated, or `None` if the range is not mapped with the rights `prot`.
+    fn store_access(
+        &self,
+        addr: usize,
+        len: usize,
+        prot: Prot,
+    ) -> Option<RwLockReadGuard<'_, Layout>> {
+        loop {
+            let layout = self.read();
+            if !layout.grants(addr, len, prot) {
+                return None;
+            }
+            if layout.is_populated(addr, len) {
+                return Some(layout);
+            }
+            drop(layout);
+            self.write().populate(addr, len);
+        }
+    }
+
+    /// Rights of the page containing `addr`, or `None` if it is unmapped.
+    pub fn prot(&self, addr: usize) -> Option<Prot> {
+        self.read().prot(addr)
+    }
+
+    /// Whether no page of `addr..addr + len` is mapped.
+    pub fn is_free(&self, addr: usize, len: usize) -> bool {
+        self.read().is_free(addr, len)
+    }
+
+    /// Whether every page of `addr..addr + len` is mapped.
+    pub fn is_mapped(&self, addr: usize, len: usize) -> bool {
+        self.read().is_mapped(addr, len)
+    }
+
+    /// How many bytes from `addr`, up to `len`, are mapped with `prot`, without touching them.
+    pub fn granted_len(&self, addr: usize, len: usize, prot: Prot) -> usize {
+        self.read().granted_len(addr, len, prot)
+    }
+
+    /// Whether any page of `addr..addr + len` belongs to a shared file mapping.
+    pub fn is_shared(&self, addr: usize, len: usize) -> bool {
+        let layout = self.read();
+        let end = addr.saturating_add(len);
+        layout
+            .mapping(addr)
+            .is_some_and(|(_, mapping)| mapping.shared)
+            || layout
+                .mappings
+                .range(addr..end)
+                .any(|(_, mapping)| mapping.shared)
+    }
+
+    /// The highest free, page-aligned range of `len` bytes below the `mmap` limit and above the
+    /// program break.
+    pub fn find_free(&self, len: usize) -> Option<usize> {
+        self.read().find_free(len)
+    }
+
+    /// Map `len` bytes of zeroed memory at `addr`, replacing whatever was mapped there. Both
+    /// must be page aligned.
+    pub fn map(&self, addr: usize, len: usize, prot: Prot) {
+        self.write().map(addr, len, prot, false)
+    }
+
+    /// Map `data` at `addr` with the rights `prot`, zero-filling the rest of `len` bytes.
+    pub fn map_with(&self, addr: usize, len: usize, prot: Prot, data: &[u8]) {
+        self.map_data(addr, len, prot, false, data)
+    }
+
+    /// Map a copy of `data` as a shared file mapping, which [`AddressSpace::is_shared`] reports
+    /// so that it is never made writable.
+    pub fn map_shared(&self, addr: usize, len: usize, prot: Prot, data: &[u8]) {
+        debug_assert!(!prot.contains(Prot::WRITE));
+        self.map_data(addr, len, prot, true, data)
+    }
+
+    fn map_data(&self, addr: usize, len: usize, prot: Prot, shared: bool, data: &[u8]) {
+        let mut layout = self.write();
+        layout.map(addr, len, prot, shared);
+        layout.populate(addr, data.len());
+        layout.write_from(addr, data);
+    }
+
+    /// Remove the pages of `addr..addr + len`; unmapped pages in the range are skipped.
+    pub fn unmap(&self, addr: usize, len: usize) {
+        self.write().unmap(addr, len)
+    }
+
+    /// Change the rights of `addr..addr + len`, or return `false` if part of it is unmapped.
+    pub fn protect(&self, addr: usize, len: usize, prot: Prot) -> bool {
+        self.write().protect(addr, len, prot)
+    }
+
+    /// Resize the mapped range `old..old + old_len` to `new_len` bytes, in place if the pages
+    /// after it are free or, with `may_move`, by moving it. Returns the new address, or `None`
+    /// if the old range is not mapped or there is no room.
+    pub fn remap(
+        &self,
+        old: usize,
+        old_len: usize,
+        new_len: usize,
+        may_move: bool,
+    ) -> Option<usize> {
+        self.write().remap(old, old_len, new_len, may_move)
+    }
+
+    /// Start the heap at `addr`, just past the loaded program.
+    pub fn set_brk_start(&self, addr: usize) {
+        self.write().set_brk_start(addr)
+    }
+
+    /// Current program break.
+    pub fn current_brk(&self) -> usize {
+        self.read().brk
+    }
+
+    /// Move the program break to `addr` and return the new break. As with the Linux system
+    /// call, a request that cannot be met leaves the break where it was.
+    pub fn brk(&self, addr: usize) -> usize {
+        self.write().brk(addr)
+    }
+}
+
+impl Bus for AddressSpace {
+    fn init_from(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.write_bytes(0, data)?;
+        Ok(())
+    }
+    fn address_range(&self) -> &RangeInclusive<usize> {
+        &self.range
+    }
+    fn read_bytes(&self, addr: usize, len: usize) -> anyhow::Result<Vec<u8>, OperationError> {
+        let mut data = vec![0; len];
+        self.load_into(addr, &mut data, Prot::READ)
+            .ok_or(OperationError::LoadAddressFault(addr))?;
+        Ok(data)
+    }
+    fn write_bytes(&mut self, addr: usize, data: &[u8]) -> anyhow::Result<(), OperationError> {
+        self.store_access(addr, data.len(), Prot::WRITE)
+            .ok_or(OperationError::StoreAddressFault(addr))?
+            .write_from(addr, data);
+        Ok(())
+    }
+    fn fetch(&self, addr: usize) -> anyhow::Result<u16, OperationError> {
+        let mut bytes = [0; 2];
+        self.load_into(addr, &mut bytes, Prot::EXEC)
+            .ok_or(OperationError::InstructionAccessFault(addr))?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+    fn address_space(&mut self) -> Option<&mut AddressSpace> {
+        Some(self)
+    }
+}
+
+// Misaligned accesses are allowed, as Linux emulates them for user processes.
+macro_rules! impl_address_space_operation {
+    ($($ty:ty),+) => {$(
+        impl BusOperation<$ty> for AddressSpace {
+            fn load(&self, addr: usize) -> anyhow::Result<$ty, OperationError> {
+                let mut bytes = [0; size_of::<$ty>()];
+                self.load_into(addr, &mut bytes, Prot::READ)
+                    .ok_or(OperationError::LoadAddressFault(addr))?;
+                Ok(<$ty>::from_le_bytes(bytes))
+            }
+            fn store(&mut self, addr: usize, data: $ty) -> anyhow::Result<(), OperationError> {
+                self.write_bytes(addr, &data.to_le_bytes())
+            }
+            // Both hold the locks of the pages they access throughout, so they are atomic with
+            // respect to other threads.
+            fn fetch_update(
+                &mut self,
+                addr: usize,
+                f: &mut dyn FnMut($ty) -> $ty,
+            ) -> anyhow::Result<$ty, OperationError> {
+                let mut bytes = [0; size_of::<$ty>()];
+                let mut old = 0;
+                self.store_access(addr, bytes.len(), Prot::READ | Prot::WRITE)
+                    .ok_or(OperationError::StoreAddressFault(addr))?
+                    .update(addr, &mut bytes, |bytes, _| {
+                        old = <$ty>::from_le_bytes(bytes.try_into().unwrap());
+                        bytes.copy_from_slice(&f(old).to_le_bytes());
+                        true
+                    });
+                Ok(old)
+            }
+            fn compare_exchange(
+                &mut self,
+                addr: usize,
+                current: $ty,
+                new: $ty,
+            ) -> anyhow::Result<bool, OperationError> {
+                let mut bytes = [0; size_of::<$ty>()];
+                Ok(self
+                    .store_access(addr, bytes.len(), Prot::READ | Prot::WRITE)
+                    .ok_or(OperationError::StoreAddressFault(addr))?
+                    .update(addr, &mut bytes, |bytes, _| {
+                        if <$ty>::from_le_bytes((&*bytes).try_into().unwrap()) != current {
+                            return false;
+                        }
+                        bytes.copy_from_slice(&new.to_le_bytes());
+                        true
+                    }))
+            }
+            // A reservation is on the page holding the reserved value, and is lost by any store
+            // to that page, even one writing back the value `lr` read. Both must be aligned, so
+            // that the value lies in one page.
+            fn load_reserved(&self, addr: usize) -> anyhow::Result<($ty, u64), OperationError> {
+                if !addr.is_multiple_of(size_of::<$ty>()) {
+                    return Err(OperationError::LoadMisaligned(addr));
+                }
+                let mut bytes = [0; size_of::<$ty>()];
+                let generation = self
+                    .load_into(addr, &mut bytes, Prot::READ)
+                    .ok_or(OperationError::LoadAddressFault(addr))?;
+                Ok((<$ty>::from_le_bytes(bytes), generation))
+            }
+            fn store_conditional(
+                &mut self,
+                addr: usize,
+                _reserved: $ty,
+                token: u64,
+                new: $ty,
+            ) -> anyhow::Result<bool, OperationError> {
+                if !addr.is_multiple_of(size_of::<$ty>()) {
+                    return Err(OperationError::StoreMisaligned(addr));
+                }
+                let mut bytes = [0; size_of::<$ty>()];
+                Ok(self
+                    .store_access(addr, bytes.len(), Prot::WRITE)
+                    .ok_or(OperationError::StoreAddressFault(addr))?
+                    .update(addr, &mut bytes, |bytes, generation| {
+                        if generation != token {
+                            return false;
+                        }
+                        bytes.copy_from_slice(&new.to_le_bytes());
+                        true
+                    }))
+            }
+        }
+    )+};
+}
+impl_address_space_operation!(u8, u16, u32, u64, usize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RW: Prot = Prot(3);
+    const TOP: usize = 0x1000_0000;
+
+    #[test]
+    fn test_map_split_and_coalesce() {
+        let mut space = AddressSpace::new(TOP);
+        space.map(0x1000, 0x3000, RW);
+        space.map(0x4000, 0x1000, RW);
+        assert_eq!(space.read().mappings.len(), 1);
+        space.write_bytes(0x3ffe, &[1, 2, 3, 4]).unwrap();
+
+        space.unmap(0x2000, 0x1000);
+        assert!(space.is_free(0x2000, 0x1000));
+        assert!(space.is_mapped(0x1000, 0x1000) && space.is_mapped(0x3000, 0x2000));
+        assert!(!space.is_mapped(0x1000, 0x3000));
+        assert_eq!(space.read_bytes(0x3ffe, 4).unwrap(), [1, 2, 3, 4]);
+
+        // Mapping over existing pages replaces them with zeroed memory.
+        space.map(0x3000, 0x1000, RW);
+        assert_eq!(space.read_bytes(0x3ffe, 4).unwrap(), [0, 0, 3, 4]);
+        space.map(0x2000, 0x1000, RW);
+        assert_eq!(space.read().mappings.len(), 1);
+    }
+
+    #[test]
+    fn test_protection() {
+        let mut space = AddressSpace::new(TOP);
+        space.map(0x1000, 0x3000, RW);
+        assert!(space.protect(0x2000, 0x1000, Prot::READ | Prot::EXEC));
+        assert!(!space.protect(0x3000, 0x2000, Prot::NONE));
+        assert_eq!(space.prot(0x2fff), Some(Prot(5)));
+
+        assert!(space.fetch(0x1000).is_err());
+        assert!(space.fetch(0x2ffe).is_ok());
+        assert!(BusOperation::<u16>::store(&mut space, 0x1ffe, 7).is_ok());
+        assert!(matches!(
+            BusOperation::<u32>::store(&mut space, 0x2000, 7),
+            Err(OperationError::StoreAddressFault(0x2000))
+        ));
+        // An access may straddle mappings with different rights if each grants it.
+        assert_eq!(BusOperation::<u32>::load(&space, 0x1ffe).unwrap(), 7);
+        assert_eq!(space.read_bytes(0x1ffe, 4).unwrap(), [7, 0, 0, 0]);
+        assert!(BusOperation::<u32>::store(&mut space, 0x1ffe, 7).is_err());
+        assert!(space.write_bytes(0x1fff, &[1, 2]).is_err());
+        assert_eq!(BusOperation::<u16>::load(&space, 0x1ffe).unwrap(), 7);
+
+        assert!(space.protect(0x1000, 0x3000, Prot::NONE));
+        assert_eq!(space.read().mappings.len(), 1);
+        assert!(matches!(
+            BusOperation::<u8>::load(&space, 0x1000),
+            Err(OperationError::LoadAddressFault(0x1000))
+        ));
+    }
+
+    #[test]
+    fn test_brk_and_find_free() {
+        let mut space = AddressSpace::new(TOP);
+        space.map(0x1_0000, 0x1000, RW);
+        space.set_brk_start(0x1_0800);
+        assert_eq!(space.brk(0), 0x1_0800);
+        assert_eq!(space.brk(0x1_2010), 0x1_2010);
+        assert!(space.is_mapped(0x1_0000, 0x3000));
+        BusOperation::<u64>::store(&mut space, 0x1_2008, 1).unwrap();
+        assert_eq!(space.brk(0x1_1000), 0x1_1000);
+        assert!(space.is_free(0x1_1000, 0x2000));
+
+        assert_eq!(space.find_free(0x2000), Some(TOP - 0x2000));
+        space.map(TOP - 0x1000, 0x1000, RW);
+        space.map(TOP - 0x4000, 0x1000, RW);
+        assert_eq!(space.find_free(0x2000), Some(TOP - 0x3000));
+        assert_eq!(space.find_free(0x3000), Some(TOP - 0x7000));
+        assert_eq!(space.find_free(TOP), None);
+
+        // The break cannot grow into a mapping.
+        space.map(0x2_0000, 0x1000, RW);
+        assert_eq!(space.brk(0x2_0010), 0x1_1000);
+    }
+
+    #[test]
+    fn test_remap() {
+        let mut space = AddressSpace::new(TOP);
+        space.map(0x1000, 0x2000, RW);
+        space.map(0x4000, 0x1000, Prot::READ);
+        space.write_bytes(0x2ff0, b"remapped").unwrap();
+
+        assert_eq!(space.remap(0x1000, 0x2000, 0x3000, false), Some(0x1000));
+        assert!(space.is_mapped(0x1000, 0x3000));
+        assert_eq!(space.remap(0x1000, 0x3000, 0x4000, false), None);
+        let new = space.remap(0x1000, 0x3000, 0x4000, true).unwrap();
+        assert_eq!(new, TOP - 0x4000);
+        assert!(space.is_free(0x1000, 0x3000));
+        assert_eq!(space.read_bytes(new + 0x1ff0, 8).unwrap(), b"remapped");
+        assert_eq!(space.remap(new, 0x4000, 0x1000, false), Some(new));
+        assert!(space.is_free(new + 0x1000, 0x3000));
+        assert_eq!(space.remap(0x8000, 0x1000, 0x2000, true), None);
+    }
+
+    #[test]
+    fn test_pages_allocated_on_store() {
+        const GIB: usize = 1 << 30;
+        let mut space = AddressSpace::new(TOP);
+        // Reserving, merging and protecting allocate no memory.
+        space.map(GIB, GIB, Prot::NONE);
+        space.map(2 * GIB, 0x1000, Prot::NONE);
+        assert_eq!(space.read().mappings.len(), 1);
+        assert!(space.protect(GIB, 0x2000, RW));
+        assert_eq!(space.read_bytes(GIB, 8).unwrap(), [0; 8]);
+        assert!(space.read().pages.is_empty());
+
+        BusOperation::<u16>::store(&mut space, GIB + 0xfff, 0x0201).unwrap();
+        assert_eq!(space.read().pages.len(), 2);
+        assert_eq!(space.read_bytes(GIB + 0xffe, 4).unwrap(), [0, 1, 2, 0]);
+
+        // Moving a mapping moves its pages.
+        let new = space.remap(GIB, 0x2000, 0x3000, true).unwrap();
+        assert_eq!(new, TOP - 0x3000);
+        assert_eq!(space.read_bytes(new + 0xffe, 4).unwrap(), [0, 1, 2, 0]);
+        let pages: Vec<usize> = space.read().pages.keys().copied().collect();
+        assert_eq!(pages, [new, new + 0x1000]);
+        space.unmap(new, 0x3000);
+        assert!(space.read().pages.is_empty());
+    }
+
+    #[test]
+    fn test_atomic_across_threads() {
+        let space = AddressSpace::new(TOP);
+        space.map(0x1000, 0x1000, RW);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let mut space = space.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        BusOperation::<u64>::fetch_update(&mut space, 0x1008, &mut |x| x + 1)
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(BusOperation::<u64>::load(&space, 0x1008).unwrap(), 4000);
+    }
+}
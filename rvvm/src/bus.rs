@@ -1,15 +1,84 @@
+use super::address_space::AddressSpace;
 use super::error::OperationError;
 use core::ops::RangeInclusive;
 
 pub trait Bus:
-    BusOperation<u8> + BusOperation<u16> + BusOperation<u32> + BusOperation<u64> + BusOperation<usize>
+    Send
+    + BusOperation<u8>
+    + BusOperation<u16>
+    + BusOperation<u32>
+    + BusOperation<u64>
+    + BusOperation<usize>
 {
     fn init_from(&mut self, data: &[u8]) -> anyhow::Result<()>;
     fn address_range(&self) -> &RangeInclusive<usize>;
-    fn read_bytes(&self, addr: usize, len: usize) -> anyhow::Result<&[u8], OperationError>;
+    fn read_bytes(&self, addr: usize, len: usize) -> anyhow::Result<Vec<u8>, OperationError>;
     fn write_bytes(&mut self, addr: usize, data: &[u8]) -> anyhow::Result<(), OperationError>;
+    /// Read an instruction parcel. Buses that track execute permission refuse other addresses.
+    fn fetch(&self, addr: usize) -> anyhow::Result<u16, OperationError> {
+        self.load(addr)
+    }
+    /// The user-mode address space behind this bus, if it is one.
+    fn address_space(&mut self) -> Option<&mut AddressSpace> {
+        None
+    }
 }
-pub trait BusOperation<T: Sized> {
+pub trait BusOperation<T: Sized + Copy + PartialEq> {
     fn load(&self, addr: usize) -> anyhow::Result<T, OperationError>;
     fn store(&mut self, addr: usize, data: T) -> anyhow::Result<(), OperationError>;
+    /// Atomically replace the value at `addr` with `f(old)` and return `old`.
+    ///
+    /// Faults are reported as store faults, as required for AMOs. The default implementation is
+    /// only atomic with respect to the holder of `&mut self`.
+    fn fetch_update(
+        &mut self,
+        addr: usize,
+        f: &mut dyn FnMut(T) -> T,
+    ) -> anyhow::Result<T, OperationError> {
+        let old = self.load(addr).map_err(as_store_fault)?;
+        self.store(addr, f(old))?;
+        Ok(old)
+    }
+    /// Atomically store `new` to `addr` if it still holds `current`, returning whether the store
+    /// took place.
+    fn compare_exchange(
+        &mut self,
+        addr: usize,
+        current: T,
+        new: T,
+    ) -> anyhow::Result<bool, OperationError> {
+        if self.load(addr).map_err(as_store_fault)? != current {
+            return Ok(false);
+        }
+        self.store(addr, new)?;
+        Ok(true)
+    }
+    /// Load `addr` for `lr`, along with a token that [`BusOperation::store_conditional`] checks
+    /// to tell whether the reservation still holds. The default token is unused.
+    fn load_reserved(&self, addr: usize) -> anyhow::Result<(T, u64), OperationError> {
+        Ok((self.load(addr)?, 0))
+    }
+    /// Atomically store `new` to `addr` for `sc` if the reservation that `lr` made, reading
+    /// `reserved` with `token`, still holds, returning whether the store took place.
+    ///
+    /// By default the reservation holds while memory still contains `reserved`. That is only
+    /// right for buses a single hart stores to, whose own stores already cancel its
+    /// reservation; shared buses must detect stores that put the same value back.
+    fn store_conditional(
+        &mut self,
+        addr: usize,
+        reserved: T,
+        _token: u64,
+        new: T,
+    ) -> anyhow::Result<bool, OperationError> {
+        self.compare_exchange(addr, reserved, new)
+    }
+}
+
+fn as_store_fault(err: OperationError) -> OperationError {
+    match err {
+        OperationError::LoadAddressFault(addr) => OperationError::StoreAddressFault(addr),
+        OperationError::LoadMisaligned(addr) => OperationError::StoreMisaligned(addr),
+        err => err,
+    }
 }
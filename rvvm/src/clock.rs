@@ -0,0 +1,123 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Frequency of the `time` counter, in Hz.
+pub const TIMEBASE_FREQ: u64 = 10_000_000;
+
+#[derive(Debug)]
+enum Source {
+    /// Follow the host's monotonic clock.
+    Host(Instant),
+    /// Advance one tick per retired instruction, for deterministic runs.
+    Instructions,
+}
+
+#[derive(Debug)]
+struct State {
+    source: Source,
+    /// Added to the host clock, or the tick count itself for the instruction source.
+    offset: AtomicU64,
+}
+
+/// The platform timebase, shared by the `time` CSR and the CLINT `mtime` register.
+#[derive(Debug, Clone)]
+pub struct Clock {
+    state: Arc<State>,
+}
+
+impl Clock {
+    pub fn host() -> Self {
+        Self::with_source(Source::Host(Instant::now()))
+    }
+
+    pub fn instructions() -> Self {
+        Self::with_source(Source::Instructions)
+    }
+
+    fn with_source(source: Source) -> Self {
+        Self {
+            state: Arc::new(State {
+                source,
+                offset: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    fn host_ticks(&self) -> u64 {
+        match self.state.source {
+            Source::Host(boot) => {
+                let nanos = boot.elapsed().as_nanos() as u64;
+                nanos / (1_000_000_000 / TIMEBASE_FREQ)
+            }
+            Source::Instructions => 0,
+        }
+    }
+
+    /// Current value of `mtime`.
+    pub fn now(&self) -> u64 {
+        self.host_ticks()
+            .wrapping_add(self.state.offset.load(Ordering::Acquire))
+    }
+
+    /// Overwrite `mtime`.
+    pub fn set(&self, value: u64) {
+        let offset = value.wrapping_sub(self.host_ticks());
+        self.state.offset.store(offset, Ordering::Release);
+    }
+
+    /// Account for `ticks` retired instructions. Host clocks ignore this.
+    #[inline]
+    pub fn advance(&self, ticks: u64) {
+        if let Source::Instructions = self.state.source {
+            self.state.offset.fetch_add(ticks, Ordering::AcqRel);
+        }
+    }
+
+    /// Host time until `now()` reaches `target`, or `None` if the clock does not follow the host.
+    pub fn until(&self, target: u64) -> Option<Duration> {
+        match self.state.source {
+            Source::Host(_) => {
+                let ticks = target.saturating_sub(self.now());
+                Some(Duration::from_nanos(
+                    ticks.saturating_mul(1_000_000_000 / TIMEBASE_FREQ),
+                ))
+            }
+            Source::Instructions => None,
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::host()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instruction_clock() {
+        let clock = Clock::instructions();
+        clock.advance(5);
+        assert_eq!(clock.now(), 5);
+        clock.set(100);
+        clock.advance(1);
+        assert_eq!(clock.clone().now(), 101);
+        assert_eq!(clock.until(200), None);
+    }
+
+    #[test]
+    fn test_host_clock() {
+        let clock = Clock::host();
+        clock.advance(1_000_000);
+        assert!(clock.now() < 1_000_000);
+        clock.set(1 << 40);
+        let now = clock.now();
+        assert!(now >= 1 << 40);
+        assert!(clock.until(now + TIMEBASE_FREQ).unwrap() <= Duration::from_secs(1));
+        assert_eq!(clock.until(0), Some(Duration::ZERO));
+    }
+}
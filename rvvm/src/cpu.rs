@@ -1,46 +1,142 @@
 #![allow(dead_code)]
 #![allow(unused)]
 
-use super::bus::Bus;
+use super::bus::{Bus, BusOperation};
+use super::csr::{CsrFile, Privilege, interrupt, status};
+use super::elf::Symbols;
 use super::error::OperationError;
+use super::fd_table::FdTable;
+use super::interrupt::InterruptLines;
+use super::logger::CPU;
+use super::mmu::Mmu;
 use super::operation::instruction_operation;
 use super::register::Register;
+use super::sbi::{self, Sbi};
+use super::signal;
+use super::strace::Strace;
+use super::thread::Thread;
+use super::trap::Exception;
 use crate::register::{Float, Generic};
-use colored::Colorize;
-use riscv::Op;
+use riscv::mmu::AccessType;
+use riscv::{Csr, Op};
+use std::sync::Arc;
 pub type Gsr = Register<isize, 32>;
 pub type Fsr = Register<isize, 32>;
 
+/// Reservation set registered by `lr`, checked by the next `sc`.
+#[derive(Clone, Copy, Debug)]
+struct Reservation {
+    addr: usize,
+    size: usize,
+    /// Value observed by `lr`.
+    value: u64,
+    /// Token the bus returned with the value, which lets it tell whether another hart stored to
+    /// the reservation set in between.
+    token: u64,
+}
+
+/// How the hart interacts with its environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutionMode {
+    /// Run a user-space program in U-mode, servicing `ecall` as a Linux system call on the host.
+    /// Any other exception stops the hart.
+    User,
+    /// Run from M-mode, delivering every exception to the guest's trap handlers.
+    System,
+}
+
+/// Why [`Cpu::run`] returned.
+#[derive(Debug)]
+pub enum RunOutcome {
+    /// The thread called `exit` with this status; other threads of the process may go on.
+    Exit(i32),
+    /// The process ended with this status, through `exit_group`, the exit of its last thread or
+    /// a fatal signal, possibly raised in another thread.
+    ExitGroup(i32),
+    /// An exception nothing took stopped the hart at `pc`.
+    Fault { pc: usize, cause: OperationError },
+    /// An `ebreak` nothing took stopped the hart at `pc`.
+    Breakpoint { pc: usize },
+    /// The hart executed its [`Cpu::instruction_limit`].
+    InstructionLimit,
+    /// The guest stopped the hart itself, as with an SBI shutdown or `hart_stop`.
+    Stopped,
+}
+
 pub struct Cpu {
     generic: Gsr,
     float: Fsr,
     pub mem: Box<dyn Bus>,
     pub pc: isize,
-    is_debug: bool,
     pub running: bool,
+    reservation: Option<Reservation>,
+    pub csr: CsrFile,
+    pub mmu: Mmu,
+    prv: Privilege,
+    mode: ExecutionMode,
+    /// Built-in firmware servicing `ecall` from S-mode, if the hart runs without an M-mode image.
+    pub sbi: Option<Sbi>,
+    /// Symbols of the loaded program, used to annotate debug output.
+    pub symbols: Symbols,
+    /// Tracer of the system calls made in user mode, shared by the process's threads.
+    pub strace: Option<Arc<Strace>>,
+    /// Most instructions a call to [`Cpu::run`] executes.
+    pub instruction_limit: Option<u64>,
+    /// Guest file descriptors of a user-mode process.
+    pub fds: FdTable,
+    /// The guest thread this hart runs in user mode.
+    pub thread: Thread,
 }
 impl Cpu {
     pub fn new(mem: impl Bus + 'static) -> Self {
-        Self {
+        let mut cpu = Self {
             generic: Gsr::new(),
             float: Fsr::new(),
             mem: Box::new(mem),
             pc: 0,
-            is_debug: false,
             running: false,
+            reservation: None,
+            csr: CsrFile::new(0),
+            mmu: Mmu::new(),
+            prv: Privilege::User,
+            mode: ExecutionMode::User,
+            sbi: None,
+            symbols: Symbols::default(),
+            strace: None,
+            instruction_limit: None,
+            fds: FdTable::with_stdio(),
+            thread: Thread::main(),
+        };
+        cpu.set_execution_mode(ExecutionMode::User);
+        cpu
+    }
+    /// A hart for `thread`, starting from a copy of this hart's registers and sharing its file
+    /// descriptors, with `mem` as its view of memory.
+    pub fn spawn_thread(&self, mem: impl Bus + 'static, thread: Thread) -> Self {
+        Self {
+            generic: self.generic,
+            float: self.float,
+            mem: Box::new(mem),
+            pc: self.pc,
+            running: false,
+            reservation: None,
+            csr: self.csr.clone(),
+            mmu: Mmu::new(),
+            prv: self.prv,
+            mode: self.mode,
+            sbi: None,
+            symbols: self.symbols.clone(),
+            strace: self.strace.clone(),
+            instruction_limit: self.instruction_limit,
+            fds: self.fds.clone(),
+            thread,
         }
     }
     fn fetch_instruction(&mut self) -> anyhow::Result<(Op, u64, u32), OperationError> {
-        let bits: u16 = self.mem.load(self.pc as usize)?;
+        let bits = self.fetch_parcel(self.pc as usize)?;
         if bits & 3 == 3 {
-            // The instruction will cross page boundary.
-            if self.pc & 4095 == 4094 {
-                return Err(OperationError::IllegalInstruction(
-                    bits as _,
-                    self.pc as usize,
-                ));
-            }
-            let hi_bits: u16 = self.mem.load((self.pc + 2) as usize)?;
+            // The two halves are translated separately, as they may lie on different pages.
+            let hi_bits = self.fetch_parcel((self.pc + 2) as usize)?;
             let bits = (hi_bits as u32) << 16 | bits as u32;
             let op = riscv::decode(bits);
             Ok((op, 4, bits))
@@ -49,42 +145,393 @@ impl Cpu {
             Ok((op, 2, bits as u32))
         }
     }
+    /// Fetch the 16-bit instruction parcel at `vaddr`.
+    fn fetch_parcel(&mut self, vaddr: usize) -> anyhow::Result<u16, OperationError> {
+        let paddr = self.mmu.translate(
+            self.mem.as_mut(),
+            &self.csr,
+            self.prv,
+            vaddr,
+            AccessType::Execute,
+        )?;
+        self.mem
+            .fetch(paddr)
+            .map_err(|_| OperationError::InstructionAccessFault(vaddr))
+    }
+    /// Privilege for loads and stores: with `mstatus.MPRV` set, M-mode accesses act as `MPP`.
+    fn data_privilege(&self) -> Privilege {
+        let mstatus = self.csr.mstatus;
+        if self.prv == Privilege::Machine && mstatus & status::MPRV != 0 {
+            Privilege::from_bits((mstatus & status::MPP) >> 11).unwrap_or(Privilege::User)
+        } else {
+            self.prv
+        }
+    }
+    /// Translate a `size`-byte data access to `vaddr`. Misaligned accesses fault before
+    /// translation.
+    fn translate(
+        &mut self,
+        vaddr: usize,
+        size: usize,
+        access: AccessType,
+    ) -> anyhow::Result<usize, OperationError> {
+        if !vaddr.is_multiple_of(size) {
+            return Err(match access {
+                AccessType::Read => OperationError::LoadMisaligned(vaddr),
+                _ => OperationError::StoreMisaligned(vaddr),
+            });
+        }
+        let prv = self.data_privilege();
+        self.mmu
+            .translate(self.mem.as_mut(), &self.csr, prv, vaddr, access)
+    }
     pub fn set_pc(&mut self, pc: isize) {
         self.pc = pc
     }
-    pub fn set_debug(&mut self, debug: bool) {
-        self.is_debug = debug
+    /// Switch execution mode, resetting the privilege level to the mode's starting point.
+    pub fn set_execution_mode(&mut self, mode: ExecutionMode) {
+        self.mode = mode;
+        match mode {
+            ExecutionMode::User => {
+                self.prv = Privilege::User;
+                // User programs may read the counters, as they can under Linux.
+                self.csr.mcounteren = 0b111;
+                self.csr.scounteren = 0b111;
+            }
+            ExecutionMode::System => self.prv = Privilege::Machine,
+        }
+    }
+    pub fn execution_mode(&self) -> ExecutionMode {
+        self.mode
+    }
+    /// Current privilege mode.
+    pub fn privilege(&self) -> Privilege {
+        self.prv
     }
     pub fn tick(&mut self) -> anyhow::Result<(), OperationError> {
-        match self.fetch_instruction() {
-            Ok((op, len, bits)) => match op {
-                Op::Illegal => {
-                    return Err(OperationError::IllegalInstruction(bits, self.pc as usize));
-                }
+        if self.mode == ExecutionMode::System {
+            self.csr.irq.poll_timers(&self.csr.clock);
+            if let Some(code) = self.pending_interrupt() {
+                self.trap(1 << 63 | code, 0);
+                return Ok(());
+            }
+        } else if self.thread.has_pending() {
+            signal::deliver_pending(self);
+            return Ok(());
+        }
+        let result = self
+            .fetch_instruction()
+            .and_then(|(op, len, bits)| match op {
+                Op::Illegal => Err(OperationError::IllegalInstruction(bits, self.pc as usize)),
                 _ => {
-                    if self.is_debug {
-                        println!("{}", op.pretty_print(self.pc as u64, bits));
+                    if log::log_enabled!(target: CPU, log::Level::Trace) {
+                        if let Some((symbol, 0)) = self.symbols.lookup(self.pc as u64) {
+                            log::trace!(target: CPU, "<{}>:", symbol.name);
+                        }
+                        log::trace!(target: CPU, "{}", op.pretty_print(self.pc as u64, bits));
                     }
-                    instruction_operation(op, self, len as isize)?;
+                    instruction_operation(op, self, len as isize, bits)
                 }
-            },
-            Err(ee) => return Err(ee),
+            });
+        match result {
+            Ok(()) => {
+                self.csr.retire();
+                Ok(())
+            }
+            Err(err) => self.raise(err),
+        }
+    }
+    /// A handle to this hart's interrupt lines, for devices to drive.
+    pub fn interrupt_lines(&self) -> InterruptLines {
+        self.csr.irq.clone()
+    }
+    /// The highest-priority interrupt that is pending, enabled and not masked at the current
+    /// privilege, as an exception code.
+    pub fn pending_interrupt(&self) -> Option<u64> {
+        let pending = self.csr.pending_interrupts() & self.csr.mie;
+        if pending == 0 {
+            return None;
+        }
+        let mstatus = self.csr.mstatus;
+        let machine_enabled = self.prv < Privilege::Machine || mstatus & status::MIE != 0;
+        let supervisor_enabled = self.prv < Privilege::Supervisor
+            || (self.prv == Privilege::Supervisor && mstatus & status::SIE != 0);
+        let mut enabled = 0;
+        if machine_enabled {
+            enabled |= pending & !self.csr.mideleg;
+        }
+        if supervisor_enabled {
+            enabled |= pending & self.csr.mideleg;
+        }
+        // Machine-level interrupts come first, then external, software and timer.
+        const PRIORITY: [u64; 6] = [
+            interrupt::MEIP,
+            interrupt::MSIP,
+            interrupt::MTIP,
+            interrupt::SEIP,
+            interrupt::SSIP,
+            interrupt::STIP,
+        ];
+        PRIORITY
+            .iter()
+            .find(|&&bit| enabled & bit != 0)
+            .map(|bit| bit.trailing_zeros() as u64)
+    }
+    /// Idle the host thread until an interrupt enabled in `mie` is pending, regardless of the
+    /// global interrupt enables. An instruction-count clock skips ahead to the next timer
+    /// deadline instead of sleeping. With nothing enabled in `mie` and no timer armed no
+    /// interrupt could ever wake the hart, so the WFI completes at once.
+    pub fn wait_for_interrupt(&self) {
+        let (irq, clock, mie) = (&self.csr.irq, &self.csr.clock, self.csr.mie);
+        loop {
+            irq.poll_timers(clock);
+            if self.csr.pending_interrupts() & mie != 0 {
+                return;
+            }
+            match irq.next_deadline(mie) {
+                Some(deadline) => match clock.until(deadline) {
+                    Some(timeout) => irq.wait(mie, Some(timeout)),
+                    None => clock.set(deadline),
+                },
+                None if mie == 0 => return,
+                None => irq.wait(mie, None),
+            }
+        }
+    }
+    /// Deliver `err` to the guest as an exception if possible, otherwise hand it back.
+    fn raise(&mut self, err: OperationError) -> anyhow::Result<(), OperationError> {
+        if let OperationError::EnvironmentCall(_) = err
+            && self.prv == Privilege::Supervisor
+            && let Some(mut sbi) = self.sbi.take()
+        {
+            sbi::ecall(self, &mut sbi);
+            self.sbi = Some(sbi);
+            return Ok(());
+        }
+        if self.mode == ExecutionMode::System
+            && let Some((exception, tval)) = Exception::from_error(&err, self.prv)
+        {
+            self.trap(exception as u64, tval);
+            return Ok(());
+        }
+        // In user mode the exception becomes a signal; unless a handler takes it, the process
+        // dies and the error is handed back to stop the hart.
+        if self.mode == ExecutionMode::User
+            && let Some((sig, info)) = signal::fault_signal(self, &err)
+            && signal::deliver_fault(self, sig, info)
+        {
+            return Ok(());
+        }
+        Err(err)
+    }
+    /// Take a trap with the `xcause` value `cause`, delegating it to S-mode if `medeleg` (or
+    /// `mideleg` for interrupts) allows.
+    pub fn trap(&mut self, cause: u64, tval: u64) {
+        let interrupt = cause >> 63 != 0;
+        let code = cause & !(1 << 63);
+        let delegated = if interrupt {
+            self.csr.mideleg
+        } else {
+            self.csr.medeleg
+        };
+        let pc = self.pc as u64;
+        log::trace!(target: CPU, "trap cause {cause:#x} tval {tval:#x} at {pc:#x}");
+        let mstatus = self.csr.mstatus;
+        let tvec = if self.prv <= Privilege::Supervisor && delegated >> code & 1 != 0 {
+            self.csr.scause = cause;
+            self.csr.sepc = pc;
+            self.csr.stval = tval;
+            let mut new = mstatus & !(status::SPIE | status::SIE | status::SPP);
+            if mstatus & status::SIE != 0 {
+                new |= status::SPIE;
+            }
+            if self.prv == Privilege::Supervisor {
+                new |= status::SPP;
+            }
+            self.csr.mstatus = new;
+            self.prv = Privilege::Supervisor;
+            self.csr.stvec
+        } else {
+            self.csr.mcause = cause;
+            self.csr.mepc = pc;
+            self.csr.mtval = tval;
+            let mut new = mstatus & !(status::MPIE | status::MIE | status::MPP);
+            if mstatus & status::MIE != 0 {
+                new |= status::MPIE;
+            }
+            new |= (self.prv as u64) << 11;
+            self.csr.mstatus = new;
+            self.prv = Privilege::Machine;
+            self.csr.mtvec
+        };
+        // Vectored mode only applies to interrupts.
+        let base = tvec & !0b11;
+        self.pc = if interrupt && tvec & 0b11 == 1 {
+            base + 4 * code
+        } else {
+            base
+        } as isize;
+    }
+    /// Return from an M-mode trap handler.
+    pub fn mret(&mut self) {
+        let mstatus = self.csr.mstatus;
+        let mpp = Privilege::from_bits((mstatus & status::MPP) >> 11).unwrap_or(Privilege::User);
+        let mut new = (mstatus & !(status::MIE | status::MPP)) | status::MPIE;
+        if mstatus & status::MPIE != 0 {
+            new |= status::MIE;
+        }
+        if mpp != Privilege::Machine {
+            new &= !status::MPRV;
         }
-        Ok(())
+        self.csr.mstatus = new;
+        self.prv = mpp;
+        self.pc = self.csr.mepc as isize;
     }
-    pub fn run(&mut self) {
+    /// Return from an S-mode trap handler.
+    pub fn sret(&mut self) {
+        let mstatus = self.csr.mstatus;
+        let spp = if mstatus & status::SPP != 0 {
+            Privilege::Supervisor
+        } else {
+            Privilege::User
+        };
+        let mut new = (mstatus & !(status::SIE | status::SPP | status::MPRV)) | status::SPIE;
+        if mstatus & status::SPIE != 0 {
+            new |= status::SIE;
+        }
+        self.csr.mstatus = new;
+        self.prv = spp;
+        self.pc = self.csr.sepc as isize;
+    }
+    /// Execute instructions until the thread exits, the guest stops the hart, an exception
+    /// cannot be delivered or the instruction limit is reached.
+    pub fn run(&mut self) -> RunOutcome {
         self.running = true;
+        let mut executed = 0;
         loop {
+            if let Some(status) = self.thread.exit_status() {
+                return RunOutcome::Exit(status);
+            }
+            if self.thread.group.exiting() {
+                self.running = false;
+                return RunOutcome::ExitGroup(self.thread.group.status().unwrap_or_default());
+            }
             if !self.running {
-                return;
+                return RunOutcome::Stopped;
             }
-            match self.tick() {
-                Ok(_) => {}
-                Err(err) => {
-                    println!("{}", err.to_string().red());
-                    break;
-                }
+            if self
+                .instruction_limit
+                .is_some_and(|limit| executed >= limit)
+            {
+                self.running = false;
+                return RunOutcome::InstructionLimit;
             }
+            executed += 1;
+            if let Err(err) = self.tick() {
+                log::error!(target: CPU, "{err} (pc {:#x})", self.pc);
+                self.running = false;
+                return match err {
+                    OperationError::Breakpoint(pc) => RunOutcome::Breakpoint { pc },
+                    cause => RunOutcome::Fault {
+                        pc: self.pc as usize,
+                        cause,
+                    },
+                };
+            }
+        }
+    }
+    #[inline]
+    pub fn load<T: Copy + PartialEq>(&mut self, addr: usize) -> anyhow::Result<T, OperationError>
+    where
+        dyn Bus: BusOperation<T>,
+    {
+        let paddr = self.translate(addr, size_of::<T>(), AccessType::Read)?;
+        self.mem.load(paddr).map_err(|err| err.at_virtual(addr))
+    }
+    #[inline]
+    pub fn store<T: Copy + PartialEq>(
+        &mut self,
+        addr: usize,
+        data: T,
+    ) -> anyhow::Result<(), OperationError>
+    where
+        dyn Bus: BusOperation<T>,
+    {
+        let paddr = self.translate(addr, size_of::<T>(), AccessType::Write)?;
+        self.invalidate_reservation(paddr, size_of::<T>());
+        self.mem
+            .store(paddr, data)
+            .map_err(|err| err.at_virtual(addr))
+    }
+    /// Atomically apply `f` to the value at `addr`, returning the old value.
+    pub fn fetch_update<T: Copy + PartialEq>(
+        &mut self,
+        addr: usize,
+        mut f: impl FnMut(T) -> T,
+    ) -> anyhow::Result<T, OperationError>
+    where
+        dyn Bus: BusOperation<T>,
+    {
+        let paddr = self.translate(addr, size_of::<T>(), AccessType::Write)?;
+        self.invalidate_reservation(paddr, size_of::<T>());
+        self.mem
+            .fetch_update(paddr, &mut f)
+            .map_err(|err| err.at_virtual(addr))
+    }
+    /// Load `addr` and register a reservation on it.
+    pub fn load_reserved<T: Copy + PartialEq + Into<u64>>(
+        &mut self,
+        addr: usize,
+    ) -> anyhow::Result<T, OperationError>
+    where
+        dyn Bus: BusOperation<T>,
+    {
+        let paddr = self.translate(addr, size_of::<T>(), AccessType::Read)?;
+        let (value, token): (T, u64) = self
+            .mem
+            .load_reserved(paddr)
+            .map_err(|err| err.at_virtual(addr))?;
+        self.reservation = Some(Reservation {
+            addr: paddr,
+            size: size_of::<T>(),
+            value: value.into(),
+            token,
+        });
+        Ok(value)
+    }
+    /// Store `data` to `addr` if the reservation registered by `load_reserved` is still intact.
+    /// The reservation is consumed whether or not the store succeeds.
+    pub fn store_conditional<T: Copy + PartialEq + TryFrom<u64>>(
+        &mut self,
+        addr: usize,
+        data: T,
+    ) -> anyhow::Result<bool, OperationError>
+    where
+        dyn Bus: BusOperation<T>,
+    {
+        let paddr = self.translate(addr, size_of::<T>(), AccessType::Write)?;
+        let reservation = match self.reservation.take() {
+            Some(reservation)
+                if reservation.addr == paddr && reservation.size == size_of::<T>() =>
+            {
+                reservation
+            }
+            _ => return Ok(false),
+        };
+        match T::try_from(reservation.value) {
+            Ok(reserved) => self
+                .mem
+                .store_conditional(paddr, reserved, reservation.token, data)
+                .map_err(|err| err.at_virtual(addr)),
+            Err(_) => Ok(false),
+        }
+    }
+    fn invalidate_reservation(&mut self, addr: usize, size: usize) {
+        if let Some(reservation) = self.reservation
+            && addr < reservation.addr + reservation.size
+            && reservation.addr < addr + size
+        {
+            self.reservation = None;
         }
     }
     #[inline]
@@ -109,4 +556,396 @@ impl Cpu {
     pub fn get_float(&self, name: Float) -> isize {
         self.float.get(name)
     }
+    /// Read a single-precision register. Values that are not properly NaN-boxed read as the
+    /// canonical NaN.
+    #[inline]
+    pub fn get_f32(&self, name: Float) -> u32 {
+        let bits = self.float.get(name) as u64;
+        if bits >> 32 == 0xffff_ffff {
+            bits as u32
+        } else {
+            0x7fc0_0000
+        }
+    }
+    /// Write a single-precision register, NaN-boxing the value.
+    #[inline]
+    pub fn set_f32(&mut self, name: Float, value: u32) {
+        self.float
+            .set(name, (0xffff_ffff_0000_0000 | value as u64) as isize);
+        self.csr.set_fs_dirty();
+    }
+    #[inline]
+    pub fn get_f64(&self, name: Float) -> u64 {
+        self.float.get(name) as u64
+    }
+    #[inline]
+    pub fn set_f64(&mut self, name: Float, value: u64) {
+        self.float.set(name, value as isize);
+        self.csr.set_fs_dirty();
+    }
+    /// Dynamic rounding mode held in `frm`.
+    #[inline]
+    pub fn frm(&self) -> u8 {
+        self.csr.frm()
+    }
+    /// Accumulate floating-point exception flags into `fflags`.
+    #[inline]
+    pub fn accrue_fflags(&mut self, flags: u8) {
+        self.csr.accrue_fflags(flags);
+    }
+    /// Read a CSR at the current privilege, or `None` if the access is illegal.
+    pub fn read_csr(&self, csr: Csr) -> Option<u64> {
+        self.csr.read(csr, self.prv)
+    }
+    /// Write a CSR at the current privilege, or return `None` if the access is illegal.
+    pub fn write_csr(&mut self, csr: Csr, value: u64) -> Option<()> {
+        let satp = self.csr.satp;
+        self.csr.write(csr, value, self.prv)?;
+        if self.csr.satp != satp {
+            self.mmu.satp_changed(satp, self.csr.satp);
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Memory;
+    use crate::address_space::{AddressSpace, Prot};
+    use crate::csr::interrupt;
+
+    const ECALL: u32 = 0x0000_0073;
+    const MRET: u32 = 0x3020_0073;
+    const SRET: u32 = 0x1020_0073;
+
+    fn system(code: &[(usize, u32)]) -> Cpu {
+        let mut image = vec![0u8; 0x200];
+        for &(addr, bits) in code {
+            image[addr..addr + 4].copy_from_slice(&bits.to_le_bytes());
+        }
+        let mut mem = Memory::new(0..=0x1ff);
+        mem.init_from(&image).unwrap();
+        let mut cpu = Cpu::new(mem);
+        cpu.set_execution_mode(ExecutionMode::System);
+        cpu
+    }
+
+    #[test]
+    fn test_machine_trap_and_mret() {
+        let mut cpu = system(&[(0x0, ECALL), (0x100, MRET)]);
+        cpu.csr.mtvec = 0x100;
+        cpu.csr.mstatus |= status::MIE;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.csr.mcause, Exception::MachineEcall as u64);
+        assert_eq!(cpu.csr.mepc, 0);
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.csr.mstatus & status::MPP, status::MPP);
+        assert_eq!(cpu.csr.mstatus & (status::MIE | status::MPIE), status::MPIE);
+
+        // Return to U-mode at 0x8, which holds an illegal all-zero instruction.
+        cpu.csr.mepc = 0x8;
+        cpu.csr.mstatus &= !status::MPP;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.privilege(), Privilege::User);
+        assert_eq!(cpu.pc, 0x8);
+        assert_eq!(
+            cpu.csr.mstatus & (status::MIE | status::MPIE),
+            status::MIE | status::MPIE
+        );
+
+        cpu.tick().unwrap();
+        assert_eq!(cpu.privilege(), Privilege::Machine);
+        assert_eq!(cpu.csr.mcause, Exception::IllegalInstruction as u64);
+        assert_eq!(cpu.csr.mepc, 0x8);
+        assert_eq!(cpu.csr.mtval, 0);
+        assert_eq!(cpu.csr.mstatus & status::MPP, 0);
+    }
+
+    #[test]
+    fn test_delegated_trap_and_sret() {
+        let mut cpu = system(&[(0x0, MRET), (0x8, ECALL), (0x180, SRET)]);
+        cpu.csr.medeleg = 1 << Exception::UserEcall as u64;
+        // Vectored mode does not apply to exceptions.
+        cpu.csr.stvec = 0x181;
+        cpu.csr.mepc = 0x8;
+        cpu.csr.mstatus |= status::SIE;
+        cpu.tick().unwrap();
+        cpu.tick().unwrap();
+        assert_eq!(cpu.privilege(), Privilege::Supervisor);
+        assert_eq!(cpu.pc, 0x180);
+        assert_eq!(cpu.csr.scause, Exception::UserEcall as u64);
+        assert_eq!(cpu.csr.sepc, 0x8);
+        assert_eq!(
+            cpu.csr.mstatus & (status::SPP | status::SIE | status::SPIE),
+            status::SPIE
+        );
+        assert_eq!(cpu.csr.mcause, 0);
+
+        cpu.csr.sepc += 4;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.privilege(), Privilege::User);
+        assert_eq!(cpu.pc, 0xc);
+        assert_ne!(cpu.csr.mstatus & status::SIE, 0);
+
+        // SRET is illegal from U-mode.
+        cpu.pc = 0x180;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.privilege(), Privilege::Machine);
+        assert_eq!(cpu.csr.mcause, Exception::IllegalInstruction as u64);
+        assert_eq!(cpu.csr.mtval, SRET as u64);
+    }
+
+    #[test]
+    fn test_vectored_interrupt() {
+        let mut cpu = system(&[]);
+        cpu.csr.mtvec = 0x101;
+        cpu.trap(1 << 63 | 7, 0);
+        assert_eq!(cpu.pc, 0x100 + 4 * 7);
+        assert_eq!(cpu.csr.mcause, 1 << 63 | 7);
+
+        cpu.mret();
+        cpu.csr.mideleg = interrupt::STIP;
+        cpu.csr.mstatus &= !status::MPP;
+        cpu.csr.stvec = 0x41;
+        cpu.prv = Privilege::Supervisor;
+        cpu.trap(1 << 63 | 5, 0);
+        assert_eq!(cpu.pc, 0x40 + 4 * 5);
+        assert_eq!(cpu.privilege(), Privilege::Supervisor);
+        assert_ne!(cpu.csr.mstatus & status::SPP, 0);
+    }
+
+    #[test]
+    fn test_fetch_fault() {
+        let mut cpu = system(&[]);
+        cpu.csr.mtvec = 0x100;
+        cpu.pc = 0x400;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.csr.mcause, Exception::InstructionAccessFault as u64);
+        assert_eq!(cpu.csr.mtval, 0x400);
+        assert_eq!(cpu.pc, 0x100);
+    }
+
+    #[test]
+    fn test_page_faults() {
+        let mut mem = Memory::new(0..=0x1fff);
+        // lw a0, 0(a1)
+        BusOperation::<u32>::store(&mut mem, 0x0, 0x0005_a503).unwrap();
+        // Identity-map the first gigabyte with a single Sv39 leaf at 0x1000.
+        let leaf = riscv::mmu::PTE_V
+            | riscv::mmu::PTE_R
+            | riscv::mmu::PTE_W
+            | riscv::mmu::PTE_X
+            | riscv::mmu::PTE_A
+            | riscv::mmu::PTE_D;
+        BusOperation::<u64>::store(&mut mem, 0x1000, leaf).unwrap();
+        let mut cpu = Cpu::new(mem);
+        cpu.set_execution_mode(ExecutionMode::System);
+        cpu.prv = Privilege::Supervisor;
+        cpu.csr.satp = crate::csr::SATP_MODE_SV39 << 60 | 1;
+        cpu.csr.mtvec = 0x100;
+
+        cpu.set_generic(Generic::a1, 0x4000_0000);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.csr.mcause, Exception::LoadPageFault as u64);
+        assert_eq!(cpu.csr.mtval, 0x4000_0000);
+        assert_eq!(cpu.csr.mepc, 0);
+
+        cpu.prv = Privilege::Supervisor;
+        cpu.pc = 0x8000_0000;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.csr.mcause, Exception::InstructionPageFault as u64);
+        assert_eq!(cpu.csr.mtval, 0x8000_0000);
+
+        // MPRV applies translation to M-mode data accesses.
+        cpu.csr.mstatus |= status::MPRV | 1 << 11;
+        cpu.pc = 0;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.csr.mcause, Exception::LoadPageFault as u64);
+        assert_eq!(cpu.pc, 0x100);
+    }
+
+    #[test]
+    fn test_user_mode_stops_on_exception() {
+        let mut cpu = Cpu::new(Memory::new(0..=0xf));
+        assert!(matches!(
+            cpu.tick(),
+            Err(OperationError::IllegalInstruction(0, 0))
+        ));
+    }
+
+    #[test]
+    fn test_run_outcomes() {
+        use crate::thread::{LIMIT_STATUS, run_thread};
+        fn user(code: &[u32]) -> Cpu {
+            let image: Vec<u8> = code.iter().flat_map(|op| op.to_le_bytes()).collect();
+            let mut mem = Memory::new(0..=0xff);
+            mem.init_from(&image).unwrap();
+            Cpu::new(mem)
+        }
+        const EBREAK: u32 = 0x0010_0073;
+        const LOOP: u32 = 0x0000_006f;
+        // li a0, 3; li a7, 93 (exit) or 94 (exit_group); ecall
+        let exit = |nr: u32| user(&[0x0030_0513, 0x0000_0893 | nr << 20, ECALL]);
+
+        assert!(matches!(exit(93).run(), RunOutcome::Exit(3)));
+        let mut cpu = exit(94);
+        assert!(matches!(cpu.run(), RunOutcome::ExitGroup(3)));
+        assert!(!cpu.running);
+
+        let mut cpu = user(&[0x0000_0013, 0]);
+        assert!(matches!(
+            cpu.run(),
+            RunOutcome::Fault {
+                pc: 4,
+                cause: OperationError::IllegalInstruction(0, 4)
+            }
+        ));
+        assert_eq!(cpu.thread.group.status(), Some(128 + libc::SIGILL));
+        assert!(matches!(
+            user(&[EBREAK]).run(),
+            RunOutcome::Breakpoint { pc: 0 }
+        ));
+
+        let mut cpu = user(&[LOOP]);
+        cpu.instruction_limit = Some(10);
+        assert!(matches!(run_thread(&mut cpu), RunOutcome::InstructionLimit));
+        assert_eq!(cpu.csr.instret, 10);
+        assert_eq!(cpu.thread.group.status(), Some(LIMIT_STATUS));
+    }
+
+    #[test]
+    fn test_interrupt_arbitration() {
+        let mut cpu = system(&[]);
+        let lines = cpu.interrupt_lines();
+        cpu.csr.mtvec = 0x100;
+        cpu.csr.stvec = 0x180;
+        lines.raise(interrupt::MTIP | interrupt::MSIP);
+        cpu.csr.mie = interrupt::ALL;
+
+        // Globally disabled in M-mode.
+        assert_eq!(cpu.pending_interrupt(), None);
+        cpu.csr.mstatus |= status::MIE;
+        assert_eq!(cpu.pending_interrupt(), Some(3));
+        cpu.tick().unwrap();
+        assert_eq!(cpu.csr.mcause, 1 << 63 | 3);
+        assert_eq!(cpu.pc, 0x100);
+        assert_eq!(cpu.pending_interrupt(), None);
+
+        // Delegated interrupts are taken in S-mode, and M-level ones preempt them.
+        lines.clear(interrupt::MTIP | interrupt::MSIP);
+        cpu.csr.mideleg = interrupt::SUPERVISOR;
+        cpu.csr.mip = interrupt::SSIP;
+        lines.raise(interrupt::SEIP);
+        cpu.prv = Privilege::Supervisor;
+        cpu.csr.mstatus |= status::SIE;
+        assert_eq!(cpu.pending_interrupt(), Some(9));
+        lines.raise(interrupt::MTIP);
+        assert_eq!(cpu.pending_interrupt(), Some(7));
+        lines.clear(interrupt::MTIP);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.privilege(), Privilege::Supervisor);
+        assert_eq!(cpu.csr.scause, 1 << 63 | 9);
+        assert_eq!(cpu.pc, 0x180);
+        // SIE was cleared on entry.
+        assert_eq!(cpu.pending_interrupt(), None);
+        // U-mode always takes S-level interrupts.
+        cpu.prv = Privilege::User;
+        assert_eq!(cpu.pending_interrupt(), Some(9));
+    }
+
+    #[test]
+    fn test_wfi_sleeps_until_interrupt() {
+        const WFI: u32 = 0x1050_0073;
+        let mut cpu = system(&[(0x0, WFI)]);
+        cpu.csr.mie = interrupt::MTIP;
+        let lines = cpu.interrupt_lines();
+        let device = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            lines.raise(interrupt::MTIP);
+        });
+        cpu.tick().unwrap();
+        device.join().unwrap();
+        // MIE is clear, so execution resumes after the WFI instead of trapping.
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(cpu.csr.mcause, 0);
+    }
+
+    #[test]
+    fn test_wfi_without_enabled_interrupts() {
+        const WFI: u32 = 0x1050_0073;
+        let mut cpu = system(&[(0x0, WFI)]);
+        cpu.csr.mie = 0;
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 4);
+    }
+
+    #[test]
+    fn test_clint_timer_with_instruction_clock() {
+        use crate::clock::Clock;
+        use crate::devices::{MmioDevice, clint::Clint};
+        const WFI: u32 = 0x1050_0073;
+        let mut cpu = system(&[(0x0, WFI)]);
+        cpu.csr.clock = Clock::instructions();
+        let clint = Clint::new(cpu.csr.clock.clone(), vec![cpu.interrupt_lines()]);
+        clint.write(0x4000, 8, 1000).unwrap();
+        cpu.csr.mtvec = 0x100;
+        cpu.csr.mie = interrupt::MTIP;
+        cpu.csr.mstatus |= status::MIE;
+
+        // Nothing else can wake the hart, so the clock skips ahead to the deadline.
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 4);
+        assert_eq!(cpu.csr.time(), 1001);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.csr.mcause, 1 << 63 | 7);
+        assert_eq!(cpu.csr.mepc, 4);
+
+        // Moving the deadline out acknowledges the interrupt.
+        clint.write(0x4000, 8, u64::MAX).unwrap();
+        cpu.csr.irq.poll_timers(&cpu.csr.clock);
+        assert_eq!(cpu.csr.pending_interrupts(), 0);
+    }
+
+    #[test]
+    fn test_counter_writes() {
+        // csrw minstret, a0; csrr a1, minstret; csrw mcycle, a0; csrr a2, mcycle
+        let mut cpu = system(&[
+            (0x0, 0xb025_1073),
+            (0x4, 0xb020_25f3),
+            (0x8, 0xb005_1073),
+            (0xc, 0xb000_2673),
+        ]);
+        cpu.set_generic(Generic::a0, 10);
+        for _ in 0..4 {
+            cpu.tick().unwrap();
+        }
+        // The instruction after a write reads the written value, not one more.
+        assert_eq!(cpu.get_generic(Generic::a1), 10);
+        assert_eq!(cpu.get_generic(Generic::a2), 10);
+        assert_eq!(cpu.csr.instret, 13);
+        assert_eq!(cpu.csr.cycle, 11);
+    }
+
+    #[test]
+    fn test_reservation_lost_to_other_hart() {
+        let space = AddressSpace::new(0x1000_0000);
+        space.map(0x1000, 0x1000, Prot::READ | Prot::WRITE);
+        let mut hart = Cpu::new(space.clone());
+        let mut other = hart.spawn_thread(space, Thread::main());
+
+        // The other hart puts back the value `lr` read, which still cancels the reservation.
+        assert_eq!(hart.load_reserved::<u32>(0x1010).unwrap(), 0);
+        other.store(0x1010, 1u32).unwrap();
+        other.store(0x1010, 0u32).unwrap();
+        assert!(!hart.store_conditional(0x1010, 2u32).unwrap());
+
+        assert_eq!(hart.load_reserved::<u32>(0x1010).unwrap(), 0);
+        assert!(hart.store_conditional(0x1010, 2u32).unwrap());
+        assert_eq!(other.load::<u32>(0x1010).unwrap(), 2);
+        assert!(matches!(
+            hart.load_reserved::<u32>(0x1012),
+            Err(OperationError::LoadMisaligned(0x1012))
+        ));
+    }
 }
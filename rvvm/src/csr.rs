@@ -0,0 +1,487 @@
+use super::clock::Clock;
+use super::interrupt::InterruptLines;
+use riscv::Csr;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    /// Decode an `xPP` field. The reserved encoding 2 is not a valid mode.
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0 => Some(Self::User),
+            1 => Some(Self::Supervisor),
+            3 => Some(Self::Machine),
+            _ => None,
+        }
+    }
+}
+
+pub mod status {
+    pub const SIE: u64 = 1 << 1;
+    pub const MIE: u64 = 1 << 3;
+    pub const SPIE: u64 = 1 << 5;
+    pub const MPIE: u64 = 1 << 7;
+    pub const SPP: u64 = 1 << 8;
+    pub const MPP: u64 = 0b11 << 11;
+    pub const FS: u64 = 0b11 << 13;
+    pub const MPRV: u64 = 1 << 17;
+    pub const SUM: u64 = 1 << 18;
+    pub const MXR: u64 = 1 << 19;
+    pub const TVM: u64 = 1 << 20;
+    pub const TW: u64 = 1 << 21;
+    pub const TSR: u64 = 1 << 22;
+    pub const UXL: u64 = 0b11 << 32;
+    pub const SD: u64 = 1 << 63;
+
+    pub const FS_INITIAL: u64 = 0b01 << 13;
+    pub const FS_DIRTY: u64 = 0b11 << 13;
+    /// UXL and SXL are hardwired to 64-bit.
+    pub const XLEN_64: u64 = 0b10 << 32 | 0b10 << 34;
+
+    /// Fields visible through `sstatus`.
+    pub const SSTATUS_MASK: u64 = SIE | SPIE | SPP | FS | SUM | MXR | UXL | SD;
+    /// Fields software may write through `mstatus`.
+    pub const MSTATUS_WRITABLE: u64 =
+        SIE | MIE | SPIE | MPIE | SPP | MPP | FS | MPRV | SUM | MXR | TVM | TW | TSR;
+}
+
+pub mod interrupt {
+    pub const SSIP: u64 = 1 << 1;
+    pub const MSIP: u64 = 1 << 3;
+    pub const STIP: u64 = 1 << 5;
+    pub const MTIP: u64 = 1 << 7;
+    pub const SEIP: u64 = 1 << 9;
+    pub const MEIP: u64 = 1 << 11;
+
+    pub const SUPERVISOR: u64 = SSIP | STIP | SEIP;
+    pub const ALL: u64 = SUPERVISOR | MSIP | MTIP | MEIP;
+}
+
+const fn extensions(letters: &[u8]) -> u64 {
+    let mut bits = 0;
+    let mut i = 0;
+    while i < letters.len() {
+        bits |= 1 << (letters[i] - b'A');
+        i += 1;
+    }
+    bits
+}
+
+/// `misa`: RV64 with the A, C, D, F, I, M, S and U extensions.
+const MISA: u64 = 2 << 62 | extensions(b"ACDFIMSU");
+
+/// Exceptions that can be delegated. Environment call from M-mode (11) never is.
+const MEDELEG_MASK: u64 = 0xb3ff;
+/// `cycle`, `time` and `instret`; the hpm counters are not implemented.
+const COUNTEREN_MASK: u64 = 0b111;
+
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_MODE_SV48: u64 = 9;
+pub const SATP_MODE_SV57: u64 = 10;
+
+/// Architectural CSR state of a hart.
+#[derive(Debug, Clone)]
+pub struct CsrFile {
+    /// Floating-point control and status: `frm` in bits 7:5, `fflags` in bits 4:0.
+    pub fcsr: u32,
+    pub mstatus: u64,
+    pub medeleg: u64,
+    pub mideleg: u64,
+    pub mie: u64,
+    /// Software-writable pending bits. Device-driven bits live in `irq`.
+    pub mip: u64,
+    pub irq: InterruptLines,
+    pub mtvec: u64,
+    pub mcounteren: u64,
+    pub mscratch: u64,
+    pub mepc: u64,
+    pub mcause: u64,
+    pub mtval: u64,
+    pub stvec: u64,
+    pub scounteren: u64,
+    pub sscratch: u64,
+    pub sepc: u64,
+    pub scause: u64,
+    pub stval: u64,
+    pub satp: u64,
+    pub mhartid: u64,
+    pub cycle: u64,
+    pub instret: u64,
+    /// Set when `mcycle` or `minstret` is written, so that the writing instruction's own
+    /// retirement does not advance the value the next instruction reads.
+    cycle_written: bool,
+    instret_written: bool,
+    /// Timebase behind the `time` CSR, shared with the CLINT.
+    pub clock: Clock,
+}
+
+impl CsrFile {
+    pub fn new(hartid: u64) -> Self {
+        Self {
+            fcsr: 0,
+            mstatus: status::XLEN_64 | status::FS_INITIAL,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mip: 0,
+            irq: InterruptLines::new(),
+            mtvec: 0,
+            mcounteren: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            stvec: 0,
+            scounteren: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            mhartid: hartid,
+            cycle: 0,
+            instret: 0,
+            cycle_written: false,
+            instret_written: false,
+            clock: Clock::host(),
+        }
+    }
+
+    /// Current value of the `time` counter.
+    pub fn time(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Count one retired instruction.
+    #[inline]
+    pub fn retire(&mut self) {
+        if !std::mem::take(&mut self.cycle_written) {
+            self.cycle = self.cycle.wrapping_add(1);
+        }
+        if !std::mem::take(&mut self.instret_written) {
+            self.instret = self.instret.wrapping_add(1);
+        }
+        self.clock.advance(1);
+    }
+
+    /// The value of `mip`: software-set bits combined with the asserted interrupt lines.
+    #[inline]
+    pub fn pending_interrupts(&self) -> u64 {
+        self.mip | self.irq.pending()
+    }
+
+    /// Dynamic rounding mode held in `frm`.
+    #[inline]
+    pub fn frm(&self) -> u8 {
+        (self.fcsr >> 5) as u8 & 0b111
+    }
+
+    /// Accumulate floating-point exception flags into `fflags`.
+    #[inline]
+    pub fn accrue_fflags(&mut self, flags: u8) {
+        if flags != 0 {
+            self.fcsr |= (flags & 0x1f) as u32;
+            self.set_fs_dirty();
+        }
+    }
+
+    /// Whether the floating-point unit is usable: with `mstatus.FS` Off, the F and D
+    /// instructions and CSRs are illegal.
+    #[inline]
+    pub fn fs_enabled(&self) -> bool {
+        self.mstatus & status::FS != 0
+    }
+
+    /// Record that the floating-point state was modified.
+    #[inline]
+    pub fn set_fs_dirty(&mut self) {
+        self.mstatus |= status::FS_DIRTY;
+    }
+
+    fn status(&self) -> u64 {
+        if self.mstatus & status::FS == status::FS_DIRTY {
+            self.mstatus | status::SD
+        } else {
+            self.mstatus
+        }
+    }
+
+    fn set_status(&mut self, value: u64, mask: u64) {
+        let mut value = value;
+        // MPP is WARL: the reserved mode 2 leaves the field unchanged.
+        if Privilege::from_bits((value & status::MPP) >> 11).is_none() {
+            value = (value & !status::MPP) | (self.mstatus & status::MPP);
+        }
+        self.mstatus = (self.mstatus & !mask) | (value & mask);
+    }
+
+    /// Whether `prv` may access the counter `csr` under `mcounteren` and `scounteren`.
+    fn counter_enabled(&self, csr: Csr, prv: Privilege) -> bool {
+        let bit = 1 << (csr.0 & 0x1f);
+        match prv {
+            Privilege::Machine => true,
+            Privilege::Supervisor => self.mcounteren & bit != 0,
+            Privilege::User => self.mcounteren & self.scounteren & bit != 0,
+        }
+    }
+
+    /// Read `csr` on behalf of `prv`, or `None` if the access is illegal.
+    pub fn read(&self, csr: Csr, prv: Privilege) -> Option<u64> {
+        if (prv as u8) < csr.min_prv_level() {
+            return None;
+        }
+        let value = match csr {
+            Csr::Fflags | Csr::Frm | Csr::Fcsr if !self.fs_enabled() => return None,
+            Csr::Fflags => (self.fcsr & 0x1f) as u64,
+            Csr::Frm => self.frm() as u64,
+            Csr::Fcsr => self.fcsr as u64,
+
+            Csr::Cycle | Csr::Time | Csr::Instret if !self.counter_enabled(csr, prv) => {
+                return None;
+            }
+            Csr::Cycle | Csr::Mcycle => self.cycle,
+            Csr::Time | Csr::Mtime => self.time(),
+            Csr::Instret | Csr::Minstret => self.instret,
+
+            Csr::Sstatus => self.status() & status::SSTATUS_MASK,
+            Csr::Sie => self.mie & self.mideleg,
+            Csr::Stvec => self.stvec,
+            Csr::Scounteren => self.scounteren,
+            Csr::Sscratch => self.sscratch,
+            Csr::Sepc => self.sepc,
+            Csr::Scause => self.scause,
+            Csr::Stval => self.stval,
+            Csr::Sip => self.pending_interrupts() & self.mideleg,
+            Csr::Satp if prv == Privilege::Supervisor && self.mstatus & status::TVM != 0 => {
+                return None;
+            }
+            Csr::Satp => self.satp,
+
+            Csr::Mvendorid | Csr::Marchid | Csr::Mimpid => 0,
+            Csr::Mhartid => self.mhartid,
+            Csr::Mstatus => self.status(),
+            Csr::Misa => MISA,
+            Csr::Medeleg => self.medeleg,
+            Csr::Mideleg => self.mideleg,
+            Csr::Mie => self.mie,
+            Csr::Mtvec => self.mtvec,
+            Csr::Mcounteren => self.mcounteren,
+            Csr::Mscratch => self.mscratch,
+            Csr::Mepc => self.mepc,
+            Csr::Mcause => self.mcause,
+            Csr::Mtval => self.mtval,
+            Csr::Mip => self.pending_interrupts(),
+            // Including the RV32-only `cycleh`, `timeh` and `instreth`.
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Write `csr` on behalf of `prv`, or return `None` if the access is illegal. Fields that are
+    /// not writable keep their value.
+    pub fn write(&mut self, csr: Csr, value: u64, prv: Privilege) -> Option<()> {
+        if csr.readonly() {
+            return None;
+        }
+        // Reading checks privilege and existence, which apply to writes as well.
+        self.read(csr, prv)?;
+        match csr {
+            Csr::Fflags => self.fcsr = (self.fcsr & !0x1f) | (value as u32 & 0x1f),
+            Csr::Frm => self.fcsr = (self.fcsr & 0x1f) | (value as u32 & 0b111) << 5,
+            Csr::Fcsr => self.fcsr = value as u32 & 0xff,
+
+            Csr::Sstatus => self.set_status(value, status::SSTATUS_MASK & status::MSTATUS_WRITABLE),
+            Csr::Sie => self.mie = (self.mie & !self.mideleg) | (value & self.mideleg),
+            Csr::Stvec => self.stvec = legalize_tvec(self.stvec, value),
+            Csr::Scounteren => self.scounteren = value & COUNTEREN_MASK,
+            Csr::Sscratch => self.sscratch = value,
+            Csr::Sepc => self.sepc = value & !1,
+            Csr::Scause => self.scause = value,
+            Csr::Stval => self.stval = value,
+            Csr::Sip => {
+                let mask = self.mideleg & interrupt::SSIP;
+                self.mip = (self.mip & !mask) | (value & mask);
+            }
+            Csr::Satp => {
+                // Writes selecting an unsupported mode have no effect.
+                if matches!(
+                    value >> 60,
+                    SATP_MODE_BARE | SATP_MODE_SV39 | SATP_MODE_SV48 | SATP_MODE_SV57
+                ) {
+                    self.satp = value;
+                }
+            }
+
+            Csr::Mstatus => self.set_status(value, status::MSTATUS_WRITABLE),
+            Csr::Misa => (),
+            Csr::Medeleg => self.medeleg = value & MEDELEG_MASK,
+            Csr::Mideleg => self.mideleg = value & interrupt::SUPERVISOR,
+            Csr::Mie => self.mie = value & interrupt::ALL,
+            Csr::Mtvec => self.mtvec = legalize_tvec(self.mtvec, value),
+            Csr::Mcounteren => self.mcounteren = value & COUNTEREN_MASK,
+            Csr::Mscratch => self.mscratch = value,
+            Csr::Mepc => self.mepc = value & !1,
+            Csr::Mcause => self.mcause = value,
+            Csr::Mtval => self.mtval = value,
+            // The machine-level pending bits are driven by the interrupt sources.
+            Csr::Mip => {
+                let mask = interrupt::SUPERVISOR;
+                self.mip = (self.mip & !mask) | (value & mask);
+            }
+            Csr::Mcycle => {
+                self.cycle = value;
+                self.cycle_written = true;
+            }
+            Csr::Minstret => {
+                self.instret = value;
+                self.instret_written = true;
+            }
+            // `mtime` is a memory-mapped register on real platforms; the CSR alias is read-only.
+            Csr::Mtime => (),
+            _ => return None,
+        }
+        if matches!(csr, Csr::Fflags | Csr::Frm | Csr::Fcsr) {
+            self.set_fs_dirty();
+        }
+        Some(())
+    }
+}
+
+/// Only direct (0) and vectored (1) modes are supported; reserved modes keep the old mode.
+fn legalize_tvec(old: u64, value: u64) -> u64 {
+    if value & 0b11 >= 2 {
+        (value & !0b11) | (old & 0b11)
+    } else {
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_privilege_checks() {
+        let mut csr = CsrFile::new(0);
+        assert_eq!(csr.read(Csr::Mstatus, Privilege::Supervisor), None);
+        assert_eq!(csr.read(Csr::Sstatus, Privilege::User), None);
+        assert_eq!(csr.write(Csr::Mhartid, 1, Privilege::Machine), None);
+        assert_eq!(csr.write(Csr::Cycle, 1, Privilege::Machine), None);
+        assert_eq!(csr.read(Csr::Cycleh, Privilege::Machine), None);
+        assert!(csr.read(Csr::Fcsr, Privilege::User).is_some());
+
+        // Counters need both enable bits below M-mode.
+        assert_eq!(csr.read(Csr::Cycle, Privilege::User), None);
+        csr.write(Csr::Mcounteren, 0b101, Privilege::Machine)
+            .unwrap();
+        assert!(csr.read(Csr::Cycle, Privilege::Supervisor).is_some());
+        assert_eq!(csr.read(Csr::Time, Privilege::Supervisor), None);
+        assert_eq!(csr.read(Csr::Cycle, Privilege::User), None);
+        csr.write(Csr::Scounteren, 0b111, Privilege::Supervisor)
+            .unwrap();
+        assert!(csr.read(Csr::Instret, Privilege::User).is_some());
+        assert_eq!(csr.read(Csr::Time, Privilege::User), None);
+
+        csr.write(Csr::Mstatus, status::TVM, Privilege::Machine)
+            .unwrap();
+        assert_eq!(csr.read(Csr::Satp, Privilege::Supervisor), None);
+        assert!(csr.read(Csr::Satp, Privilege::Machine).is_some());
+    }
+
+    #[test]
+    fn test_warl_fields() {
+        let mut csr = CsrFile::new(0);
+        let m = Privilege::Machine;
+
+        csr.write(Csr::Mstatus, u64::MAX, m).unwrap();
+        let mstatus = csr.read(Csr::Mstatus, m).unwrap();
+        assert_eq!(mstatus & status::UXL, 0b10 << 32);
+        assert_eq!(mstatus & status::MPP, status::MPP);
+        assert_ne!(mstatus & status::SD, 0);
+        // MPP = 2 is reserved and leaves the field alone.
+        csr.write(Csr::Mstatus, mstatus & !status::MPP | 0b10 << 11, m)
+            .unwrap();
+        assert_eq!(
+            csr.read(Csr::Mstatus, m).unwrap() & status::MPP,
+            status::MPP
+        );
+
+        csr.write(Csr::Sstatus, 0, m).unwrap();
+        let mstatus = csr.read(Csr::Mstatus, m).unwrap();
+        assert_eq!(mstatus & (status::SIE | status::SPP), 0);
+        assert_ne!(mstatus & status::MIE, 0);
+
+        csr.write(Csr::Mtvec, 0x1001, m).unwrap();
+        csr.write(Csr::Mtvec, 0x2002, m).unwrap();
+        assert_eq!(csr.read(Csr::Mtvec, m), Some(0x2001));
+
+        csr.write(Csr::Mepc, 0x1003, m).unwrap();
+        assert_eq!(csr.read(Csr::Mepc, m), Some(0x1002));
+
+        csr.write(Csr::Medeleg, u64::MAX, m).unwrap();
+        assert_eq!(csr.read(Csr::Medeleg, m).unwrap() & 1 << 11, 0);
+
+        csr.write(Csr::Mideleg, interrupt::SSIP | interrupt::STIP, m)
+            .unwrap();
+        csr.write(Csr::Mie, u64::MAX, m).unwrap();
+        assert_eq!(
+            csr.read(Csr::Sie, m),
+            Some(interrupt::SSIP | interrupt::STIP)
+        );
+        csr.write(Csr::Mip, u64::MAX, m).unwrap();
+        assert_eq!(csr.read(Csr::Mip, m), Some(interrupt::SUPERVISOR));
+        csr.write(Csr::Sip, 0, m).unwrap();
+        assert_eq!(
+            csr.read(Csr::Mip, m),
+            Some(interrupt::STIP | interrupt::SEIP)
+        );
+
+        let sv39 = SATP_MODE_SV39 << 60 | 0x1234;
+        csr.write(Csr::Satp, sv39, m).unwrap();
+        csr.write(Csr::Satp, 5 << 60, m).unwrap();
+        assert_eq!(csr.read(Csr::Satp, m), Some(sv39));
+
+        csr.write(Csr::Misa, 0, m).unwrap();
+        assert_eq!(csr.read(Csr::Misa, m), Some(MISA));
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut csr = CsrFile::new(0);
+        let m = Privilege::Machine;
+        csr.retire();
+        csr.retire();
+        assert_eq!(csr.read(Csr::Instret, m), Some(2));
+        assert_eq!(csr.read(Csr::Cycle, m), Some(2));
+
+        let before = csr.read(Csr::Time, m).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        assert!(csr.read(Csr::Time, m).unwrap() > before);
+    }
+
+    #[test]
+    fn test_interrupt_lines_in_mip() {
+        let mut csr = CsrFile::new(0);
+        let m = Privilege::Machine;
+        csr.irq.raise(interrupt::MTIP | interrupt::SEIP);
+        csr.write(Csr::Mideleg, interrupt::SEIP, m).unwrap();
+        assert_eq!(
+            csr.read(Csr::Mip, m),
+            Some(interrupt::MTIP | interrupt::SEIP)
+        );
+        assert_eq!(csr.read(Csr::Sip, m), Some(interrupt::SEIP));
+        // Clearing the software bit does not deassert the external line.
+        csr.write(Csr::Mip, 0, m).unwrap();
+        assert_eq!(
+            csr.read(Csr::Mip, m),
+            Some(interrupt::MTIP | interrupt::SEIP)
+        );
+    }
+}
@@ -0,0 +1,24 @@
+pub mod clint;
+pub mod plic;
+pub mod terminal;
+pub mod uart;
+
+/// A memory-mapped device register window.
+///
+/// Offsets are relative to the base the device is mapped at and accesses are 1, 2, 4 or 8 bytes
+/// wide. Devices are shared with host threads, so registers are accessed through `&self` and
+/// kept behind interior mutability. Returning `None` reports an access fault to the hart.
+pub trait MmioDevice: Send + Sync {
+    fn read(&self, offset: u64, size: usize) -> Option<u64>;
+    fn write(&self, offset: u64, size: usize, value: u64) -> Option<()>;
+}
+
+/// Insert the low `size` bytes of `value` into `word` at byte `shift`, for devices exposing
+/// 64-bit registers to narrower accesses.
+pub(crate) fn merge(word: u64, shift: u64, size: usize, value: u64) -> u64 {
+    let mask = match size {
+        8 => u64::MAX,
+        _ => (1 << (size * 8)) - 1,
+    } << (shift * 8);
+    word & !mask | (value << (shift * 8)) & mask
+}
@@ -0,0 +1,162 @@
+use super::{MmioDevice, merge};
+use crate::clock::Clock;
+use crate::csr::interrupt;
+use crate::interrupt::{InterruptLines, Timer};
+
+/// Size of the CLINT register window.
+pub const CLINT_SIZE: u64 = 0x1_0000;
+
+const MSIP: u64 = 0x0000;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
+
+/// A SiFive-compatible core-local interruptor: one `msip` word and one `mtimecmp` register per
+/// hart, and the shared `mtime` counter.
+///
+/// `mtime` is the platform [`Clock`]; comparisons against `mtimecmp` are made by the harts as
+/// they poll their timer lines, so the clock may follow either the host or the instruction count.
+#[derive(Debug)]
+pub struct Clint {
+    clock: Clock,
+    harts: Vec<InterruptLines>,
+}
+
+impl Clint {
+    /// A CLINT driving the interrupt lines of `harts`, indexed by hart ID.
+    pub fn new(clock: Clock, harts: Vec<InterruptLines>) -> Self {
+        Self { clock, harts }
+    }
+
+    /// The hart addressed by `offset` within a per-hart register array.
+    fn hart(&self, offset: u64, base: u64, stride: u64) -> Option<&InterruptLines> {
+        self.harts.get(((offset - base) / stride) as usize)
+    }
+}
+
+/// Reject accesses that are misaligned or straddle a register.
+fn check(offset: u64, size: usize, width: u64) -> Option<u64> {
+    let shift = offset % width;
+    match size as u64 {
+        1 | 2 | 4 | 8 if shift.is_multiple_of(size as u64) && shift + size as u64 <= width => {
+            Some(shift)
+        }
+        _ => None,
+    }
+}
+
+fn extract(word: u64, shift: u64, size: usize) -> u64 {
+    match size {
+        8 => word,
+        _ => (word >> (shift * 8)) & ((1 << (size * 8)) - 1),
+    }
+}
+
+impl MmioDevice for Clint {
+    fn read(&self, offset: u64, size: usize) -> Option<u64> {
+        match offset {
+            MSIP..MTIMECMP => {
+                let shift = check(offset, size, 4)?;
+                let lines = self.hart(offset, MSIP, 4)?;
+                let word = (lines.pending() & interrupt::MSIP != 0) as u64;
+                Some(extract(word, shift, size))
+            }
+            MTIMECMP..MTIME => {
+                let shift = check(offset, size, 8)?;
+                let lines = self.hart(offset, MTIMECMP, 8)?;
+                Some(extract(lines.timer(Timer::Machine), shift, size))
+            }
+            MTIME..CLINT_SIZE => {
+                let shift = check(offset, size, 8)?;
+                Some(extract(self.clock.now(), shift, size))
+            }
+            _ => None,
+        }
+    }
+
+    fn write(&self, offset: u64, size: usize, value: u64) -> Option<()> {
+        match offset {
+            MSIP..MTIMECMP => {
+                let shift = check(offset, size, 4)?;
+                let lines = self.hart(offset, MSIP, 4)?;
+                // Only bit 0 is implemented; writes to the upper bytes are ignored.
+                if shift == 0 {
+                    lines.set(interrupt::MSIP, value & 1 != 0);
+                }
+                Some(())
+            }
+            MTIMECMP..MTIME => {
+                let shift = check(offset, size, 8)?;
+                let lines = self.hart(offset, MTIMECMP, 8)?;
+                let deadline = merge(lines.timer(Timer::Machine), shift, size, value);
+                lines.set_timer(Timer::Machine, deadline);
+                Some(())
+            }
+            MTIME..CLINT_SIZE => {
+                let shift = check(offset, size, 8)?;
+                self.clock.set(merge(self.clock.now(), shift, size, value));
+                Some(())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clint() -> (Clint, Clock, Vec<InterruptLines>) {
+        let clock = Clock::instructions();
+        let harts = vec![InterruptLines::new(), InterruptLines::new()];
+        (Clint::new(clock.clone(), harts.clone()), clock, harts)
+    }
+
+    #[test]
+    fn test_software_interrupts() {
+        let (clint, _, harts) = clint();
+        clint.write(MSIP + 4, 4, 1).unwrap();
+        assert_eq!(harts[0].pending(), 0);
+        assert_eq!(harts[1].pending(), interrupt::MSIP);
+        assert_eq!(clint.read(MSIP + 4, 4), Some(1));
+        clint.write(MSIP + 4, 4, 0).unwrap();
+        assert_eq!(harts[1].pending(), 0);
+        // No third hart, and no misaligned words.
+        assert_eq!(clint.write(MSIP + 8, 4, 1), None);
+        assert_eq!(clint.read(MSIP + 2, 4), None);
+    }
+
+    #[test]
+    fn test_timer_interrupts() {
+        let (clint, clock, harts) = clint();
+        assert_eq!(clint.read(MTIMECMP, 8), Some(u64::MAX));
+        clint.write(MTIMECMP + 8, 8, 100).unwrap();
+        clock.advance(99);
+        harts[1].poll_timers(&clock);
+        assert_eq!(harts[1].pending(), 0);
+        clock.advance(1);
+        harts[1].poll_timers(&clock);
+        assert_eq!(harts[1].pending(), interrupt::MTIP);
+        harts[0].poll_timers(&clock);
+        assert_eq!(harts[0].pending(), 0);
+
+        // RV32-style update of the halves: high word first keeps the timer from firing early.
+        clint.write(MTIMECMP + 12, 4, 0x1).unwrap();
+        clint.write(MTIMECMP + 8, 4, 0x2).unwrap();
+        assert_eq!(clint.read(MTIMECMP + 8, 8), Some(0x1_0000_0002));
+        assert_eq!(clint.read(MTIMECMP + 12, 4), Some(0x1));
+        harts[1].poll_timers(&clock);
+        assert_eq!(harts[1].pending(), 0);
+    }
+
+    #[test]
+    fn test_mtime() {
+        let (clint, clock, _) = clint();
+        clock.advance(42);
+        assert_eq!(clint.read(MTIME, 8), Some(42));
+        clint.write(MTIME + 4, 4, 1).unwrap();
+        assert_eq!(clock.now(), 1 << 32 | 42);
+        assert_eq!(clint.read(MTIME + 4, 4), Some(1));
+        assert_eq!(clint.read(MTIME + 1, 2), None);
+        assert_eq!(clint.read(CLINT_SIZE, 8), None);
+    }
+}
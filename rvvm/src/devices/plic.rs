@@ -0,0 +1,349 @@
+use super::MmioDevice;
+use crate::csr::interrupt;
+use crate::interrupt::InterruptLines;
+use std::sync::{Arc, Mutex};
+
+const PRIORITY: u64 = 0x00_0000;
+const PENDING: u64 = 0x00_1000;
+const ENABLE: u64 = 0x00_2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+/// Number of interrupt sources, including the reserved source 0.
+pub const PLIC_SOURCES: u32 = 64;
+/// Highest implemented priority level.
+const MAX_PRIORITY: u32 = 7;
+const WORDS: usize = PLIC_SOURCES as usize / 32;
+
+#[derive(Debug, Default, Clone)]
+struct Context {
+    enable: [u32; WORDS],
+    threshold: u32,
+}
+
+#[derive(Debug)]
+struct State {
+    priority: [u32; PLIC_SOURCES as usize],
+    /// Input level of each source as driven by its device.
+    level: [u32; WORDS],
+    pending: [u32; WORDS],
+    /// Sources claimed and not yet completed; their gateways hold off new requests.
+    claimed: [u32; WORDS],
+    contexts: Vec<Context>,
+}
+
+fn bit(words: &[u32; WORDS], irq: u32) -> bool {
+    words[irq as usize / 32] & 1 << (irq % 32) != 0
+}
+
+fn set_bit(words: &mut [u32; WORDS], irq: u32, value: bool) {
+    let mask = 1 << (irq % 32);
+    if value {
+        words[irq as usize / 32] |= mask;
+    } else {
+        words[irq as usize / 32] &= !mask;
+    }
+}
+
+impl State {
+    /// Forward a source's level through its gateway. The pending bit follows the level unless
+    /// the previous request is still being serviced.
+    fn gateway(&mut self, irq: u32) {
+        if !bit(&self.claimed, irq) {
+            let level = bit(&self.level, irq);
+            set_bit(&mut self.pending, irq, level);
+        }
+    }
+
+    /// The pending source a claim from `context` would return, or 0.
+    fn best(&self, context: usize) -> u32 {
+        let context = &self.contexts[context];
+        let mut best = (0, context.threshold);
+        for irq in 1..PLIC_SOURCES {
+            if bit(&self.pending, irq)
+                && bit(&context.enable, irq)
+                && self.priority[irq as usize] > best.1
+            {
+                best = (irq, self.priority[irq as usize]);
+            }
+        }
+        best.0
+    }
+
+    /// Take the best pending source for `context`, holding off its gateway until completion.
+    fn claim(&mut self, context: usize) -> u32 {
+        let irq = self.best(context);
+        if irq != 0 {
+            set_bit(&mut self.pending, irq, false);
+            set_bit(&mut self.claimed, irq, true);
+        }
+        irq
+    }
+
+    fn complete(&mut self, context: usize, irq: u32) {
+        // Completions for sources the context does not enable are ignored.
+        if (1..PLIC_SOURCES).contains(&irq) && bit(&self.contexts[context].enable, irq) {
+            set_bit(&mut self.claimed, irq, false);
+            self.gateway(irq);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    /// Interrupt lines of each hart; context `2 * hart` targets M-mode, `2 * hart + 1` S-mode.
+    harts: Vec<InterruptLines>,
+}
+
+impl Shared {
+    /// Drive each context's external interrupt line from the current state.
+    fn update(&self, state: &State) {
+        for (context, _) in state.contexts.iter().enumerate() {
+            let lines = &self.harts[context / 2];
+            let line = match context % 2 {
+                0 => interrupt::MEIP,
+                _ => interrupt::SEIP,
+            };
+            lines.set(line, state.best(context) != 0);
+        }
+    }
+
+    fn set_level(&self, irq: u32, level: bool) {
+        let mut state = self.state.lock().unwrap();
+        set_bit(&mut state.level, irq, level);
+        state.gateway(irq);
+        self.update(&state);
+    }
+}
+
+/// A platform-level interrupt controller with the SiFive register layout: per-source priorities
+/// and pending bits, and per-context enables, thresholds and claim/complete registers.
+///
+/// Each hart has two contexts, machine then supervisor, driving MEIP and SEIP. Sources are
+/// level-triggered; devices drive them through [`Irq`] handles.
+#[derive(Debug, Clone)]
+pub struct Plic {
+    shared: Arc<Shared>,
+}
+
+/// An interrupt source input of a [`Plic`], held by the device that drives it.
+#[derive(Debug, Clone)]
+pub struct Irq {
+    shared: Arc<Shared>,
+    irq: u32,
+}
+
+impl Irq {
+    /// The source number, as seen in the claim register and the device tree.
+    pub fn number(&self) -> u32 {
+        self.irq
+    }
+
+    /// Drive the source to `level`.
+    pub fn set(&self, level: bool) {
+        self.shared.set_level(self.irq, level);
+    }
+
+    pub fn raise(&self) {
+        self.set(true);
+    }
+
+    pub fn lower(&self) {
+        self.set(false);
+    }
+}
+
+impl Plic {
+    /// A PLIC serving `harts`, indexed by hart ID.
+    pub fn new(harts: Vec<InterruptLines>) -> Self {
+        let state = State {
+            priority: [0; PLIC_SOURCES as usize],
+            level: [0; WORDS],
+            pending: [0; WORDS],
+            claimed: [0; WORDS],
+            contexts: vec![Context::default(); harts.len() * 2],
+        };
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(state),
+                harts,
+            }),
+        }
+    }
+
+    /// The input of source `irq`, or `None` if it is out of range.
+    pub fn irq(&self, irq: u32) -> Option<Irq> {
+        (1..PLIC_SOURCES).contains(&irq).then(|| Irq {
+            shared: self.shared.clone(),
+            irq,
+        })
+    }
+
+    /// Number of contexts, two per hart.
+    pub fn contexts(&self) -> usize {
+        self.shared.harts.len() * 2
+    }
+
+    /// Size of the register window, covering every context.
+    pub fn size(&self) -> u64 {
+        CONTEXT + CONTEXT_STRIDE * self.contexts() as u64
+    }
+}
+
+impl MmioDevice for Plic {
+    fn read(&self, offset: u64, size: usize) -> Option<u64> {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        let value = match offset {
+            PRIORITY..PENDING => *state.priority.get((offset / 4) as usize)?,
+            PENDING..ENABLE => *state.pending.get(((offset - PENDING) / 4) as usize)?,
+            ENABLE..CONTEXT => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                let word = ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize;
+                *state.contexts.get(context)?.enable.get(word)?
+            }
+            _ => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                state.contexts.get(context)?;
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    0 => state.contexts[context].threshold,
+                    4 => {
+                        let irq = state.claim(context);
+                        self.shared.update(&state);
+                        irq
+                    }
+                    _ => return None,
+                }
+            }
+        };
+        Some(value as u64)
+    }
+
+    fn write(&self, offset: u64, size: usize, value: u64) -> Option<()> {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+        let value = value as u32;
+        let mut state = self.shared.state.lock().unwrap();
+        match offset {
+            PRIORITY..PENDING => {
+                let irq = (offset / 4) as usize;
+                *state.priority.get_mut(irq)? = match irq {
+                    0 => 0,
+                    _ => value.min(MAX_PRIORITY),
+                };
+            }
+            // Pending bits are read-only.
+            PENDING..ENABLE => {}
+            ENABLE..CONTEXT => {
+                let context = ((offset - ENABLE) / ENABLE_STRIDE) as usize;
+                let word = ((offset - ENABLE) % ENABLE_STRIDE / 4) as usize;
+                let enable = state.contexts.get_mut(context)?.enable.get_mut(word)?;
+                // Source 0 does not exist.
+                *enable = if word == 0 { value & !1 } else { value };
+            }
+            _ => {
+                let context = ((offset - CONTEXT) / CONTEXT_STRIDE) as usize;
+                state.contexts.get(context)?;
+                match (offset - CONTEXT) % CONTEXT_STRIDE {
+                    0 => state.contexts[context].threshold = value.min(MAX_PRIORITY),
+                    4 => state.complete(context, value),
+                    _ => return None,
+                }
+            }
+        }
+        self.shared.update(&state);
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLAIM: u64 = CONTEXT + 4;
+
+    fn plic() -> (Plic, Vec<InterruptLines>) {
+        let harts = vec![InterruptLines::new(), InterruptLines::new()];
+        (Plic::new(harts.clone()), harts)
+    }
+
+    #[test]
+    fn test_claim_and_complete() {
+        let (plic, harts) = plic();
+        let uart = plic.irq(10).unwrap();
+        plic.write(PRIORITY + 4 * 10, 4, 1).unwrap();
+        uart.raise();
+        assert_eq!(plic.read(PENDING, 4), Some(1 << 10));
+        // Not enabled for any context yet.
+        assert_eq!(harts[0].pending(), 0);
+
+        plic.write(ENABLE, 4, 1 << 10).unwrap();
+        assert_eq!(harts[0].pending(), interrupt::MEIP);
+        assert_eq!(plic.read(CLAIM, 4), Some(10));
+        assert_eq!(plic.read(PENDING, 4), Some(0));
+        assert_eq!(harts[0].pending(), 0);
+        // Claimed sources are not re-requested until completion, even if still asserted.
+        assert_eq!(plic.read(CLAIM, 4), Some(0));
+
+        plic.write(CLAIM, 4, 10).unwrap();
+        assert_eq!(harts[0].pending(), interrupt::MEIP);
+        assert_eq!(plic.read(CLAIM, 4), Some(10));
+        uart.lower();
+        plic.write(CLAIM, 4, 10).unwrap();
+        assert_eq!(harts[0].pending(), 0);
+        assert_eq!(plic.read(PENDING, 4), Some(0));
+    }
+
+    #[test]
+    fn test_priority_and_threshold() {
+        let (plic, harts) = plic();
+        // Supervisor context of hart 1.
+        let context = 3;
+        let enable = ENABLE + ENABLE_STRIDE * context;
+        let threshold = CONTEXT + CONTEXT_STRIDE * context;
+        plic.write(PRIORITY + 4 * 3, 4, 2).unwrap();
+        plic.write(PRIORITY + 4 * 40, 4, 5).unwrap();
+        plic.write(enable, 4, 1 << 3).unwrap();
+        plic.write(enable + 4, 4, 1 << (40 - 32)).unwrap();
+        plic.write(threshold, 4, 2).unwrap();
+
+        plic.irq(3).unwrap().raise();
+        assert_eq!(harts[1].pending(), 0);
+        plic.irq(40).unwrap().raise();
+        assert_eq!(harts[1].pending(), interrupt::SEIP);
+        assert_eq!(harts[0].pending(), 0);
+
+        plic.write(threshold, 4, 1).unwrap();
+        assert_eq!(plic.read(threshold + 4, 4), Some(40));
+        assert_eq!(plic.read(threshold + 4, 4), Some(3));
+        assert_eq!(plic.read(threshold + 4, 4), Some(0));
+
+        // Priority zero never interrupts.
+        plic.write(threshold, 4, 0).unwrap();
+        plic.write(PRIORITY + 4 * 3, 4, 0).unwrap();
+        plic.write(threshold + 4, 4, 3).unwrap();
+        assert_eq!(plic.read(PENDING, 4), Some(1 << 3));
+        assert_eq!(harts[1].pending(), 0);
+    }
+
+    #[test]
+    fn test_register_bounds() {
+        let (plic, _) = plic();
+        assert!(plic.irq(0).is_none());
+        assert!(plic.irq(PLIC_SOURCES).is_none());
+        assert_eq!(plic.size(), CONTEXT + 4 * CONTEXT_STRIDE);
+        assert_eq!(plic.read(CONTEXT + 4 * CONTEXT_STRIDE, 4), None);
+        assert_eq!(plic.read(ENABLE + ENABLE_STRIDE * 4, 4), None);
+        assert_eq!(plic.read(CONTEXT + 8, 4), None);
+        assert_eq!(plic.read(PRIORITY + 2, 2), None);
+        plic.write(PRIORITY, 4, 7).unwrap();
+        assert_eq!(plic.read(PRIORITY, 4), Some(0));
+        plic.write(PRIORITY + 4, 4, 100).unwrap();
+        assert_eq!(plic.read(PRIORITY + 4, 4), Some(MAX_PRIORITY as u64));
+    }
+}
@@ -0,0 +1,71 @@
+use super::uart::Uart;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Terminal settings saved by [`enter_raw_mode`].
+static SAVED: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// Ctrl-A, the prefix of console escape sequences.
+const ESCAPE: u8 = 0x01;
+
+/// Switch the host terminal on stdin to raw mode, so keystrokes, including control characters,
+/// reach the guest unprocessed. Does nothing if stdin is not a terminal.
+pub fn enter_raw_mode() {
+    let mut saved = SAVED.lock().unwrap();
+    if saved.is_some() {
+        return;
+    }
+    // SAFETY: `termios` is plain data and both calls only access the struct passed in.
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+            return;
+        }
+        *saved = Some(termios);
+        libc::cfmakeraw(&mut termios);
+        // Keep output post-processing so host-side messages still start at column 0.
+        termios.c_oflag |= libc::OPOST;
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+    }
+}
+
+/// Restore the terminal settings saved by [`enter_raw_mode`].
+pub fn restore_terminal() {
+    if let Some(termios) = SAVED.lock().unwrap().take() {
+        // SAFETY: restores settings previously returned by `tcgetattr`.
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+        }
+    }
+}
+
+/// Forward host stdin to `uart` from a background thread.
+///
+/// `Ctrl-A x` restores the terminal and exits the emulator; `Ctrl-A Ctrl-A` sends a literal
+/// Ctrl-A.
+pub fn attach_stdin(uart: Uart) {
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut escaped = false;
+        let mut byte = [0u8];
+        while let Ok(1) = stdin.read(&mut byte) {
+            match (escaped, byte[0]) {
+                (false, ESCAPE) => {
+                    escaped = true;
+                    continue;
+                }
+                (true, b'x') => {
+                    restore_terminal();
+                    std::process::exit(0);
+                }
+                _ => escaped = false,
+            }
+            // Apply backpressure rather than overrunning the guest's FIFO.
+            while !uart.can_receive() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            uart.receive(&byte);
+        }
+    });
+}
@@ -0,0 +1,393 @@
+use super::MmioDevice;
+use super::plic::Irq;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// Size of the UART register window.
+pub const UART_SIZE: u64 = 0x100;
+/// Depth of the receive FIFO.
+const FIFO_DEPTH: usize = 16;
+
+const RBR_THR: u64 = 0;
+const IER: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RDA: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+const IER_RLS: u8 = 1 << 2;
+
+const IIR_NONE: u8 = 0x01;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_RLS: u8 = 0x06;
+const IIR_FIFO: u8 = 0xc0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_LOOP: u8 = 1 << 4;
+
+const LSR_DR: u8 = 1 << 0;
+const LSR_OE: u8 = 1 << 1;
+const LSR_THRE: u8 = 1 << 5;
+const LSR_TEMT: u8 = 1 << 6;
+
+/// Modem status with the carrier, data-set-ready and clear-to-send inputs asserted.
+const MSR_CONNECTED: u8 = 0xb0;
+
+struct State {
+    rx: VecDeque<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    fcr: u8,
+    divisor: u16,
+    overrun: bool,
+    /// The transmitter-empty interrupt, raised when THR drains and acknowledged by reading IIR.
+    thre: bool,
+    output: Box<dyn Write + Send>,
+}
+
+impl State {
+    fn capacity(&self) -> usize {
+        match self.fcr & FCR_ENABLE {
+            0 => 1,
+            _ => FIFO_DEPTH,
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.rx.len() < self.capacity() {
+            self.rx.push_back(byte);
+        } else {
+            self.overrun = true;
+        }
+    }
+
+    /// The highest-priority pending interrupt, as an IIR identification.
+    fn interrupt(&self) -> u8 {
+        if self.ier & IER_RLS != 0 && self.overrun {
+            IIR_RLS
+        } else if self.ier & IER_RDA != 0 && !self.rx.is_empty() {
+            IIR_RDA
+        } else if self.ier & IER_THRE != 0 && self.thre {
+            IIR_THRE
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn lsr(&self) -> u8 {
+        let mut lsr = LSR_THRE | LSR_TEMT;
+        if !self.rx.is_empty() {
+            lsr |= LSR_DR;
+        }
+        if self.overrun {
+            lsr |= LSR_OE;
+        }
+        lsr
+    }
+
+    fn msr(&self) -> u8 {
+        if self.mcr & MCR_LOOP == 0 {
+            return MSR_CONNECTED;
+        }
+        // Loopback wires RTS, DTR, OUT1 and OUT2 back to CTS, DSR, RI and DCD.
+        (self.mcr & 0x0f) << 4
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.mcr & MCR_LOOP != 0 {
+            self.receive(byte);
+        } else {
+            // The guest cannot observe a failing host stream; drop the byte.
+            let _ = self
+                .output
+                .write_all(&[byte])
+                .and_then(|_| self.output.flush());
+        }
+        // The transmitter drains instantly.
+        self.thre = true;
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    irq: Option<Irq>,
+}
+
+impl Shared {
+    fn update(&self, state: &State) {
+        if let Some(irq) = &self.irq {
+            irq.set(state.interrupt() != IIR_NONE);
+        }
+    }
+}
+
+/// An NS16550A-compatible UART with byte-wide registers.
+///
+/// Transmitted bytes go straight to the output stream, so the transmitter is always empty. Host
+/// input is queued with [`Uart::receive`], usually from a thread reading the terminal.
+#[derive(Clone)]
+pub struct Uart {
+    shared: Arc<Shared>,
+}
+
+impl std::fmt::Debug for Uart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Uart")
+            .field("irq", &self.shared.irq)
+            .finish()
+    }
+}
+
+impl Uart {
+    /// A UART writing to `output` and signalling interrupts on `irq`.
+    pub fn new(output: Box<dyn Write + Send>, irq: Option<Irq>) -> Self {
+        let state = State {
+            rx: VecDeque::with_capacity(FIFO_DEPTH),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            fcr: 0,
+            divisor: 0,
+            overrun: false,
+            thre: true,
+            output,
+        };
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(state),
+                irq,
+            }),
+        }
+    }
+
+    /// Queue host input for the guest. Bytes that do not fit in the receive FIFO are dropped
+    /// and flagged as an overrun.
+    pub fn receive(&self, data: &[u8]) {
+        let mut state = self.shared.state.lock().unwrap();
+        for &byte in data {
+            state.receive(byte);
+        }
+        self.shared.update(&state);
+    }
+
+    /// Whether the receive FIFO has room for more input.
+    pub fn can_receive(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        state.rx.len() < state.capacity()
+    }
+}
+
+/// Host-side output, such as a firmware debug console, bypassing the registers.
+impl Write for Uart {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.shared.state.lock().unwrap().output.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.shared.state.lock().unwrap().output.flush()
+    }
+}
+
+/// Host-side input: drains whatever is in the receive FIFO without blocking.
+impl Read for Uart {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut state = self.shared.state.lock().unwrap();
+        let len = buf.len().min(state.rx.len());
+        for (slot, byte) in buf.iter_mut().zip(state.rx.drain(..len)) {
+            *slot = byte;
+        }
+        self.shared.update(&state);
+        Ok(len)
+    }
+}
+
+impl MmioDevice for Uart {
+    fn read(&self, offset: u64, size: usize) -> Option<u64> {
+        if size != 1 {
+            return None;
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        let dlab = state.lcr & LCR_DLAB != 0;
+        let value = match offset {
+            RBR_THR if dlab => state.divisor as u8,
+            RBR_THR => state.rx.pop_front().unwrap_or(0),
+            IER if dlab => (state.divisor >> 8) as u8,
+            IER => state.ier,
+            IIR_FCR => {
+                let id = state.interrupt();
+                if id == IIR_THRE {
+                    state.thre = false;
+                }
+                let fifo = match state.fcr & FCR_ENABLE {
+                    0 => 0,
+                    _ => IIR_FIFO,
+                };
+                id | fifo
+            }
+            LCR => state.lcr,
+            MCR => state.mcr,
+            LSR => {
+                let lsr = state.lsr();
+                state.overrun = false;
+                lsr
+            }
+            MSR => state.msr(),
+            SCR => state.scr,
+            // The rest of the window reads as zero.
+            _ if offset < UART_SIZE => 0,
+            _ => return None,
+        };
+        self.shared.update(&state);
+        Some(value as u64)
+    }
+
+    fn write(&self, offset: u64, size: usize, value: u64) -> Option<()> {
+        if size != 1 {
+            return None;
+        }
+        let value = value as u8;
+        let mut state = self.shared.state.lock().unwrap();
+        let dlab = state.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR if dlab => state.divisor = state.divisor & 0xff00 | value as u16,
+            RBR_THR => state.transmit(value),
+            IER if dlab => state.divisor = state.divisor & 0x00ff | (value as u16) << 8,
+            IER => {
+                // Enabling the transmitter interrupt while THR is empty raises it right away.
+                if value & IER_THRE != 0 && state.ier & IER_THRE == 0 {
+                    state.thre = true;
+                }
+                state.ier = value & 0x0f;
+            }
+            IIR_FCR => {
+                if value & FCR_CLEAR_RX != 0 || (value ^ state.fcr) & FCR_ENABLE != 0 {
+                    state.rx.clear();
+                }
+                state.fcr = value & (FCR_ENABLE | 0xc0);
+            }
+            LCR => state.lcr = value,
+            MCR => state.mcr = value & 0x1f,
+            SCR => state.scr = value,
+            // LSR and MSR are read-only.
+            _ if offset < UART_SIZE => {}
+            _ => return None,
+        }
+        self.shared.update(&state);
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::plic::Plic;
+    use crate::interrupt::InterruptLines;
+
+    /// A `Write` handle onto a shared buffer.
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn uart() -> (Uart, Sink, Plic, InterruptLines) {
+        let lines = InterruptLines::new();
+        let plic = Plic::new(vec![lines.clone()]);
+        plic.write(0x4 * 10, 4, 1).unwrap();
+        plic.write(0x2000, 4, 1 << 10).unwrap();
+        let sink = Sink::default();
+        let uart = Uart::new(Box::new(sink.clone()), plic.irq(10));
+        (uart, sink, plic, lines)
+    }
+
+    fn pending(lines: &InterruptLines) -> bool {
+        lines.pending() & crate::csr::interrupt::MEIP != 0
+    }
+
+    #[test]
+    fn test_transmit() {
+        let (uart, sink, _, lines) = uart();
+        for &byte in b"ok\r\n" {
+            assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_THRE, LSR_THRE);
+            uart.write(RBR_THR, 1, byte as u64).unwrap();
+        }
+        assert_eq!(sink.0.lock().unwrap().as_slice(), b"ok\r\n");
+
+        // The THRE interrupt fires on enable and is acknowledged by reading IIR.
+        uart.write(IER, 1, IER_THRE as u64).unwrap();
+        assert!(pending(&lines));
+        assert_eq!(uart.read(IIR_FCR, 1), Some(IIR_THRE as u64));
+        assert!(!pending(&lines));
+        assert_eq!(uart.read(IIR_FCR, 1), Some(IIR_NONE as u64));
+        uart.write(RBR_THR, 1, b'!' as u64).unwrap();
+        assert!(pending(&lines));
+    }
+
+    #[test]
+    fn test_receive_fifo() {
+        let (uart, _, _, lines) = uart();
+        uart.write(IIR_FCR, 1, (FCR_ENABLE | FCR_CLEAR_RX) as u64)
+            .unwrap();
+        uart.write(IER, 1, (IER_RDA | IER_RLS) as u64).unwrap();
+        assert_eq!(uart.read(IIR_FCR, 1), Some((IIR_FIFO | IIR_NONE) as u64));
+
+        uart.receive(b"hi");
+        assert!(pending(&lines));
+        assert_eq!(uart.read(IIR_FCR, 1), Some((IIR_FIFO | IIR_RDA) as u64));
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_DR, LSR_DR);
+        assert_eq!(uart.read(RBR_THR, 1), Some(b'h' as u64));
+        assert_eq!(uart.read(RBR_THR, 1), Some(b'i' as u64));
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_DR, 0);
+        assert!(!pending(&lines));
+
+        // Overflowing the FIFO drops input and reports an overrun once.
+        uart.receive(&[b'x'; FIFO_DEPTH + 1]);
+        assert!(!uart.can_receive());
+        assert_eq!(uart.read(IIR_FCR, 1), Some((IIR_FIFO | IIR_RLS) as u64));
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_OE, LSR_OE);
+        assert_eq!(uart.read(LSR, 1).unwrap() as u8 & LSR_OE, 0);
+        uart.write(IIR_FCR, 1, (FCR_ENABLE | FCR_CLEAR_RX) as u64)
+            .unwrap();
+        assert!(!pending(&lines));
+    }
+
+    #[test]
+    fn test_divisor_and_loopback() {
+        let (uart, sink, _, _) = uart();
+        uart.write(LCR, 1, LCR_DLAB as u64 | 0x03).unwrap();
+        uart.write(RBR_THR, 1, 0x01).unwrap();
+        uart.write(IER, 1, 0x02).unwrap();
+        assert_eq!(uart.read(RBR_THR, 1), Some(0x01));
+        assert_eq!(uart.read(IER, 1), Some(0x02));
+        uart.write(LCR, 1, 0x03).unwrap();
+        assert_eq!(uart.read(IER, 1), Some(0));
+
+        uart.write(MCR, 1, (MCR_LOOP | 0x0a) as u64).unwrap();
+        assert_eq!(uart.read(MSR, 1), Some(0xa0));
+        uart.write(RBR_THR, 1, 0x55).unwrap();
+        assert_eq!(uart.read(RBR_THR, 1), Some(0x55));
+        assert!(sink.0.lock().unwrap().is_empty());
+
+        uart.write(SCR, 1, 0x5a).unwrap();
+        assert_eq!(uart.read(SCR, 1), Some(0x5a));
+        assert_eq!(uart.read(LSR, 4), None);
+    }
+}
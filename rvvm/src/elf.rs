@@ -0,0 +1,555 @@
+use super::bus::Bus;
+use super::error::OperationError;
+use core::ops::RangeInclusive;
+use thiserror::Error;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 243;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const SHT_SYMTAB: u32 = 2;
+const SHT_DYNSYM: u32 = 11;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+const SYM_SIZE: usize = 24;
+
+/// The object uses compressed instructions.
+pub const EF_RISCV_RVC: u32 = 0x1;
+/// Floating-point calling convention: soft, single, double or quad.
+pub const EF_RISCV_FLOAT_ABI: u32 = 0x6;
+const EF_RISCV_FLOAT_ABI_QUAD: u32 = 0x6;
+/// RV32E/RV64E: 16 integer registers.
+pub const EF_RISCV_RVE: u32 = 0x8;
+/// Total store ordering, which a sequential hart satisfies.
+pub const EF_RISCV_TSO: u32 = 0x10;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    #[error("not an ELF file")]
+    BadMagic,
+    #[error("not a little-endian ELF64 file")]
+    UnsupportedClass,
+    #[error("not a RISC-V file (e_machine {0})")]
+    WrongMachine(u16),
+    #[error("not an executable (e_type {0})")]
+    UnsupportedType(u16),
+    #[error("unsupported ABI flags {0:#x}")]
+    UnsupportedFlags(u32),
+    #[error("truncated or malformed {0}")]
+    Malformed(&'static str),
+}
+
+/// A loadable segment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub paddr: u64,
+    /// Bytes taken from the file; the rest of `mem_size` is zero-filled.
+    pub data: Vec<u8>,
+    pub mem_size: u64,
+    /// `PF_X`, `PF_W` and `PF_R` in bits 0, 1 and 2.
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+}
+
+/// Symbols sorted by address, for naming code locations in traces.
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.value);
+        Self { symbols }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    /// The symbol called `name`.
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// The symbol covering `addr`, and the offset of `addr` into it. Zero-sized symbols cover
+    /// only their own address.
+    pub fn lookup(&self, addr: u64) -> Option<(&Symbol, u64)> {
+        let index = self.symbols.partition_point(|symbol| symbol.value <= addr);
+        self.symbols[..index]
+            .iter()
+            .rev()
+            .find(|symbol| addr - symbol.value < symbol.size.max(1))
+            .map(|symbol| (symbol, addr - symbol.value))
+    }
+}
+
+/// A parsed ELF64 RISC-V executable or kernel.
+#[derive(Debug, Clone)]
+pub struct Elf {
+    pub entry: u64,
+    pub flags: u32,
+    /// Whether the file is position independent (`ET_DYN`).
+    pub dynamic: bool,
+    pub segments: Vec<Segment>,
+    /// Virtual address of the program headers, if a loaded segment contains them.
+    pub phdr: Option<u64>,
+    pub phnum: u16,
+    pub symbols: Symbols,
+}
+
+fn read<const N: usize>(
+    data: &[u8],
+    offset: usize,
+    what: &'static str,
+) -> Result<[u8; N], ElfError> {
+    offset
+        .checked_add(N)
+        .and_then(|end| data.get(offset..end))
+        .map(|bytes| bytes.try_into().unwrap())
+        .ok_or(ElfError::Malformed(what))
+}
+
+fn u16_at(data: &[u8], offset: usize, what: &'static str) -> Result<u16, ElfError> {
+    read(data, offset, what).map(u16::from_le_bytes)
+}
+
+fn u32_at(data: &[u8], offset: usize, what: &'static str) -> Result<u32, ElfError> {
+    read(data, offset, what).map(u32::from_le_bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize, what: &'static str) -> Result<u64, ElfError> {
+    read(data, offset, what).map(u64::from_le_bytes)
+}
+
+fn slice<'a>(
+    data: &'a [u8],
+    offset: u64,
+    len: u64,
+    what: &'static str,
+) -> Result<&'a [u8], ElfError> {
+    let start = usize::try_from(offset).map_err(|_| ElfError::Malformed(what))?;
+    let len = usize::try_from(len).map_err(|_| ElfError::Malformed(what))?;
+    start
+        .checked_add(len)
+        .and_then(|end| data.get(start..end))
+        .ok_or(ElfError::Malformed(what))
+}
+
+/// File offset of entry `index` in a table of `size`-byte entries at `base`.
+fn entry_offset(base: u64, index: u64, size: u64, what: &'static str) -> Result<u64, ElfError> {
+    index
+        .checked_mul(size)
+        .and_then(|offset| base.checked_add(offset))
+        .ok_or(ElfError::Malformed(what))
+}
+
+impl Elf {
+    /// Whether `data` starts with the ELF magic.
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(ELF_MAGIC)
+    }
+
+    /// Parse and validate an ELF64 RISC-V file. Files for RVE, for the quad-precision float ABI
+    /// or with unknown flags are rejected, as the hart cannot run them.
+    pub fn parse(data: &[u8]) -> Result<Self, ElfError> {
+        if !Self::is_elf(data) {
+            return Err(ElfError::BadMagic);
+        }
+        let ident: [u8; 16] = read(data, 0, "ELF header")?;
+        if ident[4] != ELFCLASS64 || ident[5] != ELFDATA2LSB {
+            return Err(ElfError::UnsupportedClass);
+        }
+        read::<EHDR_SIZE>(data, 0, "ELF header")?;
+        let kind = u16_at(data, 16, "ELF header")?;
+        let machine = u16_at(data, 18, "ELF header")?;
+        if machine != EM_RISCV {
+            return Err(ElfError::WrongMachine(machine));
+        }
+        if kind != ET_EXEC && kind != ET_DYN {
+            return Err(ElfError::UnsupportedType(kind));
+        }
+        let flags = u32_at(data, 48, "ELF header")?;
+        let known = EF_RISCV_RVC | EF_RISCV_FLOAT_ABI | EF_RISCV_TSO;
+        if flags & EF_RISCV_RVE != 0
+            || flags & EF_RISCV_FLOAT_ABI == EF_RISCV_FLOAT_ABI_QUAD
+            || flags & !known != 0
+        {
+            return Err(ElfError::UnsupportedFlags(flags));
+        }
+        let entry = u64_at(data, 24, "ELF header")?;
+        let phoff = u64_at(data, 32, "ELF header")?;
+        let shoff = u64_at(data, 40, "ELF header")?;
+        let phentsize = u16_at(data, 54, "ELF header")? as u64;
+        let phnum = u16_at(data, 56, "ELF header")?;
+        let shentsize = u16_at(data, 58, "ELF header")? as u64;
+        let shnum = u16_at(data, 60, "ELF header")?;
+
+        let mut segments = Vec::new();
+        let mut phdr = None;
+        if phnum > 0 && (phentsize as usize) < PHDR_SIZE {
+            return Err(ElfError::Malformed("program header"));
+        }
+        for index in 0..phnum as u64 {
+            let header = slice(
+                data,
+                entry_offset(phoff, index, phentsize, "program header")?,
+                PHDR_SIZE as u64,
+                "program header",
+            )?;
+            let field = |offset| u64_at(header, offset, "program header");
+            let kind = u32_at(header, 0, "program header")?;
+            let (offset, vaddr, paddr, file_size, mem_size) =
+                (field(8)?, field(16)?, field(24)?, field(32)?, field(40)?);
+            match kind {
+                PT_PHDR => phdr = Some(vaddr),
+                PT_LOAD => {
+                    if file_size > mem_size
+                        || vaddr.checked_add(mem_size).is_none()
+                        || paddr.checked_add(mem_size).is_none()
+                    {
+                        return Err(ElfError::Malformed("segment sizes"));
+                    }
+                    segments.push(Segment {
+                        vaddr,
+                        paddr,
+                        data: slice(data, offset, file_size, "segment")?.to_vec(),
+                        mem_size,
+                        flags: u32_at(header, 4, "program header")?,
+                    });
+                    // Without PT_PHDR, find the program headers in the segment that maps them.
+                    if phdr.is_none() {
+                        phdr = phoff
+                            .checked_sub(offset)
+                            .filter(|&delta| delta < file_size)
+                            .map(|delta| vaddr + delta);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut symbols = Vec::new();
+        if shnum > 0 && (shentsize as usize) < SHDR_SIZE {
+            return Err(ElfError::Malformed("section header"));
+        }
+        let section = |index: u64| {
+            slice(
+                data,
+                entry_offset(shoff, index, shentsize, "section header")?,
+                SHDR_SIZE as u64,
+                "section header",
+            )
+        };
+        // Prefer the full symbol table, falling back to the dynamic one.
+        for wanted in [SHT_SYMTAB, SHT_DYNSYM] {
+            for index in 0..shnum as u64 {
+                let header = section(index)?;
+                if u32_at(header, 4, "section header")? != wanted {
+                    continue;
+                }
+                let table = slice(
+                    data,
+                    u64_at(header, 24, "section header")?,
+                    u64_at(header, 32, "section header")?,
+                    "symbol table",
+                )?;
+                let strings = section(u32_at(header, 40, "section header")? as u64)?;
+                let strings = slice(
+                    data,
+                    u64_at(strings, 24, "section header")?,
+                    u64_at(strings, 32, "section header")?,
+                    "string table",
+                )?;
+                for entry in table.chunks_exact(SYM_SIZE) {
+                    let name = u32_at(entry, 0, "symbol")? as usize;
+                    let value = u64_at(entry, 8, "symbol")?;
+                    let size = u64_at(entry, 16, "symbol")?;
+                    let name = strings
+                        .get(name..)
+                        .ok_or(ElfError::Malformed("symbol name"))?;
+                    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                    // Skip the null symbol, section symbols and mapping symbols.
+                    if name.is_empty() || name.starts_with(b"$") || value == 0 {
+                        continue;
+                    }
+                    symbols.push(Symbol {
+                        name: String::from_utf8_lossy(name).into_owned(),
+                        value,
+                        size,
+                    });
+                }
+            }
+            if !symbols.is_empty() {
+                break;
+            }
+        }
+
+        Ok(Self {
+            entry,
+            flags,
+            dynamic: kind == ET_DYN,
+            segments,
+            phdr,
+            phnum,
+            symbols: Symbols::new(symbols),
+        })
+    }
+
+    /// Addresses covered by the loaded segments, by virtual or physical address.
+    pub fn span(&self, physical: bool) -> Option<RangeInclusive<usize>> {
+        let ranges = self
+            .segments
+            .iter()
+            .filter(|segment| segment.mem_size > 0)
+            .map(|segment| {
+                let base = if physical {
+                    segment.paddr
+                } else {
+                    segment.vaddr
+                };
+                Some((base, base.checked_add(segment.mem_size - 1)?))
+            });
+        let (start, end) = ranges
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .reduce(|(start, end), (lo, hi)| (start.min(lo), end.max(hi)))?;
+        Some(start as usize..=end as usize)
+    }
+
+    /// Copy the segments into `bus`, zero-filling up to each segment's memory size, at their
+    /// physical or virtual addresses. A segment reaching past the bus faults before anything
+    /// of it is written.
+    pub fn load(&self, bus: &mut dyn Bus, physical: bool) -> Result<(), OperationError> {
+        const ZEROS: [u8; 4096] = [0; 4096];
+        for segment in &self.segments {
+            let base = if physical {
+                segment.paddr
+            } else {
+                segment.vaddr
+            } as usize;
+            let range = bus.address_range();
+            let fits = usize::try_from(segment.mem_size)
+                .ok()
+                .and_then(|size| base.checked_add(size))
+                .is_some_and(|end| {
+                    end == base || (range.contains(&base) && range.contains(&(end - 1)))
+                });
+            if !fits {
+                return Err(OperationError::StoreAddressFault(base));
+            }
+            bus.write_bytes(base, &segment.data)?;
+            let mut addr = base + segment.data.len();
+            let mut bss = (segment.mem_size as usize).saturating_sub(segment.data.len());
+            while bss > 0 {
+                let len = bss.min(ZEROS.len());
+                bus.write_bytes(addr, &ZEROS[..len])?;
+                (addr, bss) = (addr + len, bss - len);
+            }
+        }
+        Ok(())
+    }
+
+    /// The entry point as a physical address, for files whose virtual and load addresses differ,
+    /// such as `vmlinux`.
+    pub fn physical_entry(&self) -> u64 {
+        self.segments
+            .iter()
+            .find(|segment| {
+                segment
+                    .vaddr
+                    .checked_add(segment.mem_size)
+                    .is_some_and(|end| (segment.vaddr..end).contains(&self.entry))
+            })
+            .and_then(|segment| (self.entry - segment.vaddr).checked_add(segment.paddr))
+            .unwrap_or(self.entry)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    /// Build a minimal executable with one text and one data+bss segment and a symbol table.
+    pub(crate) fn executable(text: &[u8], flags: u32) -> Vec<u8> {
+        let phoff = EHDR_SIZE;
+        let text_offset = 0x1000;
+        let data_offset = 0x2000;
+        let symtab = 0x3000;
+        let strtab = symtab + 3 * SYM_SIZE;
+        let strings = b"\0_start\0counter\0";
+        let shoff = strtab + strings.len().next_multiple_of(8);
+        let mut file = vec![0u8; shoff + 3 * SHDR_SIZE];
+        let put = |file: &mut Vec<u8>, offset: usize, bytes: &[u8]| {
+            file[offset..offset + bytes.len()].copy_from_slice(bytes)
+        };
+
+        put(&mut file, 0, b"\x7fELF\x02\x01\x01");
+        put(&mut file, 16, &ET_EXEC.to_le_bytes());
+        put(&mut file, 18, &EM_RISCV.to_le_bytes());
+        put(&mut file, 20, &1u32.to_le_bytes());
+        put(&mut file, 24, &0x1_0000u64.to_le_bytes());
+        put(&mut file, 32, &(phoff as u64).to_le_bytes());
+        put(&mut file, 40, &(shoff as u64).to_le_bytes());
+        put(&mut file, 48, &flags.to_le_bytes());
+        put(&mut file, 52, &(EHDR_SIZE as u16).to_le_bytes());
+        put(&mut file, 54, &(PHDR_SIZE as u16).to_le_bytes());
+        put(&mut file, 56, &2u16.to_le_bytes());
+        put(&mut file, 58, &(SHDR_SIZE as u16).to_le_bytes());
+        put(&mut file, 60, &3u16.to_le_bytes());
+
+        let segments: [(u32, usize, u64, usize, u64); 2] = [
+            (0b101, text_offset, 0x1_0000, text.len(), text.len() as u64),
+            (0b110, data_offset, 0x1_1000, 8, 0x100),
+        ];
+        for (i, (flags, offset, vaddr, file_size, mem_size)) in segments.into_iter().enumerate() {
+            let header = phoff + i * PHDR_SIZE;
+            put(&mut file, header, &PT_LOAD.to_le_bytes());
+            put(&mut file, header + 4, &flags.to_le_bytes());
+            put(&mut file, header + 8, &(offset as u64).to_le_bytes());
+            put(&mut file, header + 16, &vaddr.to_le_bytes());
+            put(&mut file, header + 24, &(vaddr + 0x8000_0000).to_le_bytes());
+            put(&mut file, header + 32, &(file_size as u64).to_le_bytes());
+            put(&mut file, header + 40, &mem_size.to_le_bytes());
+        }
+        put(&mut file, text_offset, text);
+        put(
+            &mut file,
+            data_offset,
+            &0x1122_3344_5566_7788u64.to_le_bytes(),
+        );
+
+        for (i, (name, value, size)) in [(1u32, 0x1_0000u64, text.len() as u64), (8, 0x1_1000, 8)]
+            .into_iter()
+            .enumerate()
+        {
+            let entry = symtab + (i + 1) * SYM_SIZE;
+            put(&mut file, entry, &name.to_le_bytes());
+            put(&mut file, entry + 8, &value.to_le_bytes());
+            put(&mut file, entry + 16, &size.to_le_bytes());
+        }
+        put(&mut file, strtab, strings);
+        // Section 1 is the symbol table, linked to the string table in section 2.
+        for (index, kind, offset, size, link) in [
+            (1, SHT_SYMTAB, symtab, 3 * SYM_SIZE, 2u32),
+            (2, 3, strtab, strings.len(), 0),
+        ] {
+            let header = shoff + index * SHDR_SIZE;
+            put(&mut file, header + 4, &kind.to_le_bytes());
+            put(&mut file, header + 24, &(offset as u64).to_le_bytes());
+            put(&mut file, header + 32, &(size as u64).to_le_bytes());
+            put(&mut file, header + 40, &link.to_le_bytes());
+        }
+        file
+    }
+
+    #[test]
+    fn test_parse_and_load() {
+        let file = executable(&[0x13, 0, 0, 0], EF_RISCV_RVC | 0x4);
+        let elf = Elf::parse(&file).unwrap();
+        assert_eq!(elf.entry, 0x1_0000);
+        assert_eq!(elf.physical_entry(), 0x8001_0000);
+        assert!(!elf.dynamic);
+        assert_eq!(elf.segments.len(), 2);
+        assert_eq!(elf.phdr, None);
+        assert_eq!(elf.span(false), Some(0x1_0000..=0x1_10ff));
+        assert_eq!(elf.span(true), Some(0x8001_0000..=0x8001_10ff));
+
+        let mut mem = Memory::new(0x1_0000..=0x1_1fff);
+        mem.write_bytes(0x1_1008, &[0xff; 8]).unwrap();
+        elf.load(&mut mem, false).unwrap();
+        assert_eq!(mem.read_bytes(0x1_0000, 4).unwrap(), [0x13, 0, 0, 0]);
+        assert_eq!(
+            mem.read_bytes(0x1_1000, 8).unwrap(),
+            0x1122_3344_5566_7788u64.to_le_bytes()
+        );
+        // The .bss tail is zeroed even over stale contents.
+        assert_eq!(mem.read_bytes(0x1_1008, 8).unwrap(), [0; 8]);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let elf = Elf::parse(&executable(&[0; 16], 0)).unwrap();
+        let names: Vec<_> = elf
+            .symbols
+            .iter()
+            .map(|symbol| symbol.name.as_str())
+            .collect();
+        assert_eq!(names, ["_start", "counter"]);
+        assert_eq!(elf.symbols.get("counter").unwrap().value, 0x1_1000);
+        let (symbol, offset) = elf.symbols.lookup(0x1_0008).unwrap();
+        assert_eq!((symbol.name.as_str(), offset), ("_start", 8));
+        assert!(elf.symbols.lookup(0x1_0010).is_none());
+        assert!(elf.symbols.lookup(0xfff).is_none());
+    }
+
+    #[test]
+    fn test_validation() {
+        let file = executable(&[0; 4], 0);
+        assert_eq!(Elf::parse(b"#!/bin/sh").unwrap_err(), ElfError::BadMagic);
+        let mut wrong = file.clone();
+        wrong[18] = 62;
+        assert_eq!(Elf::parse(&wrong).unwrap_err(), ElfError::WrongMachine(62));
+        let mut class32 = file.clone();
+        class32[4] = 1;
+        assert_eq!(
+            Elf::parse(&class32).unwrap_err(),
+            ElfError::UnsupportedClass
+        );
+        let mut relocatable = file.clone();
+        relocatable[16] = 1;
+        assert_eq!(
+            Elf::parse(&relocatable).unwrap_err(),
+            ElfError::UnsupportedType(1)
+        );
+        for flags in [EF_RISCV_RVE, EF_RISCV_FLOAT_ABI_QUAD, 0x100] {
+            assert_eq!(
+                Elf::parse(&executable(&[0; 4], flags)).unwrap_err(),
+                ElfError::UnsupportedFlags(flags)
+            );
+        }
+        assert!(matches!(
+            Elf::parse(&file[..0x1800]).unwrap_err(),
+            ElfError::Malformed(_)
+        ));
+
+        // Offsets and addresses that overflow are malformed rather than wrapping.
+        let data_header = EHDR_SIZE + PHDR_SIZE;
+        for (offset, value) in [
+            (32, u64::MAX - 8),
+            (40, u64::MAX),
+            (data_header + 16, !0xff),
+        ] {
+            let mut overflowing = file.clone();
+            overflowing[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+            assert!(matches!(
+                Elf::parse(&overflowing).unwrap_err(),
+                ElfError::Malformed(_)
+            ));
+        }
+        // A .bss larger than memory faults instead of being allocated.
+        let mut huge = file.clone();
+        huge[data_header + 40..data_header + 48].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        let elf = Elf::parse(&huge).unwrap();
+        let mut mem = Memory::new(0x1_0000..=0x1_1fff);
+        assert!(elf.load(&mut mem, false).is_err());
+    }
+}
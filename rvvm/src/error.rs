@@ -6,12 +6,39 @@ pub enum OperationError {
     StoreAddressFault(usize),
     #[error("load  address fault from `{0:#X}`")]
     LoadAddressFault(usize),
-    #[error("load or store address unaligned from `{0:#X}`")]
-    UnalignedAccess(usize),
+    #[error("load address misaligned from `{0:#X}`")]
+    LoadMisaligned(usize),
+    #[error("store address misaligned from `{0:#X}`")]
+    StoreMisaligned(usize),
+    #[error("instruction fetch fault from `{0:#X}`")]
+    InstructionAccessFault(usize),
+    #[error("instruction page fault from `{0:#X}`")]
+    InstructionPageFault(usize),
+    #[error("load page fault from `{0:#X}`")]
+    LoadPageFault(usize),
+    #[error("store page fault from `{0:#X}`")]
+    StorePageFault(usize),
     #[error("address `{0:#X}` out of range")]
     AddressOutOfRange(usize),
     #[error("illegal instruction `{0:#X}` from address `{1:#X}`")]
     IllegalInstruction(u32, usize),
+    #[error("breakpoint at address `{0:#X}`")]
+    Breakpoint(usize),
+    #[error("environment call from address `{0:#X}`")]
+    EnvironmentCall(usize),
     #[error("unknown data error")]
     Unknown,
 }
+
+impl OperationError {
+    /// Report a fault raised by a translated access against the virtual address `vaddr`.
+    pub fn at_virtual(self, vaddr: usize) -> Self {
+        match self {
+            OperationError::LoadAddressFault(_) => OperationError::LoadAddressFault(vaddr),
+            OperationError::StoreAddressFault(_) => OperationError::StoreAddressFault(vaddr),
+            OperationError::LoadMisaligned(_) => OperationError::LoadMisaligned(vaddr),
+            OperationError::StoreMisaligned(_) => OperationError::StoreMisaligned(vaddr),
+            err => err,
+        }
+    }
+}
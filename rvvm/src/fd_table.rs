@@ -0,0 +1,97 @@
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+/// Highest number of descriptors a guest may hold open, like `RLIMIT_NOFILE`.
+pub const MAX_FDS: usize = 1024;
+
+/// The host file behind a guest descriptor.
+#[derive(Debug)]
+pub enum HostFd {
+    /// One of the emulator's own standard streams; closing it in the guest leaves it open here.
+    Stdio(RawFd),
+    /// A file the guest opened.
+    Owned(OwnedFd),
+}
+
+impl AsRawFd for HostFd {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            HostFd::Stdio(fd) => *fd,
+            HostFd::Owned(fd) => fd.as_raw_fd(),
+        }
+    }
+}
+
+/// Guest file descriptors, mapped onto host files. Clones share the table, as threads of one
+/// process do.
+#[derive(Debug, Clone, Default)]
+pub struct FdTable {
+    entries: Arc<Mutex<Vec<Option<Arc<HostFd>>>>>,
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A table with guest descriptors 0, 1 and 2 passed through to the host's standard streams.
+    pub fn with_stdio() -> Self {
+        let table = Self::new();
+        *table.entries.lock().unwrap() =
+            (0..3).map(|fd| Some(Arc::new(HostFd::Stdio(fd)))).collect();
+        table
+    }
+
+    /// Install `file` at the lowest free descriptor, or return `None` if the table is full.
+    pub fn insert(&self, file: HostFd) -> Option<i32> {
+        let mut entries = self.entries.lock().unwrap();
+        let fd = match entries.iter().position(Option::is_none) {
+            Some(fd) => fd,
+            None if entries.len() < MAX_FDS => {
+                entries.push(None);
+                entries.len() - 1
+            }
+            None => return None,
+        };
+        entries[fd] = Some(Arc::new(file));
+        Some(fd as i32)
+    }
+
+    /// The file behind `fd`. It stays open while the returned handle is held, even if another
+    /// thread closes the descriptor.
+    pub fn get(&self, fd: i32) -> Option<Arc<HostFd>> {
+        let index = usize::try_from(fd).ok()?;
+        self.entries.lock().unwrap().get(index)?.clone()
+    }
+
+    /// Release `fd`, returning the file it referred to.
+    pub fn remove(&self, fd: i32) -> Option<Arc<HostFd>> {
+        let index = usize::try_from(fd).ok()?;
+        self.entries.lock().unwrap().get_mut(index)?.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lowest_free_descriptor() {
+        let table = FdTable::with_stdio();
+        assert_eq!(table.get(1).unwrap().as_raw_fd(), 1);
+        assert!(table.get(3).is_none() && table.get(-1).is_none());
+
+        let file = || HostFd::Owned(std::fs::File::open("/dev/null").unwrap().into());
+        assert_eq!(table.insert(file()), Some(3));
+        assert_eq!(table.insert(file()), Some(4));
+        let shared = table.clone();
+        assert!(shared.remove(0).is_some());
+        assert!(table.remove(0).is_none());
+        assert_eq!(table.insert(file()), Some(0));
+
+        for fd in 5..MAX_FDS as i32 {
+            assert_eq!(table.insert(file()), Some(fd));
+        }
+        assert_eq!(table.insert(file()), None);
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+
+/// Magic number at the start of a flattened device tree.
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+const VERSION: u32 = 17;
+const LAST_COMPATIBLE_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+/// The memory reservation block holds only its terminating entry.
+const RESERVATION_SIZE: usize = 16;
+
+/// Serializer for flattened device tree blobs, in the version 17 format.
+///
+/// Nodes are emitted depth-first: open a node with [`FdtWriter::begin_node`], add its
+/// properties, then its children, and close it with [`FdtWriter::end_node`].
+#[derive(Debug, Default)]
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    names: HashMap<String, u32>,
+    depth: usize,
+    boot_hart: u32,
+}
+
+impl FdtWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        let padding = self.structure.len().next_multiple_of(4) - self.structure.len();
+        self.structure.extend(std::iter::repeat_n(0, padding));
+    }
+
+    /// Offset of `name` in the strings block, adding it on first use.
+    fn name(&mut self, name: &str) -> u32 {
+        if let Some(&offset) = self.names.get(name) {
+            return offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        self.names.insert(name.to_owned(), offset);
+        offset
+    }
+
+    /// Open a node; the root node has an empty name.
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "unbalanced end_node");
+        self.token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name = self.name(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(name);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    /// An empty property, whose presence is its meaning.
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    /// A list of 32-bit cells, such as `reg` or `interrupts-extended`.
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut value = Vec::new();
+        for string in values {
+            value.extend_from_slice(string.as_bytes());
+            value.push(0);
+        }
+        self.property(name, &value);
+    }
+
+    /// Physical ID of the hart that boots first.
+    pub fn set_boot_hart(&mut self, hartid: u32) {
+        self.boot_hart = hartid;
+    }
+
+    /// Finish the tree and return the blob.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unterminated node");
+        self.token(FDT_END);
+        let structure = HEADER_SIZE + RESERVATION_SIZE;
+        let strings = structure + self.structure.len();
+        let total = strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            total as u32,
+            structure as u32,
+            strings as u32,
+            HEADER_SIZE as u32,
+            VERSION,
+            LAST_COMPATIBLE_VERSION,
+            self.boot_hart,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob = Vec::with_capacity(total);
+        for field in header {
+            blob.extend_from_slice(&field.to_be_bytes());
+        }
+        blob.extend_from_slice(&[0; RESERVATION_SIZE]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// The `totalsize` of the blob in `data`, if it starts with a valid FDT header that fits.
+pub fn blob_size(data: &[u8]) -> Option<usize> {
+    let field = |index: usize| {
+        let bytes = data.get(index * 4..index * 4 + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+    let total = field(1)? as usize;
+    (field(0)? == FDT_MAGIC && total >= HEADER_SIZE && total <= data.len()).then_some(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(blob: &[u8]) -> Vec<u32> {
+        blob.chunks(4)
+            .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.property_u32("#size-cells", 2);
+        fdt.begin_node("cpu@0");
+        fdt.property_string("status", "okay");
+        fdt.property_null("#size-cells");
+        fdt.end_node();
+        fdt.end_node();
+        let blob = fdt.finish();
+
+        let header = words(&blob[..HEADER_SIZE]);
+        assert_eq!(
+            header[..8],
+            [FDT_MAGIC, blob.len() as u32, 56, 136, 40, 17, 16, 0]
+        );
+        assert_eq!(blob_size(&blob), Some(blob.len()));
+        assert_eq!(&blob[136..], b"#size-cells\0status\0");
+        #[rustfmt::skip]
+        let structure = [
+            FDT_BEGIN_NODE, 0,
+            FDT_PROP, 4, 0, 2,
+            FDT_BEGIN_NODE, u32::from_be_bytes(*b"cpu@"), u32::from_be_bytes(*b"0\0\0\0"),
+            FDT_PROP, 5, 12, u32::from_be_bytes(*b"okay"), 0,
+            FDT_PROP, 0, 0,
+            FDT_END_NODE,
+            FDT_END_NODE,
+            FDT_END,
+        ];
+        assert_eq!(words(&blob[56..136]), structure);
+    }
+
+    #[test]
+    fn test_blob_size_validation() {
+        let blob = FdtWriter::new().finish();
+        assert_eq!(blob_size(&blob), Some(blob.len()));
+        assert_eq!(blob_size(&blob[..blob.len() - 1]), None);
+        assert_eq!(blob_size(b"not a device tree, just some text"), None);
+        assert_eq!(blob_size(&[]), None);
+    }
+}
@@ -0,0 +1,232 @@
+use super::clock::Clock;
+use super::csr::interrupt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// A timer comparator in front of a hart, asserting its line once `mtime` reaches the deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    /// `mtimecmp`, driving MTIP.
+    Machine,
+    /// The supervisor timer programmed through the SBI, driving STIP.
+    Supervisor,
+}
+
+impl Timer {
+    const ALL: [Timer; 2] = [Timer::Machine, Timer::Supervisor];
+
+    /// The `mip` bit driven by this comparator.
+    pub fn line(self) -> u64 {
+        match self {
+            Timer::Machine => interrupt::MTIP,
+            Timer::Supervisor => interrupt::STIP,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Lines {
+    /// Pending bits driven by devices, laid out like `mip`.
+    pending: AtomicU64,
+    /// Deadlines of the timer comparators; `u64::MAX` when disarmed.
+    deadlines: [AtomicU64; 2],
+    /// Bumped whenever a deadline moves, so a waiting hart recomputes its timeout.
+    epoch: AtomicU64,
+    lock: Mutex<()>,
+    wake: Condvar,
+}
+
+impl Default for Lines {
+    fn default() -> Self {
+        Self {
+            pending: AtomicU64::new(0),
+            deadlines: [AtomicU64::new(u64::MAX), AtomicU64::new(u64::MAX)],
+            epoch: AtomicU64::new(0),
+            lock: Mutex::new(()),
+            wake: Condvar::new(),
+        }
+    }
+}
+
+/// The interrupt lines into a hart. Devices hold clones of this handle and may raise or clear
+/// lines from any thread; the hart samples them between instructions.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptLines {
+    inner: Arc<Lines>,
+}
+
+impl InterruptLines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Currently asserted lines, as `mip` bits.
+    #[inline]
+    pub fn pending(&self) -> u64 {
+        self.inner.pending.load(Ordering::Acquire)
+    }
+
+    /// Assert the lines in `mask`, waking the hart if it is waiting for an interrupt.
+    pub fn raise(&self, mask: u64) {
+        let old = self.inner.pending.fetch_or(mask, Ordering::AcqRel);
+        if old & mask != mask {
+            // Taking the lock orders the update with a hart about to sleep in `wait`.
+            self.notify();
+        }
+    }
+
+    fn notify(&self) {
+        let _guard = self.inner.lock.lock().unwrap();
+        self.inner.wake.notify_all();
+    }
+
+    /// Deassert the lines in `mask`.
+    pub fn clear(&self, mask: u64) {
+        self.inner.pending.fetch_and(!mask, Ordering::AcqRel);
+    }
+
+    /// Drive the lines in `mask` to `level`.
+    pub fn set(&self, mask: u64, level: bool) {
+        if level {
+            self.raise(mask);
+        } else {
+            self.clear(mask);
+        }
+    }
+
+    /// Deadline of `timer`, or `u64::MAX` if it is disarmed.
+    pub fn timer(&self, timer: Timer) -> u64 {
+        self.inner.deadlines[timer as usize].load(Ordering::Acquire)
+    }
+
+    /// Arm `timer` to fire once `mtime` reaches `deadline`, or disarm it with `u64::MAX`. The
+    /// line follows the comparison, so moving the deadline past the current time deasserts it on
+    /// the next poll.
+    pub fn set_timer(&self, timer: Timer, deadline: u64) {
+        self.inner.deadlines[timer as usize].store(deadline, Ordering::Release);
+        if deadline == u64::MAX {
+            self.clear(timer.line());
+        }
+        self.inner.epoch.fetch_add(1, Ordering::AcqRel);
+        self.notify();
+    }
+
+    /// Drive the timer lines from the current value of `clock`.
+    pub fn poll_timers(&self, clock: &Clock) {
+        let mut now = None;
+        for timer in Timer::ALL {
+            let deadline = self.timer(timer);
+            let line = timer.line();
+            if deadline == u64::MAX {
+                continue;
+            }
+            let now = *now.get_or_insert_with(|| clock.now());
+            let level = now >= deadline;
+            if (self.pending() & line != 0) != level {
+                self.set(line, level);
+            }
+        }
+    }
+
+    /// The earliest armed deadline among the timers driving a line in `mask`.
+    pub fn next_deadline(&self, mask: u64) -> Option<u64> {
+        Timer::ALL
+            .into_iter()
+            .filter(|timer| timer.line() & mask != 0)
+            .map(|timer| self.timer(timer))
+            .filter(|&deadline| deadline != u64::MAX)
+            .min()
+    }
+
+    /// Block the calling thread until one of the lines in `mask` is asserted, a timer deadline
+    /// moves, or `timeout` elapses.
+    pub fn wait(&self, mask: u64, timeout: Option<Duration>) {
+        let guard = self.inner.lock.lock().unwrap();
+        let epoch = self.inner.epoch.load(Ordering::Acquire);
+        let asleep = |_: &mut ()| {
+            self.pending() & mask == 0 && self.inner.epoch.load(Ordering::Acquire) == epoch
+        };
+        match timeout {
+            Some(timeout) => drop(self.inner.wake.wait_timeout_while(guard, timeout, asleep)),
+            None => drop(self.inner.wake.wait_while(guard, asleep)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_raise_and_clear() {
+        let lines = InterruptLines::new();
+        lines.raise(1 << 7);
+        lines.set(1 << 11, true);
+        assert_eq!(lines.pending(), 1 << 7 | 1 << 11);
+        lines.clear(1 << 7);
+        lines.set(1 << 11, false);
+        assert_eq!(lines.pending(), 0);
+    }
+
+    #[test]
+    fn test_wait_wakes_on_raise() {
+        let lines = InterruptLines::new();
+        let device = lines.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            device.raise(1 << 3);
+        });
+        let start = Instant::now();
+        lines.wait(1 << 3, Some(Duration::from_secs(10)));
+        assert_eq!(lines.pending(), 1 << 3);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        handle.join().unwrap();
+
+        // Lines outside the mask do not end the wait.
+        lines.wait(1 << 7, Some(Duration::from_millis(10)));
+        assert_eq!(lines.pending() & 1 << 7, 0);
+    }
+
+    #[test]
+    fn test_timer_comparators() {
+        let clock = Clock::instructions();
+        let lines = InterruptLines::new();
+        lines.poll_timers(&clock);
+        assert_eq!(lines.pending(), 0);
+        assert_eq!(lines.next_deadline(interrupt::ALL), None);
+
+        lines.set_timer(Timer::Machine, 10);
+        lines.set_timer(Timer::Supervisor, 5);
+        assert_eq!(lines.next_deadline(interrupt::ALL), Some(5));
+        assert_eq!(lines.next_deadline(interrupt::MTIP), Some(10));
+        clock.advance(5);
+        lines.poll_timers(&clock);
+        assert_eq!(lines.pending(), interrupt::STIP);
+        clock.advance(5);
+        lines.poll_timers(&clock);
+        assert_eq!(lines.pending(), interrupt::STIP | interrupt::MTIP);
+
+        // Moving a deadline into the future deasserts its line.
+        lines.set_timer(Timer::Machine, 20);
+        lines.set_timer(Timer::Supervisor, u64::MAX);
+        lines.poll_timers(&clock);
+        assert_eq!(lines.pending(), 0);
+    }
+
+    #[test]
+    fn test_wait_wakes_on_new_deadline() {
+        let lines = InterruptLines::new();
+        let device = lines.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            device.set_timer(Timer::Machine, 0);
+        });
+        let start = Instant::now();
+        lines.wait(interrupt::MTIP, Some(Duration::from_secs(10)));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        handle.join().unwrap();
+    }
+}
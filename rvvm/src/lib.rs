@@ -1,17 +1,60 @@
-#![feature(new_range_api)]
 #![feature(adt_const_params)]
+mod address_space;
 mod bus;
+mod clock;
 mod cpu;
+mod csr;
+mod devices;
+mod elf;
 mod error;
+mod fd_table;
+mod fdt;
+mod interrupt;
+mod logger;
+mod machine;
 mod macros;
 mod memory;
+mod mmu;
+mod operation;
+mod process;
 mod register;
+mod sbi;
+mod signal;
+mod softfloat;
+mod strace;
 mod syscall;
 mod syscall_handler;
-mod operation;
+mod system_bus;
+mod thread;
+mod trap;
+pub use address_space::{AddressSpace, PAGE_SIZE, Prot};
 pub use bus::{Bus, BusOperation};
-pub use cpu::Cpu;
+pub use clock::{Clock, TIMEBASE_FREQ};
+pub use cpu::{Cpu, ExecutionMode, RunOutcome};
+pub use csr::{CsrFile, Privilege};
+pub use devices::{
+    MmioDevice,
+    clint::Clint,
+    plic::{Irq, Plic},
+    terminal,
+    uart::Uart,
+};
+pub use elf::{Elf, ElfError, Segment, Symbol, Symbols};
 pub use error::OperationError;
+pub use fd_table::{FdTable, HostFd};
+pub use fdt::FdtWriter;
+pub use interrupt::{InterruptLines, Timer};
+pub use logger::{DEFAULT_LEVEL, Logger};
+pub use machine::{Machine, MachineConfig, RAM_BASE};
 pub use memory::Memory;
+pub use process::{MMAP_TOP, STACK_SIZE, STACK_TOP, load_program, setup_stack};
 pub use register::{Generic, Register};
+pub use sbi::{Sbi, SystemReset};
+pub use signal::{AltStack, SIGRETURN_TRAMPOLINE, SigAction, SigInfo, Signals};
+pub use strace::Strace;
 pub use syscall::Sysno;
+pub use system_bus::{DeviceMap, SystemBus};
+pub use thread::{
+    FAULT_STATUS, FutexWait, Futexes, LIMIT_STATUS, Thread, ThreadGroup, run_process, run_thread,
+};
+pub use trap::Exception;
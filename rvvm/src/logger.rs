@@ -0,0 +1,167 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Instruction execution, traps and the faults that stop a hart.
+pub const CPU: &str = "cpu";
+/// Address translation.
+pub const MMU: &str = "mmu";
+/// System calls and signals of user-mode programs.
+pub const SYSCALL: &str = "syscall";
+/// Memory-mapped devices and the firmware interface.
+pub const DEVICES: &str = "devices";
+
+/// Level used when the spec gives none, so that only problems are reported.
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Warn;
+
+/// Writes the emulator's diagnostics, leveled and tagged with their subsystem, to stderr or a
+/// file; the guest's own output never goes through it.
+///
+/// The spec is a comma-separated list of a default level and `target=level` overrides, like
+/// `warn,syscall=debug,cpu=trace`.
+pub struct Logger {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+    out: Mutex<Box<dyn Write + Send>>,
+    start: Instant,
+}
+
+impl Logger {
+    pub fn new(spec: &str, out: Box<dyn Write + Send>) -> Result<Self, String> {
+        let mut logger = Self {
+            default: DEFAULT_LEVEL,
+            targets: Vec::new(),
+            out: Mutex::new(out),
+            start: Instant::now(),
+        };
+        for part in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+        {
+            let level = |level: &str| {
+                level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| format!("unknown log level `{level}`"))
+            };
+            match part.split_once('=') {
+                Some((target, value)) => logger.targets.push((target.to_string(), level(value)?)),
+                None => logger.default = level(part)?,
+            }
+        }
+        Ok(logger)
+    }
+
+    /// Level enabled for `target`; a later override of the same target wins.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .rev()
+            .find(|(name, _)| {
+                target == name
+                    || target
+                        .strip_prefix(name.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    /// Make this the logger of the `log` macros.
+    pub fn install(self) -> Result<(), log::SetLoggerError> {
+        let max = self
+            .targets
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, Ord::max);
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(max);
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let level = match record.level() {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(
+            out,
+            "[{elapsed:10.6} {level} {}] {}",
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        let _ = self.out.lock().unwrap().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_levels_and_targets() {
+        let buffer = Buffer::default();
+        let logger = Logger::new("info, syscall=debug,cpu=off", Box::new(buffer.clone())).unwrap();
+        assert_eq!(logger.level(MMU), LevelFilter::Info);
+        assert_eq!(logger.level(SYSCALL), LevelFilter::Debug);
+        assert_eq!(logger.level("syscall::signal"), LevelFilter::Debug);
+        assert_eq!(logger.level("syscalls"), LevelFilter::Info);
+        assert_eq!(logger.level(CPU), LevelFilter::Off);
+        assert_eq!(
+            Logger::new("", Box::new(std::io::sink()))
+                .unwrap()
+                .level(CPU),
+            DEFAULT_LEVEL
+        );
+        assert!(Logger::new("cpu=loud", Box::new(std::io::sink())).is_err());
+
+        let log = |level, target, message| {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .target(target)
+                    .args(format_args!("{message}"))
+                    .build(),
+            )
+        };
+        log(Level::Debug, SYSCALL, "openat");
+        log(Level::Debug, DEVICES, "hidden");
+        log(Level::Error, CPU, "hidden");
+        log(Level::Warn, DEVICES, "unclaimed");
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" DEBUG syscall] openat"));
+        assert!(lines[1].ends_with(" WARN  devices] unclaimed"));
+    }
+}
@@ -0,0 +1,351 @@
+use super::clock::{Clock, TIMEBASE_FREQ};
+use super::cpu::{Cpu, ExecutionMode};
+use super::devices::clint::{CLINT_SIZE, Clint};
+use super::devices::plic::{PLIC_SOURCES, Plic};
+use super::devices::uart::{UART_SIZE, Uart};
+use super::fdt::{self, FdtWriter};
+use super::memory::Memory;
+use super::register::Generic;
+use super::sbi::{self, Sbi};
+use super::system_bus::{DeviceMap, SystemBus};
+use std::io::Write;
+use std::sync::Arc;
+
+pub const CLINT_BASE: usize = 0x0200_0000;
+pub const PLIC_BASE: usize = 0x0c00_0000;
+pub const UART_BASE: usize = 0x1000_0000;
+pub const UART_IRQ: u32 = 10;
+pub const RAM_BASE: usize = 0x8000_0000;
+
+/// ISA string advertised to the guest.
+pub const ISA: &str = "rv64imafdc_zicsr_zifencei";
+const ISA_EXTENSIONS: [&str; 8] = ["i", "m", "a", "f", "d", "c", "zicsr", "zifencei"];
+/// Input clock of the UART, as on common 16550 boards.
+const UART_CLOCK: u32 = 3_686_400;
+
+const CPU_INTC_PHANDLE: u32 = 1;
+const PLIC_PHANDLE: u32 = 2;
+
+/// Description of the emulated board.
+#[derive(Debug, Clone)]
+pub struct MachineConfig {
+    /// Bytes of RAM at [`RAM_BASE`].
+    pub ram_size: usize,
+    /// Kernel command line, passed in `/chosen`.
+    pub bootargs: String,
+    /// Timebase for `mtime`.
+    pub clock: Clock,
+    /// A device tree to pass to the guest instead of the generated one.
+    pub dtb: Option<Vec<u8>>,
+    /// Base addresses and interrupt numbers of virtio-mmio devices the embedder maps into
+    /// [`Machine::devices`], to be described in the generated device tree.
+    pub virtio: Vec<(usize, u32)>,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        Self {
+            ram_size: 128 << 20,
+            bootargs: String::from("console=ttyS0 earlycon"),
+            clock: Clock::host(),
+            dtb: None,
+            virtio: Vec::new(),
+        }
+    }
+}
+
+/// Size of a virtio-mmio register window.
+pub const VIRTIO_SIZE: usize = 0x1000;
+
+/// A single-hart board in the layout of QEMU's `virt` machine: RAM, CLINT, PLIC and an
+/// NS16550A UART, with a device tree placed at the top of RAM.
+pub struct Machine {
+    pub cpu: Cpu,
+    pub plic: Plic,
+    pub uart: Uart,
+    /// Handle for mapping further devices, such as virtio transports.
+    pub devices: DeviceMap,
+    dtb: Vec<u8>,
+    dtb_address: usize,
+}
+
+impl Machine {
+    /// Build the board, with the UART writing to `console`.
+    pub fn new(config: MachineConfig, console: Box<dyn Write + Send>) -> anyhow::Result<Self> {
+        if config.ram_size == 0 {
+            anyhow::bail!("the machine needs some RAM");
+        }
+        let mut bus = SystemBus::new();
+        bus.add_memory(Memory::new(RAM_BASE..=RAM_BASE + config.ram_size - 1))?;
+        let devices = bus.devices();
+
+        let mut cpu = Cpu::new(bus);
+        cpu.set_execution_mode(ExecutionMode::System);
+        cpu.csr.clock = config.clock.clone();
+        let harts = vec![cpu.interrupt_lines()];
+        let clint = Clint::new(config.clock.clone(), harts.clone());
+        let plic = Plic::new(harts);
+        let uart = Uart::new(console, plic.irq(UART_IRQ));
+        devices.map(CLINT_BASE, CLINT_SIZE as usize, Arc::new(clint))?;
+        devices.map(PLIC_BASE, plic.size() as usize, Arc::new(plic.clone()))?;
+        devices.map(UART_BASE, UART_SIZE as usize, Arc::new(uart.clone()))?;
+
+        let dtb = match &config.dtb {
+            Some(dtb) => {
+                let Some(size) = fdt::blob_size(dtb) else {
+                    anyhow::bail!("not a flattened device tree");
+                };
+                dtb[..size].to_vec()
+            }
+            None => device_tree(&config, plic.size()),
+        };
+        if dtb.len() > config.ram_size {
+            anyhow::bail!("device tree does not fit in RAM");
+        }
+        // Keep the blob page-aligned and clear of images loaded at the bottom of RAM.
+        let dtb_address = (RAM_BASE + config.ram_size - dtb.len()) & !0xfff;
+        cpu.mem.write_bytes(dtb_address, &dtb)?;
+        Ok(Self {
+            cpu,
+            plic,
+            uart,
+            devices,
+            dtb,
+            dtb_address,
+        })
+    }
+
+    /// The device tree passed to the guest.
+    pub fn dtb(&self) -> &[u8] {
+        &self.dtb
+    }
+
+    /// Guest physical address of the device tree.
+    pub fn dtb_address(&self) -> usize {
+        self.dtb_address
+    }
+
+    /// Copy `image` into guest memory at `addr`.
+    pub fn load(&mut self, addr: usize, image: &[u8]) -> anyhow::Result<()> {
+        Ok(self.cpu.mem.write_bytes(addr, image)?)
+    }
+
+    /// Point the hart at `entry` in M-mode, with the boot hart ID in `a0` and the device tree in
+    /// `a1`.
+    pub fn boot(&mut self, entry: usize) {
+        self.cpu.set_execution_mode(ExecutionMode::System);
+        self.cpu.set_pc(entry as isize);
+        self.cpu
+            .set_generic(Generic::a0, self.cpu.csr.mhartid as isize);
+        self.cpu.set_generic(Generic::a1, self.dtb_address as isize);
+    }
+
+    /// Boot an S-mode kernel at `entry` with the built-in SBI as firmware. The SBI debug console
+    /// shares the UART's host side.
+    pub fn boot_kernel(&mut self, entry: usize) {
+        self.boot(entry);
+        let sbi = Sbi::new(Box::new(self.uart.clone()), Box::new(self.uart.clone()));
+        sbi::enter_supervisor(&mut self.cpu, entry, sbi);
+    }
+}
+
+/// Split a 64-bit value into the two cells used with `#address-cells = <2>`.
+fn cells(value: usize) -> [u32; 2] {
+    [(value >> 32) as u32, value as u32]
+}
+
+fn reg(base: usize, size: usize) -> Vec<u32> {
+    [cells(base), cells(size)].concat()
+}
+
+/// Generate the device tree describing the board built from `config`.
+fn device_tree(config: &MachineConfig, plic_size: u64) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "rvvm,virt");
+
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", &config.bootargs);
+    fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", UART_BASE));
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", RAM_BASE));
+    fdt.property_string("device_type", "memory");
+    fdt.property_cells("reg", &reg(RAM_BASE, config.ram_size));
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQ as u32);
+    fdt.begin_node("cpu@0");
+    fdt.property_string("device_type", "cpu");
+    fdt.property_u32("reg", 0);
+    fdt.property_string("status", "okay");
+    fdt.property_string("compatible", "riscv");
+    fdt.property_string("riscv,isa", ISA);
+    fdt.property_string("riscv,isa-base", "rv64i");
+    fdt.property_strings("riscv,isa-extensions", &ISA_EXTENSIONS);
+    fdt.property_string("mmu-type", "riscv,sv57");
+    fdt.begin_node("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_null("interrupt-controller");
+    fdt.property_string("compatible", "riscv,cpu-intc");
+    fdt.property_u32("phandle", CPU_INTC_PHANDLE);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+
+    // Interrupt numbers on the hart's local controller, as in `mip`.
+    const MSI: u32 = 3;
+    const MTI: u32 = 7;
+    const SEI: u32 = 9;
+    const MEI: u32 = 11;
+
+    fdt.begin_node(&format!("clint@{:x}", CLINT_BASE));
+    fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+    fdt.property_cells("reg", &reg(CLINT_BASE, CLINT_SIZE as usize));
+    fdt.property_cells(
+        "interrupts-extended",
+        &[CPU_INTC_PHANDLE, MSI, CPU_INTC_PHANDLE, MTI],
+    );
+    fdt.end_node();
+
+    fdt.begin_node(&format!("plic@{:x}", PLIC_BASE));
+    fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+    fdt.property_cells("reg", &reg(PLIC_BASE, plic_size as usize));
+    fdt.property_u32("#address-cells", 0);
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_null("interrupt-controller");
+    fdt.property_u32("riscv,ndev", PLIC_SOURCES - 1);
+    fdt.property_cells(
+        "interrupts-extended",
+        &[CPU_INTC_PHANDLE, MEI, CPU_INTC_PHANDLE, SEI],
+    );
+    fdt.property_u32("phandle", PLIC_PHANDLE);
+    fdt.end_node();
+
+    fdt.begin_node(&format!("serial@{:x}", UART_BASE));
+    fdt.property_string("compatible", "ns16550a");
+    fdt.property_cells("reg", &reg(UART_BASE, UART_SIZE as usize));
+    fdt.property_u32("clock-frequency", UART_CLOCK);
+    fdt.property_u32("interrupts", UART_IRQ);
+    fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+    fdt.end_node();
+
+    for &(base, irq) in &config.virtio {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_cells("reg", &reg(base, VIRTIO_SIZE));
+        fdt.property_u32("interrupts", irq);
+        fdt.property_u32("interrupt-parent", PLIC_PHANDLE);
+        fdt.end_node();
+    }
+
+    fdt.end_node();
+    fdt.end_node();
+    fdt.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::BusOperation;
+    use crate::csr::Privilege;
+
+    fn config() -> MachineConfig {
+        MachineConfig {
+            ram_size: 0x10_0000,
+            clock: Clock::instructions(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_boot_passes_dtb() {
+        let mut machine = Machine::new(config(), Box::new(std::io::sink())).unwrap();
+        machine.boot(RAM_BASE);
+        let cpu = &machine.cpu;
+        let a1 = cpu.get_generic(Generic::a1) as usize;
+        assert_eq!(a1, machine.dtb_address());
+        assert_eq!(cpu.get_generic(Generic::a0), 0);
+        assert_eq!(cpu.pc as usize, RAM_BASE);
+        assert_eq!(cpu.privilege(), Privilege::Machine);
+        assert_eq!(a1 % 0x1000, 0);
+        assert!(a1 + machine.dtb().len() <= RAM_BASE + 0x10_0000);
+        let blob = cpu.mem.read_bytes(a1, machine.dtb().len()).unwrap();
+        assert_eq!(blob, machine.dtb());
+        assert_eq!(fdt::blob_size(&blob), Some(blob.len()));
+
+        let contains = |needle: &[u8]| blob.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(b"serial@10000000\0"));
+        assert!(contains(b"rv64imafdc_zicsr_zifencei\0"));
+        assert!(contains(b"console=ttyS0 earlycon\0"));
+    }
+
+    #[test]
+    fn test_devices_are_mapped() {
+        let machine = Machine::new(config(), Box::new(std::io::sink())).unwrap();
+        let mem = machine.cpu.mem.as_ref();
+        // UART line status: transmitter empty.
+        assert_eq!(BusOperation::<u8>::load(mem, UART_BASE + 5).unwrap(), 0x60);
+        // CLINT mtime follows the instruction clock.
+        assert_eq!(
+            BusOperation::<u64>::load(mem, CLINT_BASE + 0xbff8).unwrap(),
+            0
+        );
+        // PLIC priority of the UART source.
+        assert_eq!(
+            BusOperation::<u32>::load(mem, PLIC_BASE + 4 * UART_IRQ as usize).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_user_dtb() {
+        let mut fdt = FdtWriter::new();
+        fdt.begin_node("");
+        fdt.end_node();
+        let mut blob = fdt.finish();
+        let size = blob.len();
+        // Trailing bytes beyond `totalsize` are dropped.
+        blob.extend_from_slice(&[0xff; 7]);
+        let machine = Machine::new(
+            MachineConfig {
+                dtb: Some(blob.clone()),
+                ..config()
+            },
+            Box::new(std::io::sink()),
+        )
+        .unwrap();
+        assert_eq!(machine.dtb(), &blob[..size]);
+
+        let invalid = MachineConfig {
+            dtb: Some(b"garbage".to_vec()),
+            ..config()
+        };
+        assert!(Machine::new(invalid, Box::new(std::io::sink())).is_err());
+    }
+
+    #[test]
+    fn test_virtio_nodes() {
+        let config = MachineConfig {
+            virtio: vec![(0x1000_1000, 1)],
+            ..config()
+        };
+        let blob = device_tree(&config, 0x20_2000);
+        assert!(
+            blob.windows(22)
+                .any(|window| window == b"virtio_mmio@10001000\0\0")
+        );
+    }
+}
@@ -1,6 +1,10 @@
-#![feature(new_range_api)]
-use rvvm::{Bus, Cpu, Memory};
-use std::io::Read;
+use rvvm::{
+    Bus, Cpu, Elf, FAULT_STATUS, LIMIT_STATUS, Logger, Machine, MachineConfig, Memory, RAM_BASE,
+    RunOutcome, Strace, SystemReset, load_program, run_process, setup_stack, terminal,
+};
+use std::io::{BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 use clap::Parser;
 
@@ -14,21 +18,196 @@ struct Args {
     /// Number of times to greet
     #[arg(short, long, default_value_t = 0)]
     offset: usize,
-    /// Number of times to greet
+    /// Log each executed instruction, as if `--log cpu=trace` were given
     #[arg(short, long, action, default_value_t = false)]
     verbose: bool,
+    /// Emulator diagnostics to show: a level, then `target=level` overrides for the cpu, mmu,
+    /// syscall and devices targets
+    #[arg(long, value_name = "SPEC", default_value = "warn")]
+    log: String,
+    /// Write emulator diagnostics to this file instead of stderr
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+    /// Boot the image as M-mode firmware on the virt machine, loaded at the start of RAM
+    #[arg(long, action, default_value_t = false)]
+    system: bool,
+    /// RAM size of the virt machine, in MiB
+    #[arg(long, default_value_t = 128)]
+    memory: usize,
+    /// Pass this device tree blob to the guest instead of the generated one
+    #[arg(long)]
+    dtb: Option<PathBuf>,
+    /// Write the device tree passed to the guest to this file
+    #[arg(long)]
+    dump_dtb: Option<PathBuf>,
+    /// Boot a Linux kernel `Image` in S-mode on the virt machine, with the built-in SBI
+    #[arg(long)]
+    kernel: Option<PathBuf>,
+    /// Kernel command line
+    #[arg(long, default_value = "console=ttyS0 earlycon")]
+    append: String,
+    /// Add `KEY=VALUE` to the environment of a user-mode program
+    #[arg(short, long = "env", value_name = "KEY=VALUE")]
+    env: Vec<String>,
+    /// Print the system calls a user-mode program makes, strace-style, to stderr
+    #[arg(long, action, default_value_t = false)]
+    strace: bool,
+    /// Only trace these system calls
+    #[arg(
+        long,
+        value_name = "NAME,...",
+        value_delimiter = ',',
+        requires = "strace"
+    )]
+    strace_filter: Vec<String>,
+    /// Write the system call trace to this file instead of stderr
+    #[arg(long, value_name = "FILE", requires = "strace")]
+    strace_output: Option<PathBuf>,
+    /// Stop after executing this many instructions, on each thread of a user-mode program
+    #[arg(long, value_name = "N")]
+    max_instructions: Option<u64>,
+    /// Arguments passed to a user-mode program after its name
+    #[arg(last = true)]
+    guest_args: Vec<String>,
 }
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+
+/// Offset from the start of RAM at which a RISC-V Linux `Image` asks to be loaded, taken from
+/// its header when the magic is present.
+fn image_load_offset(image: &[u8]) -> usize {
+    const MAGIC: &[u8] = b"RSC\x05";
+    match (image.get(8..16), image.get(56..60)) {
+        (Some(offset), Some(MAGIC)) => u64::from_le_bytes(offset.try_into().unwrap()) as usize,
+        _ => 0x20_0000,
+    }
+}
+
+fn init_logging(args: &Args) -> anyhow::Result<()> {
+    let mut spec = args.log.clone();
+    if args.verbose {
+        spec.push_str(",cpu=trace");
+    }
+    let out: Box<dyn Write + Send> = match &args.log_file {
+        Some(path) => Box::new(
+            std::fs::File::create(path)
+                .map_err(|err| anyhow::anyhow!("{} {}", err, path.display()))?,
+        ),
+        None => Box::new(std::io::stderr()),
+    };
+    Logger::new(&spec, out)
+        .map_err(anyhow::Error::msg)?
+        .install()?;
+    Ok(())
+}
+
+fn strace(args: &Args) -> anyhow::Result<Strace> {
+    let filter = match args.strace_filter.is_empty() {
+        true => None,
+        false => Some(Strace::parse_filter(&args.strace_filter).map_err(anyhow::Error::msg)?),
+    };
+    let out: Box<dyn Write + Send> = match &args.strace_output {
+        Some(path) => Box::new(BufWriter::new(
+            std::fs::File::create(path)
+                .map_err(|err| anyhow::anyhow!("{} {}", err, path.display()))?,
+        )),
+        None => Box::new(std::io::stderr()),
+    };
+    Ok(Strace::new(out, filter))
+}
+
+/// Exit status for a machine that stopped with `outcome`: that of an SBI shutdown, failing
+/// only if the guest reported a system failure, or that of a process killed by the problem.
+fn system_status(outcome: &RunOutcome, reset: Option<SystemReset>) -> i32 {
+    match outcome {
+        RunOutcome::Exit(status) | RunOutcome::ExitGroup(status) => *status,
+        RunOutcome::Stopped => reset.map_or(0, |reset| (reset.reason == 1) as i32),
+        RunOutcome::Fault { .. } | RunOutcome::Breakpoint { .. } => FAULT_STATUS,
+        RunOutcome::InstructionLimit => LIMIT_STATUS,
+    }
+}
+
+fn run_system(args: &Args, image: &[u8]) -> anyhow::Result<i32> {
+    let config = MachineConfig {
+        ram_size: args.memory << 20,
+        bootargs: args.append.clone(),
+        dtb: args.dtb.as_ref().map(std::fs::read).transpose()?,
+        ..Default::default()
+    };
+    let mut machine = Machine::new(config, Box::new(std::io::stdout()))?;
+    if let Some(path) = &args.dump_dtb {
+        std::fs::write(path, machine.dtb())?;
+    }
+    let entry = if Elf::is_elf(image) {
+        let elf = Elf::parse(image)?;
+        elf.load(machine.cpu.mem.as_mut(), true)?;
+        machine.cpu.symbols = elf.symbols.clone();
+        elf.physical_entry() as usize
+    } else if args.kernel.is_some() {
+        let entry = RAM_BASE + image_load_offset(image);
+        machine.load(entry, image)?;
+        entry
+    } else {
+        machine.load(RAM_BASE, image)?;
+        RAM_BASE + args.offset
+    };
+    if args.kernel.is_some() {
+        machine.boot_kernel(entry);
+    } else {
+        machine.boot(entry);
+    }
+    machine.cpu.instruction_limit = args.max_instructions;
+    terminal::enter_raw_mode();
+    terminal::attach_stdin(machine.uart.clone());
+    let outcome = machine.cpu.run();
+    terminal::restore_terminal();
+    let reset = machine.cpu.sbi.as_ref().and_then(|sbi| sbi.reset());
+    Ok(system_status(&outcome, reset))
+}
+
+/// Run the guest, returning the status to exit with: that of the guest process, or for a
+/// machine that of its shutdown.
+fn run(args: &Args) -> anyhow::Result<i32> {
+    if let Some(kernel) = &args.kernel {
+        let image =
+            std::fs::read(kernel).map_err(|err| anyhow::anyhow!("{} {}", err, kernel.display()))?;
+        return run_system(args, &image);
+    }
     let mut buffer = Vec::new();
     std::fs::File::open(&args.name)
         .map_err(|err| anyhow::anyhow!("{} {}", err, args.name))?
         .read_to_end(&mut buffer)?;
-    let mut mem = Memory::new(0usize..=buffer.len());
-    mem.init_from(&buffer)?;
-    let mut c = Cpu::new(mem);
-    c.set_pc(args.offset as isize);
-    c.set_debug(args.verbose);
-    c.run();
-    Ok(())
+    if args.system {
+        return run_system(args, &buffer);
+    }
+    let mut c = if Elf::is_elf(&buffer) {
+        let elf = Elf::parse(&buffer)?;
+        elf.span(false)
+            .ok_or_else(|| anyhow::anyhow!("{} has nothing to load", args.name))?;
+        let mut c = Cpu::new(load_program(&elf)?);
+        let argv: Vec<String> = std::iter::once(args.name.clone())
+            .chain(args.guest_args.iter().cloned())
+            .collect();
+        setup_stack(&mut c, &elf, &argv, &args.env)?;
+        c.set_pc(elf.entry as isize);
+        c.symbols = elf.symbols;
+        c
+    } else {
+        let mut mem = Memory::new(0usize..=buffer.len());
+        mem.init_from(&buffer)?;
+        let mut c = Cpu::new(mem);
+        c.set_pc(args.offset as isize);
+        c
+    };
+    c.instruction_limit = args.max_instructions;
+    if args.strace {
+        c.strace = Some(Arc::new(strace(args)?));
+    }
+    Ok(run_process(&mut c))
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    init_logging(&args)?;
+    let status = run(&args)?;
+    log::logger().flush();
+    std::process::exit(status);
 }
@@ -8,39 +8,42 @@ pub struct Memory {
 impl Memory {
     pub fn new(range: RangeInclusive<usize>) -> Self {
         Self {
-            data: vec![0u8; (range.end() - range.start()) as usize],
+            data: vec![0u8; range.end() - range.start() + 1],
             range,
         }
     }
     #[inline(always)]
     pub fn get_address(&self, addr: usize) -> anyhow::Result<usize, OperationError> {
         if self.range.contains(&addr) {
-            Ok((addr - self.range.start()) as usize)
+            Ok(addr - self.range.start())
         } else {
             Err(OperationError::AddressOutOfRange(addr))
         }
     }
     fn load_interger<T: Sized>(&self, addr: usize) -> anyhow::Result<T, OperationError> {
-        if addr % size_of::<T>() != 0 {
-            return Err(OperationError::UnalignedAccess(addr));
+        if !addr.is_multiple_of(size_of::<T>()) {
+            return Err(OperationError::LoadMisaligned(addr));
         }
-        if self.get_address(addr).is_err() || self.get_address(addr + size_of::<T>() - 1).is_err() {
-            return Err(OperationError::LoadAddressFault(addr));
-        }
-        Ok(unsafe { (self.data.as_ptr().add(addr as usize) as *const T).read() })
+        let offset = self
+            .get_address(addr)
+            .and_then(|offset| self.get_address(addr + size_of::<T>() - 1).map(|_| offset))
+            .map_err(|_| OperationError::LoadAddressFault(addr))?;
+        Ok(unsafe { (self.data.as_ptr().add(offset) as *const T).read_unaligned() })
     }
     fn store_interger<T: Sized>(
         &mut self,
         addr: usize,
         data: T,
     ) -> anyhow::Result<(), OperationError> {
-        if addr % size_of::<T>() != 0 {
-            return Err(OperationError::UnalignedAccess(addr));
+        if !addr.is_multiple_of(size_of::<T>()) {
+            return Err(OperationError::StoreMisaligned(addr));
         }
-        if self.get_address(addr).is_err() || self.get_address(addr + size_of::<T>() - 1).is_err() {
-            return Err(OperationError::StoreAddressFault(addr));
-        }
-        Ok(unsafe { (self.data.as_ptr().add(addr as usize) as *mut T).write(data) })
+        let offset = self
+            .get_address(addr)
+            .and_then(|offset| self.get_address(addr + size_of::<T>() - 1).map(|_| offset))
+            .map_err(|_| OperationError::StoreAddressFault(addr))?;
+        unsafe { (self.data.as_mut_ptr().add(offset) as *mut T).write_unaligned(data) };
+        Ok(())
     }
 }
 impl Bus for Memory {
@@ -51,24 +54,32 @@ impl Bus for Memory {
     fn address_range(&self) -> &RangeInclusive<usize> {
         &self.range
     }
-    fn read_bytes(&self, addr: usize, len: usize) -> anyhow::Result<&[u8], OperationError> {
+    fn read_bytes(&self, addr: usize, len: usize) -> anyhow::Result<Vec<u8>, OperationError> {
         let start = self
             .get_address(addr)
             .map_err(|_| OperationError::LoadAddressFault(addr))?;
-        let end = self
-            .get_address(addr + len)
-            .map_err(|_| OperationError::LoadAddressFault(addr + len))?;
-        Ok(&self.data[start..end])
+        if len > 0 {
+            let last = addr
+                .checked_add(len - 1)
+                .ok_or(OperationError::LoadAddressFault(addr))?;
+            self.get_address(last)
+                .map_err(|_| OperationError::LoadAddressFault(last))?;
+        }
+        Ok(self.data[start..start + len].to_vec())
     }
     fn write_bytes(&mut self, addr: usize, data: &[u8]) -> anyhow::Result<(), OperationError> {
         let len = data.len();
         let start = self
             .get_address(addr)
             .map_err(|_| OperationError::StoreAddressFault(addr))?;
-        let end = self
-            .get_address(addr + len)
-            .map_err(|_| OperationError::StoreAddressFault(addr + len))?;
-        self.data[start..end].copy_from_slice(data);
+        if len > 0 {
+            let last = addr
+                .checked_add(len - 1)
+                .ok_or(OperationError::StoreAddressFault(addr))?;
+            self.get_address(last)
+                .map_err(|_| OperationError::StoreAddressFault(last))?;
+        }
+        self.data[start..start + len].copy_from_slice(data);
         Ok(())
     }
 }
@@ -0,0 +1,487 @@
+use super::bus::{Bus, BusOperation};
+use super::csr::{CsrFile, Privilege, status};
+use super::error::OperationError;
+use super::logger::MMU;
+use riscv::mmu::{
+    AccessType, PTE_A, PTE_D, PTE_G, PageWalkResult, check_permission, levels, walk_page,
+};
+
+/// The page fault raised by a failed `access` to `vaddr`.
+pub fn page_fault(vaddr: usize, access: AccessType) -> OperationError {
+    match access {
+        AccessType::Read => OperationError::LoadPageFault(vaddr),
+        AccessType::Write => OperationError::StorePageFault(vaddr),
+        AccessType::Execute => OperationError::InstructionPageFault(vaddr),
+    }
+}
+
+/// The access fault raised when the page table itself cannot be read.
+fn access_fault(vaddr: usize, access: AccessType) -> OperationError {
+    match access {
+        AccessType::Read => OperationError::LoadAddressFault(vaddr),
+        AccessType::Write => OperationError::StoreAddressFault(vaddr),
+        AccessType::Execute => OperationError::InstructionAccessFault(vaddr),
+    }
+}
+
+const TLB_ENTRIES: usize = 256;
+
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    /// Virtual page number of the access that filled the entry.
+    vpn: u64,
+    asid: u16,
+    walk: PageWalkResult,
+}
+
+impl TlbEntry {
+    /// Whether this entry maps `vpn`, taking the page size into account.
+    fn covers(&self, vpn: u64) -> bool {
+        (self.vpn ^ vpn) >> (self.walk.granularity * 9) == 0
+    }
+
+    fn global(&self) -> bool {
+        self.walk.pte & PTE_G != 0
+    }
+}
+
+/// A direct-mapped cache of page walk results, tagged with the ASID they were walked under.
+pub struct Tlb {
+    entries: Vec<Option<TlbEntry>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl Tlb {
+    pub fn new() -> Self {
+        Self {
+            entries: vec![None; TLB_ENTRIES],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    fn lookup(&self, vpn: u64, asid: u16) -> Option<PageWalkResult> {
+        match self.entries[vpn as usize % TLB_ENTRIES] {
+            Some(entry) if entry.covers(vpn) && (entry.asid == asid || entry.global()) => {
+                Some(entry.walk)
+            }
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, vpn: u64, asid: u16, walk: PageWalkResult) {
+        self.entries[vpn as usize % TLB_ENTRIES] = Some(TlbEntry { vpn, asid, walk });
+    }
+
+    /// Drop the entries selected by `sfence.vma`: those mapping `vaddr` (or all), belonging to
+    /// `asid` (or any). Global entries survive ASID-specific flushes.
+    pub fn flush(&mut self, vaddr: Option<u64>, asid: Option<u16>) {
+        for slot in self.entries.iter_mut() {
+            if let Some(entry) = slot {
+                let address_matches = vaddr.is_none_or(|vaddr| entry.covers(vaddr >> 12));
+                let asid_matches = asid.is_none_or(|asid| entry.asid == asid && !entry.global());
+                if address_matches && asid_matches {
+                    *slot = None;
+                }
+            }
+        }
+    }
+}
+
+impl Default for Tlb {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Per-hart address translation, with separate instruction and data TLBs.
+#[derive(Default)]
+pub struct Mmu {
+    pub itlb: Tlb,
+    pub dtlb: Tlb,
+}
+
+impl Mmu {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Translate `vaddr` through the page table selected by `satp`, as seen from privilege `prv`.
+    ///
+    /// M-mode accesses and Bare mode are not translated. The A and D bits of the leaf PTE are set
+    /// as part of a successful access.
+    pub fn translate(
+        &mut self,
+        mem: &mut dyn Bus,
+        csr: &CsrFile,
+        prv: Privilege,
+        vaddr: usize,
+        access: AccessType,
+    ) -> anyhow::Result<usize, OperationError> {
+        if prv == Privilege::Machine || levels(csr.satp).is_none() {
+            return Ok(vaddr);
+        }
+        let vpn = vaddr as u64 >> 12;
+        let asid = (csr.satp >> 44) as u16;
+        let tlb = if access == AccessType::Execute {
+            &mut self.itlb
+        } else {
+            &mut self.dtlb
+        };
+        let required = if access == AccessType::Write {
+            PTE_A | PTE_D
+        } else {
+            PTE_A
+        };
+        let walk = match tlb.lookup(vpn, asid) {
+            // An entry without the A/D bits this access needs is refilled, which sets them.
+            Some(walk) if walk.pte & required == required => {
+                check_permission(walk.pte, access, prv as u8, csr.mstatus & !status::SD)
+                    .map_err(|_| page_fault(vaddr, access))
+                    .inspect_err(|err| log::trace!(target: MMU, "{err}"))?;
+                tlb.hits += 1;
+                walk
+            }
+            _ => {
+                tlb.misses += 1;
+                let walk = walk(mem, csr, prv, vaddr, access)
+                    .inspect_err(|err| log::trace!(target: MMU, "{err}"))?;
+                tlb.insert(vpn, asid, walk);
+                walk
+            }
+        };
+        let leaf = walk.synthesise_4k(vaddr as u64).pte;
+        let ppn = (leaf >> 10) & ((1 << 44) - 1);
+        Ok((ppn << 12 | vaddr as u64 & 0xfff) as usize)
+    }
+
+    /// Execute `sfence.vma` on both TLBs.
+    pub fn sfence_vma(&mut self, vaddr: Option<u64>, asid: Option<u16>) {
+        self.itlb.flush(vaddr, asid);
+        self.dtlb.flush(vaddr, asid);
+    }
+
+    /// Drop entries made stale by a `satp` write. Entries are tagged with their ASID, so only a
+    /// change of mode or root table under the same ASID needs a flush.
+    pub fn satp_changed(&mut self, old: u64, new: u64) {
+        let asid = |satp: u64| (satp >> 44) as u16;
+        if old >> 60 != new >> 60 {
+            self.sfence_vma(None, None);
+        } else if asid(old) == asid(new) && old != new {
+            self.sfence_vma(None, Some(asid(old)));
+        }
+    }
+}
+
+/// Walk the page table for `access` to `vaddr`, checking permissions and setting the A and D
+/// bits of the leaf PTE.
+fn walk(
+    mem: &mut dyn Bus,
+    csr: &CsrFile,
+    prv: Privilege,
+    vaddr: usize,
+    access: AccessType,
+) -> anyhow::Result<PageWalkResult, OperationError> {
+    let vpn = vaddr as u64 >> 12;
+    loop {
+        let mut walk_fault = false;
+        let mut walk = walk_page(csr.satp, vpn, |addr| {
+            BusOperation::<u64>::load(&*mem, addr as usize).unwrap_or_else(|_| {
+                walk_fault = true;
+                0
+            })
+        });
+        if walk_fault {
+            return Err(access_fault(vaddr, access));
+        }
+        let pte = walk.pte;
+        let mut updated = pte | PTE_A;
+        if access == AccessType::Write {
+            updated |= PTE_D;
+        }
+        // Check with A/D already set, so that the update only happens for permitted accesses.
+        check_permission(updated, access, prv as u8, csr.mstatus & !status::SD)
+            .map_err(|_| page_fault(vaddr, access))?;
+        if updated != pte {
+            let addr = walk.pte_addr as usize;
+            let raw: u64 =
+                BusOperation::<u64>::load(&*mem, addr).map_err(|_| access_fault(vaddr, access))?;
+            // `walk_page` folds the global bit of non-leaf levels into the PTE it returns.
+            let unchanged = raw | (pte & PTE_G) == pte;
+            let swapped = unchanged
+                && mem
+                    .compare_exchange(addr, raw, raw | (updated & !pte))
+                    .map_err(|_| access_fault(vaddr, access))?;
+            if !swapped {
+                // The PTE changed under us; walk again.
+                continue;
+            }
+            walk.pte = updated;
+        }
+        return Ok(walk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Memory;
+    use crate::csr::{SATP_MODE_SV39, SATP_MODE_SV48};
+    use riscv::mmu::{PTE_R, PTE_U, PTE_V, PTE_W, PTE_X};
+
+    const ROOT: usize = 0x1000;
+
+    fn pte(ppn: usize, flags: u64) -> u64 {
+        (ppn as u64) << 10 | flags
+    }
+
+    fn setup(mode: u64) -> (Memory, CsrFile) {
+        let mem = Memory::new(0..=0xffff);
+        let mut csr = CsrFile::new(0);
+        csr.satp = mode << 60 | (ROOT >> 12) as u64;
+        (mem, csr)
+    }
+
+    fn write_pte(mem: &mut Memory, table: usize, index: usize, value: u64) {
+        BusOperation::<u64>::store(mem, table + index * 8, value).unwrap();
+    }
+
+    fn read_pte(mem: &Memory, table: usize, index: usize) -> u64 {
+        BusOperation::<u64>::load(mem, table + index * 8).unwrap()
+    }
+
+    /// Translate with an empty TLB, i.e. through a full page walk.
+    fn translate(
+        mem: &mut Memory,
+        csr: &CsrFile,
+        prv: Privilege,
+        vaddr: usize,
+        access: AccessType,
+    ) -> anyhow::Result<usize, OperationError> {
+        Mmu::new().translate(mem, csr, prv, vaddr, access)
+    }
+
+    /// Map the 4K page at `vaddr` (below 2M) to `ppn` under the Sv39 root.
+    fn map_4k(mem: &mut Memory, vaddr: usize, ppn: usize, flags: u64) {
+        write_pte(mem, ROOT, 0, pte(0x2, PTE_V));
+        write_pte(mem, 0x2000, 0, pte(0x3, PTE_V));
+        write_pte(mem, 0x3000, vaddr >> 12, pte(ppn, flags));
+    }
+
+    #[test]
+    fn test_sv39_translation_and_ad_bits() {
+        let (mut mem, csr) = setup(SATP_MODE_SV39);
+        // 0x4000_5000 -> 0x8000 through a 3-level walk.
+        write_pte(&mut mem, ROOT, 1, pte(0x2, PTE_V));
+        write_pte(&mut mem, 0x2000, 0, pte(0x3, PTE_V));
+        write_pte(&mut mem, 0x3000, 5, pte(0x8, PTE_V | PTE_R | PTE_W | PTE_U));
+        let s = Privilege::Supervisor;
+        let u = Privilege::User;
+
+        let paddr = translate(&mut mem, &csr, u, 0x4000_5123, AccessType::Read).unwrap();
+        assert_eq!(paddr, 0x8123);
+        assert_eq!(read_pte(&mem, 0x3000, 5) & (PTE_A | PTE_D), PTE_A);
+        translate(&mut mem, &csr, u, 0x4000_5000, AccessType::Write).unwrap();
+        assert_eq!(read_pte(&mem, 0x3000, 5) & (PTE_A | PTE_D), PTE_A | PTE_D);
+
+        // Not executable, and user pages need SUM from S-mode.
+        assert!(matches!(
+            translate(&mut mem, &csr, u, 0x4000_5004, AccessType::Execute),
+            Err(OperationError::InstructionPageFault(0x4000_5004))
+        ));
+        assert!(matches!(
+            translate(&mut mem, &csr, s, 0x4000_5008, AccessType::Read),
+            Err(OperationError::LoadPageFault(0x4000_5008))
+        ));
+        // Unmapped and non-canonical addresses fault too.
+        assert!(matches!(
+            translate(&mut mem, &csr, u, 0x4000_6000, AccessType::Write),
+            Err(OperationError::StorePageFault(0x4000_6000))
+        ));
+        assert!(matches!(
+            translate(&mut mem, &csr, u, 0x80_4000_5000, AccessType::Read),
+            Err(OperationError::LoadPageFault(_))
+        ));
+        // M-mode bypasses translation.
+        let m = Privilege::Machine;
+        assert_eq!(
+            translate(&mut mem, &csr, m, 0x4000_6000, AccessType::Read).unwrap(),
+            0x4000_6000
+        );
+    }
+
+    #[test]
+    fn test_faulting_access_leaves_ad_bits() {
+        let (mut mem, csr) = setup(SATP_MODE_SV39);
+        write_pte(&mut mem, ROOT, 0, pte(0x2, PTE_V));
+        write_pte(&mut mem, 0x2000, 0, pte(0x3, PTE_V));
+        write_pte(&mut mem, 0x3000, 0, pte(0x8, PTE_V | PTE_R));
+        let result = translate(&mut mem, &csr, Privilege::User, 0, AccessType::Read);
+        assert!(matches!(result, Err(OperationError::LoadPageFault(0))));
+        assert_eq!(read_pte(&mem, 0x3000, 0) & PTE_A, 0);
+    }
+
+    #[test]
+    fn test_sv48_superpage() {
+        let (mut mem, csr) = setup(SATP_MODE_SV48);
+        // A 2M page at 0x80_0020_0000, above the Sv39 range, mapped to physical 0x20_0000.
+        write_pte(&mut mem, ROOT, 1, pte(0x2, PTE_V));
+        write_pte(&mut mem, 0x2000, 0, pte(0x3, PTE_V));
+        write_pte(&mut mem, 0x3000, 1, pte(0x200, PTE_V | PTE_R | PTE_X));
+        let vaddr = 0x80_0023_4567;
+        let paddr = translate(
+            &mut mem,
+            &csr,
+            Privilege::Supervisor,
+            vaddr,
+            AccessType::Execute,
+        )
+        .unwrap();
+        assert_eq!(paddr, 0x23_4567);
+
+        // A misaligned superpage is invalid.
+        write_pte(&mut mem, 0x3000, 1, pte(0x201, PTE_V | PTE_R | PTE_X));
+        assert!(
+            translate(
+                &mut mem,
+                &csr,
+                Privilege::Supervisor,
+                vaddr,
+                AccessType::Read
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_tlb_hits_and_sfence() {
+        let (mut mem, csr) = setup(SATP_MODE_SV39);
+        let mut mmu = Mmu::new();
+        let s = Privilege::Supervisor;
+        map_4k(&mut mem, 0x5000, 0x8, PTE_V | PTE_R | PTE_W | PTE_A | PTE_D);
+
+        assert_eq!(
+            mmu.translate(&mut mem, &csr, s, 0x5010, AccessType::Read)
+                .unwrap(),
+            0x8010
+        );
+        assert_eq!(
+            mmu.translate(&mut mem, &csr, s, 0x5020, AccessType::Write)
+                .unwrap(),
+            0x8020
+        );
+        assert_eq!((mmu.dtlb.hits(), mmu.dtlb.misses()), (1, 1));
+
+        // The cached translation is used until it is flushed.
+        map_4k(&mut mem, 0x5000, 0x9, PTE_V | PTE_R | PTE_W | PTE_A | PTE_D);
+        assert_eq!(
+            mmu.translate(&mut mem, &csr, s, 0x5000, AccessType::Read)
+                .unwrap(),
+            0x8000
+        );
+        mmu.sfence_vma(Some(0x6000), None);
+        assert_eq!(
+            mmu.translate(&mut mem, &csr, s, 0x5000, AccessType::Read)
+                .unwrap(),
+            0x8000
+        );
+        mmu.sfence_vma(Some(0x5fff), None);
+        assert_eq!(
+            mmu.translate(&mut mem, &csr, s, 0x5000, AccessType::Read)
+                .unwrap(),
+            0x9000
+        );
+        assert_eq!((mmu.dtlb.hits(), mmu.dtlb.misses()), (3, 2));
+        assert_eq!(mmu.itlb.misses(), 0);
+    }
+
+    #[test]
+    fn test_tlb_refills_for_dirty_bit() {
+        let (mut mem, csr) = setup(SATP_MODE_SV39);
+        let mut mmu = Mmu::new();
+        let s = Privilege::Supervisor;
+        map_4k(&mut mem, 0x5000, 0x8, PTE_V | PTE_R | PTE_W);
+        mmu.translate(&mut mem, &csr, s, 0x5000, AccessType::Read)
+            .unwrap();
+        assert_eq!(read_pte(&mem, 0x3000, 5) & PTE_D, 0);
+        mmu.translate(&mut mem, &csr, s, 0x5000, AccessType::Write)
+            .unwrap();
+        assert_eq!(read_pte(&mem, 0x3000, 5) & PTE_D, PTE_D);
+        assert_eq!(mmu.dtlb.misses(), 2);
+    }
+
+    #[test]
+    fn test_tlb_asids() {
+        let (mut mem, mut csr) = setup(SATP_MODE_SV39);
+        let mut mmu = Mmu::new();
+        let s = Privilege::Supervisor;
+        let flags = PTE_V | PTE_R | PTE_A;
+        map_4k(&mut mem, 0x5000, 0x8, flags);
+        map_4k(&mut mem, 0x6000, 0x9, flags | PTE_G);
+        csr.satp |= 1 << 44;
+        mmu.translate(&mut mem, &csr, s, 0x5000, AccessType::Read)
+            .unwrap();
+        mmu.translate(&mut mem, &csr, s, 0x6000, AccessType::Read)
+            .unwrap();
+
+        // Under another ASID only the global entry is shared.
+        let old = csr.satp;
+        csr.satp = old & !(0xffff << 44) | 2 << 44;
+        mmu.satp_changed(old, csr.satp);
+        mmu.translate(&mut mem, &csr, s, 0x5000, AccessType::Read)
+            .unwrap();
+        mmu.translate(&mut mem, &csr, s, 0x6000, AccessType::Read)
+            .unwrap();
+        assert_eq!((mmu.dtlb.hits(), mmu.dtlb.misses()), (1, 3));
+
+        // An ASID flush keeps global entries and other ASIDs.
+        map_4k(&mut mem, 0x5000, 0xa, flags);
+        map_4k(&mut mem, 0x6000, 0xb, flags | PTE_G);
+        mmu.sfence_vma(None, Some(1));
+        let read = |mmu: &mut Mmu, mem: &mut Memory, csr: &CsrFile, vaddr| {
+            mmu.translate(mem, csr, s, vaddr, AccessType::Read).unwrap()
+        };
+        assert_eq!(read(&mut mmu, &mut mem, &csr, 0x5000), 0x8000);
+        assert_eq!(read(&mut mmu, &mut mem, &csr, 0x6000), 0x9000);
+        mmu.sfence_vma(None, Some(2));
+        assert_eq!(read(&mut mmu, &mut mem, &csr, 0x5000), 0xa000);
+        assert_eq!(read(&mut mmu, &mut mem, &csr, 0x6000), 0x9000);
+        mmu.sfence_vma(None, None);
+        assert_eq!(read(&mut mmu, &mut mem, &csr, 0x6000), 0xb000);
+    }
+
+    #[test]
+    fn test_tlb_superpage_entry() {
+        let (mut mem, csr) = setup(SATP_MODE_SV39);
+        let mut mmu = Mmu::new();
+        let s = Privilege::Supervisor;
+        // A 1G page at 0x4000_0000.
+        write_pte(&mut mem, ROOT, 1, pte(0x40000, PTE_V | PTE_R | PTE_A));
+        mmu.translate(&mut mem, &csr, s, 0x4000_0000, AccessType::Read)
+            .unwrap();
+        // A flush of any address inside the superpage drops the entry.
+        mmu.sfence_vma(Some(0x7fff_f000), None);
+        mmu.translate(&mut mem, &csr, s, 0x4000_0000, AccessType::Read)
+            .unwrap();
+        assert_eq!(mmu.dtlb.misses(), 2);
+    }
+
+    #[test]
+    fn test_accessed_bit_under_global_parent() {
+        let (mut mem, csr) = setup(SATP_MODE_SV39);
+        write_pte(&mut mem, ROOT, 0, pte(0x2, PTE_V | PTE_G));
+        write_pte(&mut mem, 0x2000, 0, pte(0x3, PTE_V));
+        write_pte(&mut mem, 0x3000, 0, pte(0x8, PTE_V | PTE_R));
+        translate(&mut mem, &csr, Privilege::Supervisor, 0, AccessType::Read).unwrap();
+        assert_eq!(read_pte(&mem, 0x3000, 0), pte(0x8, PTE_V | PTE_R | PTE_A));
+    }
+}
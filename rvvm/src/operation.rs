@@ -1,15 +1,178 @@
-use super::cpu::Cpu;
+use super::cpu::{Cpu, ExecutionMode};
+use super::csr::{Privilege, status};
 use super::error::OperationError;
 use super::syscall_handler::syscall_handler;
-use crate::register::Generic;
-use riscv::Op;
+use crate::register::{Float, Generic};
+use crate::softfloat::{DOUBLE, Format, RoundingMode, SINGLE};
+use riscv::{Csr, Op};
 
 pub fn instruction_operation(
     op: Op,
     cpu: &mut Cpu,
     len: isize,
+    bits: u32,
 ) -> anyhow::Result<(), OperationError> {
+    if op.is_float() && !cpu.csr.fs_enabled() {
+        return Err(OperationError::IllegalInstruction(bits, cpu.pc as usize));
+    }
     match op {
+        /* Base Opcode = LOAD */
+        Op::Lb { rd, rs1, imm } => {
+            let src = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data: u8 = cpu.load(src as usize)?;
+            cpu.set_generic(Generic::from(rd), data as i8 as isize);
+        }
+        Op::Lh { rd, rs1, imm } => {
+            let src = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data: u16 = cpu.load(src as usize)?;
+            cpu.set_generic(Generic::from(rd), data as i16 as isize);
+        }
+        Op::Lw { rd, rs1, imm } => {
+            let src = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data: u32 = cpu.load(src as usize)?;
+            cpu.set_generic(Generic::from(rd), data as i32 as isize);
+        }
+        Op::Ld { rd, rs1, imm } => {
+            let src = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data: u64 = cpu.load(src as usize)?;
+            cpu.set_generic(Generic::from(rd), data as isize);
+        }
+        Op::Lbu { rd, rs1, imm } => {
+            let src = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data: u8 = cpu.load(src as usize)?;
+            cpu.set_generic(Generic::from(rd), data as isize);
+        }
+        Op::Lhu { rd, rs1, imm } => {
+            let src = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data: u16 = cpu.load(src as usize)?;
+            cpu.set_generic(Generic::from(rd), data as isize);
+        }
+        Op::Lwu { rd, rs1, imm } => {
+            let src = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data: u32 = cpu.load(src as usize)?;
+            cpu.set_generic(Generic::from(rd), data as isize);
+        }
+
+        /* Base Opcode = MISC-MEM */
+        // Harts only share memory through an `AddressSpace`, and every access takes its lock, which
+        // orders it against the accesses of all other harts; fences have nothing left to order.
+        Op::Fence | Op::FenceI => {}
+
+        /* Base Opcode = OP-IMM */
+        Op::Addi { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(Generic::from(rd), src.wrapping_add(imm as isize));
+        }
+        Op::Slli { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(Generic::from(rd), src << (imm & 0x3f));
+        }
+        Op::Slti { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(Generic::from(rd), (src < imm as isize) as isize);
+        }
+        Op::Sltiu { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(
+                Generic::from(rd),
+                ((src as usize) < (imm as isize as usize)) as isize,
+            );
+        }
+        Op::Xori { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(Generic::from(rd), src ^ imm as isize);
+        }
+        Op::Srli { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(Generic::from(rd), ((src as usize) >> (imm & 0x3f)) as isize);
+        }
+        Op::Srai { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(Generic::from(rd), src >> (imm & 0x3f));
+        }
+        Op::Ori { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(Generic::from(rd), src | imm as isize);
+        }
+        Op::Andi { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(Generic::from(rd), src & imm as isize);
+        }
+
+        /* Base Opcode = AUIPC */
+        Op::Auipc { rd, imm } => {
+            cpu.set_generic(Generic::from(rd), cpu.pc.wrapping_add(imm as isize));
+        }
+
+        /* Base Opcode = OP-IMM-32 */
+        Op::Addiw { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(
+                Generic::from(rd),
+                src.wrapping_add(imm as isize) as i32 as isize,
+            );
+        }
+        Op::Slliw { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(Generic::from(rd), ((src as i32) << (imm & 0x1f)) as isize);
+        }
+        Op::Srliw { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(
+                Generic::from(rd),
+                ((src as u32) >> (imm & 0x1f)) as i32 as isize,
+            );
+        }
+        Op::Sraiw { rd, rs1, imm } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_generic(Generic::from(rd), ((src as i32) >> (imm & 0x1f)) as isize);
+        }
+
+        /* Base Opcode = STORE */
+        Op::Sb { rs1, rs2, imm } => {
+            let dst = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data = cpu.get_generic(Generic::from(rs2));
+            cpu.store(dst as usize, data as u8)?;
+        }
+        Op::Sh { rs1, rs2, imm } => {
+            let dst = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data = cpu.get_generic(Generic::from(rs2));
+            cpu.store(dst as usize, data as u16)?;
+        }
+        Op::Sw { rs1, rs2, imm } => {
+            let dst = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data = cpu.get_generic(Generic::from(rs2));
+            cpu.store(dst as usize, data as u32)?;
+        }
+        Op::Sd { rs1, rs2, imm } => {
+            let dst = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data = cpu.get_generic(Generic::from(rs2));
+            cpu.store(dst as usize, data as u64)?;
+        }
+
+        /* Base Opcode = OP */
         Op::Add { rd, rs1, rs2 } => {
             cpu.set_generic(
                 Generic::from(rd),
@@ -17,7 +180,6 @@ pub fn instruction_operation(
                     .wrapping_add(cpu.get_generic(Generic::from(rs2))),
             );
         }
-
         Op::Sub { rd, rs1, rs2 } => {
             cpu.set_generic(
                 Generic::from(rd),
@@ -25,68 +187,1153 @@ pub fn instruction_operation(
                     .wrapping_sub(cpu.get_generic(Generic::from(rs2))),
             );
         }
-        Op::Slli { rd, rs1, imm } => {
+        Op::Sll { rd, rs1, rs2 } => {
+            let shamt = cpu.get_generic(Generic::from(rs2)) & 0x3f;
             cpu.set_generic(
                 Generic::from(rd),
-                cpu.get_generic(Generic::from(rs1)) << imm,
+                cpu.get_generic(Generic::from(rs1)) << shamt,
             );
         }
-        Op::Addi { rd, rs1, imm } => {
-            let src = cpu.get_generic(Generic::from(rs1));
-            cpu.set_generic(Generic::from(rd), src.wrapping_add(imm as isize));
+        Op::Slt { rd, rs1, rs2 } => {
+            cpu.set_generic(
+                Generic::from(rd),
+                (cpu.get_generic(Generic::from(rs1)) < cpu.get_generic(Generic::from(rs2)))
+                    as isize,
+            );
         }
-        Op::Addiw { rd, rs1, imm } => {
-            let src = cpu.get_generic(Generic::from(rs1));
+        Op::Sltu { rd, rs1, rs2 } => {
             cpu.set_generic(
                 Generic::from(rd),
-                src.wrapping_add(imm as isize) as i32 as isize,
+                ((cpu.get_generic(Generic::from(rs1)) as usize)
+                    < (cpu.get_generic(Generic::from(rs2)) as usize)) as isize,
             );
         }
-        Op::Auipc { rd, imm } => {
-            cpu.set_generic(Generic::from(rd), cpu.pc.wrapping_add(imm as isize));
+        Op::Xor { rd, rs1, rs2 } => {
+            cpu.set_generic(
+                Generic::from(rd),
+                cpu.get_generic(Generic::from(rs1)) ^ cpu.get_generic(Generic::from(rs2)),
+            );
         }
-        Op::Lb { rd, rs1, imm } => {
-            let src = cpu
+        Op::Srl { rd, rs1, rs2 } => {
+            let shamt = cpu.get_generic(Generic::from(rs2)) & 0x3f;
+            cpu.set_generic(
+                Generic::from(rd),
+                ((cpu.get_generic(Generic::from(rs1)) as usize) >> shamt) as isize,
+            );
+        }
+        Op::Sra { rd, rs1, rs2 } => {
+            let shamt = cpu.get_generic(Generic::from(rs2)) & 0x3f;
+            cpu.set_generic(
+                Generic::from(rd),
+                cpu.get_generic(Generic::from(rs1)) >> shamt,
+            );
+        }
+        Op::Or { rd, rs1, rs2 } => {
+            cpu.set_generic(
+                Generic::from(rd),
+                cpu.get_generic(Generic::from(rs1)) | cpu.get_generic(Generic::from(rs2)),
+            );
+        }
+        Op::And { rd, rs1, rs2 } => {
+            cpu.set_generic(
+                Generic::from(rd),
+                cpu.get_generic(Generic::from(rs1)) & cpu.get_generic(Generic::from(rs2)),
+            );
+        }
+
+        /* Base Opcode = LUI */
+        Op::Lui { rd, imm } => {
+            cpu.set_generic(Generic::from(rd), imm as isize);
+        }
+
+        /* Base Opcode = OP-32 */
+        Op::Addw { rd, rs1, rs2 } => {
+            cpu.set_generic(
+                Generic::from(rd),
+                (cpu.get_generic(Generic::from(rs1)) as i32)
+                    .wrapping_add(cpu.get_generic(Generic::from(rs2)) as i32)
+                    as isize,
+            );
+        }
+        Op::Subw { rd, rs1, rs2 } => {
+            cpu.set_generic(
+                Generic::from(rd),
+                (cpu.get_generic(Generic::from(rs1)) as i32)
+                    .wrapping_sub(cpu.get_generic(Generic::from(rs2)) as i32)
+                    as isize,
+            );
+        }
+        Op::Sllw { rd, rs1, rs2 } => {
+            let shamt = cpu.get_generic(Generic::from(rs2)) & 0x1f;
+            cpu.set_generic(
+                Generic::from(rd),
+                ((cpu.get_generic(Generic::from(rs1)) as i32) << shamt) as isize,
+            );
+        }
+        Op::Srlw { rd, rs1, rs2 } => {
+            let shamt = cpu.get_generic(Generic::from(rs2)) & 0x1f;
+            cpu.set_generic(
+                Generic::from(rd),
+                ((cpu.get_generic(Generic::from(rs1)) as u32) >> shamt) as i32 as isize,
+            );
+        }
+        Op::Sraw { rd, rs1, rs2 } => {
+            let shamt = cpu.get_generic(Generic::from(rs2)) & 0x1f;
+            cpu.set_generic(
+                Generic::from(rd),
+                ((cpu.get_generic(Generic::from(rs1)) as i32) >> shamt) as isize,
+            );
+        }
+
+        /* Base Opcode = BRANCH */
+        Op::Beq { rs1, rs2, imm } => {
+            if cpu.get_generic(Generic::from(rs1)) == cpu.get_generic(Generic::from(rs2)) {
+                cpu.pc = branch_target(cpu, len, imm);
+            } else {
+                cpu.pc = cpu.pc.wrapping_add(len);
+            }
+            return Ok(());
+        }
+        Op::Bne { rs1, rs2, imm } => {
+            if cpu.get_generic(Generic::from(rs1)) != cpu.get_generic(Generic::from(rs2)) {
+                cpu.pc = branch_target(cpu, len, imm);
+            } else {
+                cpu.pc = cpu.pc.wrapping_add(len);
+            }
+            return Ok(());
+        }
+        Op::Blt { rs1, rs2, imm } => {
+            if cpu.get_generic(Generic::from(rs1)) < cpu.get_generic(Generic::from(rs2)) {
+                cpu.pc = branch_target(cpu, len, imm);
+            } else {
+                cpu.pc = cpu.pc.wrapping_add(len);
+            }
+            return Ok(());
+        }
+        Op::Bge { rs1, rs2, imm } => {
+            if cpu.get_generic(Generic::from(rs1)) >= cpu.get_generic(Generic::from(rs2)) {
+                cpu.pc = branch_target(cpu, len, imm);
+            } else {
+                cpu.pc = cpu.pc.wrapping_add(len);
+            }
+            return Ok(());
+        }
+        Op::Bltu { rs1, rs2, imm } => {
+            if (cpu.get_generic(Generic::from(rs1)) as usize)
+                < (cpu.get_generic(Generic::from(rs2)) as usize)
+            {
+                cpu.pc = branch_target(cpu, len, imm);
+            } else {
+                cpu.pc = cpu.pc.wrapping_add(len);
+            }
+            return Ok(());
+        }
+        Op::Bgeu { rs1, rs2, imm } => {
+            if (cpu.get_generic(Generic::from(rs1)) as usize)
+                >= (cpu.get_generic(Generic::from(rs2)) as usize)
+            {
+                cpu.pc = branch_target(cpu, len, imm);
+            } else {
+                cpu.pc = cpu.pc.wrapping_add(len);
+            }
+            return Ok(());
+        }
+
+        /* Base Opcode = JALR */
+        Op::Jalr { rd, rs1, imm } => {
+            // Compute the target before writing `rd`, since `rd` and `rs1` may be the same register.
+            let target = cpu
                 .get_generic(Generic::from(rs1))
-                .wrapping_add(imm as isize);
-            let data: u8 = cpu.mem.load(src as usize)?;
+                .wrapping_add(imm as isize)
+                & !1;
+            cpu.set_generic(Generic::from(rd), cpu.pc.wrapping_add(len));
+            cpu.pc = target;
+            return Ok(());
+        }
+
+        /* Base Opcode = JAL */
+        Op::Jal { rd, imm } => {
+            cpu.set_generic(Generic::from(rd), cpu.pc.wrapping_add(len));
+            cpu.pc = branch_target(cpu, len, imm);
+            return Ok(());
+        }
+
+        /* Base Opcode = SYSTEM */
+        Op::Ecall => match cpu.execution_mode() {
+            // The handler steps past the `ecall` itself, as `rt_sigreturn` resumes elsewhere.
+            ExecutionMode::User => return syscall_handler(cpu),
+            ExecutionMode::System => {
+                return Err(OperationError::EnvironmentCall(cpu.pc as usize));
+            }
+        },
+        Op::Ebreak => {
+            return Err(OperationError::Breakpoint(cpu.pc as usize));
+        }
+        Op::Csrrw { rd, rs1, csr } => {
+            let src = cpu.get_generic(Generic::from(rs1)) as u64;
+            csr_operation(cpu, bits, rd, csr, |_| Some(src))?;
+        }
+        Op::Csrrs { rd, rs1, csr } => {
+            let src = cpu.get_generic(Generic::from(rs1)) as u64;
+            csr_operation(cpu, bits, rd, csr, |old| (rs1 != 0).then_some(old | src))?;
+        }
+        Op::Csrrc { rd, rs1, csr } => {
+            let src = cpu.get_generic(Generic::from(rs1)) as u64;
+            csr_operation(cpu, bits, rd, csr, |old| (rs1 != 0).then_some(old & !src))?;
+        }
+        Op::Csrrwi { rd, imm, csr } => {
+            csr_operation(cpu, bits, rd, csr, |_| Some(imm as u64))?;
+        }
+        Op::Csrrsi { rd, imm, csr } => {
+            csr_operation(cpu, bits, rd, csr, |old| {
+                (imm != 0).then_some(old | imm as u64)
+            })?;
+        }
+        Op::Csrrci { rd, imm, csr } => {
+            csr_operation(cpu, bits, rd, csr, |old| {
+                (imm != 0).then_some(old & !(imm as u64))
+            })?;
+        }
+
+        /* M extension */
+        // Division never traps: dividing by zero and signed overflow produce the results
+        // mandated by the spec, which `wrapping_div`/`wrapping_rem` already give for overflow.
+        /* Base Opcode = OP */
+        Op::Mul { rd, rs1, rs2 } => {
+            cpu.set_generic(
+                Generic::from(rd),
+                cpu.get_generic(Generic::from(rs1))
+                    .wrapping_mul(cpu.get_generic(Generic::from(rs2))),
+            );
+        }
+        Op::Mulh { rd, rs1, rs2 } => {
+            let lhs = cpu.get_generic(Generic::from(rs1)) as i128;
+            let rhs = cpu.get_generic(Generic::from(rs2)) as i128;
+            cpu.set_generic(Generic::from(rd), ((lhs * rhs) >> 64) as isize);
+        }
+        Op::Mulhsu { rd, rs1, rs2 } => {
+            let lhs = cpu.get_generic(Generic::from(rs1)) as i128;
+            let rhs = cpu.get_generic(Generic::from(rs2)) as usize as i128;
+            cpu.set_generic(Generic::from(rd), ((lhs * rhs) >> 64) as isize);
+        }
+        Op::Mulhu { rd, rs1, rs2 } => {
+            let lhs = cpu.get_generic(Generic::from(rs1)) as usize as u128;
+            let rhs = cpu.get_generic(Generic::from(rs2)) as usize as u128;
+            cpu.set_generic(Generic::from(rd), ((lhs * rhs) >> 64) as isize);
+        }
+        Op::Div { rd, rs1, rs2 } => {
+            let lhs = cpu.get_generic(Generic::from(rs1));
+            let rhs = cpu.get_generic(Generic::from(rs2));
+            let quotient = if rhs == 0 { -1 } else { lhs.wrapping_div(rhs) };
+            cpu.set_generic(Generic::from(rd), quotient);
+        }
+        Op::Divu { rd, rs1, rs2 } => {
+            let lhs = cpu.get_generic(Generic::from(rs1)) as usize;
+            let rhs = cpu.get_generic(Generic::from(rs2)) as usize;
+            let quotient = lhs.checked_div(rhs).unwrap_or(usize::MAX);
+            cpu.set_generic(Generic::from(rd), quotient as isize);
+        }
+        Op::Rem { rd, rs1, rs2 } => {
+            let lhs = cpu.get_generic(Generic::from(rs1));
+            let rhs = cpu.get_generic(Generic::from(rs2));
+            let remainder = if rhs == 0 { lhs } else { lhs.wrapping_rem(rhs) };
+            cpu.set_generic(Generic::from(rd), remainder);
+        }
+        Op::Remu { rd, rs1, rs2 } => {
+            let lhs = cpu.get_generic(Generic::from(rs1)) as usize;
+            let rhs = cpu.get_generic(Generic::from(rs2)) as usize;
+            let remainder = lhs.checked_rem(rhs).unwrap_or(lhs);
+            cpu.set_generic(Generic::from(rd), remainder as isize);
+        }
+        /* Base Opcode = OP-32 */
+        Op::Mulw { rd, rs1, rs2 } => {
+            cpu.set_generic(
+                Generic::from(rd),
+                (cpu.get_generic(Generic::from(rs1)) as i32)
+                    .wrapping_mul(cpu.get_generic(Generic::from(rs2)) as i32)
+                    as isize,
+            );
+        }
+        Op::Divw { rd, rs1, rs2 } => {
+            let lhs = cpu.get_generic(Generic::from(rs1)) as i32;
+            let rhs = cpu.get_generic(Generic::from(rs2)) as i32;
+            let quotient = if rhs == 0 { -1 } else { lhs.wrapping_div(rhs) };
+            cpu.set_generic(Generic::from(rd), quotient as isize);
+        }
+        Op::Divuw { rd, rs1, rs2 } => {
+            let lhs = cpu.get_generic(Generic::from(rs1)) as u32;
+            let rhs = cpu.get_generic(Generic::from(rs2)) as u32;
+            let quotient = lhs.checked_div(rhs).unwrap_or(u32::MAX);
+            cpu.set_generic(Generic::from(rd), quotient as i32 as isize);
+        }
+        Op::Remw { rd, rs1, rs2 } => {
+            let lhs = cpu.get_generic(Generic::from(rs1)) as i32;
+            let rhs = cpu.get_generic(Generic::from(rs2)) as i32;
+            let remainder = if rhs == 0 { lhs } else { lhs.wrapping_rem(rhs) };
+            cpu.set_generic(Generic::from(rd), remainder as isize);
+        }
+        Op::Remuw { rd, rs1, rs2 } => {
+            let lhs = cpu.get_generic(Generic::from(rs1)) as u32;
+            let rhs = cpu.get_generic(Generic::from(rs2)) as u32;
+            let remainder = lhs.checked_rem(rhs).unwrap_or(lhs);
+            cpu.set_generic(Generic::from(rd), remainder as i32 as isize);
+        }
+
+        /* A extension */
+        // Every access to memory shared between harts takes the lock of its `AddressSpace`, and
+        // atomics hold the locks of the pages they touch throughout, so every atomic is
+        // sequentially consistent and the `aqrl` bits need no further handling.
+        /* Base Opcode = AMO */
+        Op::LrW { rd, rs1, .. } => {
+            let addr = cpu.get_generic(Generic::from(rs1)) as usize;
+            let data: u32 = cpu.load_reserved(addr)?;
+            cpu.set_generic(Generic::from(rd), data as i32 as isize);
+        }
+        Op::LrD { rd, rs1, .. } => {
+            let addr = cpu.get_generic(Generic::from(rs1)) as usize;
+            let data: u64 = cpu.load_reserved(addr)?;
             cpu.set_generic(Generic::from(rd), data as isize);
         }
-        Op::Ld { rd, rs1, imm } => {
+        Op::ScW { rd, rs1, rs2, .. } => {
+            let addr = cpu.get_generic(Generic::from(rs1)) as usize;
+            let data = cpu.get_generic(Generic::from(rs2)) as u32;
+            let stored = cpu.store_conditional(addr, data)?;
+            cpu.set_generic(Generic::from(rd), !stored as isize);
+        }
+        Op::ScD { rd, rs1, rs2, .. } => {
+            let addr = cpu.get_generic(Generic::from(rs1)) as usize;
+            let data = cpu.get_generic(Generic::from(rs2)) as u64;
+            let stored = cpu.store_conditional(addr, data)?;
+            cpu.set_generic(Generic::from(rd), !stored as isize);
+        }
+        Op::AmoswapW { rd, rs1, rs2, .. } => amo_w(cpu, rd, rs1, rs2, |_, src| src)?,
+        Op::AmoswapD { rd, rs1, rs2, .. } => amo_d(cpu, rd, rs1, rs2, |_, src| src)?,
+        Op::AmoaddW { rd, rs1, rs2, .. } => amo_w(cpu, rd, rs1, rs2, u32::wrapping_add)?,
+        Op::AmoaddD { rd, rs1, rs2, .. } => amo_d(cpu, rd, rs1, rs2, u64::wrapping_add)?,
+        Op::AmoxorW { rd, rs1, rs2, .. } => amo_w(cpu, rd, rs1, rs2, |old, src| old ^ src)?,
+        Op::AmoxorD { rd, rs1, rs2, .. } => amo_d(cpu, rd, rs1, rs2, |old, src| old ^ src)?,
+        Op::AmoandW { rd, rs1, rs2, .. } => amo_w(cpu, rd, rs1, rs2, |old, src| old & src)?,
+        Op::AmoandD { rd, rs1, rs2, .. } => amo_d(cpu, rd, rs1, rs2, |old, src| old & src)?,
+        Op::AmoorW { rd, rs1, rs2, .. } => amo_w(cpu, rd, rs1, rs2, |old, src| old | src)?,
+        Op::AmoorD { rd, rs1, rs2, .. } => amo_d(cpu, rd, rs1, rs2, |old, src| old | src)?,
+        Op::AmominW { rd, rs1, rs2, .. } => amo_w(cpu, rd, rs1, rs2, |old, src| {
+            (old as i32).min(src as i32) as u32
+        })?,
+        Op::AmominD { rd, rs1, rs2, .. } => amo_d(cpu, rd, rs1, rs2, |old, src| {
+            (old as i64).min(src as i64) as u64
+        })?,
+        Op::AmomaxW { rd, rs1, rs2, .. } => amo_w(cpu, rd, rs1, rs2, |old, src| {
+            (old as i32).max(src as i32) as u32
+        })?,
+        Op::AmomaxD { rd, rs1, rs2, .. } => amo_d(cpu, rd, rs1, rs2, |old, src| {
+            (old as i64).max(src as i64) as u64
+        })?,
+        Op::AmominuW { rd, rs1, rs2, .. } => amo_w(cpu, rd, rs1, rs2, u32::min)?,
+        Op::AmominuD { rd, rs1, rs2, .. } => amo_d(cpu, rd, rs1, rs2, u64::min)?,
+        Op::AmomaxuW { rd, rs1, rs2, .. } => amo_w(cpu, rd, rs1, rs2, u32::max)?,
+        Op::AmomaxuD { rd, rs1, rs2, .. } => amo_d(cpu, rd, rs1, rs2, u64::max)?,
+
+        /* F extension */
+        /* Base Opcode = LOAD-FP */
+        Op::Flw { frd, rs1, imm } => {
             let src = cpu
                 .get_generic(Generic::from(rs1))
                 .wrapping_add(imm as isize);
-            let address: usize = cpu.mem.load(src as usize)?;
-            cpu.set_generic(Generic::from(rd), address as isize);
+            let data: u32 = cpu.load(src as usize)?;
+            cpu.set_f32(Float::from(frd), data);
         }
-        Op::Jal { rd, imm } => {
-            cpu.set_generic(Generic::from(rd), cpu.pc + 4);
-            cpu.pc = cpu.pc.wrapping_add(imm as isize);
-            return Ok(());
+        /* Base Opcode = STORE-FP */
+        Op::Fsw { rs1, frs2, imm } => {
+            let dst = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data = cpu.get_f64(Float::from(frs2));
+            cpu.store(dst as usize, data as u32)?;
         }
-        Op::Jalr { rd, rs1, imm } => {
-            cpu.set_generic(Generic::from(rd), cpu.pc + 4);
-            cpu.pc = cpu
+        /* Base Opcode = OP-FP */
+        Op::FaddS {
+            frd,
+            frs1,
+            frs2,
+            rm,
+        } => fp_binary(cpu, SINGLE, bits, frd, frs1, frs2, rm, Format::add)?,
+        Op::FsubS {
+            frd,
+            frs1,
+            frs2,
+            rm,
+        } => fp_binary(cpu, SINGLE, bits, frd, frs1, frs2, rm, Format::sub)?,
+        Op::FmulS {
+            frd,
+            frs1,
+            frs2,
+            rm,
+        } => fp_binary(cpu, SINGLE, bits, frd, frs1, frs2, rm, Format::mul)?,
+        Op::FdivS {
+            frd,
+            frs1,
+            frs2,
+            rm,
+        } => fp_binary(cpu, SINGLE, bits, frd, frs1, frs2, rm, Format::div)?,
+        Op::FsqrtS { frd, frs1, rm } => fp_sqrt(cpu, SINGLE, bits, frd, frs1, rm)?,
+        Op::FsgnjS { frd, frs1, frs2 } => {
+            fp_sign_inject(cpu, SINGLE, frd, frs1, frs2, |_, sign| sign)
+        }
+        Op::FsgnjnS { frd, frs1, frs2 } => {
+            fp_sign_inject(cpu, SINGLE, frd, frs1, frs2, |_, sign| !sign)
+        }
+        Op::FsgnjxS { frd, frs1, frs2 } => {
+            fp_sign_inject(cpu, SINGLE, frd, frs1, frs2, |a, b| a != b)
+        }
+        Op::FminS { frd, frs1, frs2 } => fp_min_max(cpu, SINGLE, frd, frs1, frs2, Format::min),
+        Op::FmaxS { frd, frs1, frs2 } => fp_min_max(cpu, SINGLE, frd, frs1, frs2, Format::max),
+        Op::FcvtWS { rd, frs1, rm } => fp_to_int(cpu, SINGLE, bits, rd, frs1, rm, true, 32)?,
+        Op::FcvtWuS { rd, frs1, rm } => fp_to_int(cpu, SINGLE, bits, rd, frs1, rm, false, 32)?,
+        Op::FcvtLS { rd, frs1, rm } => fp_to_int(cpu, SINGLE, bits, rd, frs1, rm, true, 64)?,
+        Op::FcvtLuS { rd, frs1, rm } => fp_to_int(cpu, SINGLE, bits, rd, frs1, rm, false, 64)?,
+        Op::FmvXW { rd, frs1 } => {
+            let src = cpu.get_f64(Float::from(frs1));
+            cpu.set_generic(Generic::from(rd), src as i32 as isize);
+        }
+        Op::FclassS { rd, frs1 } => {
+            let class = SINGLE.classify(read_fp(cpu, SINGLE, frs1));
+            cpu.set_generic(Generic::from(rd), class as isize);
+        }
+        Op::FeqS { rd, frs1, frs2 } => fp_compare(cpu, SINGLE, rd, frs1, frs2, Format::eq),
+        Op::FltS { rd, frs1, frs2 } => fp_compare(cpu, SINGLE, rd, frs1, frs2, Format::lt),
+        Op::FleS { rd, frs1, frs2 } => fp_compare(cpu, SINGLE, rd, frs1, frs2, Format::le),
+        Op::FcvtSW { frd, rs1, rm } => int_to_fp(cpu, SINGLE, bits, frd, rs1, rm, true, 32)?,
+        Op::FcvtSWu { frd, rs1, rm } => int_to_fp(cpu, SINGLE, bits, frd, rs1, rm, false, 32)?,
+        Op::FcvtSL { frd, rs1, rm } => int_to_fp(cpu, SINGLE, bits, frd, rs1, rm, true, 64)?,
+        Op::FcvtSLu { frd, rs1, rm } => int_to_fp(cpu, SINGLE, bits, frd, rs1, rm, false, 64)?,
+        Op::FmvWX { frd, rs1 } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_f32(Float::from(frd), src as u32);
+        }
+        /* Base Opcode = MADD, MSUB, NMSUB, NMADD */
+        Op::FmaddS {
+            frd,
+            frs1,
+            frs2,
+            frs3,
+            rm,
+        } => fp_fused(cpu, SINGLE, bits, [frd, frs1, frs2, frs3], rm, false, false)?,
+        Op::FmsubS {
+            frd,
+            frs1,
+            frs2,
+            frs3,
+            rm,
+        } => fp_fused(cpu, SINGLE, bits, [frd, frs1, frs2, frs3], rm, false, true)?,
+        Op::FnmsubS {
+            frd,
+            frs1,
+            frs2,
+            frs3,
+            rm,
+        } => fp_fused(cpu, SINGLE, bits, [frd, frs1, frs2, frs3], rm, true, false)?,
+        Op::FnmaddS {
+            frd,
+            frs1,
+            frs2,
+            frs3,
+            rm,
+        } => fp_fused(cpu, SINGLE, bits, [frd, frs1, frs2, frs3], rm, true, true)?,
+
+        /* D extension */
+        /* Base Opcode = LOAD-FP */
+        Op::Fld { frd, rs1, imm } => {
+            let src = cpu
                 .get_generic(Generic::from(rs1))
                 .wrapping_add(imm as isize);
-            return Ok(());
+            let data: u64 = cpu.load(src as usize)?;
+            cpu.set_f64(Float::from(frd), data);
         }
-        Op::Bne { rs1, rs2, imm } => {
-            if cpu.get_generic(Generic::from(rs1)) != cpu.get_generic(Generic::from(rs2)) {
-                cpu.pc = cpu.pc.wrapping_add(imm as isize);
-            } else {
-                cpu.pc = cpu.pc.wrapping_add(len);
+        /* Base Opcode = STORE-FP */
+        Op::Fsd { rs1, frs2, imm } => {
+            let dst = cpu
+                .get_generic(Generic::from(rs1))
+                .wrapping_add(imm as isize);
+            let data = cpu.get_f64(Float::from(frs2));
+            cpu.store(dst as usize, data)?;
+        }
+        /* Base Opcode = OP-FP */
+        Op::FaddD {
+            frd,
+            frs1,
+            frs2,
+            rm,
+        } => fp_binary(cpu, DOUBLE, bits, frd, frs1, frs2, rm, Format::add)?,
+        Op::FsubD {
+            frd,
+            frs1,
+            frs2,
+            rm,
+        } => fp_binary(cpu, DOUBLE, bits, frd, frs1, frs2, rm, Format::sub)?,
+        Op::FmulD {
+            frd,
+            frs1,
+            frs2,
+            rm,
+        } => fp_binary(cpu, DOUBLE, bits, frd, frs1, frs2, rm, Format::mul)?,
+        Op::FdivD {
+            frd,
+            frs1,
+            frs2,
+            rm,
+        } => fp_binary(cpu, DOUBLE, bits, frd, frs1, frs2, rm, Format::div)?,
+        Op::FsqrtD { frd, frs1, rm } => fp_sqrt(cpu, DOUBLE, bits, frd, frs1, rm)?,
+        Op::FsgnjD { frd, frs1, frs2 } => {
+            fp_sign_inject(cpu, DOUBLE, frd, frs1, frs2, |_, sign| sign)
+        }
+        Op::FsgnjnD { frd, frs1, frs2 } => {
+            fp_sign_inject(cpu, DOUBLE, frd, frs1, frs2, |_, sign| !sign)
+        }
+        Op::FsgnjxD { frd, frs1, frs2 } => {
+            fp_sign_inject(cpu, DOUBLE, frd, frs1, frs2, |a, b| a != b)
+        }
+        Op::FminD { frd, frs1, frs2 } => fp_min_max(cpu, DOUBLE, frd, frs1, frs2, Format::min),
+        Op::FmaxD { frd, frs1, frs2 } => fp_min_max(cpu, DOUBLE, frd, frs1, frs2, Format::max),
+        Op::FcvtSD { frd, frs1, rm } => fp_convert(cpu, DOUBLE, SINGLE, bits, frd, frs1, rm)?,
+        Op::FcvtDS { frd, frs1, rm } => fp_convert(cpu, SINGLE, DOUBLE, bits, frd, frs1, rm)?,
+        Op::FcvtWD { rd, frs1, rm } => fp_to_int(cpu, DOUBLE, bits, rd, frs1, rm, true, 32)?,
+        Op::FcvtWuD { rd, frs1, rm } => fp_to_int(cpu, DOUBLE, bits, rd, frs1, rm, false, 32)?,
+        Op::FcvtLD { rd, frs1, rm } => fp_to_int(cpu, DOUBLE, bits, rd, frs1, rm, true, 64)?,
+        Op::FcvtLuD { rd, frs1, rm } => fp_to_int(cpu, DOUBLE, bits, rd, frs1, rm, false, 64)?,
+        Op::FmvXD { rd, frs1 } => {
+            let src = cpu.get_f64(Float::from(frs1));
+            cpu.set_generic(Generic::from(rd), src as isize);
+        }
+        Op::FclassD { rd, frs1 } => {
+            let class = DOUBLE.classify(read_fp(cpu, DOUBLE, frs1));
+            cpu.set_generic(Generic::from(rd), class as isize);
+        }
+        Op::FeqD { rd, frs1, frs2 } => fp_compare(cpu, DOUBLE, rd, frs1, frs2, Format::eq),
+        Op::FltD { rd, frs1, frs2 } => fp_compare(cpu, DOUBLE, rd, frs1, frs2, Format::lt),
+        Op::FleD { rd, frs1, frs2 } => fp_compare(cpu, DOUBLE, rd, frs1, frs2, Format::le),
+        Op::FcvtDW { frd, rs1, rm } => int_to_fp(cpu, DOUBLE, bits, frd, rs1, rm, true, 32)?,
+        Op::FcvtDWu { frd, rs1, rm } => int_to_fp(cpu, DOUBLE, bits, frd, rs1, rm, false, 32)?,
+        Op::FcvtDL { frd, rs1, rm } => int_to_fp(cpu, DOUBLE, bits, frd, rs1, rm, true, 64)?,
+        Op::FcvtDLu { frd, rs1, rm } => int_to_fp(cpu, DOUBLE, bits, frd, rs1, rm, false, 64)?,
+        Op::FmvDX { frd, rs1 } => {
+            let src = cpu.get_generic(Generic::from(rs1));
+            cpu.set_f64(Float::from(frd), src as u64);
+        }
+        /* Base Opcode = MADD, MSUB, NMSUB, NMADD */
+        Op::FmaddD {
+            frd,
+            frs1,
+            frs2,
+            frs3,
+            rm,
+        } => fp_fused(cpu, DOUBLE, bits, [frd, frs1, frs2, frs3], rm, false, false)?,
+        Op::FmsubD {
+            frd,
+            frs1,
+            frs2,
+            frs3,
+            rm,
+        } => fp_fused(cpu, DOUBLE, bits, [frd, frs1, frs2, frs3], rm, false, true)?,
+        Op::FnmsubD {
+            frd,
+            frs1,
+            frs2,
+            frs3,
+            rm,
+        } => fp_fused(cpu, DOUBLE, bits, [frd, frs1, frs2, frs3], rm, true, false)?,
+        Op::FnmaddD {
+            frd,
+            frs1,
+            frs2,
+            frs3,
+            rm,
+        } => fp_fused(cpu, DOUBLE, bits, [frd, frs1, frs2, frs3], rm, true, true)?,
+
+        /* Privileged */
+        Op::Mret => {
+            if cpu.privilege() < Privilege::Machine {
+                return Err(OperationError::IllegalInstruction(bits, cpu.pc as usize));
             }
+            cpu.mret();
             return Ok(());
         }
-
-        Op::Ecall => {
-            syscall_handler(cpu)?;
+        Op::Sret => {
+            check_supervisor(cpu, bits, status::TSR)?;
+            cpu.sret();
+            return Ok(());
+        }
+        Op::Wfi => {
+            check_supervisor(cpu, bits, status::TW)?;
+            cpu.wait_for_interrupt();
+        }
+        Op::SfenceVma { rs1, rs2 } => {
+            check_supervisor(cpu, bits, status::TVM)?;
+            let vaddr = (rs1 != 0).then(|| cpu.get_generic(Generic::from(rs1)) as u64);
+            let asid = (rs2 != 0).then(|| cpu.get_generic(Generic::from(rs2)) as u16);
+            cpu.mmu.sfence_vma(vaddr, asid);
         }
-        _ => {
-            panic!("op: {:?} Not implemented!", op);
+        Op::Illegal => {
+            return Err(OperationError::IllegalInstruction(bits, cpu.pc as usize));
         }
     }
     cpu.pc += len;
     Ok(())
 }
+
+/// Branch and JAL immediates are relative to `pc after - 4` (see `riscv::Op::Beq`), which is
+/// `pc before - 2` for compressed instructions.
+fn branch_target(cpu: &Cpu, len: isize, imm: i32) -> isize {
+    cpu.pc.wrapping_add(len - 4).wrapping_add(imm as isize)
+}
+
+/// Supervisor instructions are illegal in U-mode, and in S-mode when the `mstatus` bit `trap` is
+/// set.
+fn check_supervisor(cpu: &Cpu, bits: u32, trap: u64) -> anyhow::Result<(), OperationError> {
+    match cpu.privilege() {
+        Privilege::User => Err(OperationError::IllegalInstruction(bits, cpu.pc as usize)),
+        Privilege::Supervisor if cpu.csr.mstatus & trap != 0 => {
+            Err(OperationError::IllegalInstruction(bits, cpu.pc as usize))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Shared body of the Zicsr ops. `update` computes the value to write from the old value, or
+/// returns `None` when the op must not write the CSR.
+fn csr_operation(
+    cpu: &mut Cpu,
+    bits: u32,
+    rd: u8,
+    csr: Csr,
+    update: impl FnOnce(u64) -> Option<u64>,
+) -> anyhow::Result<(), OperationError> {
+    let pc = cpu.pc as usize;
+    let illegal = || OperationError::IllegalInstruction(bits, pc);
+    let old = cpu.read_csr(csr).ok_or_else(illegal)?;
+    if let Some(new) = update(old) {
+        cpu.write_csr(csr, new).ok_or_else(illegal)?;
+    }
+    cpu.set_generic(Generic::from(rd), old as isize);
+    Ok(())
+}
+
+/// Resolve the `rm` field of an op, substituting `frm` for the dynamic rounding mode. Reserved
+/// modes are illegal instructions.
+fn rounding_mode(cpu: &Cpu, bits: u32, rm: u8) -> anyhow::Result<RoundingMode, OperationError> {
+    let rm = if rm == 0b111 { cpu.frm() } else { rm };
+    RoundingMode::from_bits(rm).ok_or(OperationError::IllegalInstruction(bits, cpu.pc as usize))
+}
+
+fn read_fp(cpu: &Cpu, format: Format, reg: u8) -> u64 {
+    if format == SINGLE {
+        cpu.get_f32(Float::from(reg)) as u64
+    } else {
+        cpu.get_f64(Float::from(reg))
+    }
+}
+
+fn write_fp(cpu: &mut Cpu, format: Format, reg: u8, value: u64) {
+    if format == SINGLE {
+        cpu.set_f32(Float::from(reg), value as u32);
+    } else {
+        cpu.set_f64(Float::from(reg), value);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn fp_binary(
+    cpu: &mut Cpu,
+    format: Format,
+    bits: u32,
+    frd: u8,
+    frs1: u8,
+    frs2: u8,
+    rm: u8,
+    f: fn(Format, u64, u64, RoundingMode, &mut u8) -> u64,
+) -> anyhow::Result<(), OperationError> {
+    let rm = rounding_mode(cpu, bits, rm)?;
+    let mut flags = 0;
+    let result = f(
+        format,
+        read_fp(cpu, format, frs1),
+        read_fp(cpu, format, frs2),
+        rm,
+        &mut flags,
+    );
+    write_fp(cpu, format, frd, result);
+    cpu.accrue_fflags(flags);
+    Ok(())
+}
+
+fn fp_sqrt(
+    cpu: &mut Cpu,
+    format: Format,
+    bits: u32,
+    frd: u8,
+    frs1: u8,
+    rm: u8,
+) -> anyhow::Result<(), OperationError> {
+    let rm = rounding_mode(cpu, bits, rm)?;
+    let mut flags = 0;
+    let result = format.sqrt(read_fp(cpu, format, frs1), rm, &mut flags);
+    write_fp(cpu, format, frd, result);
+    cpu.accrue_fflags(flags);
+    Ok(())
+}
+
+/// Fused multiply-add on `[frd, frs1, frs2, frs3]`, optionally negating the product and/or the
+/// addend.
+fn fp_fused(
+    cpu: &mut Cpu,
+    format: Format,
+    bits: u32,
+    [frd, frs1, frs2, frs3]: [u8; 4],
+    rm: u8,
+    negate_product: bool,
+    negate_addend: bool,
+) -> anyhow::Result<(), OperationError> {
+    let rm = rounding_mode(cpu, bits, rm)?;
+    let mut flags = 0;
+    let mut a = read_fp(cpu, format, frs1);
+    let mut c = read_fp(cpu, format, frs3);
+    if negate_product {
+        a ^= format.sign_bit();
+    }
+    if negate_addend {
+        c ^= format.sign_bit();
+    }
+    let result = format.fma(a, read_fp(cpu, format, frs2), c, rm, &mut flags);
+    write_fp(cpu, format, frd, result);
+    cpu.accrue_fflags(flags);
+    Ok(())
+}
+
+/// Sign injection: the result takes the magnitude of `frs1` and the sign computed by `sign` from
+/// the signs of `frs1` and `frs2`.
+fn fp_sign_inject(
+    cpu: &mut Cpu,
+    format: Format,
+    frd: u8,
+    frs1: u8,
+    frs2: u8,
+    sign: fn(bool, bool) -> bool,
+) {
+    let a = read_fp(cpu, format, frs1);
+    let b = read_fp(cpu, format, frs2);
+    let sign_bit = format.sign_bit();
+    let negative = sign(a & sign_bit != 0, b & sign_bit != 0);
+    let result = (a & !sign_bit) | if negative { sign_bit } else { 0 };
+    write_fp(cpu, format, frd, result);
+}
+
+fn fp_min_max(
+    cpu: &mut Cpu,
+    format: Format,
+    frd: u8,
+    frs1: u8,
+    frs2: u8,
+    f: fn(Format, u64, u64, &mut u8) -> u64,
+) {
+    let mut flags = 0;
+    let result = f(
+        format,
+        read_fp(cpu, format, frs1),
+        read_fp(cpu, format, frs2),
+        &mut flags,
+    );
+    write_fp(cpu, format, frd, result);
+    cpu.accrue_fflags(flags);
+}
+
+fn fp_compare(
+    cpu: &mut Cpu,
+    format: Format,
+    rd: u8,
+    frs1: u8,
+    frs2: u8,
+    f: fn(Format, u64, u64, &mut u8) -> bool,
+) {
+    let mut flags = 0;
+    let result = f(
+        format,
+        read_fp(cpu, format, frs1),
+        read_fp(cpu, format, frs2),
+        &mut flags,
+    );
+    cpu.set_generic(Generic::from(rd), result as isize);
+    cpu.accrue_fflags(flags);
+}
+
+fn fp_convert(
+    cpu: &mut Cpu,
+    from: Format,
+    to: Format,
+    bits: u32,
+    frd: u8,
+    frs1: u8,
+    rm: u8,
+) -> anyhow::Result<(), OperationError> {
+    let rm = rounding_mode(cpu, bits, rm)?;
+    let mut flags = 0;
+    let result = from.convert(read_fp(cpu, from, frs1), to, rm, &mut flags);
+    write_fp(cpu, to, frd, result);
+    cpu.accrue_fflags(flags);
+    Ok(())
+}
+
+/// Float to integer conversion. 32-bit results are sign-extended, even for unsigned conversions.
+#[allow(clippy::too_many_arguments)]
+fn fp_to_int(
+    cpu: &mut Cpu,
+    format: Format,
+    bits: u32,
+    rd: u8,
+    frs1: u8,
+    rm: u8,
+    signed: bool,
+    width: u32,
+) -> anyhow::Result<(), OperationError> {
+    let rm = rounding_mode(cpu, bits, rm)?;
+    let mut flags = 0;
+    let result = format.float_to_int(read_fp(cpu, format, frs1), signed, width, rm, &mut flags);
+    let result = if width == 32 {
+        result as i32 as isize
+    } else {
+        result as isize
+    };
+    cpu.set_generic(Generic::from(rd), result);
+    cpu.accrue_fflags(flags);
+    Ok(())
+}
+
+/// Integer to float conversion, reading the low `width` bits of `rs1`.
+#[allow(clippy::too_many_arguments)]
+fn int_to_fp(
+    cpu: &mut Cpu,
+    format: Format,
+    bits: u32,
+    frd: u8,
+    rs1: u8,
+    rm: u8,
+    signed: bool,
+    width: u32,
+) -> anyhow::Result<(), OperationError> {
+    let rm = rounding_mode(cpu, bits, rm)?;
+    let src = cpu.get_generic(Generic::from(rs1)) as u64;
+    let src = match (width, signed) {
+        (32, true) => src as i32 as u64,
+        (32, false) => src as u32 as u64,
+        _ => src,
+    };
+    let mut flags = 0;
+    let result = format.int_to_float(src, signed, rm, &mut flags);
+    write_fp(cpu, format, frd, result);
+    cpu.accrue_fflags(flags);
+    Ok(())
+}
+
+/// Atomically combine the word at `rs1` with `rs2`, placing the sign-extended old value in `rd`.
+fn amo_w(
+    cpu: &mut Cpu,
+    rd: u8,
+    rs1: u8,
+    rs2: u8,
+    f: impl Fn(u32, u32) -> u32,
+) -> anyhow::Result<(), OperationError> {
+    let addr = cpu.get_generic(Generic::from(rs1)) as usize;
+    let src = cpu.get_generic(Generic::from(rs2)) as u32;
+    let old = cpu.fetch_update(addr, |old| f(old, src))?;
+    cpu.set_generic(Generic::from(rd), old as i32 as isize);
+    Ok(())
+}
+
+/// Atomically combine the doubleword at `rs1` with `rs2`, placing the old value in `rd`.
+fn amo_d(
+    cpu: &mut Cpu,
+    rd: u8,
+    rs1: u8,
+    rs2: u8,
+    f: impl Fn(u64, u64) -> u64,
+) -> anyhow::Result<(), OperationError> {
+    let addr = cpu.get_generic(Generic::from(rs1)) as usize;
+    let src = cpu.get_generic(Generic::from(rs2)) as u64;
+    let old = cpu.fetch_update(addr, |old| f(old, src))?;
+    cpu.set_generic(Generic::from(rd), old as isize);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Bus, BusOperation, Memory};
+
+    fn i_type(opcode: u32, funct3: u32, rd: u8, rs1: u8, imm: i32) -> u32 {
+        (imm as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | opcode
+    }
+
+    fn s_type(opcode: u32, funct3: u32, rs1: u8, rs2: u8, imm: i32) -> u32 {
+        ((imm as u32) >> 5) << 25
+            | (rs2 as u32) << 20
+            | (rs1 as u32) << 15
+            | funct3 << 12
+            | ((imm as u32) & 0x1f) << 7
+            | opcode
+    }
+
+    fn r_type(opcode: u32, funct3: u32, funct7: u32, rd: u8, rs1: u8, rs2: u8) -> u32 {
+        funct7 << 25
+            | (rs2 as u32) << 20
+            | (rs1 as u32) << 15
+            | funct3 << 12
+            | (rd as u32) << 7
+            | opcode
+    }
+
+    fn c_li(rd: u8, imm: i32) -> u16 {
+        let imm = imm as u16;
+        0b010 << 13 | (imm >> 5 & 1) << 12 | (rd as u16) << 7 | (imm & 0x1f) << 2 | 0b01
+    }
+
+    fn c_j(offset: i32) -> u16 {
+        let o = offset as u16;
+        0b101 << 13
+            | (o >> 11 & 1) << 12
+            | (o >> 4 & 1) << 11
+            | (o >> 8 & 3) << 9
+            | (o >> 10 & 1) << 8
+            | (o >> 6 & 1) << 7
+            | (o >> 7 & 1) << 6
+            | (o >> 1 & 7) << 3
+            | (o >> 5 & 1) << 2
+            | 0b01
+    }
+
+    fn c_branch(funct3: u16, rs1: u8, offset: i32) -> u16 {
+        let o = offset as u16;
+        funct3 << 13
+            | (o >> 8 & 1) << 12
+            | (o >> 3 & 3) << 10
+            | (rs1 as u16 - 8) << 7
+            | (o >> 6 & 3) << 5
+            | (o >> 1 & 3) << 3
+            | (o >> 5 & 1) << 2
+            | 0b01
+    }
+
+    fn c_jump_register(link: bool, rs1: u8) -> u16 {
+        0b100 << 13 | (link as u16) << 12 | (rs1 as u16) << 7 | 0b10
+    }
+
+    fn run_image(mut image: Vec<u8>, data: &[u8], steps: usize) -> Cpu {
+        image.resize(0x100, 0);
+        image.extend_from_slice(data);
+        let mut mem = Memory::new(0..=0x1ff);
+        mem.init_from(&image).unwrap();
+        let mut cpu = Cpu::new(mem);
+        for _ in 0..steps {
+            cpu.tick().unwrap();
+        }
+        cpu
+    }
+
+    fn run(code: &[u32], data: &[u8]) -> Cpu {
+        let image = code.iter().flat_map(|x| x.to_le_bytes()).collect();
+        run_image(image, data, code.len())
+    }
+
+    #[test]
+    fn test_load_extension() {
+        let cpu = run(
+            &[
+                i_type(0b0000011, 0b000, 10, 0, 0x100), // lb a0, 0x100(zero)
+                i_type(0b0000011, 0b100, 11, 0, 0x100), // lbu a1, 0x100(zero)
+                i_type(0b0000011, 0b010, 12, 0, 0x104), // lw a2, 0x104(zero)
+                i_type(0b0000011, 0b110, 13, 0, 0x104), // lwu a3, 0x104(zero)
+            ],
+            &[0x80, 0, 0, 0, 0xfe, 0xff, 0xff, 0xff],
+        );
+        assert_eq!(cpu.get_generic(Generic::a0), -128);
+        assert_eq!(cpu.get_generic(Generic::a1), 0x80);
+        assert_eq!(cpu.get_generic(Generic::a2), -2);
+        assert_eq!(cpu.get_generic(Generic::a3), 0xffff_fffe);
+    }
+
+    #[test]
+    fn test_shift_amount_masking() {
+        let cpu = run(
+            &[
+                i_type(0b0010011, 0b000, 10, 0, -1),        // li a0, -1
+                i_type(0b0010011, 0b000, 11, 0, 65),        // li a1, 65
+                r_type(0b0110011, 0b101, 0, 12, 10, 11),    // srl a2, a0, a1
+                r_type(0b0111011, 0b101, 0, 13, 10, 11),    // srlw a3, a0, a1
+                r_type(0b0111011, 0b101, 0x20, 14, 10, 11), // sraw a4, a0, a1
+                i_type(0b0011011, 0b001, 15, 10, 31),       // slliw a5, a0, 31
+            ],
+            &[],
+        );
+        assert_eq!(cpu.get_generic(Generic::a2), (usize::MAX >> 1) as isize);
+        assert_eq!(cpu.get_generic(Generic::a3), 0x7fff_ffff);
+        assert_eq!(cpu.get_generic(Generic::a4), -1);
+        assert_eq!(cpu.get_generic(Generic::a5), i32::MIN as isize);
+    }
+
+    #[test]
+    fn test_set_less_than() {
+        let cpu = run(
+            &[
+                i_type(0b0010011, 0b000, 10, 0, -1),     // li a0, -1
+                i_type(0b0010011, 0b000, 11, 0, 1),      // li a1, 1
+                r_type(0b0110011, 0b010, 0, 12, 10, 11), // slt a2, a0, a1
+                r_type(0b0110011, 0b011, 0, 13, 10, 11), // sltu a3, a0, a1
+                i_type(0b0010011, 0b011, 14, 11, -1),    // sltiu a4, a1, -1
+            ],
+            &[],
+        );
+        assert_eq!(cpu.get_generic(Generic::a2), 1);
+        assert_eq!(cpu.get_generic(Generic::a3), 0);
+        assert_eq!(cpu.get_generic(Generic::a4), 1);
+    }
+
+    #[test]
+    fn test_division_edge_cases() {
+        let cpu = run(
+            &[
+                i_type(0b0010011, 0b000, 10, 0, -1),     // li a0, -1
+                i_type(0b0010011, 0b001, 11, 10, 63),    // slli a1, a0, 63
+                r_type(0b0110011, 0b100, 1, 12, 11, 10), // div a2, a1, a0
+                r_type(0b0110011, 0b110, 1, 13, 11, 10), // rem a3, a1, a0
+                r_type(0b0110011, 0b100, 1, 14, 11, 0),  // div a4, a1, zero
+                r_type(0b0110011, 0b111, 1, 15, 11, 0),  // remu a5, a1, zero
+                r_type(0b0111011, 0b101, 1, 16, 10, 0),  // divuw a6, a0, zero
+                r_type(0b0110011, 0b011, 1, 17, 10, 10), // mulhu a7, a0, a0
+            ],
+            &[],
+        );
+        assert_eq!(cpu.get_generic(Generic::a2), isize::MIN);
+        assert_eq!(cpu.get_generic(Generic::a3), 0);
+        assert_eq!(cpu.get_generic(Generic::a4), -1);
+        assert_eq!(cpu.get_generic(Generic::a5), isize::MIN);
+        assert_eq!(cpu.get_generic(Generic::a6), -1);
+        assert_eq!(cpu.get_generic(Generic::a7), -2);
+    }
+
+    #[test]
+    fn test_reservation() {
+        let cpu = run(
+            &[
+                i_type(0b0010011, 0b000, 10, 0, 0x100), // li a0, 0x100
+                i_type(0b0010011, 0b000, 11, 0, 7),     // li a1, 7
+                r_type(0b0101111, 0b010, 0b00010 << 2, 12, 10, 0), // lr.w a2, (a0)
+                r_type(0b0101111, 0b010, 0b00011 << 2, 13, 10, 11), // sc.w a3, a1, (a0)
+                r_type(0b0101111, 0b010, 0b00011 << 2, 14, 10, 11), // sc.w a4, a1, (a0)
+                r_type(0b0101111, 0b010, 0b00010 << 2, 15, 10, 0), // lr.w a5, (a0)
+                s_type(0b0100011, 0b000, 10, 1, 0),     // sb ra, 0(a0)
+                r_type(0b0101111, 0b010, 0b00011 << 2, 16, 10, 11), // sc.w a6, a1, (a0)
+                r_type(0b0101111, 0b010, 0b00000 << 2, 17, 10, 11), // amoadd.w a7, a1, (a0)
+            ],
+            &[0xff, 0xff, 0xff, 0xff],
+        );
+        assert_eq!(cpu.get_generic(Generic::a2), -1);
+        assert_eq!(cpu.get_generic(Generic::a3), 0);
+        assert_eq!(cpu.get_generic(Generic::a4), 1);
+        assert_eq!(cpu.get_generic(Generic::a5), 7);
+        assert_eq!(cpu.get_generic(Generic::a6), 1);
+        assert_eq!(cpu.get_generic(Generic::a7), 0);
+        assert_eq!(
+            BusOperation::<u32>::load(cpu.mem.as_ref(), 0x100).unwrap(),
+            7
+        );
+    }
+
+    #[test]
+    fn test_float_boxing_and_fcsr() {
+        let cpu = run(
+            &[
+                i_type(0b0000111, 0b010, 1, 0, 0x100), // flw f1, 0x100(zero)
+                i_type(0b0000111, 0b010, 2, 0, 0x104), // flw f2, 0x104(zero)
+                r_type(0b1010011, 0b111, 0b0001100, 3, 1, 2), // fdiv.s f3, f1, f2
+                r_type(0b1010011, 0b000, 0b1110001, 10, 3, 0), // fmv.x.d a0, f3
+                i_type(0b1110011, 0b010, 11, 0, 0x001), // frflags a1
+                i_type(0b1110011, 0b101, 0, 1, 0x002), // fsrmi 1 (rtz)
+                r_type(0b1010011, 0b111, 0b0001100, 3, 1, 2), // fdiv.s f3, f1, f2
+                r_type(0b1010011, 0b000, 0b1110000, 12, 3, 0), // fmv.x.w a2, f3
+                i_type(0b0000111, 0b011, 4, 0, 0x100), // fld f4, 0x100(zero)
+                r_type(0b1010011, 0b000, 0b0000000, 5, 4, 1), // fadd.s f5, f4, f1, rne
+                r_type(0b1010011, 0b000, 0b1110000, 13, 5, 0), // fmv.x.w a3, f5
+                i_type(0b1110011, 0b010, 14, 0, 0x003), // frcsr a4
+            ],
+            &[0x00, 0x00, 0x80, 0x3f, 0x00, 0x00, 0x40, 0x40], // 1.0f32, 3.0f32
+        );
+        assert_eq!(
+            cpu.get_generic(Generic::a0),
+            0xffff_ffff_3eaa_aaab_u64 as isize
+        );
+        assert_eq!(cpu.get_generic(Generic::a1), 0b00001);
+        assert_eq!(cpu.get_generic(Generic::a2), 0x3eaa_aaaa);
+        // f4 holds a double, so reading it as a single yields the canonical NaN.
+        assert_eq!(cpu.get_generic(Generic::a3), 0x7fc0_0000);
+        assert_eq!(cpu.get_generic(Generic::a4), 0b001_00001);
+    }
+
+    #[test]
+    fn test_float_disabled() {
+        let mut cpu = run(&[], &[]);
+        cpu.csr.mstatus &= !status::FS;
+        for bits in [
+            i_type(0b0000111, 0b010, 1, 0, 0x100), // flw f1, 0x100(zero)
+            r_type(0b1010011, 0b000, 0b1110001, 10, 3, 0), // fmv.x.d a0, f3
+            i_type(0b1110011, 0b010, 11, 0, 0x001), // frflags a1
+        ] {
+            assert!(matches!(
+                instruction_operation(riscv::decode(bits), &mut cpu, 4, bits),
+                Err(OperationError::IllegalInstruction(illegal, 0)) if illegal == bits
+            ));
+        }
+        assert_eq!(cpu.csr.mstatus & status::FS, 0);
+    }
+
+    #[test]
+    fn test_compressed_control_flow() {
+        // RV64C has no C.JAL; C.JALR is the only compressed form that links.
+        let code = [
+            c_j(6),                     // 0x00: c.j 0x06
+            c_li(10, 1),                // 0x02: c.li a0, 1
+            c_li(10, 1),                // 0x04: c.li a0, 1
+            c_branch(0b110, 10, 4),     // 0x06: c.beqz a0, 0x0a
+            c_li(11, 1),                // 0x08: c.li a1, 1
+            c_branch(0b111, 10, 4),     // 0x0a: c.bnez a0, 0x0e
+            c_li(12, 0x14),             // 0x0c: c.li a2, 0x14
+            c_jump_register(true, 12),  // 0x0e: c.jalr a2
+            c_li(13, 1),                // 0x10: c.li a3, 1
+            c_li(13, 1),                // 0x12: c.li a3, 1
+            c_li(14, 0x1a),             // 0x14: c.li a4, 0x1a
+            c_jump_register(false, 14), // 0x16: c.jr a4
+            c_li(15, 1),                // 0x18: c.li a5, 1
+            c_branch(0b111, 14, -2),    // 0x1a: c.bnez a4, 0x18
+            c_branch(0b110, 15, -0x1c), // 0x1c: c.beqz a5, 0x00
+        ];
+        let image = code.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let cpu = run_image(image, &[], 9);
+        assert_eq!(cpu.pc, 0x1a);
+        assert_eq!(cpu.get_generic(Generic::a0), 0);
+        assert_eq!(cpu.get_generic(Generic::a1), 0);
+        assert_eq!(cpu.get_generic(Generic::a3), 0);
+        assert_eq!(cpu.get_generic(Generic::a5), 1);
+        assert_eq!(cpu.get_generic(Generic::ra), 0x10);
+    }
+
+    #[test]
+    fn test_mixed_length_links() {
+        let mut image = Vec::new();
+        image.extend_from_slice(&c_li(10, 0x0c).to_le_bytes()); // 0x00: c.li a0, 0x0c
+        image.extend_from_slice(&(3 << 21 | 1 << 7 | 0b1101111u32).to_le_bytes()); // 0x02: jal ra, 0x08
+        image.extend_from_slice(&c_li(11, 1).to_le_bytes()); // 0x06: c.li a1, 1
+        image.extend_from_slice(&i_type(0b1100111, 0, 5, 10, 0).to_le_bytes()); // 0x08: jalr t0, a0
+        image.extend_from_slice(&c_li(11, 1).to_le_bytes()); // 0x0c: c.li a1, 1
+        let cpu = run_image(image, &[], 3);
+        assert_eq!(cpu.pc, 0x0c);
+        assert_eq!(cpu.get_generic(Generic::ra), 0x06);
+        assert_eq!(cpu.get_generic(Generic::t0), 0x0c);
+        assert_eq!(cpu.get_generic(Generic::a1), 0);
+    }
+}
@@ -0,0 +1,262 @@
+use super::address_space::{AddressSpace, PAGE_SIZE, Prot, page_up};
+use super::cpu::Cpu;
+use super::elf::Elf;
+use super::error::OperationError;
+use super::register::Generic;
+use super::signal;
+use std::collections::BTreeMap;
+
+/// Top of the initial stack of a user-mode process.
+pub const STACK_TOP: usize = 0x3f_ffff_f000;
+/// Size of the stack region mapped below [`STACK_TOP`].
+pub const STACK_SIZE: usize = 8 << 20;
+/// `mmap` places mappings below this address, leaving room for the stack above.
+pub const MMAP_TOP: usize = 0x20_0000_0000;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_PLATFORM: u64 = 15;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+/// Size of an ELF64 program header, for `AT_PHENT`.
+const PHENT_SIZE: u64 = 56;
+const CLOCK_TICKS: u64 = 100;
+
+/// `AT_HWCAP` bits: one per single-letter extension, as the kernel reports them.
+const fn hwcap(extensions: &[u8]) -> u64 {
+    let mut bits = 0;
+    let mut i = 0;
+    while i < extensions.len() {
+        bits |= 1 << (extensions[i] - b'A');
+        i += 1;
+    }
+    bits
+}
+const HWCAP: u64 = hwcap(b"IMAFDC");
+
+/// 16 bytes from the host's random source, for `AT_RANDOM`.
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    // SAFETY: the buffer is valid for writes of its length.
+    let filled = unsafe { libc::getrandom(bytes.as_mut_ptr().cast(), bytes.len(), 0) };
+    if filled != bytes.len() as isize {
+        // Fall back to the randomly keyed hasher std seeds from the OS.
+        use std::hash::{BuildHasher, RandomState};
+        let state = RandomState::new();
+        bytes[..8].copy_from_slice(&state.hash_one(0u8).to_le_bytes());
+        bytes[8..].copy_from_slice(&state.hash_one(1u8).to_le_bytes());
+    }
+    bytes
+}
+
+/// Map the loadable segments of `elf` at their virtual addresses and the stack below
+/// [`STACK_TOP`] into a new address space, and start the program break after the last segment.
+pub fn load_program(elf: &Elf) -> anyhow::Result<AddressSpace, OperationError> {
+    // Rights of each page; a page shared by two segments gets the rights of both.
+    let mut pages = BTreeMap::<usize, Prot>::new();
+    for segment in &elf.segments {
+        let start = segment.vaddr as usize & !(PAGE_SIZE - 1);
+        let end = (segment.vaddr.checked_add(segment.mem_size))
+            .and_then(|end| page_up(end as usize))
+            .ok_or(OperationError::StoreAddressFault(segment.vaddr as usize))?;
+        for page in (start..end).step_by(PAGE_SIZE) {
+            let prot = pages.entry(page).or_default();
+            *prot = *prot | Prot::from_segment_flags(segment.flags);
+        }
+    }
+    // Runs of contiguous pages with the same rights.
+    let mut runs: Vec<(usize, usize, Prot)> = Vec::new();
+    for (page, prot) in pages {
+        match runs.last_mut() {
+            Some((_, end, last)) if *end == page && *last == prot => *end += PAGE_SIZE,
+            _ => runs.push((page, page + PAGE_SIZE, prot)),
+        }
+    }
+
+    let mut space = AddressSpace::new(MMAP_TOP);
+    for &(start, end, _) in &runs {
+        space.map(start, end - start, Prot::READ | Prot::WRITE);
+    }
+    elf.load(&mut space, false)?;
+    for &(start, end, prot) in &runs {
+        space.protect(start, end - start, prot);
+    }
+    space.set_brk_start(runs.last().map_or(0, |&(_, end, _)| end));
+    space.map(STACK_TOP - STACK_SIZE, STACK_SIZE, Prot::READ | Prot::WRITE);
+    signal::map_trampoline(&space);
+    Ok(space)
+}
+
+/// Build the Linux initial process stack below [`STACK_TOP`] and point `sp` at it.
+///
+/// From `sp` upwards the stack holds `argc`, the `argv` and `envp` pointer arrays, each ending
+/// in a null pointer, and the auxiliary vector; the strings and the `AT_RANDOM` bytes sit above
+/// them. `args[0]` is the program name, also passed as `AT_EXECFN`.
+pub fn setup_stack(
+    cpu: &mut Cpu,
+    elf: &Elf,
+    args: &[String],
+    env: &[String],
+) -> anyhow::Result<(), OperationError> {
+    let mut sp = STACK_TOP;
+    let mut push = |cpu: &mut Cpu, data: &[u8]| -> anyhow::Result<u64, OperationError> {
+        sp -= data.len();
+        cpu.mem.write_bytes(sp, data)?;
+        Ok(sp as u64)
+    };
+    let mut push_string = |cpu: &mut Cpu, string: &str| {
+        let mut bytes = string.as_bytes().to_vec();
+        bytes.push(0);
+        push(cpu, &bytes)
+    };
+
+    let execfn = push_string(cpu, args.first().map_or("", String::as_str))?;
+    let platform = push_string(cpu, "riscv64")?;
+    let env_ptrs = env
+        .iter()
+        .map(|var| push_string(cpu, var))
+        .collect::<Result<Vec<_>, _>>()?;
+    let arg_ptrs = args
+        .iter()
+        .map(|arg| push_string(cpu, arg))
+        .collect::<Result<Vec<_>, _>>()?;
+    let random = push(cpu, &random_bytes())?;
+
+    // SAFETY: these calls only return the caller's credentials.
+    let (uid, euid, gid, egid) = unsafe {
+        (
+            libc::getuid(),
+            libc::geteuid(),
+            libc::getgid(),
+            libc::getegid(),
+        )
+    };
+    let mut auxv = vec![
+        (AT_PAGESZ, PAGE_SIZE as u64),
+        (AT_PHENT, PHENT_SIZE),
+        (AT_PHNUM, elf.phnum as u64),
+        (AT_BASE, 0),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, elf.entry),
+        (AT_UID, uid as u64),
+        (AT_EUID, euid as u64),
+        (AT_GID, gid as u64),
+        (AT_EGID, egid as u64),
+        (AT_PLATFORM, platform),
+        (AT_HWCAP, HWCAP),
+        (AT_CLKTCK, CLOCK_TICKS),
+        (AT_SECURE, 0),
+        (AT_RANDOM, random),
+        (AT_EXECFN, execfn),
+    ];
+    if let Some(phdr) = elf.phdr {
+        auxv.insert(0, (AT_PHDR, phdr));
+    }
+    auxv.push((AT_NULL, 0));
+
+    let mut words = vec![args.len() as u64];
+    words.extend(&arg_ptrs);
+    words.push(0);
+    words.extend(&env_ptrs);
+    words.push(0);
+    words.extend(auxv.iter().flat_map(|&(key, value)| [key, value]));
+
+    // `sp` must be 16-byte aligned once the vectors are in place.
+    let mut sp = (random as usize & !0xf) - words.len() * 8;
+    sp &= !0xf;
+    let data: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    cpu.mem.write_bytes(sp, &data)?;
+    cpu.set_generic(Generic::sp, sp as isize);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, BusOperation};
+    use crate::elf::tests::executable;
+    use crate::memory::Memory;
+
+    fn read_string(cpu: &Cpu, addr: u64) -> String {
+        let mut bytes = Vec::new();
+        let mut addr = addr as usize;
+        loop {
+            let byte: u8 = cpu.mem.load(addr).unwrap();
+            if byte == 0 {
+                return String::from_utf8(bytes).unwrap();
+            }
+            bytes.push(byte);
+            addr += 1;
+        }
+    }
+
+    #[test]
+    fn test_initial_stack() {
+        let elf = Elf::parse(&executable(&[0; 4], 0)).unwrap();
+        let mut cpu = Cpu::new(Memory::new(STACK_TOP - STACK_SIZE..=STACK_TOP - 1));
+        let args = ["/bin/prog".to_string(), "-v".to_string()];
+        let env = ["HOME=/root".to_string()];
+        setup_stack(&mut cpu, &elf, &args, &env).unwrap();
+
+        let sp = cpu.get_generic(Generic::sp) as usize;
+        assert_eq!(sp % 16, 0);
+        let word =
+            |index: usize| BusOperation::<u64>::load(cpu.mem.as_ref(), sp + index * 8).unwrap();
+        assert_eq!(word(0), 2);
+        assert_eq!(read_string(&cpu, word(1)), "/bin/prog");
+        assert_eq!(read_string(&cpu, word(2)), "-v");
+        assert_eq!(word(3), 0);
+        assert_eq!(read_string(&cpu, word(4)), "HOME=/root");
+        assert_eq!(word(5), 0);
+
+        let mut auxv = std::collections::HashMap::new();
+        let mut index = 6;
+        while word(index) != AT_NULL {
+            auxv.insert(word(index), word(index + 1));
+            index += 2;
+        }
+        assert_eq!(auxv[&AT_ENTRY], 0x1_0000);
+        assert_eq!(auxv[&AT_PAGESZ], PAGE_SIZE as u64);
+        assert_eq!(auxv[&AT_PHNUM], 2);
+        assert_eq!(
+            auxv[&AT_HWCAP],
+            1 << 0 | 1 << 2 | 1 << 3 | 1 << 5 | 1 << 8 | 1 << 12
+        );
+        assert_eq!(read_string(&cpu, auxv[&AT_EXECFN]), "/bin/prog");
+        assert_eq!(read_string(&cpu, auxv[&AT_PLATFORM]), "riscv64");
+        let random = auxv[&AT_RANDOM] as usize;
+        assert!(random > sp && random + 16 <= STACK_TOP);
+        assert_eq!(cpu.mem.read_bytes(random, 16).unwrap().len(), 16);
+        // The test executable does not map its program headers.
+        assert!(!auxv.contains_key(&AT_PHDR));
+    }
+
+    #[test]
+    fn test_load_program() {
+        let elf = Elf::parse(&executable(&[0x13, 0, 0, 0], 0)).unwrap();
+        let mut space = load_program(&elf).unwrap();
+        assert_eq!(space.prot(0x1_0000), Some(Prot::READ | Prot::EXEC));
+        assert_eq!(space.prot(0x1_1000), Some(Prot::READ | Prot::WRITE));
+        assert_eq!(space.fetch(0x1_0000).unwrap(), 0x13);
+        assert!(space.write_bytes(0x1_0000, &[0]).is_err());
+        let counter: u64 = space.load(0x1_1000).unwrap();
+        assert_eq!(counter, 0x1122_3344_5566_7788);
+        assert_eq!(space.current_brk(), 0x1_2000);
+        assert!(space.is_mapped(STACK_TOP - STACK_SIZE, STACK_SIZE));
+        assert_eq!(space.find_free(PAGE_SIZE), Some(MMAP_TOP - PAGE_SIZE));
+    }
+}
@@ -14,6 +14,11 @@ impl<T: Sized + Default + Copy, const N: usize> Register<T, N> {
         self.0[r.into()] = value
     }
 }
+impl<T: Sized + Default + Copy, const N: usize> Default for Register<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 crate::impl_numeric_enum! {
     u8,
@@ -55,7 +60,6 @@ crate::impl_numeric_enum! {
     ]
 }
 
-
 crate::impl_numeric_enum! {
     u8,
     #[allow(non_camel_case_types)]
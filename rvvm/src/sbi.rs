@@ -0,0 +1,444 @@
+use super::cpu::Cpu;
+use super::csr::{Privilege, interrupt, status};
+use super::interrupt::Timer;
+use super::logger::DEVICES;
+use super::register::Generic;
+use super::trap::Exception;
+use std::io::{Read, Write};
+
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x5449_4d45;
+const EXT_IPI: u64 = 0x73_5049;
+const EXT_RFENCE: u64 = 0x5246_4e43;
+const EXT_HSM: u64 = 0x48_534d;
+const EXT_SRST: u64 = 0x5352_5354;
+const EXT_DBCN: u64 = 0x4442_434e;
+const EXTENSIONS: [u64; 7] = [
+    EXT_BASE, EXT_TIME, EXT_IPI, EXT_RFENCE, EXT_HSM, EXT_SRST, EXT_DBCN,
+];
+
+/// SBI specification version 2.0.
+const SPEC_VERSION: i64 = 2 << 24;
+/// Implementation ID reported by `sbi_get_impl_id`, outside the range of registered IDs.
+const IMPL_ID: i64 = 0x5256_564d;
+
+const SUCCESS: i64 = 0;
+const ERR_NOT_SUPPORTED: i64 = -2;
+const ERR_INVALID_PARAM: i64 = -3;
+const ERR_ALREADY_AVAILABLE: i64 = -6;
+
+const HSM_STARTED: i64 = 0;
+const HSM_SUSPEND_RETENTIVE: u64 = 0;
+
+/// Beyond this many pages a ranged `sfence.vma` flushes the whole TLB instead.
+const RFENCE_PAGE_LIMIT: u64 = 64;
+/// Most bytes a debug console call transfers; the caller retries with the rest.
+const CONSOLE_CHUNK: usize = 4096;
+
+/// Exceptions handed straight to S-mode, as OpenSBI does.
+const DELEGATED_EXCEPTIONS: [Exception; 12] = [
+    Exception::InstructionMisaligned,
+    Exception::InstructionAccessFault,
+    Exception::IllegalInstruction,
+    Exception::Breakpoint,
+    Exception::LoadMisaligned,
+    Exception::LoadAccessFault,
+    Exception::StoreMisaligned,
+    Exception::StoreAccessFault,
+    Exception::UserEcall,
+    Exception::InstructionPageFault,
+    Exception::LoadPageFault,
+    Exception::StorePageFault,
+];
+
+/// A system reset requested through the SRST extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemReset {
+    /// 0 for shutdown, 1 for a cold reboot, 2 for a warm reboot.
+    pub kind: u32,
+    /// 0 for no reason, 1 for a system failure.
+    pub reason: u32,
+}
+
+/// Built-in M-mode firmware implementing the SBI for an S-mode guest.
+///
+/// The hart traps `ecall` from S-mode into [`Sbi`] instead of the guest's M-mode vector, so no
+/// firmware image is needed. The debug console reads and writes the host side of the console.
+pub struct Sbi {
+    console: Box<dyn Write + Send>,
+    input: Box<dyn Read + Send>,
+    reset: Option<SystemReset>,
+}
+
+type SbiResult = (i64, i64);
+
+impl Sbi {
+    pub fn new(console: Box<dyn Write + Send>, input: Box<dyn Read + Send>) -> Self {
+        Self {
+            console,
+            input,
+            reset: None,
+        }
+    }
+
+    /// The reset the guest requested, if it has asked to shut down or reboot.
+    pub fn reset(&self) -> Option<SystemReset> {
+        self.reset
+    }
+
+    fn call(&mut self, cpu: &mut Cpu, eid: u64, fid: u64, args: [u64; 6]) -> SbiResult {
+        match eid {
+            EXT_BASE => base(fid, args),
+            EXT_TIME if fid == 0 => {
+                cpu.csr.irq.set_timer(Timer::Supervisor, args[0]);
+                (SUCCESS, 0)
+            }
+            EXT_IPI if fid == 0 => match targets(cpu, args[0], args[1]) {
+                Ok(true) => {
+                    cpu.csr.mip |= interrupt::SSIP;
+                    (SUCCESS, 0)
+                }
+                Ok(false) => (SUCCESS, 0),
+                Err(err) => (err, 0),
+            },
+            EXT_RFENCE => rfence(cpu, fid, args),
+            EXT_HSM => hsm(cpu, fid, args),
+            EXT_SRST if fid == 0 => match (u32::try_from(args[0]), u32::try_from(args[1])) {
+                (Ok(kind @ 0..=2), Ok(reason)) => {
+                    self.reset = Some(SystemReset { kind, reason });
+                    cpu.running = false;
+                    (SUCCESS, 0)
+                }
+                _ => (ERR_INVALID_PARAM, 0),
+            },
+            EXT_DBCN => self.console(cpu, fid, args),
+            _ => {
+                log::debug!(target: DEVICES, "unsupported SBI call {eid:#x}/{fid}");
+                (ERR_NOT_SUPPORTED, 0)
+            }
+        }
+    }
+
+    fn console(&mut self, cpu: &mut Cpu, fid: u64, args: [u64; 6]) -> SbiResult {
+        let (len, addr) = (args[0] as usize, args[1] as usize);
+        if matches!(fid, 0 | 1) && addr.checked_add(len).is_none() {
+            return (ERR_INVALID_PARAM, 0);
+        }
+        let len = len.min(CONSOLE_CHUNK);
+        match fid {
+            0 => {
+                let Ok(data) = cpu.mem.read_bytes(addr, len) else {
+                    return (ERR_INVALID_PARAM, 0);
+                };
+                match self
+                    .console
+                    .write_all(&data)
+                    .and_then(|_| self.console.flush())
+                {
+                    Ok(()) => (SUCCESS, len as i64),
+                    Err(_) => (-1, 0),
+                }
+            }
+            1 => {
+                let mut data = vec![0; len];
+                let read = self.input.read(&mut data).unwrap_or(0);
+                match cpu.mem.write_bytes(addr, &data[..read]) {
+                    Ok(()) => (SUCCESS, read as i64),
+                    Err(_) => (ERR_INVALID_PARAM, 0),
+                }
+            }
+            2 => match self.console.write_all(&[args[0] as u8]) {
+                Ok(()) => (SUCCESS, 0),
+                Err(_) => (-1, 0),
+            },
+            _ => (ERR_NOT_SUPPORTED, 0),
+        }
+    }
+}
+
+fn base(fid: u64, args: [u64; 6]) -> SbiResult {
+    let version = |part: &str| part.parse::<i64>().unwrap_or(0);
+    match fid {
+        0 => (SUCCESS, SPEC_VERSION),
+        1 => (SUCCESS, IMPL_ID),
+        2 => (
+            SUCCESS,
+            version(env!("CARGO_PKG_VERSION_MAJOR")) << 16
+                | version(env!("CARGO_PKG_VERSION_MINOR")),
+        ),
+        3 => (SUCCESS, EXTENSIONS.contains(&args[0]) as i64),
+        // mvendorid, marchid and mimpid.
+        4..=6 => (SUCCESS, 0),
+        _ => (ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+/// Whether the hart mask selects this hart, rejecting masks that name harts that do not exist.
+fn targets(cpu: &Cpu, mask: u64, base: u64) -> Result<bool, i64> {
+    if base == u64::MAX {
+        return Ok(true);
+    }
+    let hartid = cpu.csr.mhartid;
+    let own = match hartid.checked_sub(base) {
+        Some(bit @ 0..64) => 1 << bit,
+        _ => 0,
+    };
+    match mask & !own {
+        0 => Ok(mask & own != 0),
+        _ => Err(ERR_INVALID_PARAM),
+    }
+}
+
+fn rfence(cpu: &mut Cpu, fid: u64, args: [u64; 6]) -> SbiResult {
+    match targets(cpu, args[0], args[1]) {
+        Ok(true) => {}
+        Ok(false) => return (SUCCESS, 0),
+        Err(err) => return (err, 0),
+    }
+    let (start, size) = (args[2], args[3]);
+    let asid = match fid {
+        // fence.i: there is no instruction cache to synchronise.
+        0 => return (SUCCESS, 0),
+        1 => None,
+        2 => Some(args[4] as u16),
+        _ => return (ERR_NOT_SUPPORTED, 0),
+    };
+    let pages = size.div_ceil(4096);
+    let wraps = start
+        .checked_add(pages.min(RFENCE_PAGE_LIMIT) * 4096)
+        .is_none();
+    if size == u64::MAX || pages > RFENCE_PAGE_LIMIT || wraps || (start == 0 && size == 0) {
+        cpu.mmu.sfence_vma(None, asid);
+    } else {
+        for page in 0..pages {
+            cpu.mmu.sfence_vma(Some(start + page * 4096), asid);
+        }
+    }
+    (SUCCESS, 0)
+}
+
+fn hsm(cpu: &mut Cpu, fid: u64, args: [u64; 6]) -> SbiResult {
+    let exists = |hartid: u64| hartid == cpu.csr.mhartid;
+    match fid {
+        // hart_start: the only hart is already running.
+        0 if exists(args[0]) => (ERR_ALREADY_AVAILABLE, 0),
+        0 => (ERR_INVALID_PARAM, 0),
+        1 => {
+            cpu.running = false;
+            (SUCCESS, 0)
+        }
+        2 if exists(args[0]) => (SUCCESS, HSM_STARTED),
+        2 => (ERR_INVALID_PARAM, 0),
+        3 if args[0] == HSM_SUSPEND_RETENTIVE => {
+            cpu.wait_for_interrupt();
+            (SUCCESS, 0)
+        }
+        3 => (ERR_NOT_SUPPORTED, 0),
+        _ => (ERR_NOT_SUPPORTED, 0),
+    }
+}
+
+/// Service an `ecall` from S-mode: the extension is in `a7`, the function in `a6` and the
+/// arguments in `a0`-`a5`. The error and value come back in `a0` and `a1`.
+pub(crate) fn ecall(cpu: &mut Cpu, sbi: &mut Sbi) {
+    const ARGS: [Generic; 6] = [
+        Generic::a0,
+        Generic::a1,
+        Generic::a2,
+        Generic::a3,
+        Generic::a4,
+        Generic::a5,
+    ];
+    let eid = cpu.get_generic(Generic::a7) as u64;
+    let fid = cpu.get_generic(Generic::a6) as u64;
+    let args = ARGS.map(|reg| cpu.get_generic(reg) as u64);
+    let (error, value) = sbi.call(cpu, eid, fid, args);
+    cpu.set_generic(Generic::a0, error as isize);
+    cpu.set_generic(Generic::a1, value as isize);
+    cpu.pc += 4;
+}
+
+/// Act as firmware handing over to an S-mode kernel at `entry`: delegate the exceptions and
+/// interrupts S-mode handles itself, expose the counters, install `sbi` and drop to S-mode.
+pub fn enter_supervisor(cpu: &mut Cpu, entry: usize, sbi: Sbi) {
+    cpu.csr.medeleg = DELEGATED_EXCEPTIONS
+        .iter()
+        .fold(0, |mask, &exception| mask | 1 << exception as u64);
+    cpu.csr.mideleg = interrupt::SUPERVISOR;
+    cpu.csr.mcounteren = 0b111;
+    cpu.csr.mepc = entry as u64;
+    cpu.csr.mstatus = cpu.csr.mstatus & !status::MPP | (Privilege::Supervisor as u64) << 11;
+    cpu.sbi = Some(sbi);
+    cpu.mret();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use crate::devices::MmioDevice;
+    use crate::machine::{Machine, MachineConfig, RAM_BASE};
+    use std::sync::{Arc, Mutex};
+
+    const ECALL: u32 = 0x0000_0073;
+
+    #[derive(Clone, Default)]
+    struct Sink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn machine() -> (Machine, Sink) {
+        let sink = Sink::default();
+        let config = MachineConfig {
+            ram_size: 0x10_0000,
+            clock: Clock::instructions(),
+            ..Default::default()
+        };
+        let mut machine = Machine::new(config, Box::new(sink.clone())).unwrap();
+        machine.load(RAM_BASE, &ECALL.to_le_bytes()).unwrap();
+        machine.boot_kernel(RAM_BASE);
+        (machine, sink)
+    }
+
+    fn call(machine: &mut Machine, eid: u64, fid: u64, args: &[u64]) -> (i64, i64) {
+        let cpu = &mut machine.cpu;
+        cpu.set_pc(RAM_BASE as isize);
+        cpu.set_generic(Generic::a7, eid as isize);
+        cpu.set_generic(Generic::a6, fid as isize);
+        for (i, &arg) in args.iter().enumerate() {
+            cpu.set_generic(Generic::from(10 + i), arg as isize);
+        }
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc as usize, RAM_BASE + 4);
+        (
+            cpu.get_generic(Generic::a0) as i64,
+            cpu.get_generic(Generic::a1) as i64,
+        )
+    }
+
+    #[test]
+    fn test_handover() {
+        let (machine, _) = machine();
+        let cpu = &machine.cpu;
+        assert_eq!(cpu.privilege(), Privilege::Supervisor);
+        assert_eq!(cpu.pc as usize, RAM_BASE);
+        assert_eq!(cpu.get_generic(Generic::a1) as usize, machine.dtb_address());
+        assert_eq!(cpu.csr.mideleg, interrupt::SUPERVISOR);
+        assert_eq!(cpu.csr.medeleg & 1 << Exception::SupervisorEcall as u64, 0);
+        assert_ne!(cpu.csr.medeleg & 1 << Exception::LoadPageFault as u64, 0);
+    }
+
+    #[test]
+    fn test_base() {
+        let (mut machine, _) = machine();
+        assert_eq!(call(&mut machine, EXT_BASE, 0, &[]), (SUCCESS, 2 << 24));
+        assert_eq!(call(&mut machine, EXT_BASE, 3, &[EXT_DBCN]), (SUCCESS, 1));
+        assert_eq!(call(&mut machine, EXT_BASE, 3, &[0x0123]), (SUCCESS, 0));
+        assert_eq!(call(&mut machine, 0x0123, 0, &[]).0, ERR_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn test_timer_and_ipi() {
+        let (mut machine, _) = machine();
+        assert_eq!(call(&mut machine, EXT_TIME, 0, &[100]).0, SUCCESS);
+        let cpu = &mut machine.cpu;
+        assert_eq!(cpu.csr.irq.timer(Timer::Supervisor), 100);
+        cpu.csr.clock.set(100);
+        cpu.csr.irq.poll_timers(&cpu.csr.clock);
+        assert_eq!(cpu.csr.pending_interrupts(), interrupt::STIP);
+        assert_eq!(call(&mut machine, EXT_TIME, 0, &[u64::MAX]).0, SUCCESS);
+        assert_eq!(machine.cpu.csr.pending_interrupts(), 0);
+
+        assert_eq!(
+            call(&mut machine, EXT_IPI, 0, &[0b10, 0]).0,
+            ERR_INVALID_PARAM
+        );
+        assert_eq!(machine.cpu.csr.pending_interrupts(), 0);
+        assert_eq!(call(&mut machine, EXT_IPI, 0, &[0b1, 0]).0, SUCCESS);
+        assert_eq!(machine.cpu.csr.pending_interrupts(), interrupt::SSIP);
+    }
+
+    #[test]
+    fn test_rfence_and_hsm() {
+        let (mut machine, _) = machine();
+        let all = [0, u64::MAX, 0, u64::MAX];
+        assert_eq!(call(&mut machine, EXT_RFENCE, 0, &all).0, SUCCESS);
+        assert_eq!(call(&mut machine, EXT_RFENCE, 1, &all).0, SUCCESS);
+        assert_eq!(
+            call(&mut machine, EXT_RFENCE, 2, &[1, 0, 0x1000, 0x2000, 7]).0,
+            SUCCESS
+        );
+        // A range wrapping past the top of the address space flushes everything.
+        assert_eq!(
+            call(
+                &mut machine,
+                EXT_RFENCE,
+                1,
+                &[1, 0, u64::MAX - 0xfff, 0x2000]
+            )
+            .0,
+            SUCCESS
+        );
+        assert_eq!(call(&mut machine, EXT_RFENCE, 3, &all).0, ERR_NOT_SUPPORTED);
+
+        assert_eq!(call(&mut machine, EXT_HSM, 2, &[0]), (SUCCESS, HSM_STARTED));
+        assert_eq!(call(&mut machine, EXT_HSM, 2, &[1]).0, ERR_INVALID_PARAM);
+        assert_eq!(
+            call(&mut machine, EXT_HSM, 0, &[0]).0,
+            ERR_ALREADY_AVAILABLE
+        );
+        machine.cpu.running = true;
+        assert_eq!(call(&mut machine, EXT_HSM, 1, &[]).0, SUCCESS);
+        assert!(!machine.cpu.running);
+    }
+
+    #[test]
+    fn test_debug_console_and_reset() {
+        let (mut machine, sink) = machine();
+        let buffer = RAM_BASE + 0x1000;
+        machine.load(buffer, b"hello").unwrap();
+        assert_eq!(
+            call(&mut machine, EXT_DBCN, 0, &[5, buffer as u64, 0]),
+            (SUCCESS, 5)
+        );
+        assert_eq!(call(&mut machine, EXT_DBCN, 2, &[b'!' as u64]).0, SUCCESS);
+        assert_eq!(sink.0.lock().unwrap().as_slice(), b"hello!");
+        assert_eq!(
+            call(&mut machine, EXT_DBCN, 0, &[5, 0x1000, 0]).0,
+            ERR_INVALID_PARAM
+        );
+        for fid in [0, 1] {
+            assert_eq!(
+                call(&mut machine, EXT_DBCN, fid, &[u64::MAX, buffer as u64, 0]).0,
+                ERR_INVALID_PARAM
+            );
+        }
+
+        // Enable the receive FIFO so the UART holds more than one byte.
+        machine.uart.write(2, 1, 1).unwrap();
+        machine.uart.receive(b"ab");
+        assert_eq!(
+            call(&mut machine, EXT_DBCN, 1, &[16, buffer as u64, 0]),
+            (SUCCESS, 2)
+        );
+        assert_eq!(machine.cpu.mem.read_bytes(buffer, 2).unwrap(), b"ab");
+
+        machine.cpu.running = true;
+        assert_eq!(
+            call(&mut machine, EXT_SRST, 0, &[3, 0]).0,
+            ERR_INVALID_PARAM
+        );
+        assert_eq!(call(&mut machine, EXT_SRST, 0, &[0, 1]).0, SUCCESS);
+        assert!(!machine.cpu.running);
+        let sbi = machine.cpu.sbi.as_ref().unwrap();
+        assert_eq!(sbi.reset(), Some(SystemReset { kind: 0, reason: 1 }));
+    }
+}
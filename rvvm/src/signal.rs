@@ -0,0 +1,531 @@
+use super::address_space::{AddressSpace, PAGE_SIZE, Prot};
+use super::cpu::Cpu;
+use super::error::OperationError;
+use super::logger::SYSCALL;
+use super::process::STACK_TOP;
+use super::register::{Float, Generic};
+use super::thread::Waiter;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Number of signals, numbered from 1.
+pub const NSIG: usize = 64;
+
+pub const SIGILL: i32 = 4;
+pub const SIGTRAP: i32 = 5;
+pub const SIGBUS: i32 = 7;
+pub const SIGKILL: i32 = 9;
+pub const SIGSEGV: i32 = 11;
+pub const SIGCHLD: i32 = 17;
+pub const SIGCONT: i32 = 18;
+pub const SIGSTOP: i32 = 19;
+pub const SIGTSTP: i32 = 20;
+pub const SIGTTIN: i32 = 21;
+pub const SIGTTOU: i32 = 22;
+pub const SIGURG: i32 = 23;
+pub const SIGWINCH: i32 = 28;
+
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+pub const SA_ONSTACK: u64 = 0x0800_0000;
+pub const SA_NODEFER: u64 = 0x4000_0000;
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+pub const SS_ONSTACK: u32 = 1;
+pub const SS_DISABLE: u32 = 2;
+
+/// `si_code` values.
+const SI_USER: i32 = 0;
+const SI_TKILL: i32 = -6;
+const SEGV_MAPERR: i32 = 1;
+const SEGV_ACCERR: i32 = 2;
+const ILL_ILLOPC: i32 = 1;
+const BUS_ADRALN: i32 = 1;
+const TRAP_BRKPT: i32 = 1;
+
+/// Size of `siginfo_t`.
+const SIGINFO_SIZE: usize = 128;
+/// Offsets into `struct ucontext` on riscv64; `uc_mcontext` holds `pc` and `x1`-`x31`, then
+/// the 32 double-precision registers and `fcsr`.
+const UC_STACK: usize = 16;
+const UC_SIGMASK: usize = 40;
+const UC_MCONTEXT: usize = 176;
+const MC_FPREGS: usize = UC_MCONTEXT + 32 * 8;
+const MC_FCSR: usize = MC_FPREGS + 32 * 8;
+const UCONTEXT_SIZE: usize = UC_MCONTEXT + 32 * 8 + 528;
+/// `struct rt_sigframe`: the `siginfo_t` followed by the `ucontext`.
+const FRAME_SIZE: usize = SIGINFO_SIZE + UCONTEXT_SIZE;
+
+/// Page just above the stack holding the code signal handlers return through, in place of
+/// the vDSO's `__vdso_rt_sigreturn`: `li a7, 139; ecall`.
+pub const SIGRETURN_TRAMPOLINE: usize = STACK_TOP;
+const TRAMPOLINE_CODE: [u32; 2] = [0x08b0_0893, 0x0000_0073];
+
+/// Mask bit of `sig`.
+pub const fn sigmask(sig: i32) -> u64 {
+    1 << (sig - 1)
+}
+
+/// Signals that can be neither caught, blocked nor ignored.
+pub const UNBLOCKABLE: u64 = sigmask(SIGKILL) | sigmask(SIGSTOP);
+
+pub fn valid(sig: i32) -> bool {
+    (1..=NSIG as i32).contains(&sig)
+}
+
+/// Map the sigreturn trampoline into `space`.
+pub fn map_trampoline(space: &AddressSpace) {
+    let code: Vec<u8> = TRAMPOLINE_CODE
+        .iter()
+        .flat_map(|op| op.to_le_bytes())
+        .collect();
+    space.map_with(
+        SIGRETURN_TRAMPOLINE,
+        PAGE_SIZE,
+        Prot::READ | Prot::EXEC,
+        &code,
+    );
+}
+
+/// A signal disposition, laid out as the riscv64 kernel's `struct sigaction`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SigAction {
+    pub handler: u64,
+    pub flags: u64,
+    pub mask: u64,
+}
+
+impl SigAction {
+    pub const SIZE: usize = 24;
+
+    pub fn from_bytes(raw: &[u8]) -> Self {
+        let word = |n: usize| u64::from_le_bytes(raw[n * 8..n * 8 + 8].try_into().unwrap());
+        Self {
+            handler: word(0),
+            flags: word(1),
+            mask: word(2),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut raw = [0; Self::SIZE];
+        for (n, word) in [self.handler, self.flags, self.mask].iter().enumerate() {
+            raw[n * 8..n * 8 + 8].copy_from_slice(&word.to_le_bytes());
+        }
+        raw
+    }
+}
+
+/// What a signal does when its handler is `SIG_DFL`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DefaultAction {
+    Terminate,
+    Ignore,
+}
+
+fn default_action(sig: i32) -> DefaultAction {
+    // Stopping is not supported, so the stop signals are ignored like `SIGCONT`.
+    match sig {
+        SIGCHLD | SIGCONT | SIGURG | SIGWINCH | SIGSTOP | SIGTSTP | SIGTTIN | SIGTTOU => {
+            DefaultAction::Ignore
+        }
+        _ => DefaultAction::Terminate,
+    }
+}
+
+/// Exit status of a process killed by `sig`, as a shell reports it.
+pub fn kill_status(sig: i32) -> i32 {
+    128 + sig
+}
+
+/// An alternate signal stack from `sigaltstack`, laid out as `stack_t`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AltStack {
+    pub sp: u64,
+    pub flags: u32,
+    pub size: u64,
+}
+
+impl AltStack {
+    pub const SIZE: usize = 24;
+
+    pub fn from_bytes(raw: &[u8]) -> Self {
+        Self {
+            sp: u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+            flags: u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+            size: u64::from_le_bytes(raw[16..24].try_into().unwrap()),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut raw = [0; Self::SIZE];
+        raw[0..8].copy_from_slice(&self.sp.to_le_bytes());
+        raw[8..12].copy_from_slice(&self.flags.to_le_bytes());
+        raw[16..24].copy_from_slice(&self.size.to_le_bytes());
+        raw
+    }
+
+    pub fn contains(&self, sp: u64) -> bool {
+        self.flags & SS_DISABLE == 0 && sp > self.sp && sp - self.sp <= self.size
+    }
+}
+
+impl Default for AltStack {
+    fn default() -> Self {
+        Self {
+            sp: 0,
+            flags: SS_DISABLE,
+            size: 0,
+        }
+    }
+}
+
+/// Signal state shared by the threads of a process: dispositions, signals sent to the whole
+/// process and the pending set of each thread, for `tgkill` to reach.
+#[derive(Debug)]
+pub struct Signals {
+    actions: Mutex<[SigAction; NSIG]>,
+    pending: AtomicU64,
+    threads: Mutex<HashMap<i32, Arc<ThreadSignals>>>,
+}
+
+/// The part of a thread's signal state other threads reach: the signals sent to it alone and
+/// the futex wait it sleeps in, which a signal interrupts.
+#[derive(Debug, Default)]
+pub(crate) struct ThreadSignals {
+    pub(crate) pending: AtomicU64,
+    pub(crate) sleep: Mutex<Option<Arc<Waiter>>>,
+}
+
+impl ThreadSignals {
+    fn interrupt(&self) {
+        if let Some(waiter) = &*self.sleep.lock().unwrap() {
+            waiter.interrupt();
+        }
+    }
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self {
+            actions: Mutex::new([SigAction::default(); NSIG]),
+            pending: AtomicU64::new(0),
+            threads: Mutex::default(),
+        }
+    }
+}
+
+impl Signals {
+    pub fn action(&self, sig: i32) -> SigAction {
+        self.actions.lock().unwrap()[sig as usize - 1]
+    }
+
+    /// Install `action` for `sig`, returning the previous one. Signals left pending that are
+    /// now ignored are discarded.
+    pub fn set_action(&self, sig: i32, action: SigAction) -> SigAction {
+        let old = std::mem::replace(&mut self.actions.lock().unwrap()[sig as usize - 1], action);
+        if self.ignored(sig) {
+            self.pending.fetch_and(!sigmask(sig), Ordering::AcqRel);
+            for thread in self.threads.lock().unwrap().values() {
+                thread.pending.fetch_and(!sigmask(sig), Ordering::AcqRel);
+            }
+        }
+        old
+    }
+
+    fn ignored(&self, sig: i32) -> bool {
+        match self.action(sig).handler {
+            SIG_IGN => true,
+            SIG_DFL => default_action(sig) == DefaultAction::Ignore,
+            _ => false,
+        }
+    }
+
+    /// Queue `sig` for whichever thread of the process does not block it. Sleeping threads
+    /// are interrupted; those that block it go back to sleep.
+    pub fn send(&self, sig: i32) {
+        if !self.ignored(sig) {
+            self.pending.fetch_or(sigmask(sig), Ordering::AcqRel);
+            for thread in self.threads.lock().unwrap().values() {
+                thread.interrupt();
+            }
+        }
+    }
+
+    /// Queue `sig` for thread `tid`, interrupting its futex wait, returning `false` if there is
+    /// no such thread.
+    pub fn send_to(&self, tid: i32, sig: i32) -> bool {
+        let Some(thread) = self.threads.lock().unwrap().get(&tid).cloned() else {
+            return false;
+        };
+        if !self.ignored(sig) {
+            thread.pending.fetch_or(sigmask(sig), Ordering::AcqRel);
+            thread.interrupt();
+        }
+        true
+    }
+
+    pub fn has_thread(&self, tid: i32) -> bool {
+        self.threads.lock().unwrap().contains_key(&tid)
+    }
+
+    pub(crate) fn register(&self, tid: i32, thread: Arc<ThreadSignals>) {
+        self.threads.lock().unwrap().insert(tid, thread);
+    }
+
+    pub(crate) fn unregister(&self, tid: i32) {
+        self.threads.lock().unwrap().remove(&tid);
+    }
+
+    #[inline]
+    pub(crate) fn process_pending(&self) -> u64 {
+        self.pending.load(Ordering::Acquire)
+    }
+
+    /// Claim `sig` from the process-wide pending set, unless another thread took it first.
+    pub(crate) fn claim(&self, sig: i32) -> bool {
+        self.pending.fetch_and(!sigmask(sig), Ordering::AcqRel) & sigmask(sig) != 0
+    }
+}
+
+/// Why a signal is being delivered, for the `siginfo_t` the handler receives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigInfo {
+    /// Sent by `kill` (`thread` false) or `tgkill`/`tkill` from process `pid`.
+    Sent { pid: i32, thread: bool },
+    /// Raised by the faulting access or instruction at `addr`.
+    Fault { code: i32, addr: u64 },
+}
+
+impl SigInfo {
+    fn to_bytes(self, sig: i32) -> [u8; SIGINFO_SIZE] {
+        let mut raw = [0; SIGINFO_SIZE];
+        raw[0..4].copy_from_slice(&sig.to_le_bytes());
+        match self {
+            SigInfo::Sent { pid, thread } => {
+                let code = if thread { SI_TKILL } else { SI_USER };
+                raw[8..12].copy_from_slice(&code.to_le_bytes());
+                raw[16..20].copy_from_slice(&pid.to_le_bytes());
+                // SAFETY: getuid cannot fail.
+                raw[20..24].copy_from_slice(&unsafe { libc::getuid() }.to_le_bytes());
+            }
+            SigInfo::Fault { code, addr } => {
+                raw[8..12].copy_from_slice(&code.to_le_bytes());
+                raw[16..24].copy_from_slice(&addr.to_le_bytes());
+            }
+        }
+        raw
+    }
+}
+
+/// The signal a user-mode exception raises, as Linux would send it, if any.
+pub fn fault_signal(cpu: &mut Cpu, err: &OperationError) -> Option<(i32, SigInfo)> {
+    let (sig, code, addr) = match *err {
+        OperationError::LoadAddressFault(addr)
+        | OperationError::StoreAddressFault(addr)
+        | OperationError::LoadPageFault(addr)
+        | OperationError::StorePageFault(addr)
+        | OperationError::InstructionAccessFault(addr)
+        | OperationError::InstructionPageFault(addr) => {
+            // A mapped page that refused the access is a protection fault.
+            let mapped = cpu
+                .mem
+                .address_space()
+                .is_some_and(|space| space.prot(addr).is_some());
+            let code = if mapped { SEGV_ACCERR } else { SEGV_MAPERR };
+            (SIGSEGV, code, addr)
+        }
+        OperationError::LoadMisaligned(addr) | OperationError::StoreMisaligned(addr) => {
+            (SIGBUS, BUS_ADRALN, addr)
+        }
+        OperationError::IllegalInstruction(_, pc) => (SIGILL, ILL_ILLOPC, pc),
+        OperationError::Breakpoint(pc) => (SIGTRAP, TRAP_BRKPT, pc),
+        _ => return None,
+    };
+    Some((
+        sig,
+        SigInfo::Fault {
+            code,
+            addr: addr as u64,
+        },
+    ))
+}
+
+/// Deliver the exception `sig` to its handler. A fault that is blocked or has no handler
+/// cannot be ignored and kills the process, in which case `false` is returned.
+pub fn deliver_fault(cpu: &mut Cpu, sig: i32, info: SigInfo) -> bool {
+    let action = cpu.thread.group.signals.action(sig);
+    let blocked = cpu.thread.blocked & sigmask(sig) != 0;
+    if blocked || action.handler == SIG_DFL || action.handler == SIG_IGN {
+        cpu.thread.group.exit_group(kill_status(sig));
+        return false;
+    }
+    deliver(cpu, sig, info)
+}
+
+/// Deliver the lowest-numbered signal pending for the thread that it does not block.
+pub fn deliver_pending(cpu: &mut Cpu) {
+    let Some((sig, thread)) = cpu.thread.take_pending() else {
+        return;
+    };
+    let info = SigInfo::Sent {
+        pid: cpu.thread.group.pid(),
+        thread,
+    };
+    let action = cpu.thread.group.signals.action(sig);
+    match action.handler {
+        SIG_IGN => {}
+        SIG_DFL if sig == SIGKILL || default_action(sig) == DefaultAction::Terminate => {
+            cpu.thread.group.exit_group(kill_status(sig))
+        }
+        SIG_DFL => {}
+        _ => {
+            deliver(cpu, sig, info);
+        }
+    }
+}
+
+/// Build the signal frame and enter the handler for `sig`. A frame that cannot be written
+/// kills the process with `SIGSEGV`.
+fn deliver(cpu: &mut Cpu, sig: i32, info: SigInfo) -> bool {
+    let group = cpu.thread.group.clone();
+    let action = group.signals.action(sig);
+    log::debug!(
+        target: SYSCALL,
+        "tid {}: signal {sig} ({info:?}) at {:#x}",
+        cpu.thread.tid,
+        cpu.pc
+    );
+    let sp = cpu.get_generic(Generic::sp) as u64;
+    let alt = cpu.thread.alt_stack;
+    let top = if action.flags & SA_ONSTACK != 0 && alt.flags & SS_DISABLE == 0 && !alt.contains(sp)
+    {
+        alt.sp.wrapping_add(alt.size)
+    } else {
+        sp
+    };
+    let frame = (top.wrapping_sub(FRAME_SIZE as u64) & !15) as usize;
+
+    let mut raw = vec![0; FRAME_SIZE];
+    raw[..SIGINFO_SIZE].copy_from_slice(&info.to_bytes(sig));
+    let uc = &mut raw[SIGINFO_SIZE..];
+    let stack = AltStack {
+        flags: if alt.contains(sp) {
+            SS_ONSTACK
+        } else {
+            alt.flags
+        },
+        ..alt
+    };
+    uc[UC_STACK..UC_STACK + AltStack::SIZE].copy_from_slice(&stack.to_bytes());
+    uc[UC_SIGMASK..UC_SIGMASK + 8].copy_from_slice(&cpu.thread.blocked.to_le_bytes());
+    let mut put = |offset: usize, value: u64| {
+        uc[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    };
+    put(UC_MCONTEXT, cpu.pc as u64);
+    for reg in 1..32u8 {
+        let value = cpu.get_generic(Generic::from(reg)) as u64;
+        put(UC_MCONTEXT + reg as usize * 8, value);
+    }
+    for reg in 0..32u8 {
+        let value = cpu.get_float(Float::from(reg)) as u64;
+        put(MC_FPREGS + reg as usize * 8, value);
+    }
+    uc[MC_FCSR..MC_FCSR + 4].copy_from_slice(&cpu.csr.fcsr.to_le_bytes());
+    if cpu.mem.write_bytes(frame, &raw).is_err() {
+        group.exit_group(kill_status(SIGSEGV));
+        return false;
+    }
+
+    let mut blocked = cpu.thread.blocked | action.mask;
+    if action.flags & SA_NODEFER == 0 {
+        blocked |= sigmask(sig);
+    }
+    cpu.thread.blocked = blocked & !UNBLOCKABLE;
+    if action.flags & SA_RESETHAND != 0 {
+        group.signals.set_action(sig, SigAction::default());
+    }
+    cpu.set_generic(Generic::a0, sig as isize);
+    cpu.set_generic(Generic::a1, frame as isize);
+    cpu.set_generic(Generic::a2, (frame + SIGINFO_SIZE) as isize);
+    cpu.set_generic(Generic::sp, frame as isize);
+    cpu.set_generic(Generic::ra, SIGRETURN_TRAMPOLINE as isize);
+    cpu.set_pc(action.handler as isize);
+    true
+}
+
+/// Restore the context saved in the signal frame at `sp`, as `rt_sigreturn` does. Returns
+/// `false` if the frame cannot be read.
+pub fn restore(cpu: &mut Cpu) -> bool {
+    let frame = cpu.get_generic(Generic::sp) as usize;
+    let Some(Ok(uc)) = frame
+        .checked_add(SIGINFO_SIZE)
+        .map(|uc| cpu.mem.read_bytes(uc, UCONTEXT_SIZE))
+    else {
+        return false;
+    };
+    let get = |offset: usize| u64::from_le_bytes(uc[offset..offset + 8].try_into().unwrap());
+    cpu.set_pc(get(UC_MCONTEXT) as isize);
+    for reg in 1..32u8 {
+        cpu.set_generic(
+            Generic::from(reg),
+            get(UC_MCONTEXT + reg as usize * 8) as isize,
+        );
+    }
+    for reg in 0..32u8 {
+        cpu.set_float(Float::from(reg), get(MC_FPREGS + reg as usize * 8) as isize);
+    }
+    cpu.csr.fcsr = u32::from_le_bytes(uc[MC_FCSR..MC_FCSR + 4].try_into().unwrap()) & 0xff;
+    cpu.thread.blocked = get(UC_SIGMASK) & !UNBLOCKABLE;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread::Thread;
+
+    #[test]
+    fn test_pending_and_ignored() {
+        let mut thread = Thread::main();
+        let signals = &thread.group.signals;
+        // SIGCHLD is ignored by default, so sending it does nothing.
+        signals.send(SIGCHLD);
+        assert!(!thread.has_pending());
+
+        signals.send(libc::SIGTERM);
+        assert!(signals.send_to(thread.tid, libc::SIGINT));
+        assert!(!signals.send_to(thread.tid + 1, libc::SIGINT));
+        thread.blocked = sigmask(libc::SIGINT);
+        assert_eq!(thread.take_pending(), Some((libc::SIGTERM, false)));
+        assert!(!thread.has_pending());
+        thread.blocked = 0;
+        assert_eq!(thread.take_pending(), Some((libc::SIGINT, true)));
+        assert_eq!(thread.take_pending(), None);
+
+        // Ignoring a signal discards it while pending.
+        signals.send(libc::SIGHUP);
+        let action = SigAction {
+            handler: SIG_IGN,
+            ..SigAction::default()
+        };
+        assert_eq!(
+            signals.set_action(libc::SIGHUP, action),
+            SigAction::default()
+        );
+        assert!(!thread.has_pending());
+        assert_eq!(SigAction::from_bytes(&action.to_bytes()), action);
+    }
+
+    #[test]
+    fn test_restore_unreadable_frame() {
+        let mut cpu = Cpu::new(AddressSpace::new(0x1000_0000));
+        // The frame above a stack pointer near the top of the address space wraps around.
+        for sp in [0x1000, usize::MAX - 8] {
+            cpu.set_generic(Generic::sp, sp as isize);
+            assert!(!restore(&mut cpu));
+        }
+    }
+}
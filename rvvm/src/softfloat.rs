@@ -0,0 +1,777 @@
+//! Software implementation of IEEE-754 binary32 and binary64 arithmetic following the RISC-V
+//! F and D extensions: all five rounding modes, accrued exception flags, tininess detected after
+//! rounding and canonical NaN results.
+//!
+//! Values are passed around as raw bit patterns in the low bits of a `u64`. Every operation
+//! computes the exact result (or an exact result with a sticky bit folded into its least
+//! significant bit) and then rounds once in `Format::round_pack`.
+
+/// Inexact.
+pub const FLAG_NX: u8 = 1 << 0;
+/// Underflow.
+pub const FLAG_UF: u8 = 1 << 1;
+/// Overflow.
+pub const FLAG_OF: u8 = 1 << 2;
+/// Divide by zero.
+pub const FLAG_DZ: u8 = 1 << 3;
+/// Invalid operation.
+pub const FLAG_NV: u8 = 1 << 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RoundingMode {
+    /// Round to nearest, ties to even.
+    NearestEven = 0,
+    /// Round towards zero.
+    TowardZero = 1,
+    /// Round down, towards negative infinity.
+    Down = 2,
+    /// Round up, towards positive infinity.
+    Up = 3,
+    /// Round to nearest, ties to max magnitude.
+    NearestMaxMagnitude = 4,
+}
+
+impl RoundingMode {
+    /// Decode the `rm` encoding. The dynamic mode (`0b111`) must be resolved by the caller.
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(RoundingMode::NearestEven),
+            1 => Some(RoundingMode::TowardZero),
+            2 => Some(RoundingMode::Down),
+            3 => Some(RoundingMode::Up),
+            4 => Some(RoundingMode::NearestMaxMagnitude),
+            _ => None,
+        }
+    }
+}
+
+/// An IEEE-754 binary interchange format.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Format {
+    exp_bits: u32,
+    frac_bits: u32,
+}
+
+pub const SINGLE: Format = Format {
+    exp_bits: 8,
+    frac_bits: 23,
+};
+pub const DOUBLE: Format = Format {
+    exp_bits: 11,
+    frac_bits: 52,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Class {
+    Zero,
+    /// A non-zero finite value equal to `sig * 2^exp`.
+    Finite {
+        exp: i32,
+        sig: u128,
+    },
+    Infinite,
+    Nan {
+        signaling: bool,
+    },
+}
+
+fn bit_length(value: u128) -> i32 {
+    128 - value.leading_zeros() as i32
+}
+
+/// Shift right, folding every bit shifted out into the least significant bit.
+fn shift_right_jam(value: u128, shift: i32) -> u128 {
+    if shift >= 128 {
+        (value != 0) as u128
+    } else {
+        value >> shift | (value & ((1 << shift) - 1) != 0) as u128
+    }
+}
+
+/// Drop the low `shift` bits of `sig` and round the remainder to an integer. Returns the rounded
+/// value and whether any non-zero bits were dropped.
+fn round_shift(sig: u128, shift: i32, sign: bool, rm: RoundingMode) -> (u128, bool) {
+    if shift <= 0 {
+        return (sig << -shift, false);
+    }
+    let (kept, half, rest) = match shift {
+        129.. => (0, false, sig != 0),
+        128 => (0, sig >> 127 != 0, sig << 1 != 0),
+        _ => (
+            sig >> shift,
+            (sig >> (shift - 1)) & 1 != 0,
+            sig & ((1 << (shift - 1)) - 1) != 0,
+        ),
+    };
+    let inexact = half || rest;
+    let increment = match rm {
+        RoundingMode::NearestEven => half && (rest || kept & 1 != 0),
+        RoundingMode::TowardZero => false,
+        RoundingMode::Down => inexact && sign,
+        RoundingMode::Up => inexact && !sign,
+        RoundingMode::NearestMaxMagnitude => half,
+    };
+    (kept + increment as u128, inexact)
+}
+
+/// Integer square root, rounded down.
+fn isqrt(value: u128) -> u128 {
+    let mut remainder = value;
+    let mut root = 0u128;
+    let mut bit = 1u128 << 126;
+    while bit > value {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if remainder >= root + bit {
+            remainder -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root
+}
+
+impl Format {
+    fn bias(self) -> i32 {
+        (1 << (self.exp_bits - 1)) - 1
+    }
+
+    /// Exponent of the least significant bit of subnormals and of the smallest normal numbers.
+    fn min_lsb_exp(self) -> i32 {
+        1 - self.bias() - self.frac_bits as i32
+    }
+
+    fn max_exp_field(self) -> u64 {
+        (1 << self.exp_bits) - 1
+    }
+
+    fn frac_mask(self) -> u64 {
+        (1 << self.frac_bits) - 1
+    }
+
+    pub fn sign_bit(self) -> u64 {
+        1 << (self.exp_bits + self.frac_bits)
+    }
+
+    pub fn canonical_nan(self) -> u64 {
+        self.max_exp_field() << self.frac_bits | 1 << (self.frac_bits - 1)
+    }
+
+    fn zero(self, sign: bool) -> u64 {
+        if sign { self.sign_bit() } else { 0 }
+    }
+
+    fn infinity(self, sign: bool) -> u64 {
+        self.zero(sign) | self.max_exp_field() << self.frac_bits
+    }
+
+    fn max_finite(self, sign: bool) -> u64 {
+        self.zero(sign) | (self.max_exp_field() - 1) << self.frac_bits | self.frac_mask()
+    }
+
+    fn unpack(self, bits: u64) -> (bool, Class) {
+        let sign = bits & self.sign_bit() != 0;
+        let exp = (bits >> self.frac_bits) & self.max_exp_field();
+        let frac = bits & self.frac_mask();
+        let class = if exp == self.max_exp_field() {
+            if frac == 0 {
+                Class::Infinite
+            } else {
+                Class::Nan {
+                    signaling: frac >> (self.frac_bits - 1) == 0,
+                }
+            }
+        } else if exp == 0 {
+            if frac == 0 {
+                Class::Zero
+            } else {
+                Class::Finite {
+                    exp: self.min_lsb_exp(),
+                    sig: frac as u128,
+                }
+            }
+        } else {
+            Class::Finite {
+                exp: exp as i32 - self.bias() - self.frac_bits as i32,
+                sig: (frac | 1 << self.frac_bits) as u128,
+            }
+        };
+        (sign, class)
+    }
+
+    fn is_nan(self, bits: u64) -> bool {
+        matches!(self.unpack(bits).1, Class::Nan { .. })
+    }
+
+    fn is_signaling(self, bits: u64) -> bool {
+        matches!(self.unpack(bits).1, Class::Nan { signaling: true })
+    }
+
+    /// Result of an operation with a NaN input: the canonical NaN, raising invalid if any of the
+    /// inputs is signaling.
+    fn propagate_nan(self, operands: &[u64], flags: &mut u8) -> u64 {
+        if operands.iter().any(|&bits| self.is_signaling(bits)) {
+            *flags |= FLAG_NV;
+        }
+        self.canonical_nan()
+    }
+
+    fn invalid(self, flags: &mut u8) -> u64 {
+        *flags |= FLAG_NV;
+        self.canonical_nan()
+    }
+
+    /// Round `sig * 2^exp` to this format. `sig` must be non-zero, and if the value is inexact
+    /// the caller must have folded that into the least significant bit of `sig`, with at least
+    /// two more bits of precision than the format.
+    fn round_pack(self, sign: bool, exp: i32, sig: u128, rm: RoundingMode, flags: &mut u8) -> u64 {
+        debug_assert!(sig != 0);
+        let precision = self.frac_bits as i32 + 1;
+        let shift = bit_length(sig) - precision;
+
+        // Tininess is detected after rounding, as if the exponent range were unbounded.
+        let (unbounded, _) = round_shift(sig, shift, sign, rm);
+        let carry = (unbounded >> precision != 0) as i32;
+        let tiny = exp + shift + carry < self.min_lsb_exp();
+
+        let shift = shift.max(self.min_lsb_exp() - exp);
+        let (mut kept, inexact) = round_shift(sig, shift, sign, rm);
+        let mut lsb_exp = exp + shift;
+        if kept >> precision != 0 {
+            kept >>= 1;
+            lsb_exp += 1;
+        }
+        if inexact {
+            *flags |= FLAG_NX;
+            if tiny {
+                *flags |= FLAG_UF;
+            }
+        }
+
+        if kept >> (precision - 1) == 0 {
+            // Subnormal (or zero): the exponent field is zero.
+            return self.zero(sign) | kept as u64;
+        }
+        let biased = (lsb_exp + self.frac_bits as i32 + self.bias()) as u64;
+        if biased >= self.max_exp_field() {
+            *flags |= FLAG_OF | FLAG_NX;
+            let to_infinity = match rm {
+                RoundingMode::NearestEven | RoundingMode::NearestMaxMagnitude => true,
+                RoundingMode::TowardZero => false,
+                RoundingMode::Down => sign,
+                RoundingMode::Up => !sign,
+            };
+            return if to_infinity {
+                self.infinity(sign)
+            } else {
+                self.max_finite(sign)
+            };
+        }
+        self.zero(sign) | biased << self.frac_bits | (kept as u64 & self.frac_mask())
+    }
+
+    /// Exact sum of two non-zero finite values, rounded once.
+    #[allow(clippy::too_many_arguments)]
+    fn sum(
+        self,
+        sign_a: bool,
+        exp_a: i32,
+        sig_a: u128,
+        sign_b: bool,
+        exp_b: i32,
+        sig_b: u128,
+        rm: RoundingMode,
+        flags: &mut u8,
+    ) -> u64 {
+        // Align both operands to the one with the larger magnitude, whose leading bit is moved
+        // to bit 125. Anything shifted out of the other one only matters as a sticky bit.
+        let a_larger = exp_a + bit_length(sig_a) >= exp_b + bit_length(sig_b);
+        let ((sign_1, exp_1, sig_1), (sign_2, exp_2, sig_2)) = if a_larger {
+            ((sign_a, exp_a, sig_a), (sign_b, exp_b, sig_b))
+        } else {
+            ((sign_b, exp_b, sig_b), (sign_a, exp_a, sig_a))
+        };
+        let shift = 125 - bit_length(sig_1);
+        let sig_1 = sig_1 << shift;
+        let exp = exp_1 - shift;
+        let sig_2 = if exp_2 >= exp {
+            sig_2 << (exp_2 - exp)
+        } else {
+            shift_right_jam(sig_2, exp - exp_2)
+        };
+
+        let (sign, sig) = if sign_1 == sign_2 {
+            (sign_1, sig_1 + sig_2)
+        } else if sig_1 >= sig_2 {
+            (sign_1, sig_1 - sig_2)
+        } else {
+            (sign_2, sig_2 - sig_1)
+        };
+        if sig == 0 {
+            // An exact zero sum of opposite operands is +0 except when rounding down.
+            return self.zero(rm == RoundingMode::Down);
+        }
+        self.round_pack(sign, exp, sig, rm, flags)
+    }
+
+    pub fn add(self, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        let (sign_a, class_a) = self.unpack(a);
+        let (sign_b, class_b) = self.unpack(b);
+        match (class_a, class_b) {
+            (Class::Nan { .. }, _) | (_, Class::Nan { .. }) => self.propagate_nan(&[a, b], flags),
+            (Class::Infinite, Class::Infinite) if sign_a != sign_b => self.invalid(flags),
+            (Class::Infinite, _) => a,
+            (_, Class::Infinite) => b,
+            (Class::Zero, Class::Zero) if sign_a != sign_b => self.zero(rm == RoundingMode::Down),
+            (Class::Zero, _) => b,
+            (_, Class::Zero) => a,
+            (
+                Class::Finite {
+                    exp: exp_a,
+                    sig: sig_a,
+                },
+                Class::Finite {
+                    exp: exp_b,
+                    sig: sig_b,
+                },
+            ) => self.sum(sign_a, exp_a, sig_a, sign_b, exp_b, sig_b, rm, flags),
+        }
+    }
+
+    pub fn sub(self, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        self.add(a, b ^ self.sign_bit(), rm, flags)
+    }
+
+    pub fn mul(self, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        let (sign_a, class_a) = self.unpack(a);
+        let (sign_b, class_b) = self.unpack(b);
+        let sign = sign_a != sign_b;
+        match (class_a, class_b) {
+            (Class::Nan { .. }, _) | (_, Class::Nan { .. }) => self.propagate_nan(&[a, b], flags),
+            (Class::Infinite, Class::Zero) | (Class::Zero, Class::Infinite) => self.invalid(flags),
+            (Class::Infinite, _) | (_, Class::Infinite) => self.infinity(sign),
+            (Class::Zero, _) | (_, Class::Zero) => self.zero(sign),
+            (
+                Class::Finite {
+                    exp: exp_a,
+                    sig: sig_a,
+                },
+                Class::Finite {
+                    exp: exp_b,
+                    sig: sig_b,
+                },
+            ) => self.round_pack(sign, exp_a + exp_b, sig_a * sig_b, rm, flags),
+        }
+    }
+
+    pub fn div(self, a: u64, b: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        let (sign_a, class_a) = self.unpack(a);
+        let (sign_b, class_b) = self.unpack(b);
+        let sign = sign_a != sign_b;
+        match (class_a, class_b) {
+            (Class::Nan { .. }, _) | (_, Class::Nan { .. }) => self.propagate_nan(&[a, b], flags),
+            (Class::Infinite, Class::Infinite) | (Class::Zero, Class::Zero) => self.invalid(flags),
+            (Class::Infinite, _) => self.infinity(sign),
+            (_, Class::Infinite) | (Class::Zero, _) => self.zero(sign),
+            (_, Class::Zero) => {
+                *flags |= FLAG_DZ;
+                self.infinity(sign)
+            }
+            (
+                Class::Finite {
+                    exp: exp_a,
+                    sig: sig_a,
+                },
+                Class::Finite {
+                    exp: exp_b,
+                    sig: sig_b,
+                },
+            ) => {
+                let shift = 128 - bit_length(sig_a);
+                let dividend = sig_a << shift;
+                let quotient = (dividend / sig_b) | !dividend.is_multiple_of(sig_b) as u128;
+                self.round_pack(sign, exp_a - shift - exp_b, quotient, rm, flags)
+            }
+        }
+    }
+
+    pub fn sqrt(self, a: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        match self.unpack(a) {
+            (_, Class::Nan { .. }) => self.propagate_nan(&[a], flags),
+            (_, Class::Zero) => a,
+            (true, _) => self.invalid(flags),
+            (false, Class::Infinite) => a,
+            (false, Class::Finite { exp, sig }) => {
+                // Widen the significand as far as possible while keeping the exponent even.
+                let mut shift = 126 - bit_length(sig);
+                if (exp - shift) % 2 != 0 {
+                    shift -= 1;
+                }
+                let radicand = sig << shift;
+                let root = isqrt(radicand);
+                let root = root | (root * root != radicand) as u128;
+                self.round_pack(false, (exp - shift) / 2, root, rm, flags)
+            }
+        }
+    }
+
+    /// Fused `a * b + c` with a single rounding.
+    pub fn fma(self, a: u64, b: u64, c: u64, rm: RoundingMode, flags: &mut u8) -> u64 {
+        let (sign_a, class_a) = self.unpack(a);
+        let (sign_b, class_b) = self.unpack(b);
+        let (sign_c, class_c) = self.unpack(c);
+        let sign = sign_a != sign_b;
+        // Infinity times zero is invalid even when the addend is a quiet NaN.
+        if matches!(
+            (class_a, class_b),
+            (Class::Infinite, Class::Zero) | (Class::Zero, Class::Infinite)
+        ) {
+            return self.invalid(flags);
+        }
+        if [class_a, class_b, class_c]
+            .iter()
+            .any(|class| matches!(class, Class::Nan { .. }))
+        {
+            return self.propagate_nan(&[a, b, c], flags);
+        }
+        match (class_a, class_b, class_c) {
+            (Class::Infinite, _, _) | (_, Class::Infinite, _) => {
+                if class_c == Class::Infinite && sign_c != sign {
+                    self.invalid(flags)
+                } else {
+                    self.infinity(sign)
+                }
+            }
+            (_, _, Class::Infinite) => c,
+            (Class::Zero, _, Class::Zero) | (_, Class::Zero, Class::Zero) => {
+                if sign == sign_c {
+                    c
+                } else {
+                    self.zero(rm == RoundingMode::Down)
+                }
+            }
+            (Class::Zero, _, _) | (_, Class::Zero, _) => c,
+            (
+                Class::Finite {
+                    exp: exp_a,
+                    sig: sig_a,
+                },
+                Class::Finite {
+                    exp: exp_b,
+                    sig: sig_b,
+                },
+                class_c,
+            ) => match class_c {
+                Class::Finite {
+                    exp: exp_c,
+                    sig: sig_c,
+                } => self.sum(
+                    sign,
+                    exp_a + exp_b,
+                    sig_a * sig_b,
+                    sign_c,
+                    exp_c,
+                    sig_c,
+                    rm,
+                    flags,
+                ),
+                _ => self.round_pack(sign, exp_a + exp_b, sig_a * sig_b, rm, flags),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Sort key of a non-NaN value. Signed zeros compare equal unless `order_zeros` is set.
+    fn order_key(self, bits: u64, order_zeros: bool) -> i128 {
+        let magnitude = (bits & !self.sign_bit()) as i128;
+        if bits & self.sign_bit() == 0 {
+            magnitude
+        } else if order_zeros {
+            -magnitude - 1
+        } else {
+            -magnitude
+        }
+    }
+
+    /// Quiet equality comparison: only signaling NaNs raise invalid.
+    pub fn eq(self, a: u64, b: u64, flags: &mut u8) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            if self.is_signaling(a) || self.is_signaling(b) {
+                *flags |= FLAG_NV;
+            }
+            return false;
+        }
+        self.order_key(a, false) == self.order_key(b, false)
+    }
+
+    /// Signaling less-than comparison: any NaN raises invalid.
+    pub fn lt(self, a: u64, b: u64, flags: &mut u8) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            *flags |= FLAG_NV;
+            return false;
+        }
+        self.order_key(a, false) < self.order_key(b, false)
+    }
+
+    /// Signaling less-or-equal comparison: any NaN raises invalid.
+    pub fn le(self, a: u64, b: u64, flags: &mut u8) -> bool {
+        if self.is_nan(a) || self.is_nan(b) {
+            *flags |= FLAG_NV;
+            return false;
+        }
+        self.order_key(a, false) <= self.order_key(b, false)
+    }
+
+    fn min_max(self, a: u64, b: u64, max: bool, flags: &mut u8) -> u64 {
+        if self.is_signaling(a) || self.is_signaling(b) {
+            *flags |= FLAG_NV;
+        }
+        match (self.is_nan(a), self.is_nan(b)) {
+            (true, true) => self.canonical_nan(),
+            (true, false) => b,
+            (false, true) => a,
+            (false, false) => {
+                if (self.order_key(a, true) < self.order_key(b, true)) != max {
+                    a
+                } else {
+                    b
+                }
+            }
+        }
+    }
+
+    /// IEEE 754-2019 `minimumNumber`: -0 is smaller than +0 and a single NaN input is ignored.
+    pub fn min(self, a: u64, b: u64, flags: &mut u8) -> u64 {
+        self.min_max(a, b, false, flags)
+    }
+
+    /// IEEE 754-2019 `maximumNumber`: +0 is larger than -0 and a single NaN input is ignored.
+    pub fn max(self, a: u64, b: u64, flags: &mut u8) -> u64 {
+        self.min_max(a, b, true, flags)
+    }
+
+    /// The 10-bit class mask written by `fclass`.
+    pub fn classify(self, a: u64) -> u64 {
+        let (sign, class) = self.unpack(a);
+        let bit = match (sign, class) {
+            (true, Class::Infinite) => 0,
+            (true, Class::Finite { .. }) if (a & !self.sign_bit()) >> self.frac_bits == 0 => 2,
+            (true, Class::Finite { .. }) => 1,
+            (true, Class::Zero) => 3,
+            (false, Class::Zero) => 4,
+            (false, Class::Finite { .. }) if a >> self.frac_bits == 0 => 5,
+            (false, Class::Finite { .. }) => 6,
+            (false, Class::Infinite) => 7,
+            (_, Class::Nan { signaling: true }) => 8,
+            (_, Class::Nan { signaling: false }) => 9,
+        };
+        1 << bit
+    }
+
+    /// Convert to a `width`-bit integer. Out-of-range inputs and NaNs saturate and raise invalid.
+    /// The result is returned as the two's complement bit pattern of the integer.
+    pub fn float_to_int(
+        self,
+        a: u64,
+        signed: bool,
+        width: u32,
+        rm: RoundingMode,
+        flags: &mut u8,
+    ) -> u64 {
+        let (min, max) = if signed {
+            (-(1i128 << (width - 1)), (1i128 << (width - 1)) - 1)
+        } else {
+            (0, (1i128 << width) - 1)
+        };
+        let (sign, class) = self.unpack(a);
+        let (value, inexact) = match class {
+            Class::Nan { .. } => {
+                *flags |= FLAG_NV;
+                return max as u64;
+            }
+            Class::Infinite => (if sign { min - 1 } else { max + 1 }, false),
+            Class::Zero => (0, false),
+            Class::Finite { exp, sig } => {
+                let (magnitude, inexact) = if exp + bit_length(sig) > 80 {
+                    (1u128 << 80, false)
+                } else {
+                    round_shift(sig, -exp, sign, rm)
+                };
+                let magnitude = magnitude as i128;
+                (if sign { -magnitude } else { magnitude }, inexact)
+            }
+        };
+        if value < min || value > max {
+            *flags |= FLAG_NV;
+            return if sign { min as u64 } else { max as u64 };
+        }
+        if inexact {
+            *flags |= FLAG_NX;
+        }
+        value as u64
+    }
+
+    /// Convert an integer to this format. `value` holds the integer sign- or zero-extended to 64
+    /// bits, according to `signed`.
+    pub fn int_to_float(self, value: u64, signed: bool, rm: RoundingMode, flags: &mut u8) -> u64 {
+        let (sign, magnitude) = if signed {
+            ((value as i64) < 0, (value as i64).unsigned_abs())
+        } else {
+            (false, value)
+        };
+        if magnitude == 0 {
+            return 0;
+        }
+        self.round_pack(sign, 0, magnitude as u128, rm, flags)
+    }
+
+    /// Convert a value of this format to format `to`.
+    pub fn convert(self, a: u64, to: Format, rm: RoundingMode, flags: &mut u8) -> u64 {
+        match self.unpack(a) {
+            (_, Class::Nan { signaling }) => {
+                if signaling {
+                    *flags |= FLAG_NV;
+                }
+                to.canonical_nan()
+            }
+            (sign, Class::Infinite) => to.infinity(sign),
+            (sign, Class::Zero) => to.zero(sign),
+            (sign, Class::Finite { exp, sig }) => to.round_pack(sign, exp, sig, rm, flags),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RNE: RoundingMode = RoundingMode::NearestEven;
+
+    fn f64_op(op: impl Fn(u64, u64, &mut u8) -> u64, a: f64, b: f64) -> (f64, u8) {
+        let mut flags = 0;
+        let result = op(a.to_bits(), b.to_bits(), &mut flags);
+        (f64::from_bits(result), flags)
+    }
+
+    #[test]
+    fn test_matches_host_rounding() {
+        let values = [
+            1.0,
+            -1.5,
+            0.1,
+            3.0,
+            1e308,
+            -1e-308,
+            5e-324,
+            f64::MAX,
+            f64::MIN_POSITIVE,
+            123456.789,
+            -0.0,
+        ];
+        for &a in &values {
+            for &b in &values {
+                let add = f64_op(|a, b, f| DOUBLE.add(a, b, RNE, f), a, b).0;
+                let mul = f64_op(|a, b, f| DOUBLE.mul(a, b, RNE, f), a, b).0;
+                let div = f64_op(|a, b, f| DOUBLE.div(a, b, RNE, f), a, b).0;
+                let fma =
+                    f64::from_bits(DOUBLE.fma(a.to_bits(), b.to_bits(), a.to_bits(), RNE, &mut 0));
+                assert_eq!(add.to_bits(), (a + b).to_bits(), "{a} + {b}");
+                assert_eq!(mul.to_bits(), (a * b).to_bits(), "{a} * {b}");
+                if b != 0.0 {
+                    assert_eq!(div.to_bits(), (a / b).to_bits(), "{a} / {b}");
+                }
+                assert_eq!(
+                    fma.to_bits(),
+                    a.mul_add(b, a).to_bits(),
+                    "fma({a}, {b}, {a})"
+                );
+            }
+            if a >= 0.0 {
+                let sqrt = DOUBLE.sqrt(a.to_bits(), RNE, &mut 0);
+                assert_eq!(sqrt, a.sqrt().to_bits(), "sqrt({a})");
+            }
+        }
+    }
+
+    #[test]
+    fn test_flags() {
+        assert_eq!(
+            f64_op(|a, b, f| DOUBLE.div(a, b, RNE, f), 1.0, 3.0).1,
+            FLAG_NX
+        );
+        assert_eq!(
+            f64_op(|a, b, f| DOUBLE.div(a, b, RNE, f), 1.0, 0.0).1,
+            FLAG_DZ
+        );
+        assert_eq!(
+            f64_op(|a, b, f| DOUBLE.mul(a, b, RNE, f), f64::MAX, 2.0).1,
+            FLAG_OF | FLAG_NX
+        );
+        assert_eq!(
+            f64_op(
+                |a, b, f| DOUBLE.mul(a, b, RNE, f),
+                f64::MIN_POSITIVE,
+                1.0 / 3.0
+            )
+            .1,
+            FLAG_UF | FLAG_NX
+        );
+        let (nan, flags) = f64_op(
+            |a, b, f| DOUBLE.sub(a, b, RNE, f),
+            f64::INFINITY,
+            f64::INFINITY,
+        );
+        assert_eq!(nan.to_bits(), DOUBLE.canonical_nan());
+        assert_eq!(flags, FLAG_NV);
+    }
+
+    #[test]
+    fn test_directed_rounding() {
+        let third = |rm| {
+            f32::from_bits(
+                SINGLE.div(1f32.to_bits() as u64, 3f32.to_bits() as u64, rm, &mut 0) as u32,
+            )
+        };
+        assert!(third(RoundingMode::Down) < third(RoundingMode::Up));
+        assert_eq!(third(RoundingMode::Down), third(RoundingMode::TowardZero));
+        assert_eq!(third(RoundingMode::NearestEven), 1.0 / 3.0);
+        // 2.5 is a tie: even rounds to 2, max magnitude rounds to 3.
+        let to_int = |rm| DOUBLE.float_to_int(2.5f64.to_bits(), true, 64, rm, &mut 0);
+        assert_eq!(to_int(RoundingMode::NearestEven), 2);
+        assert_eq!(to_int(RoundingMode::NearestMaxMagnitude), 3);
+        let max = SINGLE.mul(
+            f32::MAX.to_bits() as u64,
+            2f32.to_bits() as u64,
+            RoundingMode::TowardZero,
+            &mut 0,
+        );
+        assert_eq!(f32::from_bits(max as u32), f32::MAX);
+    }
+
+    #[test]
+    fn test_conversion_saturation() {
+        let mut flags = 0;
+        assert_eq!(
+            DOUBLE.float_to_int(f64::NAN.to_bits(), true, 32, RNE, &mut flags),
+            i32::MAX as u64
+        );
+        assert_eq!(flags, FLAG_NV);
+        let mut flags = 0;
+        assert_eq!(
+            DOUBLE.float_to_int((-1.0f64).to_bits(), false, 64, RNE, &mut flags),
+            0
+        );
+        assert_eq!(flags, FLAG_NV);
+        let mut flags = 0;
+        assert_eq!(
+            DOUBLE.float_to_int((-0.25f64).to_bits(), false, 64, RNE, &mut flags),
+            0
+        );
+        assert_eq!(flags, FLAG_NX);
+        let single = SINGLE.int_to_float(u64::MAX, false, RNE, &mut 0);
+        assert_eq!(f32::from_bits(single as u32), u64::MAX as f32);
+    }
+}
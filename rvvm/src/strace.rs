@@ -0,0 +1,667 @@
+use super::cpu::Cpu;
+use super::signal::{AltStack, SigAction};
+use super::syscall::Sysno;
+use std::collections::HashSet;
+use std::ffi::CStr;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Bytes of a buffer shown before it is cut short with `...`, as strace's default `-s 32`.
+const STRING_LIMIT: usize = 32;
+/// Entries of an iovec array shown.
+const IOVEC_LIMIT: usize = 8;
+const PATH_MAX: usize = 4096;
+
+/// How an argument register is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arg {
+    Int,
+    Unsigned,
+    Hex,
+    /// A number shown in hex like a pointer, except that zero is `0` rather than `NULL`.
+    Offset,
+    Fd,
+    DirFd,
+    Path,
+    /// Data passed in, whose length is in the given argument.
+    InBuf(usize),
+    /// Data filled in by the call, as long as its return value.
+    OutBuf,
+    /// An iovec array whose length is in the given argument.
+    Iovec(usize),
+    OpenFlags,
+    Mode,
+    Whence,
+    Prot,
+    MapFlags,
+    MremapFlags,
+    CloneFlags,
+    FutexOp,
+    Timespec,
+    Stat,
+    AtFlags,
+    Signal,
+    SigAction,
+    SigSet,
+    SigHow,
+    Stack,
+}
+
+use Arg::*;
+
+/// Arguments of each traced system call; those not listed show six raw arguments.
+fn signature(no: Sysno) -> &'static [Arg] {
+    match no {
+        Sysno::openat => &[DirFd, Path, OpenFlags, Mode],
+        Sysno::close => &[Fd],
+        Sysno::read => &[Fd, OutBuf, Unsigned],
+        Sysno::write => &[Fd, InBuf(2), Unsigned],
+        Sysno::readv | Sysno::writev => &[Fd, Iovec(2), Int],
+        Sysno::lseek => &[Fd, Int, Whence],
+        Sysno::fstat => &[Fd, Stat],
+        Sysno::fstatat => &[DirFd, Path, Stat, AtFlags],
+        Sysno::brk => &[Hex],
+        Sysno::mmap => &[Hex, Unsigned, Prot, MapFlags, Fd, Offset],
+        Sysno::munmap => &[Hex, Unsigned],
+        Sysno::mprotect => &[Hex, Unsigned, Prot],
+        Sysno::mremap => &[Hex, Unsigned, Unsigned, MremapFlags, Offset],
+        Sysno::clone => &[CloneFlags, Hex, Hex, Hex, Hex],
+        Sysno::clone3 => &[Hex, Unsigned],
+        Sysno::futex => &[Hex, FutexOp, Int, Timespec, Hex, Hex],
+        Sysno::set_tid_address => &[Hex],
+        Sysno::set_robust_list => &[Hex, Unsigned],
+        Sysno::rt_sigaction => &[Signal, SigAction, SigAction, Unsigned],
+        Sysno::rt_sigprocmask => &[SigHow, SigSet, SigSet, Unsigned],
+        Sysno::sigaltstack => &[Stack, Stack],
+        Sysno::kill | Sysno::tkill => &[Int, Signal],
+        Sysno::tgkill => &[Int, Int, Signal],
+        Sysno::exit | Sysno::exit_group => &[Int],
+        Sysno::rt_sigreturn | Sysno::gettid | Sysno::getpid => &[],
+        _ => &[Hex; 6],
+    }
+}
+
+/// Open flags as the riscv64 ABI numbers them; the access mode is shown separately.
+const OPEN_FLAGS: &[(u64, &str)] = &[
+    (0o100, "O_CREAT"),
+    (0o200, "O_EXCL"),
+    (0o400, "O_NOCTTY"),
+    (0o1000, "O_TRUNC"),
+    (0o2000, "O_APPEND"),
+    (0o4000, "O_NONBLOCK"),
+    (0o4010000, "O_SYNC"),
+    (0o10000, "O_DSYNC"),
+    (0o20000, "O_ASYNC"),
+    (0o200000, "O_DIRECTORY"),
+    (0o400000, "O_NOFOLLOW"),
+    (0o1000000, "O_NOATIME"),
+    (0o2000000, "O_CLOEXEC"),
+];
+const PROT_FLAGS: &[(u64, &str)] = &[(1, "PROT_READ"), (2, "PROT_WRITE"), (4, "PROT_EXEC")];
+const MAP_FLAGS: &[(u64, &str)] = &[
+    (0x01, "MAP_SHARED"),
+    (0x02, "MAP_PRIVATE"),
+    (0x10, "MAP_FIXED"),
+    (0x20, "MAP_ANONYMOUS"),
+    (0x100, "MAP_GROWSDOWN"),
+    (0x4000, "MAP_NORESERVE"),
+    (0x8000, "MAP_POPULATE"),
+    (0x2_0000, "MAP_STACK"),
+    (0x10_0000, "MAP_FIXED_NOREPLACE"),
+];
+const MREMAP_FLAGS: &[(u64, &str)] = &[(1, "MREMAP_MAYMOVE"), (2, "MREMAP_FIXED")];
+const CLONE_FLAGS: &[(u64, &str)] = &[
+    (0x100, "CLONE_VM"),
+    (0x200, "CLONE_FS"),
+    (0x400, "CLONE_FILES"),
+    (0x800, "CLONE_SIGHAND"),
+    (0x4000, "CLONE_VFORK"),
+    (0x1_0000, "CLONE_THREAD"),
+    (0x4_0000, "CLONE_SYSVSEM"),
+    (0x8_0000, "CLONE_SETTLS"),
+    (0x10_0000, "CLONE_PARENT_SETTID"),
+    (0x20_0000, "CLONE_CHILD_CLEARTID"),
+    (0x100_0000, "CLONE_CHILD_SETTID"),
+];
+const AT_FLAGS: &[(u64, &str)] = &[
+    (0x100, "AT_SYMLINK_NOFOLLOW"),
+    (0x800, "AT_NO_AUTOMOUNT"),
+    (0x1000, "AT_EMPTY_PATH"),
+];
+const SA_FLAGS: &[(u64, &str)] = &[
+    (0x1, "SA_NOCLDSTOP"),
+    (0x2, "SA_NOCLDWAIT"),
+    (0x4, "SA_SIGINFO"),
+    (0x0800_0000, "SA_ONSTACK"),
+    (0x1000_0000, "SA_RESTART"),
+    (0x4000_0000, "SA_NODEFER"),
+    (0x8000_0000, "SA_RESETHAND"),
+];
+const SS_FLAGS: &[(u64, &str)] = &[(1, "SS_ONSTACK"), (2, "SS_DISABLE")];
+const FUTEX_OPS: &[&str] = &[
+    "FUTEX_WAIT",
+    "FUTEX_WAKE",
+    "FUTEX_FD",
+    "FUTEX_REQUEUE",
+    "FUTEX_CMP_REQUEUE",
+    "FUTEX_WAKE_OP",
+    "FUTEX_LOCK_PI",
+    "FUTEX_UNLOCK_PI",
+    "FUTEX_TRYLOCK_PI",
+    "FUTEX_WAIT_BITSET",
+    "FUTEX_WAKE_BITSET",
+];
+
+macro_rules! libc_names {
+    ($($name:ident),* $(,)?) => {
+        &[$((libc::$name, stringify!($name))),*]
+    };
+}
+
+/// Signal numbers are the same on riscv64 and every Linux host.
+const SIGNALS: &[(i32, &str)] = libc_names![
+    SIGHUP, SIGINT, SIGQUIT, SIGILL, SIGTRAP, SIGABRT, SIGBUS, SIGFPE, SIGKILL, SIGUSR1, SIGSEGV,
+    SIGUSR2, SIGPIPE, SIGALRM, SIGTERM, SIGSTKFLT, SIGCHLD, SIGCONT, SIGSTOP, SIGTSTP, SIGTTIN,
+    SIGTTOU, SIGURG, SIGXCPU, SIGXFSZ, SIGVTALRM, SIGPROF, SIGWINCH, SIGIO, SIGPWR, SIGSYS,
+];
+
+const ERRNOS: &[(i32, &str)] = libc_names![
+    EPERM,
+    ENOENT,
+    ESRCH,
+    EINTR,
+    EIO,
+    ENXIO,
+    E2BIG,
+    ENOEXEC,
+    EBADF,
+    ECHILD,
+    EAGAIN,
+    ENOMEM,
+    EACCES,
+    EFAULT,
+    ENOTBLK,
+    EBUSY,
+    EEXIST,
+    EXDEV,
+    ENODEV,
+    ENOTDIR,
+    EISDIR,
+    EINVAL,
+    ENFILE,
+    EMFILE,
+    ENOTTY,
+    ETXTBSY,
+    EFBIG,
+    ENOSPC,
+    ESPIPE,
+    EROFS,
+    EMLINK,
+    EPIPE,
+    EDOM,
+    ERANGE,
+    EDEADLK,
+    ENAMETOOLONG,
+    ENOLCK,
+    ENOSYS,
+    ENOTEMPTY,
+    ELOOP,
+    ENODATA,
+    EOVERFLOW,
+    ENOTSUP,
+    ETIMEDOUT,
+];
+
+/// Join the names of the bits set in `value`, leaving unnamed bits in hex.
+fn flags(value: u64, names: &[(u64, &str)]) -> String {
+    let mut rest = value;
+    let mut parts = Vec::new();
+    for &(bits, name) in names {
+        if bits != 0 && rest & bits == bits {
+            parts.push(name.to_string());
+            rest &= !bits;
+        }
+    }
+    if rest != 0 || parts.is_empty() {
+        parts.push(format!("{rest:#x}"));
+    }
+    parts.join("|")
+}
+
+fn signal_name(sig: i32) -> String {
+    match SIGNALS.iter().find(|&&(number, _)| number == sig) {
+        Some((_, name)) => name.to_string(),
+        // glibc keeps 32 and 33 for itself, so its SIGRTMIN is 34.
+        None if sig == 34 => "SIGRTMIN".to_string(),
+        None if (35..=64).contains(&sig) => format!("SIGRTMIN+{}", sig - 34),
+        None => sig.to_string(),
+    }
+}
+
+fn errno_name(errno: i32) -> String {
+    match ERRNOS.iter().find(|&&(number, _)| number == errno) {
+        Some((_, name)) => name.to_string(),
+        None => format!("errno {errno}"),
+    }
+}
+
+fn strerror(errno: i32) -> String {
+    let mut buf = [0 as libc::c_char; 128];
+    // SAFETY: the buffer outlives the call, which NUL-terminates what it writes.
+    match unsafe { libc::strerror_r(errno, buf.as_mut_ptr(), buf.len()) } {
+        0 => unsafe { CStr::from_ptr(buf.as_ptr()) }
+            .to_string_lossy()
+            .into_owned(),
+        _ => "Unknown error".to_string(),
+    }
+}
+
+/// Quote `bytes` as a C string, cut short after [`STRING_LIMIT`] bytes.
+fn quote(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &byte in bytes.iter().take(STRING_LIMIT) {
+        match byte {
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            b'\r' => out.push_str("\\r"),
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            0x20..0x7f => out.push(byte as char),
+            _ => {
+                let _ = write!(out, "\\x{byte:02x}");
+            }
+        }
+    }
+    out.push('"');
+    if bytes.len() > STRING_LIMIT {
+        out.push_str("...");
+    }
+    out
+}
+
+fn read(cpu: &Cpu, addr: usize, len: usize) -> Option<Vec<u8>> {
+    match len {
+        0 => Some(Vec::new()),
+        _ => cpu.mem.read_bytes(addr, len).ok(),
+    }
+}
+
+fn read_u64(cpu: &Cpu, addr: usize) -> Option<u64> {
+    read(cpu, addr, 8).map(|raw| u64::from_le_bytes(raw.try_into().unwrap()))
+}
+
+fn read_path(cpu: &Cpu, addr: usize) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    loop {
+        match read(cpu, addr + bytes.len(), 1)?[0] {
+            0 => return Some(bytes),
+            _ if bytes.len() == PATH_MAX => return Some(bytes),
+            byte => bytes.push(byte),
+        }
+    }
+}
+
+fn sigset(set: u64) -> String {
+    let names: Vec<String> = (1..=64)
+        .filter(|sig| set >> (sig - 1) & 1 != 0)
+        .map(|sig| signal_name(sig).trim_start_matches("SIG").to_string())
+        .collect();
+    format!("[{}]", names.join(" "))
+}
+
+fn file_mode(mode: u32) -> String {
+    let kind = match mode & libc::S_IFMT {
+        libc::S_IFREG => "S_IFREG",
+        libc::S_IFDIR => "S_IFDIR",
+        libc::S_IFCHR => "S_IFCHR",
+        libc::S_IFBLK => "S_IFBLK",
+        libc::S_IFIFO => "S_IFIFO",
+        libc::S_IFLNK => "S_IFLNK",
+        libc::S_IFSOCK => "S_IFSOCK",
+        _ => return format!("0{mode:o}"),
+    };
+    format!("{kind}|0{:o}", mode & 0o7777)
+}
+
+/// Show argument `n` of a call that returned `result`.
+fn argument(cpu: &Cpu, arg: Arg, args: &[usize; 7], n: usize, result: Option<isize>) -> String {
+    let value = args[n];
+    let pointer = || match value {
+        0 => "NULL".to_string(),
+        _ => format!("{value:#x}"),
+    };
+    // Pointers the guest could not read are shown as addresses.
+    let or_pointer = |shown: Option<String>| shown.unwrap_or_else(pointer);
+    match arg {
+        Int => (value as isize).to_string(),
+        Unsigned => value.to_string(),
+        Hex => pointer(),
+        Offset if value == 0 => "0".to_string(),
+        Offset => format!("{value:#x}"),
+        Fd => (value as i32).to_string(),
+        DirFd => match value as i32 {
+            -100 => "AT_FDCWD".to_string(),
+            fd => fd.to_string(),
+        },
+        Path if value == 0 => pointer(),
+        Path => or_pointer(read_path(cpu, value).map(|path| quote(&path))),
+        InBuf(len) => {
+            or_pointer(read(cpu, value, args[len].min(STRING_LIMIT + 1)).map(|data| quote(&data)))
+        }
+        OutBuf => match result {
+            Some(len) if len >= 0 => or_pointer(
+                read(cpu, value, (len as usize).min(STRING_LIMIT + 1)).map(|data| quote(&data)),
+            ),
+            _ => pointer(),
+        },
+        Iovec(count) => {
+            let entries: Option<Vec<String>> = (0..args[count].min(IOVEC_LIMIT))
+                .map(|i| {
+                    let base = read_u64(cpu, value + i * 16)? as usize;
+                    let len = read_u64(cpu, value + i * 16 + 8)? as usize;
+                    let data = read(cpu, base, len.min(STRING_LIMIT + 1))?;
+                    Some(format!("{{iov_base={}, iov_len={len}}}", quote(&data)))
+                })
+                .collect();
+            let more = if args[count] > IOVEC_LIMIT {
+                ", ..."
+            } else {
+                ""
+            };
+            or_pointer(entries.map(|entries| format!("[{}{more}]", entries.join(", "))))
+        }
+        OpenFlags => {
+            let access = ["O_RDONLY", "O_WRONLY", "O_RDWR", "O_ACCMODE"][value & 3];
+            match value as u64 & !3 {
+                0 => access.to_string(),
+                rest => format!("{access}|{}", flags(rest, OPEN_FLAGS)),
+            }
+        }
+        Mode => format!("0{value:o}"),
+        Whence => match value {
+            0 => "SEEK_SET".to_string(),
+            1 => "SEEK_CUR".to_string(),
+            2 => "SEEK_END".to_string(),
+            _ => value.to_string(),
+        },
+        Prot if value == 0 => "PROT_NONE".to_string(),
+        Prot => flags(value as u64, PROT_FLAGS),
+        MapFlags => flags(value as u64, MAP_FLAGS),
+        MremapFlags if value == 0 => "0".to_string(),
+        MremapFlags => flags(value as u64, MREMAP_FLAGS),
+        CloneFlags => match value & 0xff {
+            0 => flags(value as u64, CLONE_FLAGS),
+            sig => format!(
+                "{}|{}",
+                flags(value as u64 & !0xff, CLONE_FLAGS),
+                signal_name(sig as i32)
+            ),
+        },
+        FutexOp => {
+            let mut op = FUTEX_OPS
+                .get(value & 0x7f)
+                .map_or_else(|| (value & 0x7f).to_string(), |op| op.to_string());
+            if value & 128 != 0 {
+                op.push_str("_PRIVATE");
+            }
+            if value & 256 != 0 {
+                op.push_str("|FUTEX_CLOCK_REALTIME");
+            }
+            op
+        }
+        Timespec if value == 0 => pointer(),
+        Timespec => or_pointer(read(cpu, value, 16).map(|raw| {
+            let sec = i64::from_le_bytes(raw[..8].try_into().unwrap());
+            let nsec = i64::from_le_bytes(raw[8..].try_into().unwrap());
+            format!("{{tv_sec={sec}, tv_nsec={nsec}}}")
+        })),
+        Stat if result != Some(0) => pointer(),
+        Stat => or_pointer(read(cpu, value, 64).map(|raw| {
+            let mode = u32::from_le_bytes(raw[16..20].try_into().unwrap());
+            let size = i64::from_le_bytes(raw[48..56].try_into().unwrap());
+            format!("{{st_mode={}, st_size={size}}}", file_mode(mode))
+        })),
+        AtFlags if value == 0 => "0".to_string(),
+        AtFlags => flags(value as u64, AT_FLAGS),
+        Signal => signal_name(value as i32),
+        SigAction if value == 0 => pointer(),
+        SigAction => or_pointer(read(cpu, value, SigAction::SIZE).map(|raw| {
+            let action = SigAction::from_bytes(&raw);
+            let handler = match action.handler {
+                0 => "SIG_DFL".to_string(),
+                1 => "SIG_IGN".to_string(),
+                handler => format!("{handler:#x}"),
+            };
+            let sa_flags = match action.flags {
+                0 => "0".to_string(),
+                bits => flags(bits, SA_FLAGS),
+            };
+            format!(
+                "{{sa_handler={handler}, sa_mask={}, sa_flags={sa_flags}}}",
+                sigset(action.mask)
+            )
+        })),
+        SigSet if value == 0 => pointer(),
+        SigSet => or_pointer(read_u64(cpu, value).map(sigset)),
+        SigHow => match value {
+            0 => "SIG_BLOCK".to_string(),
+            1 => "SIG_UNBLOCK".to_string(),
+            2 => "SIG_SETMASK".to_string(),
+            _ => value.to_string(),
+        },
+        Stack if value == 0 => pointer(),
+        Stack => or_pointer(read(cpu, value, AltStack::SIZE).map(|raw| {
+            let stack = AltStack::from_bytes(&raw);
+            let ss_flags = match stack.flags {
+                0 => "0".to_string(),
+                bits => flags(bits as u64, SS_FLAGS),
+            };
+            format!(
+                "{{ss_sp={:#x}, ss_flags={ss_flags}, ss_size={}}}",
+                stack.sp, stack.size
+            )
+        })),
+    }
+}
+
+/// Format a call of `no` with `args` as strace does. `result` is what the guest got in `a0`,
+/// or `None` for a call that does not return.
+pub fn format_call(cpu: &Cpu, no: Sysno, args: &[usize; 7], result: Option<isize>) -> String {
+    let shown: Vec<String> = signature(no)
+        .iter()
+        .enumerate()
+        .map(|(n, &arg)| argument(cpu, arg, args, n, result))
+        .collect();
+    let ret = match result {
+        None => "?".to_string(),
+        Some(ret) if (-4095..0).contains(&ret) => {
+            let errno = -ret as i32;
+            format!("-1 {} ({})", errno_name(errno), strerror(errno))
+        }
+        Some(ret) if matches!(no, Sysno::brk | Sysno::mmap | Sysno::mremap) => {
+            format!("{ret:#x}")
+        }
+        Some(ret) => ret.to_string(),
+    };
+    format!("{}({}) = {ret}", no.name(), shown.join(", "))
+}
+
+/// Writes a line for each system call the guest makes, strace-style.
+pub struct Strace {
+    /// Calls to trace, or all of them.
+    filter: Option<HashSet<Sysno>>,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl std::fmt::Debug for Strace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Strace")
+            .field("filter", &self.filter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Strace {
+    pub fn new(out: Box<dyn Write + Send>, filter: Option<HashSet<Sysno>>) -> Self {
+        Self {
+            filter,
+            out: Mutex::new(out),
+        }
+    }
+
+    /// Look up the system calls `names`, as given to `--strace-filter`.
+    pub fn parse_filter<S: AsRef<str>>(names: &[S]) -> Result<HashSet<Sysno>, String> {
+        names
+            .iter()
+            .map(|name| {
+                let name = name.as_ref().trim();
+                Sysno::from_str(name).map_err(|_| format!("unknown system call `{name}`"))
+            })
+            .collect()
+    }
+
+    pub fn traces(&self, no: Sysno) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.contains(&no))
+    }
+
+    /// Record a call made by `cpu`'s thread; other threads than the first are tagged with
+    /// their thread ID, as `strace -f` does.
+    pub fn record(&self, cpu: &Cpu, no: Sysno, args: &[usize; 7], result: Option<isize>) {
+        if !self.traces(no) {
+            return;
+        }
+        let line = format_call(cpu, no, args, result);
+        let mut out = self.out.lock().unwrap();
+        let _ = match cpu.thread.tid == cpu.thread.group.pid() {
+            true => writeln!(out, "{line}"),
+            false => writeln!(out, "[pid {:>5}] {line}", cpu.thread.tid),
+        };
+        let _ = out.flush();
+    }
+
+    /// Record a call with a number no system call has.
+    pub fn record_unknown(&self, number: usize, args: &[usize; 7]) {
+        if self.filter.is_some() {
+            return;
+        }
+        let shown: Vec<String> = args[..6].iter().map(|arg| format!("{arg:#x}")).collect();
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(
+            out,
+            "syscall_{number:#x}({}) = -1 ENOSYS ({})",
+            shown.join(", "),
+            strerror(libc::ENOSYS)
+        );
+        let _ = out.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    #[test]
+    fn test_format_call() {
+        let mut cpu = Cpu::new(Memory::new(0..=0xfff));
+        cpu.mem.write_bytes(0x100, b"/etc/hosts\0").unwrap();
+        cpu.mem.write_bytes(0x200, b"hello\n").unwrap();
+        let args = |values: &[usize]| {
+            let mut args = [0; 7];
+            args[..values.len()].copy_from_slice(values);
+            args
+        };
+
+        let openat = args(&[-100isize as usize, 0x100, 0o2000101, 0o644]);
+        assert_eq!(
+            format_call(&cpu, Sysno::openat, &openat, Some(3)),
+            "openat(AT_FDCWD, \"/etc/hosts\", O_WRONLY|O_CREAT|O_CLOEXEC, 0644) = 3"
+        );
+        assert_eq!(
+            format_call(&cpu, Sysno::openat, &openat, Some(-2)),
+            "openat(AT_FDCWD, \"/etc/hosts\", O_WRONLY|O_CREAT|O_CLOEXEC, 0644) = \
+             -1 ENOENT (No such file or directory)"
+        );
+        assert_eq!(
+            format_call(&cpu, Sysno::write, &args(&[1, 0x200, 6]), Some(6)),
+            "write(1, \"hello\\n\", 6) = 6"
+        );
+        // Only the bytes read are shown, and a failed read shows the buffer's address.
+        assert_eq!(
+            format_call(&cpu, Sysno::read, &args(&[0, 0x200, 64]), Some(2)),
+            "read(0, \"he\", 64) = 2"
+        );
+        assert_eq!(
+            format_call(&cpu, Sysno::read, &args(&[0, 0x200, 64]), Some(-9)),
+            "read(0, 0x200, 64) = -1 EBADF (Bad file descriptor)"
+        );
+        assert_eq!(
+            format_call(&cpu, Sysno::write, &args(&[1, 0x2000, 6]), Some(-14)),
+            "write(1, 0x2000, 6) = -1 EFAULT (Bad address)"
+        );
+        assert_eq!(
+            format_call(
+                &cpu,
+                Sysno::mmap,
+                &args(&[0, 0x2000, 3, 0x22, -1isize as usize, 0]),
+                Some(0x7000)
+            ),
+            "mmap(NULL, 8192, PROT_READ|PROT_WRITE, MAP_PRIVATE|MAP_ANONYMOUS, -1, 0) = 0x7000"
+        );
+        assert_eq!(
+            format_call(
+                &cpu,
+                Sysno::mmap,
+                &args(&[0, 0x1000, 1, 2, 3, 0x1000]),
+                Some(0x7000)
+            ),
+            "mmap(NULL, 4096, PROT_READ, MAP_PRIVATE, 3, 0x1000) = 0x7000"
+        );
+
+        let action = SigAction {
+            handler: 0x1234,
+            flags: 0x0800_0004,
+            mask: 1 << (libc::SIGUSR1 - 1),
+        };
+        cpu.mem.write_bytes(0x300, &action.to_bytes()).unwrap();
+        assert_eq!(
+            format_call(
+                &cpu,
+                Sysno::rt_sigaction,
+                &args(&[11, 0x300, 0, 8]),
+                Some(0)
+            ),
+            "rt_sigaction(SIGSEGV, {sa_handler=0x1234, sa_mask=[USR1], \
+             sa_flags=SA_SIGINFO|SA_ONSTACK}, NULL, 8) = 0"
+        );
+        assert_eq!(
+            format_call(&cpu, Sysno::tgkill, &args(&[7, 8, 34]), Some(0)),
+            "tgkill(7, 8, SIGRTMIN) = 0"
+        );
+        assert_eq!(
+            format_call(&cpu, Sysno::kill, &args(&[7, 37]), Some(0)),
+            "kill(7, SIGRTMIN+3) = 0"
+        );
+        assert_eq!(
+            format_call(&cpu, Sysno::exit_group, &args(&[3]), None),
+            "exit_group(3) = ?"
+        );
+        assert_eq!(
+            format_call(&cpu, Sysno::acct, &args(&[0x100]), Some(-38)),
+            "acct(0x100, NULL, NULL, NULL, NULL, NULL) = -1 ENOSYS (Function not implemented)"
+        );
+    }
+
+    #[test]
+    fn test_filter() {
+        assert!(Strace::parse_filter(&["open", "read"]).is_err());
+        let filter = Strace::parse_filter(&["openat", " read"]).unwrap();
+        let strace = Strace::new(Box::new(std::io::sink()), Some(filter));
+        assert!(strace.traces(Sysno::read) && strace.traces(Sysno::openat));
+        assert!(!strace.traces(Sysno::write));
+    }
+}
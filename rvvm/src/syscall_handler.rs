@@ -1,10 +1,18 @@
+use std::ffi::CString;
 use std::fmt::{Display, Formatter, Result};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 
 use super::Cpu;
 use super::Generic;
 use super::OperationError;
+use super::address_space::{AddressSpace, PAGE_SIZE, Prot, page_up};
+use super::bus::BusOperation;
+use super::fd_table::HostFd;
+use super::logger::SYSCALL;
+use super::signal::{self, AltStack, SS_DISABLE, SS_ONSTACK, SigAction, UNBLOCKABLE};
 use super::syscall::Sysno;
-use colored::Colorize;
+use super::thread::{FutexWait, run_thread};
+use std::time::{Duration, Instant};
 pub struct SyscallArgs {
     no: Sysno,
     args: [usize; 7],
@@ -37,30 +45,1390 @@ impl SyscallArgs {
         self.args[n]
     }
 }
+/// Value returned to the guest in `a0`, or the errno it receives negated.
+type SysResult = std::result::Result<usize, i32>;
+
+/// Largest transfer a single `read` or `write` performs, as on Linux.
+const MAX_RW_COUNT: usize = 0x7fff_f000;
+const IOV_MAX: usize = 1024;
+const PATH_MAX: usize = 4096;
+const AT_FDCWD: i32 = -100;
+/// Size of `struct stat` on riscv64.
+const STAT_SIZE: usize = 128;
+
+const MAP_SHARED: usize = 0x01;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+const MAP_FIXED_NOREPLACE: usize = 0x10_0000;
+/// Bytes of a file `mmap` reads at a time.
+const MMAP_CHUNK: usize = 1 << 20;
+const MREMAP_MAYMOVE: usize = 1;
+const MREMAP_FIXED: usize = 2;
+const CLONE_VM: usize = 0x100;
+const CLONE_SIGHAND: usize = 0x800;
+const CLONE_THREAD: usize = 0x1_0000;
+const CLONE_SETTLS: usize = 0x8_0000;
+const CLONE_PARENT_SETTID: usize = 0x10_0000;
+const CLONE_CHILD_CLEARTID: usize = 0x20_0000;
+const CLONE_CHILD_SETTID: usize = 0x100_0000;
+/// Size of `struct clone_args` up to and including `tls`.
+const CLONE_ARGS_SIZE: usize = 64;
+const FUTEX_WAIT: usize = 0;
+const FUTEX_WAKE: usize = 1;
+const FUTEX_WAIT_BITSET: usize = 9;
+const FUTEX_WAKE_BITSET: usize = 10;
+const FUTEX_PRIVATE_FLAG: usize = 128;
+const FUTEX_CLOCK_REALTIME: usize = 256;
+/// Size of `struct robust_list_head`.
+const ROBUST_LIST_HEAD_SIZE: usize = 24;
+/// Most entries of a robust list released, so that a cyclic list cannot hang the exit.
+const ROBUST_LIST_LIMIT: usize = 2048;
+const FUTEX_WAITERS: u32 = 0x8000_0000;
+const FUTEX_OWNER_DIED: u32 = 0x4000_0000;
+const FUTEX_TID_MASK: u32 = 0x3fff_ffff;
+/// Size of the kernel's `sigset_t`.
+const SIGSET_SIZE: usize = 8;
+const SIG_BLOCK: usize = 0;
+const SIG_UNBLOCK: usize = 1;
+const SIG_SETMASK: usize = 2;
+const MINSIGSTKSZ: u64 = 2048;
+
+/// `open` flags as the riscv64 ABI numbers them, with their host equivalents.
+const OPEN_FLAGS: [(i32, i32); 13] = [
+    (0o100, libc::O_CREAT),
+    (0o200, libc::O_EXCL),
+    (0o400, libc::O_NOCTTY),
+    (0o1000, libc::O_TRUNC),
+    (0o2000, libc::O_APPEND),
+    (0o4000, libc::O_NONBLOCK),
+    (0o10000, libc::O_DSYNC),
+    (0o20000, libc::O_ASYNC),
+    (0o200000, libc::O_DIRECTORY),
+    (0o400000, libc::O_NOFOLLOW),
+    (0o1000000, libc::O_NOATIME),
+    (0o2000000, libc::O_CLOEXEC),
+    (0o4010000, libc::O_SYNC),
+];
+
+fn errno() -> i32 {
+    std::io::Error::last_os_error()
+        .raw_os_error()
+        .unwrap_or(libc::EIO)
+}
+
+/// Turn the return value of a host call into a syscall result.
+fn host_result(ret: isize) -> SysResult {
+    if ret < 0 {
+        Err(errno())
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+fn host_open_flags(flags: i32) -> i32 {
+    OPEN_FLAGS
+        .iter()
+        .filter(|&&(guest, _)| flags & guest == guest)
+        .fold(flags & libc::O_ACCMODE, |host, &(_, bit)| host | bit)
+}
+
+fn guest_bytes(cpu: &Cpu, addr: usize, len: usize) -> std::result::Result<Vec<u8>, i32> {
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+    cpu.mem.read_bytes(addr, len).map_err(|_| libc::EFAULT)
+}
+
+fn write_guest(cpu: &mut Cpu, addr: usize, data: &[u8]) -> std::result::Result<(), i32> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    cpu.mem.write_bytes(addr, data).map_err(|_| libc::EFAULT)
+}
+
+/// How many bytes from `addr`, up to `len`, the guest can store to, for a host call to fill.
+/// Only the rights are checked; the memory itself is left alone.
+fn writable_len(cpu: &mut Cpu, addr: usize, len: usize) -> usize {
+    if let Some(space) = cpu.mem.address_space() {
+        return space.granted_len(addr, len, Prot::WRITE);
+    }
+    let range = cpu.mem.address_range();
+    if !range.contains(&addr) {
+        return 0;
+    }
+    len.min((range.end() - addr).saturating_add(1))
+}
+
+/// Copy a NUL-terminated path out of guest memory.
+fn guest_path(cpu: &Cpu, addr: usize) -> std::result::Result<CString, i32> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte: u8 = cpu.mem.load(addr + bytes.len()).map_err(|_| libc::EFAULT)?;
+        if byte == 0 {
+            return Ok(CString::new(bytes).unwrap());
+        }
+        if bytes.len() == PATH_MAX {
+            return Err(libc::ENAMETOOLONG);
+        }
+        bytes.push(byte);
+    }
+}
+
+fn host_fd(cpu: &Cpu, fd: usize) -> std::result::Result<std::sync::Arc<HostFd>, i32> {
+    cpu.fds.get(fd as i32).ok_or(libc::EBADF)
+}
+
+/// Resolve the `dirfd` argument of the `*at` calls.
+fn host_dirfd(
+    cpu: &Cpu,
+    dirfd: usize,
+) -> std::result::Result<(RawFd, Option<std::sync::Arc<HostFd>>), i32> {
+    match dirfd as i32 {
+        AT_FDCWD => Ok((libc::AT_FDCWD, None)),
+        fd => {
+            let file = host_fd(cpu, fd as usize)?;
+            Ok((file.as_raw_fd(), Some(file)))
+        }
+    }
+}
+
+/// Read the `iovec` array at `addr` as `(base, len)` pairs.
+fn guest_iovecs(
+    cpu: &Cpu,
+    addr: usize,
+    count: usize,
+) -> std::result::Result<Vec<(usize, usize)>, i32> {
+    if count > IOV_MAX {
+        return Err(libc::EINVAL);
+    }
+    let raw = guest_bytes(cpu, addr, count * 16)?;
+    let word = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap()) as usize;
+    Ok(raw
+        .chunks_exact(16)
+        .map(|iov| (word(&iov[..8]), word(&iov[8..])))
+        .collect())
+}
+
+/// Lay out a host `stat` as the riscv64 `struct stat`.
+// Field widths vary between hosts, so some casts are no-ops here.
+#[allow(clippy::unnecessary_cast)]
+fn guest_stat(st: &libc::stat) -> [u8; STAT_SIZE] {
+    let fields: [(usize, u64, usize); 16] = [
+        (0, st.st_dev, 8),
+        (8, st.st_ino, 8),
+        (16, st.st_mode as u64, 4),
+        (20, st.st_nlink as u64, 4),
+        (24, st.st_uid as u64, 4),
+        (28, st.st_gid as u64, 4),
+        (32, st.st_rdev, 8),
+        (48, st.st_size as u64, 8),
+        (56, st.st_blksize as u64, 4),
+        (64, st.st_blocks as u64, 8),
+        (72, st.st_atime as u64, 8),
+        (80, st.st_atime_nsec as u64, 8),
+        (88, st.st_mtime as u64, 8),
+        (96, st.st_mtime_nsec as u64, 8),
+        (104, st.st_ctime as u64, 8),
+        (112, st.st_ctime_nsec as u64, 8),
+    ];
+    let mut buf = [0u8; STAT_SIZE];
+    for (offset, value, size) in fields {
+        buf[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+    }
+    buf
+}
+
+fn sys_openat(cpu: &mut Cpu, dirfd: usize, path: usize, flags: usize, mode: usize) -> SysResult {
+    let path = guest_path(cpu, path)?;
+    let (dirfd, _dir) = host_dirfd(cpu, dirfd)?;
+    let flags = host_open_flags(flags as i32);
+    // SAFETY: `path` is NUL-terminated and `dirfd` is kept open by `_dir`.
+    let fd = unsafe { libc::openat(dirfd, path.as_ptr(), flags, mode as libc::c_uint) };
+    host_result(fd as isize)?;
+    // SAFETY: `openat` returned a new descriptor that nothing else owns.
+    let file = HostFd::Owned(unsafe { OwnedFd::from_raw_fd(fd) });
+    cpu.fds
+        .insert(file)
+        .map(|fd| fd as usize)
+        .ok_or(libc::EMFILE)
+}
+
+fn sys_close(cpu: &mut Cpu, fd: usize) -> SysResult {
+    cpu.fds.remove(fd as i32).map(|_| 0).ok_or(libc::EBADF)
+}
+
+fn sys_read(cpu: &mut Cpu, fd: usize, buf: usize, count: usize) -> SysResult {
+    let file = host_fd(cpu, fd)?;
+    let count = count.min(MAX_RW_COUNT);
+    // Read no more than the buffer can take, faulting only if it takes nothing.
+    let count = match writable_len(cpu, buf, count) {
+        0 if count != 0 => return Err(libc::EFAULT),
+        writable => writable,
+    };
+    let mut data = vec![0u8; count];
+    // SAFETY: `data` is valid for writes of `count` bytes.
+    let read =
+        host_result(unsafe { libc::read(file.as_raw_fd(), data.as_mut_ptr().cast(), count) })?;
+    write_guest(cpu, buf, &data[..read])?;
+    Ok(read)
+}
+
+fn sys_write(cpu: &mut Cpu, fd: usize, buf: usize, count: usize) -> SysResult {
+    let file = host_fd(cpu, fd)?;
+    let data = guest_bytes(cpu, buf, count.min(MAX_RW_COUNT))?;
+    // SAFETY: `data` is valid for reads of its length.
+    host_result(unsafe { libc::write(file.as_raw_fd(), data.as_ptr().cast(), data.len()) })
+}
+
+fn sys_readv(cpu: &mut Cpu, fd: usize, iov: usize, count: usize) -> SysResult {
+    let file = host_fd(cpu, fd)?;
+    let mut iovecs = guest_iovecs(cpu, iov, count)?;
+    // Like `read`, fill the buffers only up to the first byte that cannot be stored to.
+    let mut total = 0usize;
+    for n in 0..iovecs.len() {
+        let (base, len) = iovecs[n];
+        let writable = writable_len(cpu, base, len);
+        total = total.saturating_add(writable);
+        if writable < len {
+            if total == 0 {
+                return Err(libc::EFAULT);
+            }
+            iovecs.truncate(n);
+            iovecs.push((base, writable));
+            break;
+        }
+    }
+    let mut data = vec![0u8; total.min(MAX_RW_COUNT)];
+    // SAFETY: `data` is valid for writes of its length.
+    let read =
+        host_result(unsafe { libc::read(file.as_raw_fd(), data.as_mut_ptr().cast(), data.len()) })?;
+    let mut rest = &data[..read];
+    for (base, len) in iovecs {
+        let (chunk, tail) = rest.split_at(len.min(rest.len()));
+        write_guest(cpu, base, chunk)?;
+        rest = tail;
+    }
+    Ok(read)
+}
+
+fn sys_writev(cpu: &mut Cpu, fd: usize, iov: usize, count: usize) -> SysResult {
+    let file = host_fd(cpu, fd)?;
+    // Gather into one buffer so the data reaches the host in a single write.
+    let mut data = Vec::new();
+    for (base, len) in guest_iovecs(cpu, iov, count)? {
+        let len = len.min(MAX_RW_COUNT - data.len());
+        data.extend_from_slice(&guest_bytes(cpu, base, len)?);
+    }
+    // SAFETY: `data` is valid for reads of its length.
+    host_result(unsafe { libc::write(file.as_raw_fd(), data.as_ptr().cast(), data.len()) })
+}
+
+fn sys_lseek(cpu: &mut Cpu, fd: usize, offset: usize, whence: usize) -> SysResult {
+    let file = host_fd(cpu, fd)?;
+    // SAFETY: plain call on a descriptor kept open by `file`.
+    let ret = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence as i32) };
+    host_result(ret as isize)
+}
+
+fn sys_fstat(cpu: &mut Cpu, fd: usize, statbuf: usize) -> SysResult {
+    let file = host_fd(cpu, fd)?;
+    // SAFETY: `stat` is plain data, filled in by the call.
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    host_result(unsafe { libc::fstat(file.as_raw_fd(), &mut st) } as isize)?;
+    write_guest(cpu, statbuf, &guest_stat(&st))?;
+    Ok(0)
+}
+
+fn sys_fstatat(
+    cpu: &mut Cpu,
+    dirfd: usize,
+    path: usize,
+    statbuf: usize,
+    flags: usize,
+) -> SysResult {
+    let path = guest_path(cpu, path)?;
+    let (dirfd, _dir) = host_dirfd(cpu, dirfd)?;
+    // SAFETY: `stat` is plain data, `path` is NUL-terminated and `dirfd` is kept open by `_dir`.
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    host_result(unsafe { libc::fstatat(dirfd, path.as_ptr(), &mut st, flags as i32) } as isize)?;
+    write_guest(cpu, statbuf, &guest_stat(&st))?;
+    Ok(0)
+}
+
+fn address_space(cpu: &mut Cpu) -> std::result::Result<&mut AddressSpace, i32> {
+    cpu.mem.address_space().ok_or(libc::ENOMEM)
+}
+
+/// Round a mapping length up to whole pages, refusing empty lengths.
+fn page_len(len: usize) -> std::result::Result<usize, i32> {
+    page_up(len).filter(|&len| len > 0).ok_or(libc::EINVAL)
+}
+
+fn sys_brk(cpu: &mut Cpu, addr: usize) -> SysResult {
+    Ok(cpu.mem.address_space().map_or(0, |space| space.brk(addr)))
+}
+
+/// Mappings of files are private copies. As stores to them could not reach the file, writable
+/// `MAP_SHARED` file mappings are refused with `ENODEV`, and read-only ones can never be made
+/// writable.
+fn sys_mmap(
+    cpu: &mut Cpu,
+    addr: usize,
+    len: usize,
+    prot: usize,
+    flags: usize,
+    fd: usize,
+    offset: usize,
+) -> SysResult {
+    let len = page_len(len)?;
+    if !offset.is_multiple_of(PAGE_SIZE) || flags & (MAP_SHARED | MAP_PRIVATE) == 0 {
+        return Err(libc::EINVAL);
+    }
+    let prot = Prot::from_bits(prot as u64);
+    let shared = flags & MAP_SHARED != 0 && flags & MAP_ANONYMOUS == 0;
+    if shared && prot.contains(Prot::WRITE) {
+        return Err(libc::ENODEV);
+    }
+    let contents = if flags & MAP_ANONYMOUS == 0 {
+        let file = host_fd(cpu, fd)?;
+        // Only the part of the file the mapping covers is read, so mapping a small file into a
+        // large range does not allocate the whole range.
+        let mut data = Vec::new();
+        while data.len() < len {
+            let filled = data.len();
+            data.resize(filled + (len - filled).min(MMAP_CHUNK), 0);
+            // SAFETY: the destination lies within `data`.
+            let read = host_result(unsafe {
+                libc::pread(
+                    file.as_raw_fd(),
+                    data[filled..].as_mut_ptr().cast(),
+                    data.len() - filled,
+                    (offset + filled) as libc::off_t,
+                )
+            })?;
+            data.truncate(filled + read);
+            if read == 0 {
+                break;
+            }
+        }
+        Some(data)
+    } else {
+        None
+    };
+
+    let space = address_space(cpu)?;
+    let addr = if flags & (MAP_FIXED | MAP_FIXED_NOREPLACE) != 0 {
+        if !addr.is_multiple_of(PAGE_SIZE) || addr.checked_add(len).is_none() {
+            return Err(libc::EINVAL);
+        }
+        if flags & MAP_FIXED == 0 && !space.is_free(addr, len) {
+            return Err(libc::EEXIST);
+        }
+        addr
+    } else if addr != 0 && addr.is_multiple_of(PAGE_SIZE) && space.is_free(addr, len) {
+        addr
+    } else {
+        space.find_free(len).ok_or(libc::ENOMEM)?
+    };
+    match contents {
+        Some(data) if shared => space.map_shared(addr, len, prot, &data),
+        Some(data) => space.map_with(addr, len, prot, &data),
+        None => space.map(addr, len, prot),
+    }
+    Ok(addr)
+}
+
+fn sys_munmap(cpu: &mut Cpu, addr: usize, len: usize) -> SysResult {
+    let len = page_len(len)?;
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        return Err(libc::EINVAL);
+    }
+    address_space(cpu)?.unmap(addr, len);
+    Ok(0)
+}
+
+fn sys_mprotect(cpu: &mut Cpu, addr: usize, len: usize, prot: usize) -> SysResult {
+    if !addr.is_multiple_of(PAGE_SIZE) {
+        return Err(libc::EINVAL);
+    }
+    let len = page_up(len).ok_or(libc::ENOMEM)?;
+    let prot = Prot::from_bits(prot as u64);
+    let space = address_space(cpu)?;
+    if prot.contains(Prot::WRITE) && space.is_shared(addr, len) {
+        return Err(libc::EACCES);
+    }
+    match space.protect(addr, len, prot) {
+        true => Ok(0),
+        false => Err(libc::ENOMEM),
+    }
+}
+
+/// `MREMAP_FIXED` is not supported.
+fn sys_mremap(
+    cpu: &mut Cpu,
+    old: usize,
+    old_len: usize,
+    new_len: usize,
+    flags: usize,
+) -> SysResult {
+    let new_len = page_len(new_len)?;
+    let old_len = page_up(old_len).ok_or(libc::EINVAL)?;
+    // A zero `old_len` asks Linux to duplicate a shared mapping, which is not supported.
+    if old_len == 0 || !old.is_multiple_of(PAGE_SIZE) || flags & MREMAP_FIXED != 0 {
+        return Err(libc::EINVAL);
+    }
+    let space = address_space(cpu)?;
+    if !space.is_mapped(old, old_len) {
+        return Err(libc::EFAULT);
+    }
+    space
+        .remap(old, old_len, new_len, flags & MREMAP_MAYMOVE != 0)
+        .ok_or(libc::ENOMEM)
+}
+
+fn store_u32(cpu: &mut Cpu, addr: usize, value: u32) -> std::result::Result<(), i32> {
+    BusOperation::<u32>::store(cpu.mem.as_mut(), addr, value).map_err(|_| libc::EFAULT)
+}
+
+/// Read a `struct timespec` from guest memory.
+fn guest_timespec(cpu: &Cpu, addr: usize) -> std::result::Result<Duration, i32> {
+    let raw = guest_bytes(cpu, addr, 16)?;
+    let sec = i64::from_le_bytes(raw[..8].try_into().unwrap());
+    let nsec = i64::from_le_bytes(raw[8..].try_into().unwrap());
+    if sec < 0 || !(0..1_000_000_000).contains(&nsec) {
+        return Err(libc::EINVAL);
+    }
+    Ok(Duration::new(sec as u64, nsec as u32))
+}
+
+fn host_clock(clock: libc::clockid_t) -> Duration {
+    // SAFETY: `timespec` is plain data, filled in by the call.
+    let mut now: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe { libc::clock_gettime(clock, &mut now) };
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+}
+
+/// Start a thread sharing this one's memory, file descriptors and signal handlers. Other kinds
+/// of process creation are not supported.
+fn spawn_thread(
+    cpu: &mut Cpu,
+    flags: usize,
+    stack: usize,
+    parent_tid: usize,
+    tls: usize,
+    child_tid: usize,
+) -> SysResult {
+    const THREAD: usize = CLONE_VM | CLONE_SIGHAND | CLONE_THREAD;
+    if flags & THREAD != THREAD {
+        return Err(libc::ENOSYS);
+    }
+    let mem = address_space(cpu)?.clone();
+    let mut thread = cpu.thread.spawn();
+    let tid = thread.tid;
+    if flags & CLONE_CHILD_CLEARTID != 0 {
+        thread.clear_child_tid = child_tid;
+    }
+    let mut child = cpu.spawn_thread(mem, thread);
+    // The child returns 0 from the `ecall`.
+    child.set_pc(cpu.pc + 4);
+    child.set_generic(Generic::a0, 0);
+    if stack != 0 {
+        child.set_generic(Generic::sp, stack as isize);
+    }
+    if flags & CLONE_SETTLS != 0 {
+        child.set_generic(Generic::tp, tls as isize);
+    }
+    let stores = [
+        (CLONE_PARENT_SETTID, parent_tid),
+        (CLONE_CHILD_SETTID, child_tid),
+    ];
+    for (flag, addr) in stores {
+        if flags & flag != 0
+            && let Err(errno) = store_u32(cpu, addr, tid as u32)
+        {
+            child.thread.exit(0);
+            return Err(errno);
+        }
+    }
+    let mut thread = child.thread.clone();
+    let spawned = std::thread::Builder::new()
+        .name(format!("tid {tid}"))
+        .spawn(move || run_thread(&mut child));
+    match spawned {
+        Ok(_) => Ok(tid as usize),
+        Err(_) => {
+            thread.exit(0);
+            Err(libc::EAGAIN)
+        }
+    }
+}
+
+fn sys_clone(
+    cpu: &mut Cpu,
+    flags: usize,
+    stack: usize,
+    parent_tid: usize,
+    tls: usize,
+    child_tid: usize,
+) -> SysResult {
+    spawn_thread(cpu, flags, stack, parent_tid, tls, child_tid)
+}
+
+fn sys_clone3(cpu: &mut Cpu, args: usize, size: usize) -> SysResult {
+    if size < CLONE_ARGS_SIZE {
+        return Err(libc::EINVAL);
+    }
+    let raw = guest_bytes(cpu, args, CLONE_ARGS_SIZE)?;
+    let field = |n: usize| u64::from_le_bytes(raw[n * 8..n * 8 + 8].try_into().unwrap()) as usize;
+    let (flags, child_tid, parent_tid) = (field(0), field(2), field(3));
+    let (stack, stack_size, tls) = (field(5), field(6), field(7));
+    let stack = if stack == 0 { 0 } else { stack + stack_size };
+    spawn_thread(cpu, flags, stack, parent_tid, tls, child_tid)
+}
+
+fn sys_futex(
+    cpu: &mut Cpu,
+    addr: usize,
+    op: usize,
+    val: usize,
+    timeout: usize,
+    val3: usize,
+) -> SysResult {
+    let cmd = op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME);
+    let (wait, bitset) = match cmd {
+        FUTEX_WAIT => (true, u32::MAX),
+        FUTEX_WAKE => (false, u32::MAX),
+        FUTEX_WAIT_BITSET => (true, val3 as u32),
+        FUTEX_WAKE_BITSET => (false, val3 as u32),
+        _ => return Err(libc::ENOSYS),
+    };
+    if bitset == 0 || !addr.is_multiple_of(4) {
+        return Err(libc::EINVAL);
+    }
+    let group = cpu.thread.group.clone();
+    if !wait {
+        return Ok(group.futexes.wake(addr, val as u32 as usize, bitset));
+    }
+
+    // `FUTEX_WAIT` takes a relative timeout, the bitset variant an absolute one.
+    let deadline = match timeout {
+        0 => None,
+        _ if cmd == FUTEX_WAIT => Some(Instant::now() + guest_timespec(cpu, timeout)?),
+        _ => {
+            let clock = match op & FUTEX_CLOCK_REALTIME {
+                0 => libc::CLOCK_MONOTONIC,
+                _ => libc::CLOCK_REALTIME,
+            };
+            let remaining = guest_timespec(cpu, timeout)?.saturating_sub(host_clock(clock));
+            Some(Instant::now() + remaining)
+        }
+    };
+    let mut fault = false;
+    let check = || match BusOperation::<u32>::load(cpu.mem.as_ref(), addr) {
+        Ok(current) => current == val as u32 && !group.exiting(),
+        Err(_) => {
+            fault = true;
+            false
+        }
+    };
+    match group
+        .futexes
+        .wait(&cpu.thread, addr, bitset, check, deadline)
+    {
+        _ if fault => Err(libc::EFAULT),
+        FutexWait::Woken => Ok(0),
+        FutexWait::ValueChanged => Err(libc::EAGAIN),
+        FutexWait::TimedOut => Err(libc::ETIMEDOUT),
+        FutexWait::Interrupted => Err(libc::EINTR),
+    }
+}
+
+fn sys_set_tid_address(cpu: &mut Cpu, addr: usize) -> SysResult {
+    cpu.thread.clear_child_tid = addr;
+    Ok(cpu.thread.tid as usize)
+}
+
+fn sys_set_robust_list(cpu: &mut Cpu, head: usize, len: usize) -> SysResult {
+    if len != ROBUST_LIST_HEAD_SIZE {
+        return Err(libc::EINVAL);
+    }
+    cpu.thread.robust_list = head;
+    Ok(0)
+}
+
+/// Mark the futexes on the robust list of the exiting thread that it still owns with
+/// `FUTEX_OWNER_DIED`, waking a waiter of each, so that the next owner of a robust mutex
+/// learns it was abandoned. The list is walked as far as it can be read.
+fn release_robust_list(cpu: &mut Cpu) {
+    let head = cpu.thread.robust_list;
+    let word = |cpu: &Cpu, addr: usize| {
+        guest_bytes(cpu, addr, 8).map(|raw| u64::from_le_bytes(raw.try_into().unwrap()) as usize)
+    };
+    let (Ok(mut entry), Ok(offset), Ok(pending)) = (
+        word(cpu, head),
+        word(cpu, head.wrapping_add(8)),
+        word(cpu, head.wrapping_add(16)),
+    ) else {
+        return;
+    };
+    // The low bit of an entry marks a priority-inheritance futex.
+    let futex = |entry: usize| (entry & !1).wrapping_add(offset);
+    for _ in 0..ROBUST_LIST_LIMIT {
+        if entry == head {
+            break;
+        }
+        let Ok(next) = word(cpu, entry & !1) else {
+            break;
+        };
+        if entry != pending {
+            release_robust_futex(cpu, futex(entry));
+        }
+        entry = next;
+    }
+    // A lock or unlock was under way when the thread exited.
+    if pending != 0 {
+        release_robust_futex(cpu, futex(pending));
+    }
+}
+
+fn release_robust_futex(cpu: &mut Cpu, addr: usize) {
+    let tid = cpu.thread.tid as u32;
+    loop {
+        let Ok(current) = BusOperation::<u32>::load(cpu.mem.as_ref(), addr) else {
+            return;
+        };
+        if current & FUTEX_TID_MASK != tid {
+            return;
+        }
+        let released = (current & FUTEX_WAITERS) | FUTEX_OWNER_DIED;
+        match cpu.mem.compare_exchange(addr, current, released) {
+            Ok(true) => break,
+            Ok(false) => continue,
+            Err(_) => return,
+        }
+    }
+    cpu.thread.group.futexes.wake(addr, 1, u32::MAX);
+}
+
+/// End the calling thread, releasing its robust futexes and clearing and waking its
+/// `clear_child_tid` futex for `pthread_join`.
+fn sys_exit(cpu: &mut Cpu, status: usize) {
+    release_robust_list(cpu);
+    let addr = cpu.thread.clear_child_tid;
+    if addr != 0 && store_u32(cpu, addr, 0).is_ok() {
+        cpu.thread.group.futexes.wake(addr, 1, u32::MAX);
+    }
+    cpu.thread.exit(status as i32 & 0xff);
+    cpu.running = false;
+}
+
+fn sys_exit_group(cpu: &mut Cpu, status: usize) {
+    cpu.thread.group.exit_group(status as i32 & 0xff);
+    cpu.running = false;
+}
+
+fn sys_rt_sigaction(
+    cpu: &mut Cpu,
+    sig: usize,
+    act: usize,
+    old_act: usize,
+    size: usize,
+) -> SysResult {
+    let sig = sig as i32;
+    if size != SIGSET_SIZE || !signal::valid(sig) {
+        return Err(libc::EINVAL);
+    }
+    let signals = &cpu.thread.group.signals;
+    let current = match act {
+        0 => signals.action(sig),
+        _ if signal::sigmask(sig) & UNBLOCKABLE != 0 => return Err(libc::EINVAL),
+        _ => {
+            let action = SigAction::from_bytes(&guest_bytes(cpu, act, SigAction::SIZE)?);
+            let mask = action.mask & !UNBLOCKABLE;
+            signals.set_action(sig, SigAction { mask, ..action })
+        }
+    };
+    if old_act != 0 {
+        write_guest(cpu, old_act, &current.to_bytes())?;
+    }
+    Ok(0)
+}
+
+fn sys_rt_sigprocmask(
+    cpu: &mut Cpu,
+    how: usize,
+    set: usize,
+    old_set: usize,
+    size: usize,
+) -> SysResult {
+    if size != SIGSET_SIZE {
+        return Err(libc::EINVAL);
+    }
+    let old = cpu.thread.blocked;
+    if set != 0 {
+        let set = u64::from_le_bytes(guest_bytes(cpu, set, 8)?.try_into().unwrap());
+        let blocked = match how {
+            SIG_BLOCK => old | set,
+            SIG_UNBLOCK => old & !set,
+            SIG_SETMASK => set,
+            _ => return Err(libc::EINVAL),
+        };
+        cpu.thread.blocked = blocked & !UNBLOCKABLE;
+    }
+    if old_set != 0 {
+        write_guest(cpu, old_set, &old.to_le_bytes())?;
+    }
+    Ok(0)
+}
+
+fn sys_sigaltstack(cpu: &mut Cpu, stack: usize, old_stack: usize) -> SysResult {
+    let current = cpu.thread.alt_stack;
+    let on_stack = current.contains(cpu.get_generic(Generic::sp) as u64);
+    if old_stack != 0 {
+        let flags = if on_stack { SS_ONSTACK } else { current.flags };
+        write_guest(cpu, old_stack, &AltStack { flags, ..current }.to_bytes())?;
+    }
+    if stack != 0 {
+        let new = AltStack::from_bytes(&guest_bytes(cpu, stack, AltStack::SIZE)?);
+        if on_stack {
+            return Err(libc::EPERM);
+        }
+        cpu.thread.alt_stack = match new.flags {
+            SS_DISABLE => AltStack::default(),
+            // `SS_ONSTACK` is accepted and means the same as 0, as on Linux.
+            0 | SS_ONSTACK if new.size < MINSIGSTKSZ => return Err(libc::ENOMEM),
+            0 | SS_ONSTACK => AltStack { flags: 0, ..new },
+            _ => return Err(libc::EINVAL),
+        };
+    }
+    Ok(0)
+}
+
+/// Send `sig` to the process, or to thread `tid` of it. Signal 0 only checks the target
+/// exists, and `SIGKILL` takes the process down at once, even with all its threads asleep.
+fn send_signal(cpu: &mut Cpu, tid: Option<i32>, sig: i32) -> SysResult {
+    if sig != 0 && !signal::valid(sig) {
+        return Err(libc::EINVAL);
+    }
+    let group = &cpu.thread.group;
+    if let Some(tid) = tid
+        && !group.signals.has_thread(tid)
+    {
+        return Err(libc::ESRCH);
+    }
+    match (tid, sig) {
+        (_, 0) => {}
+        (_, signal::SIGKILL) => group.exit_group(signal::kill_status(sig)),
+        (Some(tid), _) => {
+            group.signals.send_to(tid, sig);
+        }
+        (None, _) => group.signals.send(sig),
+    }
+    Ok(0)
+}
+
+/// The guest's process is the only one it can see, and its only process group.
+fn sys_kill(cpu: &mut Cpu, pid: usize, sig: usize) -> SysResult {
+    let pid = pid as i32;
+    if pid > 0 && pid != cpu.thread.group.pid() {
+        return Err(libc::ESRCH);
+    }
+    send_signal(cpu, None, sig as i32)
+}
+
+fn sys_tkill(cpu: &mut Cpu, tid: usize, sig: usize) -> SysResult {
+    match tid as i32 {
+        tid if tid <= 0 => Err(libc::EINVAL),
+        tid => send_signal(cpu, Some(tid), sig as i32),
+    }
+}
+
+fn sys_tgkill(cpu: &mut Cpu, pid: usize, tid: usize, sig: usize) -> SysResult {
+    if pid as i32 <= 0 {
+        return Err(libc::EINVAL);
+    }
+    if pid as i32 != cpu.thread.group.pid() {
+        return Err(libc::ESRCH);
+    }
+    sys_tkill(cpu, tid, sig)
+}
+
+/// Resume the context a signal handler interrupted; a corrupt frame kills the process.
+fn sys_rt_sigreturn(cpu: &mut Cpu) {
+    if !signal::restore(cpu) {
+        cpu.thread
+            .group
+            .exit_group(signal::kill_status(signal::SIGSEGV));
+        cpu.running = false;
+    }
+}
+
+/// Run the system call the guest's `ecall` requests and step past the `ecall`.
 pub fn syscall_handler(cpu: &mut Cpu) -> anyhow::Result<(), OperationError> {
+    let number = cpu.get_generic(Generic::a7) as usize;
+    if Sysno::new(number).is_none() {
+        log::info!(target: SYSCALL, "unknown system call {number}");
+        if let Some(strace) = &cpu.strace {
+            strace.record_unknown(number, &SyscallArgs::from_register(cpu).args);
+        }
+        cpu.set_generic(Generic::a0, -libc::ENOSYS as isize);
+        cpu.pc += 4;
+        return Ok(());
+    }
     let syscall = SyscallArgs::from_register(cpu);
-    let mem = &mut cpu.mem;
-    println!("{}", format!("Syscall: {}", syscall.no).blue().bold());
-    match syscall.no {
-        Sysno::write => {
-            let data = mem.read_bytes(syscall.arg(1), syscall.arg(2))?;
-            println!(
-                "    {}",
-                format!("{:?}", String::from_utf8_lossy(data)).green()
-            );
-            cpu.set_generic(Generic::a0, 0);
-        }
-        Sysno::exit => {
-            println!(
-                "    {}",
-                match syscall.arg(1) {
-                    0 => format!("exit({})", syscall.arg(0)).green(),
-                    _ => format!("exit({})", syscall.arg(0)).red(),
-                }
-            );
-            cpu.running = false;
+    // Calls that do not return are traced before they run.
+    let strace = cpu.strace.clone();
+    if let Some(strace) = &strace
+        && matches!(
+            syscall.no,
+            Sysno::exit | Sysno::exit_group | Sysno::rt_sigreturn
+        )
+    {
+        strace.record(cpu, syscall.no, &syscall.args, None);
+    }
+    log::debug!(target: SYSCALL, "tid {}: {syscall}", cpu.thread.tid);
+    let arg = |n| syscall.arg(n);
+    let result = match syscall.no {
+        Sysno::openat => sys_openat(cpu, arg(0), arg(1), arg(2), arg(3)),
+        Sysno::close => sys_close(cpu, arg(0)),
+        Sysno::read => sys_read(cpu, arg(0), arg(1), arg(2)),
+        Sysno::write => sys_write(cpu, arg(0), arg(1), arg(2)),
+        Sysno::readv => sys_readv(cpu, arg(0), arg(1), arg(2)),
+        Sysno::writev => sys_writev(cpu, arg(0), arg(1), arg(2)),
+        Sysno::lseek => sys_lseek(cpu, arg(0), arg(1), arg(2)),
+        Sysno::fstat => sys_fstat(cpu, arg(0), arg(1)),
+        Sysno::fstatat => sys_fstatat(cpu, arg(0), arg(1), arg(2), arg(3)),
+        Sysno::brk => sys_brk(cpu, arg(0)),
+        Sysno::mmap => sys_mmap(cpu, arg(0), arg(1), arg(2), arg(3), arg(4), arg(5)),
+        Sysno::munmap => sys_munmap(cpu, arg(0), arg(1)),
+        Sysno::mprotect => sys_mprotect(cpu, arg(0), arg(1), arg(2)),
+        Sysno::mremap => sys_mremap(cpu, arg(0), arg(1), arg(2), arg(3)),
+        Sysno::clone => sys_clone(cpu, arg(0), arg(1), arg(2), arg(3), arg(4)),
+        Sysno::clone3 => sys_clone3(cpu, arg(0), arg(1)),
+        Sysno::futex => sys_futex(cpu, arg(0), arg(1), arg(2), arg(3), arg(5)),
+        Sysno::set_tid_address => sys_set_tid_address(cpu, arg(0)),
+        Sysno::set_robust_list => sys_set_robust_list(cpu, arg(0), arg(1)),
+        Sysno::rt_sigaction => sys_rt_sigaction(cpu, arg(0), arg(1), arg(2), arg(3)),
+        Sysno::rt_sigprocmask => sys_rt_sigprocmask(cpu, arg(0), arg(1), arg(2), arg(3)),
+        Sysno::sigaltstack => sys_sigaltstack(cpu, arg(0), arg(1)),
+        Sysno::kill => sys_kill(cpu, arg(0), arg(1)),
+        Sysno::tkill => sys_tkill(cpu, arg(0), arg(1)),
+        Sysno::tgkill => sys_tgkill(cpu, arg(0), arg(1), arg(2)),
+        Sysno::rt_sigreturn => {
+            sys_rt_sigreturn(cpu);
+            return Ok(());
+        }
+        Sysno::gettid => Ok(cpu.thread.tid as usize),
+        Sysno::getpid => Ok(cpu.thread.group.pid() as usize),
+        Sysno::exit | Sysno::exit_group => {
+            match syscall.no {
+                Sysno::exit => sys_exit(cpu, arg(0)),
+                _ => sys_exit_group(cpu, arg(0)),
+            }
+            return Ok(());
+        }
+        _ => {
+            log::info!(target: SYSCALL, "{} is not implemented", syscall.no);
+            Err(libc::ENOSYS)
         }
-        _ => {}
+    };
+    let a0 = match result {
+        Ok(value) => value as isize,
+        Err(errno) => -(errno as isize),
+    };
+    if let Some(strace) = &strace {
+        strace.record(cpu, syscall.no, &syscall.args, Some(a0));
     }
+    cpu.set_generic(Generic::a0, a0);
+    cpu.pc += 4;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::BusOperation;
+    use crate::memory::Memory;
+
+    fn load(cpu: &Cpu, addr: usize) -> anyhow::Result<u64, OperationError> {
+        BusOperation::<u64>::load(cpu.mem.as_ref(), addr)
+    }
+
+    fn call(cpu: &mut Cpu, no: Sysno, args: &[usize]) -> isize {
+        cpu.set_generic(Generic::a7, no as isize);
+        for (n, &value) in args.iter().enumerate() {
+            cpu.set_generic(Generic::from(10 + n as u8), value as isize);
+        }
+        syscall_handler(cpu).unwrap();
+        cpu.get_generic(Generic::a0)
+    }
+
+    #[test]
+    fn test_file_io() {
+        let mut cpu = Cpu::new(Memory::new(0..=0xffff));
+        let path = std::env::temp_dir().join(format!("rvvm-fd-{}", std::process::id()));
+        let mut name = path.to_str().unwrap().as_bytes().to_vec();
+        name.push(0);
+        cpu.mem.write_bytes(0x100, &name).unwrap();
+        cpu.mem.write_bytes(0x1000, b"hello, world").unwrap();
+
+        // O_RDWR | O_CREAT | O_TRUNC
+        let fd = call(
+            &mut cpu,
+            Sysno::openat,
+            &[AT_FDCWD as usize, 0x100, 0o1102, 0o600],
+        );
+        assert_eq!(fd, 3);
+        let fd = fd as usize;
+        assert_eq!(call(&mut cpu, Sysno::write, &[fd, 0x1000, 5]), 5);
+        // Two iovecs: ", " and "world".
+        for (n, (base, len)) in [(0x1005usize, 2usize), (0x1007, 5)].into_iter().enumerate() {
+            let at = 0x2000 + n * 16;
+            cpu.mem
+                .write_bytes(at, &(base as u64).to_le_bytes())
+                .unwrap();
+            cpu.mem
+                .write_bytes(at + 8, &(len as u64).to_le_bytes())
+                .unwrap();
+        }
+        assert_eq!(call(&mut cpu, Sysno::writev, &[fd, 0x2000, 2]), 7);
+
+        assert_eq!(
+            call(&mut cpu, Sysno::lseek, &[fd, 7, libc::SEEK_SET as usize]),
+            7
+        );
+        assert_eq!(call(&mut cpu, Sysno::read, &[fd, 0x3000, 100]), 5);
+        assert_eq!(cpu.mem.read_bytes(0x3000, 5).unwrap(), b"world");
+        assert_eq!(
+            call(&mut cpu, Sysno::lseek, &[fd, 0, libc::SEEK_SET as usize]),
+            0
+        );
+        assert_eq!(call(&mut cpu, Sysno::readv, &[fd, 0x2000, 2]), 7);
+        assert_eq!(cpu.mem.read_bytes(0x1005, 7).unwrap(), b"hello, ");
+
+        assert_eq!(call(&mut cpu, Sysno::fstat, &[fd, 0x4000]), 0);
+        let field = |offset: usize, size: usize| {
+            let mut word = [0u8; 8];
+            word[..size].copy_from_slice(&cpu.mem.read_bytes(0x4000 + offset, size).unwrap());
+            u64::from_le_bytes(word)
+        };
+        assert_eq!(field(48, 8), 12);
+        assert_eq!(field(16, 4) as u32 & libc::S_IFMT, libc::S_IFREG);
+        assert_eq!(field(16, 4) & 0o777, 0o600);
+
+        assert_eq!(call(&mut cpu, Sysno::close, &[fd]), 0);
+        assert_eq!(call(&mut cpu, Sysno::close, &[fd]), -libc::EBADF as isize);
+        assert_eq!(
+            call(&mut cpu, Sysno::read, &[fd, 0x3000, 1]),
+            -libc::EBADF as isize
+        );
+        std::fs::remove_file(&path).unwrap();
+        let at = [AT_FDCWD as usize, 0x100, 0, 0];
+        assert_eq!(call(&mut cpu, Sysno::openat, &at), -libc::ENOENT as isize);
+    }
+
+    #[test]
+    fn test_memory_management() {
+        let space = AddressSpace::new(0x1000_0000);
+        space.map(0x1_0000, 0x1000, Prot::READ | Prot::WRITE);
+        space.set_brk_start(0x1_1000);
+        let mut cpu = Cpu::new(space);
+        let rw = (libc::PROT_READ | libc::PROT_WRITE) as usize;
+        let anonymous = MAP_PRIVATE | MAP_ANONYMOUS;
+
+        assert_eq!(call(&mut cpu, Sysno::brk, &[0]), 0x1_1000);
+        assert_eq!(call(&mut cpu, Sysno::brk, &[0x1_3000]), 0x1_3000);
+        cpu.mem.store(0x1_2ff8, 1u64).unwrap();
+
+        let addr = call(
+            &mut cpu,
+            Sysno::mmap,
+            &[0, 0x2001, rw, anonymous, usize::MAX, 0],
+        ) as usize;
+        assert_eq!(addr, 0x1000_0000 - 0x3000);
+        cpu.mem.store(addr + 0x2ff8, 2u64).unwrap();
+        assert_eq!(
+            call(
+                &mut cpu,
+                Sysno::mprotect,
+                &[addr, 0x3000, libc::PROT_READ as usize]
+            ),
+            0
+        );
+        assert!(cpu.mem.store(addr, 3u64).is_err());
+        assert_eq!(load(&cpu, addr + 0x2ff8).unwrap(), 2);
+        // A read-only buffer cannot receive data.
+        assert_eq!(
+            call(&mut cpu, Sysno::read, &[0, addr, 1]),
+            -libc::EFAULT as isize
+        );
+
+        let moved = call(
+            &mut cpu,
+            Sysno::mremap,
+            &[addr, 0x3000, 0x8000, MREMAP_MAYMOVE, 0],
+        );
+        assert_eq!(load(&cpu, moved as usize + 0x2ff8).unwrap(), 2);
+        assert_eq!(call(&mut cpu, Sysno::munmap, &[moved as usize, 0x8000]), 0);
+        assert!(load(&cpu, moved as usize).is_err());
+
+        let fixed = MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED_NOREPLACE;
+        assert_eq!(
+            call(&mut cpu, Sysno::mmap, &[0x1_0000, 0x1000, rw, fixed, 0, 0]),
+            -libc::EEXIST as isize
+        );
+        assert_eq!(
+            call(&mut cpu, Sysno::mmap, &[0x1234, 0x1000, rw, fixed, 0, 0]),
+            -libc::EINVAL as isize
+        );
+        assert_eq!(
+            call(&mut cpu, Sysno::mprotect, &[0x5_0000, 0x1000, 0]),
+            -libc::ENOMEM as isize
+        );
+        assert_eq!(
+            call(&mut cpu, Sysno::mremap, &[0x5_0000, 0x1000, 0x2000, 1, 0]),
+            -libc::EFAULT as isize
+        );
+        assert_eq!(
+            call(&mut cpu, Sysno::mremap, &[0x1_0000, 0, 0x1000, 1, 0]),
+            -libc::EINVAL as isize
+        );
+
+        // A file mapping copies the file, zero-filling past its end.
+        let path = std::env::temp_dir().join(format!("rvvm-mmap-{}", std::process::id()));
+        std::fs::write(&path, vec![7u8; 0x1800]).unwrap();
+        let mut name = path.to_str().unwrap().as_bytes().to_vec();
+        name.push(0);
+        cpu.mem.write_bytes(0x1_0000, &name).unwrap();
+        let fd = call(
+            &mut cpu,
+            Sysno::openat,
+            &[AT_FDCWD as usize, 0x1_0000, 0, 0],
+        ) as usize;
+        let prot = libc::PROT_READ as usize;
+        let addr = call(
+            &mut cpu,
+            Sysno::mmap,
+            &[0, 0x1000, prot, MAP_PRIVATE, fd, 0x1000],
+        ) as usize;
+        assert_eq!(cpu.mem.read_bytes(addr + 0x7ff, 2).unwrap(), [7, 0]);
+        // Stores to a shared file mapping could not reach the file, so it cannot be writable.
+        let shared = call(&mut cpu, Sysno::mmap, &[0, 0x1000, prot, MAP_SHARED, fd, 0]) as usize;
+        assert_eq!(cpu.mem.read_bytes(shared, 1).unwrap(), [7]);
+        assert_eq!(
+            call(&mut cpu, Sysno::mprotect, &[shared, 0x1000, rw]),
+            -libc::EACCES as isize
+        );
+        assert_eq!(
+            call(&mut cpu, Sysno::mmap, &[0, 0x1000, rw, MAP_SHARED, fd, 0]),
+            -libc::ENODEV as isize
+        );
+
+        // A read buffer need only be writable, and the read stops where the buffer does.
+        let wo = libc::PROT_WRITE as usize;
+        let buffer = call(
+            &mut cpu,
+            Sysno::mmap,
+            &[0, 0x2000, wo, anonymous, -1isize as usize, 0],
+        ) as usize;
+        assert_eq!(call(&mut cpu, Sysno::munmap, &[buffer + 0x1000, 0x1000]), 0);
+        assert_eq!(
+            call(&mut cpu, Sysno::read, &[fd, buffer + 0xff0, 0x100]),
+            0x10
+        );
+        assert_eq!(call(&mut cpu, Sysno::mprotect, &[buffer, 0x1000, prot]), 0);
+        assert_eq!(cpu.mem.read_bytes(buffer + 0xff0, 0x10).unwrap(), [7; 0x10]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_threads() {
+        let space = AddressSpace::new(0x1000_0000);
+        space.map(0x1_0000, 0x1000, Prot::READ | Prot::EXEC);
+        space.map(0x2_0000, 0x1000, Prot::READ | Prot::WRITE);
+        // The child resumes after the parent's `ecall`: it stores 42 through `tp` and exits.
+        let child: [u32; 4] = [0x02a0_0293, 0x0052_2023, 0x05d0_0893, 0x0000_0073];
+        let code: Vec<u8> = [0x0000_0073u32]
+            .iter()
+            .chain(&child)
+            .flat_map(|op| op.to_le_bytes())
+            .collect();
+        space.map_with(0x1_0000, 0x1000, Prot::READ | Prot::EXEC, &code);
+        let mut cpu = Cpu::new(space);
+        cpu.set_pc(0x1_0000);
+
+        let (data, parent_tid, child_tid) = (0x2_0000, 0x2_0100, 0x2_0104);
+        let flags = (libc::CLONE_VM
+            | libc::CLONE_FS
+            | libc::CLONE_FILES
+            | libc::CLONE_SIGHAND
+            | libc::CLONE_THREAD
+            | libc::CLONE_SYSVSEM
+            | libc::CLONE_SETTLS
+            | libc::CLONE_PARENT_SETTID
+            | libc::CLONE_CHILD_SETTID
+            | libc::CLONE_CHILD_CLEARTID) as usize;
+        let tid = call(
+            &mut cpu,
+            Sysno::clone,
+            &[flags, 0, parent_tid, data, child_tid],
+        );
+        assert_eq!(tid, cpu.thread.tid as isize + 1);
+        assert_eq!(
+            BusOperation::<u32>::load(cpu.mem.as_ref(), parent_tid).unwrap(),
+            tid as u32
+        );
+
+        // Join the child the way pthread_join does.
+        loop {
+            let current = BusOperation::<u32>::load(cpu.mem.as_ref(), child_tid).unwrap();
+            if current == 0 {
+                break;
+            }
+            let ret = call(
+                &mut cpu,
+                Sysno::futex,
+                &[child_tid, FUTEX_WAIT, current as usize, 0],
+            );
+            assert!(ret == 0 || ret == -libc::EAGAIN as isize);
+        }
+        assert_eq!(load(&cpu, data).unwrap(), 42);
+        assert_eq!(cpu.thread.group.status(), None);
+
+        assert_eq!(
+            call(&mut cpu, Sysno::set_tid_address, &[0x2_0200]),
+            cpu.thread.tid as isize
+        );
+        assert_eq!(call(&mut cpu, Sysno::futex, &[data, FUTEX_WAKE, 1]), 0);
+        assert_eq!(
+            call(&mut cpu, Sysno::futex, &[data, FUTEX_WAIT, 0, 0]),
+            -libc::EAGAIN as isize
+        );
+        let timeout = 0x2_0300;
+        cpu.mem
+            .write_bytes(timeout + 8, &1_000_000u64.to_le_bytes())
+            .unwrap();
+        let op = FUTEX_WAIT | FUTEX_PRIVATE_FLAG;
+        assert_eq!(
+            call(&mut cpu, Sysno::futex, &[data, op, 42, timeout]),
+            -libc::ETIMEDOUT as isize
+        );
+        assert_eq!(
+            call(&mut cpu, Sysno::clone, &[0x11, 0, 0, 0, 0]),
+            -libc::ENOSYS as isize
+        );
+
+        // The robust list holds a mutex the thread owns, while another thread's is pending.
+        let (head, owned, other) = (0x2_0400, 0x2_0440, 0x2_0480);
+        for (addr, value) in [
+            (head, owned),
+            (head + 8, 0x10),
+            (head + 16, other),
+            (owned, head),
+            (
+                owned + 0x10,
+                cpu.thread.tid as usize | FUTEX_WAITERS as usize,
+            ),
+            (other + 0x10, cpu.thread.tid as usize + 1),
+        ] {
+            cpu.mem.store(addr, value as u64).unwrap();
+        }
+        assert_eq!(
+            call(&mut cpu, Sysno::set_robust_list, &[head, 16]),
+            -libc::EINVAL as isize
+        );
+        assert_eq!(
+            call(
+                &mut cpu,
+                Sysno::set_robust_list,
+                &[head, ROBUST_LIST_HEAD_SIZE]
+            ),
+            0
+        );
+
+        BusOperation::<u32>::store(cpu.mem.as_mut(), 0x2_0200, 1).unwrap();
+        call(&mut cpu, Sysno::exit, &[3]);
+        assert!(!cpu.running);
+        assert_eq!(load(&cpu, 0x2_0200).unwrap() as u32, 0);
+        assert_eq!(
+            load(&cpu, owned + 0x10).unwrap() as u32,
+            FUTEX_WAITERS | FUTEX_OWNER_DIED
+        );
+        assert_eq!(load(&cpu, other + 0x10).unwrap(), cpu.thread.tid as u64 + 1);
+        assert_eq!(cpu.thread.group.wait(), 3);
+    }
+
+    #[test]
+    fn test_signals() {
+        use crate::signal::{SA_ONSTACK, SIGILL, SIGSEGV, sigmask};
+        let space = AddressSpace::new(0x1000_0000);
+        space.map(0x2_0000, 0x1_0000, Prot::READ | Prot::WRITE);
+        signal::map_trampoline(&space);
+        // The main code faults on a load from 0, then hits an illegal instruction. The handler
+        // steps the saved pc past the faulting instruction and returns.
+        let main: [u32; 2] = [0x0000_3283, 0x0000_0000];
+        let handler: [u32; 4] = [0x0b06_3303, 0x0043_0313, 0x0a66_3823, 0x0000_8067];
+        let mut code: Vec<u8> = main.iter().flat_map(|op| op.to_le_bytes()).collect();
+        code.resize(0x100, 0);
+        code.extend(handler.iter().flat_map(|op| op.to_le_bytes()));
+        space.map_with(0x1_0000, 0x1000, Prot::READ | Prot::EXEC, &code);
+        let mut cpu = Cpu::new(space.clone());
+
+        let (act, old) = (0x2_0000, 0x2_0020);
+        let action = SigAction {
+            handler: 0x1_0100,
+            ..SigAction::default()
+        };
+        cpu.mem.write_bytes(act, &action.to_bytes()).unwrap();
+        assert_eq!(
+            call(
+                &mut cpu,
+                Sysno::rt_sigaction,
+                &[SIGSEGV as usize, act, old, 8]
+            ),
+            0
+        );
+        assert_eq!(load(&cpu, old).unwrap(), signal::SIG_DFL);
+        assert_eq!(cpu.thread.group.signals.action(SIGSEGV), action);
+
+        cpu.set_pc(0x1_0000);
+        cpu.set_generic(Generic::sp, 0x2_8000);
+        cpu.set_generic(Generic::a0, 5);
+        cpu.set_generic(Generic::ra, 0x1234);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 0x1_0100);
+        assert_eq!(cpu.get_generic(Generic::a0), SIGSEGV as isize);
+        assert_eq!(
+            cpu.get_generic(Generic::ra),
+            signal::SIGRETURN_TRAMPOLINE as isize
+        );
+        let frame = cpu.get_generic(Generic::sp) as usize;
+        assert!(frame < 0x2_8000 && frame.is_multiple_of(16));
+        assert_eq!(cpu.get_generic(Generic::a1), frame as isize);
+        // siginfo: SIGSEGV, SEGV_MAPERR, at address 0.
+        let info = cpu.mem.read_bytes(frame, 24).unwrap();
+        assert_eq!(&info[..12], &[11, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(&info[16..], &[0; 8]);
+        assert_eq!(cpu.thread.blocked, sigmask(SIGSEGV));
+
+        // Four handler instructions, then `li a7, 139; ecall` in the trampoline.
+        for _ in 0..6 {
+            cpu.tick().unwrap();
+        }
+        assert_eq!(cpu.pc, 0x1_0004);
+        assert_eq!(cpu.get_generic(Generic::sp), 0x2_8000);
+        assert_eq!(cpu.get_generic(Generic::a0), 5);
+        assert_eq!(cpu.get_generic(Generic::ra), 0x1234);
+        assert_eq!(cpu.thread.blocked, 0);
+
+        // A blocked signal waits until it is unblocked, then runs on the alternate stack.
+        let usr1 = libc::SIGUSR1 as usize;
+        let action = SigAction {
+            handler: 0x1_0100,
+            flags: SA_ONSTACK,
+            mask: 0,
+        };
+        cpu.mem.write_bytes(act, &action.to_bytes()).unwrap();
+        assert_eq!(call(&mut cpu, Sysno::rt_sigaction, &[usr1, act, 0, 8]), 0);
+        let stack = AltStack {
+            sp: 0x2_c000,
+            flags: 0,
+            size: 0x2000,
+        };
+        cpu.mem.write_bytes(act, &stack.to_bytes()).unwrap();
+        assert_eq!(call(&mut cpu, Sysno::sigaltstack, &[act, 0]), 0);
+        cpu.mem
+            .write_bytes(act, &sigmask(libc::SIGUSR1).to_le_bytes())
+            .unwrap();
+        assert_eq!(
+            call(&mut cpu, Sysno::rt_sigprocmask, &[SIG_BLOCK, act, 0, 8]),
+            0
+        );
+        let (pid, tid) = (cpu.thread.group.pid() as usize, cpu.thread.tid as usize);
+        assert_eq!(call(&mut cpu, Sysno::tgkill, &[pid, tid, usr1]), 0);
+        assert!(!cpu.thread.has_pending());
+        assert_eq!(
+            call(&mut cpu, Sysno::rt_sigprocmask, &[SIG_UNBLOCK, act, old, 8]),
+            0
+        );
+        assert_eq!(load(&cpu, old).unwrap(), sigmask(libc::SIGUSR1));
+        cpu.set_pc(0x1_0004);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, 0x1_0100);
+        assert_eq!(cpu.get_generic(Generic::a0), libc::SIGUSR1 as isize);
+        let frame = cpu.get_generic(Generic::sp) as usize;
+        assert!((0x2_c000..0x2_e000).contains(&frame));
+        assert_eq!(
+            call(&mut cpu, Sysno::sigaltstack, &[act, 0]),
+            -libc::EPERM as isize
+        );
+
+        assert_eq!(
+            call(
+                &mut cpu,
+                Sysno::rt_sigaction,
+                &[libc::SIGKILL as usize, act, 0, 8]
+            ),
+            -libc::EINVAL as isize
+        );
+        assert_eq!(
+            call(&mut cpu, Sysno::rt_sigaction, &[usr1, 0, 0, 4]),
+            -libc::EINVAL as isize
+        );
+        assert_eq!(
+            call(&mut cpu, Sysno::rt_sigprocmask, &[7, act, 0, 8]),
+            -libc::EINVAL as isize
+        );
+        assert_eq!(
+            call(&mut cpu, Sysno::kill, &[pid + 1, usr1]),
+            -libc::ESRCH as isize
+        );
+        assert_eq!(
+            call(&mut cpu, Sysno::tgkill, &[pid, tid + 1, usr1]),
+            -libc::ESRCH as isize
+        );
+        assert_eq!(call(&mut cpu, Sysno::kill, &[pid, 0]), 0);
+
+        // A thread asleep on a futex returns `EINTR` once a signal is sent to it.
+        let word = 0x2_1000;
+        let mut sleeper = cpu.spawn_thread(space, cpu.thread.spawn());
+        let sleeper_tid = sleeper.thread.tid as usize;
+        let sleeper =
+            std::thread::spawn(move || call(&mut sleeper, Sysno::futex, &[word, FUTEX_WAIT, 0, 0]));
+        std::thread::sleep(Duration::from_millis(10));
+        let usr2 = libc::SIGUSR2 as usize;
+        assert_eq!(call(&mut cpu, Sysno::tgkill, &[pid, sleeper_tid, usr2]), 0);
+        assert_eq!(sleeper.join().unwrap(), -libc::EINTR as isize);
+
+        // An illegal instruction without a handler kills the process.
+        cpu.set_pc(0x1_0004);
+        assert!(matches!(
+            cpu.tick(),
+            Err(OperationError::IllegalInstruction(0, 0x1_0004))
+        ));
+        assert_eq!(cpu.thread.group.status(), Some(128 + SIGILL));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut cpu = Cpu::new(Memory::new(0..=0xfff));
+        assert_eq!(
+            call(&mut cpu, Sysno::write, &[1, 0x2000, 4]),
+            -libc::EFAULT as isize
+        );
+        assert_eq!(call(&mut cpu, Sysno::write, &[1, 0, 0]), 0);
+        assert_eq!(
+            call(&mut cpu, Sysno::writev, &[1, 0, 2000]),
+            -libc::EINVAL as isize
+        );
+        assert_eq!(
+            call(&mut cpu, Sysno::fstat, &[77, 0]),
+            -libc::EBADF as isize
+        );
+        assert_eq!(call(&mut cpu, Sysno::acct, &[]), -libc::ENOSYS as isize);
+        cpu.set_generic(Generic::a7, 10_000);
+        syscall_handler(&mut cpu).unwrap();
+        assert_eq!(cpu.get_generic(Generic::a0), -libc::ENOSYS as isize);
+    }
+}
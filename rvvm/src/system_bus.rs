@@ -0,0 +1,351 @@
+use super::bus::{Bus, BusOperation};
+use super::devices::MmioDevice;
+use super::error::OperationError;
+use super::logger::DEVICES;
+use core::ops::RangeInclusive;
+use std::sync::{Arc, RwLock};
+
+fn overlaps(a: &RangeInclusive<usize>, b: &RangeInclusive<usize>) -> bool {
+    a.start() <= b.end() && b.start() <= a.end()
+}
+
+#[derive(Default)]
+struct Map {
+    /// Ranges backed by RAM regions, kept so devices cannot be mapped over them.
+    memory: Vec<RangeInclusive<usize>>,
+    devices: Vec<(RangeInclusive<usize>, Arc<dyn MmioDevice>)>,
+}
+
+impl Map {
+    fn reserve(&self, range: &RangeInclusive<usize>) -> anyhow::Result<()> {
+        let taken = self
+            .memory
+            .iter()
+            .chain(self.devices.iter().map(|(range, _)| range))
+            .find(|taken| overlaps(taken, range));
+        match taken {
+            Some(taken) => anyhow::bail!(
+                "{:#x}..={:#x} overlaps {:#x}..={:#x}",
+                range.start(),
+                range.end(),
+                taken.start(),
+                taken.end()
+            ),
+            None => Ok(()),
+        }
+    }
+
+    fn device(&self, addr: usize, size: usize) -> Option<(Arc<dyn MmioDevice>, u64)> {
+        let (range, device) = self
+            .devices
+            .iter()
+            .find(|(range, _)| range.contains(&addr))?;
+        if addr.checked_add(size - 1)? > *range.end() {
+            return None;
+        }
+        Some((device.clone(), (addr - range.start()) as u64))
+    }
+}
+
+/// A handle for mapping MMIO devices into a [`SystemBus`], usable after the bus has been handed
+/// to a hart.
+#[derive(Clone, Default)]
+pub struct DeviceMap {
+    map: Arc<RwLock<Map>>,
+}
+
+impl DeviceMap {
+    /// Map `device` at `base`, covering `size` bytes.
+    pub fn map(&self, base: usize, size: usize, device: Arc<dyn MmioDevice>) -> anyhow::Result<()> {
+        let Some(end) = size.checked_sub(1).and_then(|last| base.checked_add(last)) else {
+            anyhow::bail!("empty or wrapping device window at {:#x}", base);
+        };
+        let range = base..=end;
+        let mut map = self.map.write().unwrap();
+        map.reserve(&range)?;
+        map.devices.push((range, device));
+        Ok(())
+    }
+
+    /// Remove the device mapped at `base`, returning it.
+    pub fn unmap(&self, base: usize) -> Option<Arc<dyn MmioDevice>> {
+        let mut map = self.map.write().unwrap();
+        let index = map
+            .devices
+            .iter()
+            .position(|(range, _)| *range.start() == base)?;
+        Some(map.devices.remove(index).1)
+    }
+}
+
+/// The physical address map: RAM regions and MMIO devices behind a single [`Bus`].
+///
+/// Accesses are dispatched by address range. Addresses that hit neither report access faults,
+/// as do accesses that straddle the end of a region or that a device rejects.
+pub struct SystemBus {
+    regions: Vec<Box<dyn Bus>>,
+    devices: DeviceMap,
+    /// Span from the lowest to the highest RAM address; `0..=0` until a region is added.
+    range: RangeInclusive<usize>,
+}
+
+impl SystemBus {
+    pub fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+            devices: DeviceMap::default(),
+            range: 0..=0,
+        }
+    }
+
+    /// Add a RAM or ROM region at its own [`Bus::address_range`].
+    pub fn add_memory(&mut self, region: impl Bus + 'static) -> anyhow::Result<()> {
+        let range = region.address_range().clone();
+        let mut map = self.devices.map.write().unwrap();
+        map.reserve(&range)?;
+        map.memory.push(range.clone());
+        self.range = match self.regions.is_empty() {
+            true => range,
+            false => *self.range.start().min(range.start())..=*self.range.end().max(range.end()),
+        };
+        self.regions.push(Box::new(region));
+        Ok(())
+    }
+
+    /// Map `device` at `base`, covering `size` bytes.
+    pub fn add_device(
+        &mut self,
+        base: usize,
+        size: usize,
+        device: Arc<dyn MmioDevice>,
+    ) -> anyhow::Result<()> {
+        self.devices.map(base, size, device)
+    }
+
+    /// A handle for mapping devices once the bus is owned by a hart.
+    pub fn devices(&self) -> DeviceMap {
+        self.devices.clone()
+    }
+
+    #[inline]
+    fn region(&self, addr: usize) -> Option<&dyn Bus> {
+        self.regions
+            .iter()
+            .find(|region| region.address_range().contains(&addr))
+            .map(|region| region.as_ref())
+    }
+
+    #[inline]
+    fn region_mut(&mut self, addr: usize) -> Option<&mut Box<dyn Bus>> {
+        self.regions
+            .iter_mut()
+            .find(|region| region.address_range().contains(&addr))
+    }
+
+    fn device_read(&self, addr: usize, size: usize) -> Option<u64> {
+        let value = self
+            .devices
+            .map
+            .read()
+            .unwrap()
+            .device(addr, size)
+            .and_then(|(device, offset)| device.read(offset, size));
+        if value.is_none() {
+            log::debug!(target: DEVICES, "no device answers a {size}-byte load at {addr:#x}");
+        }
+        value
+    }
+
+    fn device_write(&self, addr: usize, size: usize, value: u64) -> Option<()> {
+        let done = self
+            .devices
+            .map
+            .read()
+            .unwrap()
+            .device(addr, size)
+            .and_then(|(device, offset)| device.write(offset, size, value));
+        if done.is_none() {
+            log::debug!(target: DEVICES, "no device answers a {size}-byte store at {addr:#x}");
+        }
+        done
+    }
+}
+
+impl Default for SystemBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for SystemBus {
+    /// Copy `data` to the start of the lowest RAM region.
+    fn init_from(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let start = *self.range.start();
+        Ok(self.write_bytes(start, data)?)
+    }
+    fn address_range(&self) -> &RangeInclusive<usize> {
+        &self.range
+    }
+    fn read_bytes(&self, addr: usize, len: usize) -> anyhow::Result<Vec<u8>, OperationError> {
+        self.region(addr)
+            .ok_or(OperationError::LoadAddressFault(addr))?
+            .read_bytes(addr, len)
+    }
+    fn write_bytes(&mut self, addr: usize, data: &[u8]) -> anyhow::Result<(), OperationError> {
+        self.region_mut(addr)
+            .ok_or(OperationError::StoreAddressFault(addr))?
+            .write_bytes(addr, data)
+    }
+}
+
+macro_rules! impl_system_bus_operation {
+    ($($ty:ty),+) => {$(
+        impl BusOperation<$ty> for SystemBus {
+            fn load(&self, addr: usize) -> anyhow::Result<$ty, OperationError> {
+                match self.region(addr) {
+                    Some(region) => region.load(addr),
+                    None => self
+                        .device_read(addr, size_of::<$ty>())
+                        .map(|value| value as $ty)
+                        .ok_or(OperationError::LoadAddressFault(addr)),
+                }
+            }
+            fn store(&mut self, addr: usize, data: $ty) -> anyhow::Result<(), OperationError> {
+                match self.region_mut(addr) {
+                    Some(region) => region.store(addr, data),
+                    None => self
+                        .device_write(addr, size_of::<$ty>(), data as u64)
+                        .ok_or(OperationError::StoreAddressFault(addr)),
+                }
+            }
+            fn fetch_update(
+                &mut self,
+                addr: usize,
+                f: &mut dyn FnMut($ty) -> $ty,
+            ) -> anyhow::Result<$ty, OperationError> {
+                match self.region_mut(addr) {
+                    Some(region) => region.fetch_update(addr, f),
+                    None => {
+                        let fault = || OperationError::StoreAddressFault(addr);
+                        let old = self.device_read(addr, size_of::<$ty>()).ok_or_else(fault)? as $ty;
+                        self.device_write(addr, size_of::<$ty>(), f(old) as u64).ok_or_else(fault)?;
+                        Ok(old)
+                    }
+                }
+            }
+            fn compare_exchange(
+                &mut self,
+                addr: usize,
+                current: $ty,
+                new: $ty,
+            ) -> anyhow::Result<bool, OperationError> {
+                match self.region_mut(addr) {
+                    Some(region) => region.compare_exchange(addr, current, new),
+                    // Page tables cannot live in device memory.
+                    None => Err(OperationError::StoreAddressFault(addr)),
+                }
+            }
+        }
+    )+};
+}
+
+impl_system_bus_operation!(u8, u16, u32, u64, usize);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use std::sync::Mutex;
+
+    /// A device recording every access.
+    #[derive(Default)]
+    struct Probe(Mutex<Vec<(u64, usize, Option<u64>)>>);
+
+    impl MmioDevice for Probe {
+        fn read(&self, offset: u64, size: usize) -> Option<u64> {
+            self.0.lock().unwrap().push((offset, size, None));
+            (offset < 0x8).then_some(0x1122_3344_5566_7788)
+        }
+        fn write(&self, offset: u64, size: usize, value: u64) -> Option<()> {
+            self.0.lock().unwrap().push((offset, size, Some(value)));
+            Some(())
+        }
+    }
+
+    fn bus() -> (SystemBus, Arc<Probe>) {
+        let mut bus = SystemBus::new();
+        bus.add_memory(Memory::new(0x8000_0000..=0x8000_0fff))
+            .unwrap();
+        bus.add_memory(Memory::new(0x1000..=0x1fff)).unwrap();
+        let probe = Arc::new(Probe::default());
+        bus.add_device(0x1000_0000, 0x100, probe.clone()).unwrap();
+        (bus, probe)
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let (mut bus, probe) = bus();
+        assert_eq!(*bus.address_range(), 0x1000..=0x8000_0fff);
+        BusOperation::<u32>::store(&mut bus, 0x8000_0010, 0xdead_beef).unwrap();
+        BusOperation::<u16>::store(&mut bus, 0x1ffe, 0x55aa).unwrap();
+        assert_eq!(
+            BusOperation::<u32>::load(&bus, 0x8000_0010).unwrap(),
+            0xdead_beef
+        );
+        assert_eq!(BusOperation::<u16>::load(&bus, 0x1ffe).unwrap(), 0x55aa);
+
+        assert_eq!(BusOperation::<u8>::load(&bus, 0x1000_0000).unwrap(), 0x88);
+        BusOperation::<u32>::store(&mut bus, 0x1000_0004, 7).unwrap();
+        assert_eq!(*probe.0.lock().unwrap(), [(0, 1, None), (4, 4, Some(7))]);
+
+        bus.init_from(&[1, 2, 3]).unwrap();
+        assert_eq!(bus.read_bytes(0x1000, 3).unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_access_faults() {
+        let (mut bus, _) = bus();
+        assert!(matches!(
+            BusOperation::<u64>::load(&bus, 0x4000),
+            Err(OperationError::LoadAddressFault(0x4000))
+        ));
+        assert!(matches!(
+            BusOperation::<u8>::store(&mut bus, 0x2000, 0),
+            Err(OperationError::StoreAddressFault(0x2000))
+        ));
+        // Rejected by the device, and straddling the end of its window.
+        assert!(matches!(
+            BusOperation::<u64>::load(&bus, 0x1000_0008),
+            Err(OperationError::LoadAddressFault(0x1000_0008))
+        ));
+        assert!(matches!(
+            BusOperation::<u64>::load(&bus, 0x1000_00fc),
+            Err(OperationError::LoadAddressFault(0x1000_00fc))
+        ));
+        assert!(matches!(
+            BusOperation::<u32>::compare_exchange(&mut bus, 0x1000_0000, 0, 1),
+            Err(OperationError::StoreAddressFault(0x1000_0000))
+        ));
+    }
+
+    #[test]
+    fn test_runtime_registration() {
+        let (bus, _) = bus();
+        let devices = bus.devices();
+        let mut cpu = crate::cpu::Cpu::new(bus);
+
+        let late = Arc::new(Probe::default());
+        assert!(devices.map(0x8000_0800, 0x100, late.clone()).is_err());
+        assert!(devices.map(0x1000_0080, 0x100, late.clone()).is_err());
+        assert!(devices.map(0x2000_0000, 0, late.clone()).is_err());
+        devices.map(0x2000_0000, 0x100, late.clone()).unwrap();
+        BusOperation::<u64>::store(cpu.mem.as_mut(), 0x2000_0008, 3).unwrap();
+        assert_eq!(*late.0.lock().unwrap(), [(8, 8, Some(3))]);
+
+        assert!(devices.unmap(0x2000_0000).is_some());
+        assert!(matches!(
+            BusOperation::<u64>::load(cpu.mem.as_ref(), 0x2000_0000),
+            Err(OperationError::LoadAddressFault(0x2000_0000))
+        ));
+    }
+}
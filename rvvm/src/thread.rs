@@ -0,0 +1,435 @@
+use super::cpu::{Cpu, RunOutcome};
+use super::signal::{AltStack, Signals, ThreadSignals, sigmask};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+/// Exit status of a process taken down by an exception no thread handled, as if by `SIGSEGV`.
+pub const FAULT_STATUS: i32 = 128 + libc::SIGSEGV;
+/// Exit status of a process whose thread ran out of instructions, as if by `SIGXCPU`.
+pub const LIMIT_STATUS: i32 = 128 + libc::SIGXCPU;
+
+/// How a futex wait ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexWait {
+    Woken,
+    /// The futex word no longer held the expected value, so the thread did not sleep.
+    ValueChanged,
+    TimedOut,
+    /// A signal the thread does not block arrived first.
+    Interrupted,
+}
+
+#[derive(Debug)]
+pub(crate) struct Waiter {
+    bitset: u32,
+    woken: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl Waiter {
+    /// Make the sleeping thread look for pending signals, without waking it as a futex would.
+    pub(crate) fn interrupt(&self) {
+        let _woken = self.woken.lock().unwrap();
+        self.wake.notify_one();
+    }
+}
+
+/// The futex wait queues of a process, keyed by guest address.
+#[derive(Debug, Default)]
+pub struct Futexes {
+    queues: Mutex<HashMap<usize, Vec<Arc<Waiter>>>>,
+}
+
+impl Futexes {
+    /// Sleep on `addr` until woken through a bit of `bitset`, until `deadline` or until a signal
+    /// `thread` does not block is pending. `check` runs with the queues locked and must read the
+    /// futex word, so a wake issued after the guest changed the word cannot be missed; the
+    /// thread only sleeps if it returns `true`.
+    pub fn wait(
+        &self,
+        thread: &Thread,
+        addr: usize,
+        bitset: u32,
+        check: impl FnOnce() -> bool,
+        deadline: Option<Instant>,
+    ) -> FutexWait {
+        let waiter = Arc::new(Waiter {
+            bitset,
+            woken: Mutex::new(false),
+            wake: Condvar::new(),
+        });
+        {
+            let mut queues = self.queues.lock().unwrap();
+            if !check() {
+                return FutexWait::ValueChanged;
+            }
+            queues.entry(addr).or_default().push(waiter.clone());
+        }
+
+        // Signals sent from here on interrupt the waiter; those sent before are seen below.
+        *thread.signals.sleep.lock().unwrap() = Some(waiter.clone());
+        let mut woken = waiter.woken.lock().unwrap();
+        let mut interrupted = false;
+        while !*woken {
+            if thread.has_pending() {
+                interrupted = true;
+                break;
+            }
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    woken = waiter.wake.wait_timeout(woken, deadline - now).unwrap().0;
+                }
+                None => woken = waiter.wake.wait(woken).unwrap(),
+            }
+        }
+        drop(woken);
+        *thread.signals.sleep.lock().unwrap() = None;
+        if *waiter.woken.lock().unwrap() {
+            return FutexWait::Woken;
+        }
+
+        // Timed out or interrupted; a wake may still have raced in before the waiter left the
+        // queue.
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(queue) = queues.get_mut(&addr) {
+            queue.retain(|other| !Arc::ptr_eq(other, &waiter));
+            if queue.is_empty() {
+                queues.remove(&addr);
+            }
+        }
+        match *waiter.woken.lock().unwrap() {
+            true => FutexWait::Woken,
+            false if interrupted => FutexWait::Interrupted,
+            false => FutexWait::TimedOut,
+        }
+    }
+
+    /// Wake up to `count` threads waiting on `addr` through a bit of `bitset`, oldest first.
+    /// Returns how many were woken.
+    pub fn wake(&self, addr: usize, count: usize, bitset: u32) -> usize {
+        let mut queues = self.queues.lock().unwrap();
+        let Some(queue) = queues.get_mut(&addr) else {
+            return 0;
+        };
+        let mut woken = 0;
+        queue.retain(|waiter| {
+            if woken == count || waiter.bitset & bitset == 0 {
+                return true;
+            }
+            woken += 1;
+            Self::notify(waiter);
+            false
+        });
+        if queue.is_empty() {
+            queues.remove(&addr);
+        }
+        woken
+    }
+
+    fn wake_all(&self) {
+        let mut queues = self.queues.lock().unwrap();
+        for waiter in queues.drain().flat_map(|(_, queue)| queue) {
+            Self::notify(&waiter);
+        }
+    }
+
+    fn notify(waiter: &Waiter) {
+        *waiter.woken.lock().unwrap() = true;
+        waiter.wake.notify_one();
+    }
+}
+
+#[derive(Debug, Default)]
+struct GroupState {
+    live: usize,
+    status: Option<i32>,
+}
+
+/// The threads of one user-mode process.
+#[derive(Debug)]
+pub struct ThreadGroup {
+    pid: i32,
+    next_tid: AtomicI32,
+    state: Mutex<GroupState>,
+    done: Condvar,
+    /// Set once the process is exiting, for the harts to notice between instructions.
+    exiting: AtomicBool,
+    pub futexes: Futexes,
+    pub signals: Signals,
+}
+
+impl ThreadGroup {
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// Whether the process is exiting; its threads stop at the next instruction.
+    #[inline]
+    pub fn exiting(&self) -> bool {
+        self.exiting.load(Ordering::Acquire)
+    }
+
+    /// End the process with `status`, unless it is already exiting, stopping all its threads.
+    pub fn exit_group(&self, status: i32) {
+        let mut state = self.state.lock().unwrap();
+        state.status.get_or_insert(status);
+        self.exiting.store(true, Ordering::Release);
+        self.done.notify_all();
+        drop(state);
+        self.futexes.wake_all();
+    }
+
+    /// Account for a thread that exited with `status`; the last one ends the process.
+    fn exit_thread(&self, status: i32) {
+        let mut state = self.state.lock().unwrap();
+        state.live -= 1;
+        if state.live == 0 {
+            drop(state);
+            self.exit_group(status);
+        }
+    }
+
+    /// Block until the process has exited and return its exit status.
+    pub fn wait(&self) -> i32 {
+        let state = self.state.lock().unwrap();
+        let state = self
+            .done
+            .wait_while(state, |state| state.status.is_none())
+            .unwrap();
+        state.status.unwrap()
+    }
+
+    /// Exit status, once the process has exited.
+    pub fn status(&self) -> Option<i32> {
+        self.state.lock().unwrap().status
+    }
+}
+
+/// One thread of a user-mode process.
+#[derive(Debug, Clone)]
+pub struct Thread {
+    pub tid: i32,
+    /// Address zeroed and woken as a futex when the thread exits, from `set_tid_address` or
+    /// `CLONE_CHILD_CLEARTID`.
+    pub clear_child_tid: usize,
+    /// Head of the thread's robust futex list from `set_robust_list`, released when it exits.
+    pub robust_list: usize,
+    pub group: Arc<ThreadGroup>,
+    /// Signals the thread blocks.
+    pub blocked: u64,
+    pub alt_stack: AltStack,
+    /// Signals sent to this thread alone, and the futex wait they interrupt.
+    signals: Arc<ThreadSignals>,
+    exit_status: Option<i32>,
+}
+
+impl Thread {
+    /// The initial thread of a new process, whose thread ID is the process ID.
+    pub fn main() -> Self {
+        let pid = std::process::id() as i32;
+        let group = ThreadGroup {
+            pid,
+            next_tid: AtomicI32::new(pid + 1),
+            state: Mutex::new(GroupState {
+                live: 1,
+                status: None,
+            }),
+            done: Condvar::new(),
+            exiting: AtomicBool::new(false),
+            futexes: Futexes::default(),
+            signals: Signals::default(),
+        };
+        Self::new(pid, Arc::new(group), 0)
+    }
+
+    fn new(tid: i32, group: Arc<ThreadGroup>, blocked: u64) -> Self {
+        let signals = Arc::new(ThreadSignals::default());
+        group.signals.register(tid, signals.clone());
+        Self {
+            tid,
+            clear_child_tid: 0,
+            robust_list: 0,
+            group,
+            blocked,
+            alt_stack: AltStack::default(),
+            signals,
+            exit_status: None,
+        }
+    }
+
+    /// A new thread in the same process, blocking the same signals.
+    pub fn spawn(&self) -> Self {
+        self.group.state.lock().unwrap().live += 1;
+        let tid = self.group.next_tid.fetch_add(1, Ordering::Relaxed);
+        Self::new(tid, self.group.clone(), self.blocked)
+    }
+
+    /// Leave the process with `status`.
+    pub fn exit(&mut self, status: i32) {
+        self.exit_status = Some(status);
+        self.group.signals.unregister(self.tid);
+        self.group.exit_thread(status);
+    }
+
+    /// Status the thread passed to [`Thread::exit`], once it has exited.
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
+    }
+
+    /// Whether a signal the thread does not block is pending for it or its process.
+    #[inline]
+    pub fn has_pending(&self) -> bool {
+        (self.signals.pending.load(Ordering::Acquire) | self.group.signals.process_pending())
+            & !self.blocked
+            != 0
+    }
+
+    /// Dequeue the lowest-numbered signal the thread does not block, preferring those sent to
+    /// the thread itself, which are reported with `true`.
+    pub fn take_pending(&self) -> Option<(i32, bool)> {
+        loop {
+            let own = self.signals.pending.load(Ordering::Acquire) & !self.blocked;
+            if own != 0 {
+                let sig = own.trailing_zeros() as i32 + 1;
+                self.signals
+                    .pending
+                    .fetch_and(!sigmask(sig), Ordering::AcqRel);
+                return Some((sig, true));
+            }
+            let shared = self.group.signals.process_pending() & !self.blocked;
+            if shared == 0 {
+                return None;
+            }
+            let sig = shared.trailing_zeros() as i32 + 1;
+            if self.group.signals.claim(sig) {
+                return Some((sig, false));
+            }
+        }
+    }
+}
+
+impl Default for Thread {
+    fn default() -> Self {
+        Self::main()
+    }
+}
+
+/// Run the hart of a guest thread until the thread exits. A thread stopped by an error no
+/// signal accounts for, or by its instruction limit, takes the whole process down.
+pub fn run_thread(cpu: &mut Cpu) -> RunOutcome {
+    let outcome = cpu.run();
+    match outcome {
+        RunOutcome::Fault { .. } | RunOutcome::Breakpoint { .. } => {
+            cpu.thread.group.exit_group(FAULT_STATUS)
+        }
+        RunOutcome::InstructionLimit => cpu.thread.group.exit_group(LIMIT_STATUS),
+        _ => {}
+    }
+    outcome
+}
+
+/// Run the initial thread of a process and wait for the process to exit, returning its status.
+pub fn run_process(cpu: &mut Cpu) -> i32 {
+    run_thread(cpu);
+    cpu.thread.group.wait()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicU32;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_futex_wait_and_wake() {
+        let futexes = Arc::new(Futexes::default());
+        let word = Arc::new(AtomicU32::new(0));
+        let thread = Thread::main();
+        assert_eq!(
+            futexes.wait(
+                &thread,
+                0x100,
+                !0,
+                || word.load(Ordering::SeqCst) == 1,
+                None
+            ),
+            FutexWait::ValueChanged
+        );
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert_eq!(
+            futexes.wait(&thread, 0x100, !0, || true, Some(deadline)),
+            FutexWait::TimedOut
+        );
+        assert_eq!(futexes.wake(0x100, 1, !0), 0);
+
+        let waiters: Vec<_> = (0..3)
+            .map(|n| {
+                let (futexes, word) = (futexes.clone(), word.clone());
+                thread::spawn(move || {
+                    // Waiter 2 only listens on bit 1.
+                    let bitset = if n == 2 { 0b10 } else { 0b01 };
+                    let thread = Thread::main();
+                    futexes.wait(
+                        &thread,
+                        0x100,
+                        bitset,
+                        || word.load(Ordering::SeqCst) == 0,
+                        None,
+                    )
+                })
+            })
+            .collect();
+        while futexes
+            .queues
+            .lock()
+            .unwrap()
+            .get(&0x100)
+            .map_or(0, Vec::len)
+            < 3
+        {
+            thread::yield_now();
+        }
+        word.store(1, Ordering::SeqCst);
+        assert_eq!(futexes.wake(0x200, 5, !0), 0);
+        assert_eq!(futexes.wake(0x100, 5, 0b01), 2);
+        assert_eq!(futexes.wake(0x100, 5, !0), 1);
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap(), FutexWait::Woken);
+        }
+    }
+
+    #[test]
+    fn test_thread_group_exit() {
+        let mut main = Thread::main();
+        assert_eq!(main.tid, main.group.pid());
+        let mut worker = main.spawn();
+        assert_eq!(worker.tid, main.tid + 1);
+
+        main.exit(3);
+        assert!(!main.group.exiting());
+        assert_eq!(main.group.status(), None);
+        worker.exit(5);
+        assert!(main.group.exiting());
+        assert_eq!(main.group.wait(), 5);
+
+        // exit_group wakes sleeping threads and keeps the first status.
+        let main = Thread::main();
+        let group = main.group.clone();
+        let sleeper =
+            thread::spawn(move || group.futexes.wait(&Thread::main(), 0, !0, || true, None));
+        while main.group.futexes.queues.lock().unwrap().is_empty() {
+            thread::yield_now();
+        }
+        main.group.exit_group(7);
+        main.group.exit_group(9);
+        assert_eq!(sleeper.join().unwrap(), FutexWait::Woken);
+        assert_eq!(main.group.wait(), 7);
+    }
+}
@@ -0,0 +1,50 @@
+use super::csr::Privilege;
+use super::error::OperationError;
+
+/// Synchronous exception causes, as written to `mcause`/`scause`.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreMisaligned = 6,
+    StoreAccessFault = 7,
+    UserEcall = 8,
+    SupervisorEcall = 9,
+    MachineEcall = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+impl Exception {
+    /// The architectural exception and trap value raised by `err` at privilege `prv`, or `None`
+    /// if the error is internal to the emulator.
+    pub fn from_error(err: &OperationError, prv: Privilege) -> Option<(Self, u64)> {
+        let (exception, tval) = match *err {
+            OperationError::InstructionAccessFault(addr) => (Self::InstructionAccessFault, addr),
+            OperationError::LoadAddressFault(addr) => (Self::LoadAccessFault, addr),
+            OperationError::StoreAddressFault(addr) => (Self::StoreAccessFault, addr),
+            OperationError::InstructionPageFault(addr) => (Self::InstructionPageFault, addr),
+            OperationError::LoadPageFault(addr) => (Self::LoadPageFault, addr),
+            OperationError::StorePageFault(addr) => (Self::StorePageFault, addr),
+            OperationError::LoadMisaligned(addr) => (Self::LoadMisaligned, addr),
+            OperationError::StoreMisaligned(addr) => (Self::StoreMisaligned, addr),
+            OperationError::IllegalInstruction(bits, _) => {
+                (Self::IllegalInstruction, bits as usize)
+            }
+            OperationError::Breakpoint(pc) => (Self::Breakpoint, pc),
+            OperationError::EnvironmentCall(_) => match prv {
+                Privilege::User => (Self::UserEcall, 0),
+                Privilege::Supervisor => (Self::SupervisorEcall, 0),
+                Privilege::Machine => (Self::MachineEcall, 0),
+            },
+            _ => return None,
+        };
+        Some((exception, tval as u64))
+    }
+}